@@ -20,6 +20,8 @@ fn get_two_sites() {
 }
 // ANCHOR_END: get_two_sites
 
+// 这里只是为了演示 `join!`，并不真的下载。第 9 章的 `final_tcp_server::client` 只支持
+// 明文 HTTP，下载不了上面这些 https 地址，第 1 章的例子也不应该依赖第 9 章的代码。
 async fn download_async(_url: &str) {
     // ...
 }
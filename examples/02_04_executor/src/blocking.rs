@@ -0,0 +1,262 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+/// 全局阻塞线程池最多同时存在的线程数。
+const DEFAULT_MAX_THREADS: usize = 64;
+
+/// 空闲线程在没有新工作时存活的时间，超时后线程退出。
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// 在全局阻塞线程池上运行 `f`，并返回一个在 `f` 完成后就绪的期物。
+///
+/// 文件读写这类阻塞调用不应直接出现在 `async` 函数中：它们会占住执行器的线程，
+/// 让同一线程上的其他任务都无法推进。把它们交给这里的线程池即可。
+pub fn spawn_blocking<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    static POOL: OnceLock<BlockingPool> = OnceLock::new();
+    POOL.get_or_init(|| BlockingPool::new(DEFAULT_MAX_THREADS, DEFAULT_KEEP_ALIVE))
+        .spawn(f)
+}
+
+/// 弹性的阻塞线程池：有工作而没有空闲线程时按需创建线程，线程空闲超过
+/// `keep_alive` 后自动退出。
+#[derive(Clone)]
+pub struct BlockingPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    state: Mutex<PoolState>,
+    /// 有新工作入队时通知空闲线程。
+    condvar: Condvar,
+    max_threads: usize,
+    keep_alive: Duration,
+}
+
+struct PoolState {
+    queue: VecDeque<Job>,
+    /// 当前存活的线程数。
+    threads: usize,
+    /// 其中正在等待工作的线程数。
+    idle: usize,
+}
+
+impl BlockingPool {
+    pub fn new(max_threads: usize, keep_alive: Duration) -> Self {
+        assert!(max_threads > 0, "线程池至少需要一个线程");
+        BlockingPool {
+            inner: Arc::new(PoolInner {
+                state: Mutex::new(PoolState {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    idle: 0,
+                }),
+                condvar: Condvar::new(),
+                max_threads,
+                keep_alive,
+            }),
+        }
+    }
+
+    /// 在线程池上运行 `f`。
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(JoinState {
+            result: None,
+            waker: None,
+        }));
+        let job_shared = shared.clone();
+        let job: Job = Box::new(move || {
+            // 捕获恐慌，这样工作线程可以继续服务后面的工作，
+            // 恐慌则在 `JoinHandle` 被等待时重新抛出。
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let mut state = job_shared.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake()
+            }
+        });
+
+        let mut state = self.inner.state.lock().unwrap();
+        state.queue.push_back(job);
+        // 被通知的空闲线程要等醒来之后才会把 `idle` 减一，所以不能只看 `idle > 0`：
+        // 排队的工作比空闲线程多时，多出来的工作需要新的线程。
+        if state.queue.len() > state.idle && state.threads < self.inner.max_threads {
+            state.threads += 1;
+            let inner = self.inner.clone();
+            thread::Builder::new()
+                .name("blocking-worker".into())
+                .spawn(move || inner.work())
+                .expect("failed to spawn blocking worker thread");
+        } else if state.idle > 0 {
+            self.inner.condvar.notify_one();
+        }
+        // 否则所有线程都在忙，工作会留在队列中，等到某个线程空闲下来。
+
+        JoinHandle { shared }
+    }
+
+    /// 当前存活的工作线程数。
+    pub fn thread_count(&self) -> usize {
+        self.inner.state.lock().unwrap().threads
+    }
+
+    #[cfg(test)]
+    fn idle_count(&self) -> usize {
+        self.inner.state.lock().unwrap().idle
+    }
+}
+
+impl PoolInner {
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            state.idle += 1;
+            let (guard, timeout) = self
+                .condvar
+                .wait_timeout_while(state, self.keep_alive, |state| state.queue.is_empty())
+                .unwrap();
+            state = guard;
+            state.idle -= 1;
+            if timeout.timed_out() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+struct JoinState<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// `spawn_blocking` 返回的期物，在闭包运行结束后产出它的返回值。
+///
+/// 如果闭包发生了恐慌，恐慌会在等待该期物时重新抛出。
+/// 丢弃 `JoinHandle` 并不会取消已经提交的闭包。
+pub struct JoinHandle<T> {
+    shared: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.shared.lock().unwrap();
+        match state.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(payload)) => panic::resume_unwind(payload),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_executor_and_spawner;
+    use futures::executor::block_on;
+    use std::{sync::mpsc, time::Instant};
+
+    /// 等到 `condition` 成立，最多等 10 秒。线程什么时候进入空闲、什么时候退出都取决于调度，
+    /// 在繁忙的机器上可能比 `keep_alive` 慢得多，所以不能只睡一段固定的时间。
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    #[test]
+    fn runs_closure_off_the_executor_thread() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (tx, rx) = mpsc::channel();
+        spawner.spawn(async move {
+            let executor_thread = thread::current().id();
+            let worker_thread = spawn_blocking(|| thread::current().id()).await;
+            tx.send(executor_thread != worker_thread).unwrap();
+        });
+        drop(spawner);
+        executor.run();
+        assert!(rx.recv().unwrap());
+    }
+
+    #[test]
+    fn grows_up_to_max_threads_and_shrinks_when_idle() {
+        let pool = BlockingPool::new(2, Duration::from_millis(50));
+        // 放行之前所有工作都卡在这里，线程不会因为空闲而提前退出。
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let gate = gate.clone();
+                pool.spawn(move || {
+                    drop(gate.lock().unwrap());
+                    i
+                })
+            })
+            .collect();
+        assert_eq!(pool.thread_count(), 2);
+        drop(closed);
+        let results: Vec<_> = handles.into_iter().map(block_on).collect();
+        assert_eq!(results, vec![0, 1, 2, 3]);
+
+        assert!(eventually(|| pool.thread_count() == 0));
+    }
+
+    #[test]
+    fn burst_of_jobs_does_not_pile_onto_one_idle_thread() {
+        let pool = BlockingPool::new(4, Duration::from_secs(10));
+        block_on(pool.spawn(|| ()));
+        // 等唯一的线程进入空闲状态。
+        assert!(eventually(|| pool.idle_count() == 1));
+        assert_eq!(pool.thread_count(), 1);
+
+        let handles: Vec<_> = (0..3)
+            .map(|_| pool.spawn(|| thread::sleep(Duration::from_millis(20))))
+            .collect();
+        assert_eq!(pool.thread_count(), 3);
+        handles.into_iter().for_each(block_on);
+    }
+
+    #[test]
+    fn propagates_panics_to_the_awaiting_task() {
+        let pool = BlockingPool::new(1, Duration::from_millis(50));
+        let handle = pool.spawn(|| panic!("boom"));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| block_on(handle)));
+        assert!(result.is_err());
+
+        // 恐慌之后工作线程仍然可用。
+        assert_eq!(block_on(pool.spawn(|| 42)), 42);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    panic::Location,
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
+
+use crate::instrumented::TaskWaker;
+
+/// 调试模式下，执行器对一个挂起任务的观察结果。
+#[derive(Debug, Clone)]
+pub struct TaskReport {
+    /// 生成该任务的代码位置（由 `#[track_caller]` 捕获）。
+    pub spawned_at: &'static Location<'static>,
+    /// 该任务最后一次被轮询的时间。
+    pub last_polled: Instant,
+    /// 该任务一共被轮询了多少次。
+    pub polls: u64,
+    /// 是否仍有唤醒器持有该任务。
+    ///
+    /// 为 `false` 时，任务最后一次轮询时拿到的唤醒器已经全部被丢弃，它永远不会再被唤醒了。
+    pub waker_alive: bool,
+}
+
+struct Record {
+    /// 任务最后一次轮询时拿到的唤醒器。这里只持有弱引用，以免调试模式本身让任务
+    /// “看起来”还有唤醒器。
+    waker: Weak<TaskWaker>,
+    spawned_at: &'static Location<'static>,
+    last_polled: Instant,
+    polls: u64,
+}
+
+/// 记录挂起任务的状态，供 `Executor::with_stall_detector` 使用。
+///
+/// 只有执行器的线程会访问它，但它和 `Spawner` 放在一起共享，所以需要 `Mutex`。
+#[derive(Default)]
+pub(crate) struct StallDetector {
+    records: Mutex<HashMap<u64, Record>>,
+}
+
+impl StallDetector {
+    /// 在编号为 `id` 的任务返回 `Poll::Pending` 后调用，`waker` 是这次轮询交给它的唤醒器。
+    pub(crate) fn record_pending(
+        &self,
+        id: u64,
+        spawned_at: &'static Location<'static>,
+        waker: &Arc<TaskWaker>,
+    ) {
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(id).or_insert_with(|| Record {
+            waker: Weak::new(),
+            spawned_at,
+            last_polled: Instant::now(),
+            polls: 0,
+        });
+        record.waker = Arc::downgrade(waker);
+        record.last_polled = Instant::now();
+        record.polls += 1;
+
+        // 此时 `waker` 是唯一的强引用，期物在轮询期间也没有唤醒过自己：
+        // 任务不在队列中，也没有人能再把它放回队列，它永远不会再被轮询了。
+        if Arc::strong_count(waker) == 1 && !waker.woken() {
+            eprintln!(
+                "警告：生成于 {spawned_at} 的任务返回了 `Poll::Pending`，但没有保留任何唤醒器，它永远不会再被唤醒"
+            );
+        }
+    }
+
+    /// 在任务完成后调用。
+    pub(crate) fn record_completed(&self, id: u64) {
+        self.records.lock().unwrap().remove(&id);
+    }
+
+    pub(crate) fn reports(&self) -> Vec<TaskReport> {
+        let mut reports: Vec<_> = self
+            .records
+            .lock()
+            .unwrap()
+            .values()
+            .map(|record| TaskReport {
+                spawned_at: record.spawned_at,
+                last_polled: record.last_polled,
+                polls: record.polls,
+                waker_alive: record.waker.strong_count() > 0,
+            })
+            .collect();
+        reports.sort_by_key(|report| report.last_polled);
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instrumented::new_executor_and_spawner;
+    use futures::channel::oneshot;
+    use std::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    /// 返回 `Pending` 却不保存唤醒器的错误期物。
+    struct ForgetsWaker;
+
+    impl Future for ForgetsWaker {
+        type Output = ();
+        fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn reports_task_without_waker() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let executor = executor.with_stall_detector();
+        let line = line!() + 1;
+        spawner.spawn(ForgetsWaker);
+        drop(spawner);
+
+        // 任务被丢弃后，它持有的发送端也随之消失，所以 `run` 会返回而不是挂起。
+        executor.run();
+
+        let reports = executor.pending_tasks();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].spawned_at.file(), file!());
+        assert_eq!(reports[0].spawned_at.line(), line);
+        assert_eq!(reports[0].polls, 1);
+        assert!(!reports[0].waker_alive);
+    }
+
+    #[test]
+    fn keeps_track_of_tasks_with_live_wakers() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let executor = executor.with_stall_detector();
+        let (tx, rx) = oneshot::channel::<()>();
+        spawner.spawn(async {
+            let _ = rx.await;
+        });
+        spawner.spawn(async move {
+            let _ = tx.send(());
+        });
+        drop(spawner);
+        executor.run();
+
+        // 第一个任务被第二个任务唤醒后完成，因此不应留下任何记录。
+        assert!(executor.pending_tasks().is_empty());
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(ForgetsWaker);
+        drop(spawner);
+        executor.run();
+        assert!(executor.pending_tasks().is_empty());
+    }
+}
@@ -0,0 +1,288 @@
+//! 异步文件 I/O。
+//!
+//! 默认情况下，所有阻塞的文件操作都交给 [`spawn_blocking`] 的线程池完成；
+//! 在 Linux 上开启 `io-uring` 特性后，读取会改为通过 io_uring 提交给内核。
+//! 两种后端都使用带偏移量的读取，文件位置由 [`File`] 自己维护。
+
+use std::{
+    fs,
+    io::{self, SeekFrom},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures::{
+    future::BoxFuture,
+    io::{AsyncRead, AsyncReadExt, AsyncSeek},
+    FutureExt,
+};
+
+use crate::spawn_blocking;
+
+/// 单次读取至少向底层请求的字节数，避免调用者用很小的缓冲区读取时频繁地提交操作。
+const MIN_READ: usize = 8 * 1024;
+
+/// 单次读取最多向底层请求的字节数。
+const MAX_READ: usize = 64 * 1024;
+
+/// 读取整个文件的内容。
+pub async fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path).await?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents).await?;
+    Ok(contents)
+}
+
+/// 以字符串形式读取整个文件的内容。
+pub async fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    let mut file = File::open(path).await?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).await?;
+    Ok(contents)
+}
+
+/// 以只读方式打开的异步文件。
+///
+/// `File` 实现了 [`AsyncRead`] 和 [`AsyncSeek`]，因此可以使用 [`AsyncReadExt`] 中的
+/// `read_to_end`、`read_to_string` 等方法。
+pub struct File {
+    std: Arc<fs::File>,
+    /// 下一次向底层读取时的偏移量。
+    pos: u64,
+    /// 已经读上来、但还没有交给调用者的数据。
+    buf: Vec<u8>,
+    buf_pos: usize,
+    state: State,
+}
+
+enum State {
+    Idle,
+    /// 一次正在进行中的读取。
+    Reading(BoxFuture<'static, (io::Result<usize>, Vec<u8>)>),
+    /// 为了 `SeekFrom::End` 正在查询文件长度。
+    Seeking(BoxFuture<'static, io::Result<u64>>),
+}
+
+impl File {
+    /// 打开 `path` 处的文件。
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<File> {
+        let path = path.as_ref().to_owned();
+        let std = spawn_blocking(move || fs::File::open(path)).await?;
+        Ok(File {
+            std: Arc::new(std),
+            pos: 0,
+            buf: Vec::new(),
+            buf_pos: 0,
+            state: State::Idle,
+        })
+    }
+
+    /// 查询文件的元数据。
+    pub async fn metadata(&self) -> io::Result<fs::Metadata> {
+        let std = self.std.clone();
+        spawn_blocking(move || std.metadata()).await
+    }
+
+    /// 调用者视角下的当前位置：底层偏移量减去还没交出去的缓冲数据。
+    fn position(&self) -> u64 {
+        self.pos - (self.buf.len() - self.buf_pos) as u64
+    }
+
+    /// 等待正在进行中的读取完成，并把读到的数据放进缓冲区。
+    fn poll_pending_read(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Reading(read) = &mut self.state {
+            let (result, mut data) = ready!(read.poll_unpin(cx));
+            self.state = State::Idle;
+            let n = result?;
+            data.truncate(n);
+            self.pos += n as u64;
+            self.buf = data;
+            self.buf_pos = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for File {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.buf_pos < this.buf.len() {
+                let n = out.len().min(this.buf.len() - this.buf_pos);
+                out[..n].copy_from_slice(&this.buf[this.buf_pos..this.buf_pos + n]);
+                this.buf_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            match &this.state {
+                State::Idle => {
+                    if out.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let len = out.len().clamp(MIN_READ, MAX_READ);
+                    let read = read_at(this.std.clone(), vec![0; len], this.pos);
+                    this.state = State::Reading(read);
+                }
+                State::Reading(_) => {
+                    ready!(this.poll_pending_read(cx))?;
+                    if this.buf.is_empty() {
+                        // 读到了文件末尾。
+                        return Poll::Ready(Ok(0));
+                    }
+                }
+                // 上一次定位在完成前就被放弃了，它不会改变位置，直接丢掉即可。
+                State::Seeking(_) => this.state = State::Idle,
+            }
+        }
+    }
+}
+
+impl AsyncSeek for File {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        // 正在进行的读取无法撤回，只能等它完成后丢掉多读的数据。
+        ready!(this.poll_pending_read(cx))?;
+
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (this.position(), offset),
+            SeekFrom::End(offset) => {
+                if !matches!(this.state, State::Seeking(_)) {
+                    let std = this.std.clone();
+                    let len = spawn_blocking(move || std.metadata().map(|m| m.len()));
+                    this.state = State::Seeking(len.boxed());
+                }
+                let State::Seeking(seeking) = &mut this.state else {
+                    unreachable!()
+                };
+                let len = ready!(seeking.poll_unpin(cx));
+                this.state = State::Idle;
+                (len?, offset)
+            }
+        };
+
+        let new_pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        this.pos = new_pos;
+        this.buf.clear();
+        this.buf_pos = 0;
+        Poll::Ready(Ok(new_pos))
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn read_at(
+    file: Arc<fs::File>,
+    buf: Vec<u8>,
+    offset: u64,
+) -> BoxFuture<'static, (io::Result<usize>, Vec<u8>)> {
+    crate::uring::read_at(file, buf, offset).boxed()
+}
+
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+fn read_at(
+    file: Arc<fs::File>,
+    mut buf: Vec<u8>,
+    offset: u64,
+) -> BoxFuture<'static, (io::Result<usize>, Vec<u8>)> {
+    spawn_blocking(move || {
+        #[cfg(unix)]
+        let result = std::os::unix::fs::FileExt::read_at(&*file, &mut buf, offset);
+        #[cfg(windows)]
+        let result = std::os::windows::fs::FileExt::seek_read(&*file, &mut buf, offset);
+        #[cfg(not(any(unix, windows)))]
+        let result = {
+            use std::io::{Read, Seek};
+            // 没有按偏移量读取的系统调用，只能先移动共享的文件位置再读。
+            // 加锁保证同一时刻只有一个读取在移动文件位置。
+            static SEEK_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+            let _guard = SEEK_LOCK.lock().unwrap();
+            (&*file)
+                .seek(SeekFrom::Start(offset))
+                .and_then(|_| (&*file).read(&mut buf))
+        };
+        (result, buf)
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::AsyncSeekExt};
+    use std::path::PathBuf;
+
+    /// 在临时目录中写入一个测试文件，并在离开作用域时删除它。
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> TempFile {
+            let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn reads_whole_file() {
+        let contents: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        let file = TempFile::new("reads_whole_file", &contents);
+
+        assert_eq!(block_on(read(&file.0)).unwrap(), contents);
+        let hello = TempFile::new("hello.html", b"<h1>Hello!</h1>");
+        assert_eq!(block_on(read_to_string(&hello.0)).unwrap(), "<h1>Hello!</h1>");
+    }
+
+    #[test]
+    fn small_reads_and_seeks() {
+        let file = TempFile::new("small_reads_and_seeks", b"0123456789");
+        block_on(async {
+            let mut f = File::open(&file.0).await.unwrap();
+            let mut two = [0u8; 2];
+            f.read_exact(&mut two).await.unwrap();
+            assert_eq!(&two, b"01");
+
+            assert_eq!(f.seek(SeekFrom::Current(3)).await.unwrap(), 5);
+            f.read_exact(&mut two).await.unwrap();
+            assert_eq!(&two, b"56");
+
+            assert_eq!(f.seek(SeekFrom::End(-1)).await.unwrap(), 9);
+            let mut rest = Vec::new();
+            f.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"9");
+
+            assert_eq!(f.seek(SeekFrom::Start(0)).await.unwrap(), 0);
+            let mut all = String::new();
+            f.read_to_string(&mut all).await.unwrap();
+            assert_eq!(all, "0123456789");
+
+            assert!(f.seek(SeekFrom::Current(-100)).await.is_err());
+        });
+    }
+
+    #[test]
+    fn missing_file() {
+        let err = block_on(File::open("this/file/does/not/exist")).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,208 @@
+//! 在书中的执行器外面再包一层。
+//!
+//! 书中的 `Executor`、`Spawner` 和 `Task` 保持原样；这里的 [`Spawner`] 把生成的每个期物
+//! 都包装起来，在每次轮询前后以及每次唤醒时做记录。运行指标和调试模式都建立在这层包装之上。
+
+use std::{
+    future::Future,
+    panic::Location,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+    task::{Context, Poll, Waker},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    task::{waker_ref, ArcWake},
+};
+
+use crate::{debug::StallDetector, metrics::Counters, Metrics, TaskReport};
+
+/// 运行书中的执行器，同时收集运行指标，还可以开启调试模式。
+pub struct Executor {
+    executor: crate::Executor,
+    shared: Arc<Shared>,
+}
+
+/// 将新的期物生成到 [`Executor`] 中。
+#[derive(Clone)]
+pub struct Spawner {
+    spawner: crate::Spawner,
+    shared: Arc<Shared>,
+}
+
+/// 执行器、生成器和所有任务共享的状态。
+#[derive(Default)]
+struct Shared {
+    /// 运行指标。
+    counters: Counters,
+    /// 调试模式下用于发现“永远不会再被唤醒”的任务，默认关闭。
+    stall_detector: OnceLock<StallDetector>,
+    next_id: AtomicU64,
+}
+
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    let (executor, spawner) = crate::new_executor_and_spawner();
+    let shared = Arc::new(Shared::default());
+    let executor = Executor {
+        executor,
+        shared: shared.clone(),
+    };
+    (executor, Spawner { spawner, shared })
+}
+
+impl Spawner {
+    #[track_caller]
+    pub fn spawn(&self, future: impl Future<Output = ()> + 'static + Send) {
+        let task = Instrumented {
+            future: future.boxed(),
+            id: self.shared.next_id.fetch_add(1, Ordering::Relaxed),
+            spawned_at: Location::caller(),
+            state: Arc::new(TaskState {
+                queued: AtomicUsize::new(1),
+                done: AtomicBool::new(false),
+            }),
+            shared: self.shared.clone(),
+        };
+        self.shared.counters.record_spawn();
+        self.spawner.spawn(task);
+    }
+
+    /// 返回所属执行器当前的运行指标。`Spawner` 可以被克隆并发送到其他线程，
+    /// 因此可以在执行器运行期间从任意位置抓取指标。
+    pub fn metrics(&self) -> Metrics {
+        self.shared.counters.snapshot()
+    }
+}
+
+impl Executor {
+    /// 开启调试模式：记录每个挂起任务最后一次被轮询的时间，以及是否还有唤醒器存活。
+    ///
+    /// 一旦某个任务返回了 `Poll::Pending` 却没有留下任何唤醒器，执行器会立刻在标准错误上
+    /// 打印该任务的生成位置，而不是默默地挂起。
+    pub fn with_stall_detector(self) -> Self {
+        let _ = self.shared.stall_detector.set(StallDetector::default());
+        self
+    }
+
+    /// 运行执行器直到任务队列为空，见书中的 `Executor::run`。
+    pub fn run(&self) {
+        self.executor.run()
+    }
+
+    /// 返回执行器当前的运行指标。
+    pub fn metrics(&self) -> Metrics {
+        self.shared.counters.snapshot()
+    }
+
+    /// 返回调试模式下记录的所有挂起任务；未开启调试模式时为空。
+    pub fn pending_tasks(&self) -> Vec<TaskReport> {
+        match self.shared.stall_detector.get() {
+            Some(detector) => detector.reports(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// 包装后的期物，书中的执行器轮询的就是它。
+struct Instrumented {
+    future: BoxFuture<'static, ()>,
+    id: u64,
+    /// 生成该任务的代码位置，用于调试输出。
+    spawned_at: &'static Location<'static>,
+    state: Arc<TaskState>,
+    shared: Arc<Shared>,
+}
+
+/// 任务和它的唤醒器共享的状态。
+struct TaskState {
+    /// 任务在书中执行器的队列里出现了几次。
+    queued: AtomicUsize,
+    /// 任务是否已经完成或者被丢弃。
+    done: AtomicBool,
+}
+
+impl Instrumented {
+    /// 任务不会再被轮询了，它在队列中剩下的副本会被执行器直接跳过。
+    fn finish(&self) {
+        self.state.done.store(true, Ordering::Release);
+        let queued = self.state.queued.swap(0, Ordering::AcqRel);
+        self.shared.counters.record_skipped(queued);
+    }
+}
+
+/// 轮询期物时交给它的唤醒器，唤醒时转交给书中执行器的任务。
+pub(crate) struct TaskWaker {
+    task: Waker,
+    woken: AtomicBool,
+    state: Arc<TaskState>,
+    shared: Arc<Shared>,
+}
+
+impl TaskWaker {
+    /// 期物是否已经通过这个唤醒器唤醒过自己。
+    pub(crate) fn woken(&self) -> bool {
+        self.woken.load(Ordering::Acquire)
+    }
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::Release);
+        // 已经完成的任务即使放回队列也不会再被轮询，不必计入。
+        if arc_self.state.done.load(Ordering::Acquire) {
+            return;
+        }
+        arc_self.state.queued.fetch_add(1, Ordering::AcqRel);
+        arc_self.shared.counters.record_wake();
+        arc_self.task.wake_by_ref();
+    }
+}
+
+impl Future for Instrumented {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // 每次轮询都换一个新的唤醒器，轮询之后就能从它的引用计数看出期物有没有留下它。
+        let waker = Arc::new(TaskWaker {
+            task: cx.waker().clone(),
+            woken: AtomicBool::new(false),
+            state: self.state.clone(),
+            shared: self.shared.clone(),
+        });
+        self.state.queued.fetch_sub(1, Ordering::AcqRel);
+        self.shared.counters.record_dequeue();
+
+        let this = &mut *self;
+        let task_waker = waker_ref(&waker);
+        let context = &mut Context::from_waker(&task_waker);
+        let poll = this
+            .shared
+            .counters
+            .time_poll(|| this.future.as_mut().poll(context));
+        if poll.is_ready() {
+            self.shared.counters.record_completed();
+            self.finish();
+        }
+        if let Some(detector) = self.shared.stall_detector.get() {
+            match poll {
+                Poll::Pending => detector.record_pending(self.id, self.spawned_at, &waker),
+                Poll::Ready(()) => detector.record_completed(self.id),
+            }
+        }
+        poll
+    }
+}
+
+impl Drop for Instrumented {
+    fn drop(&mut self) {
+        // 还没有完成，说明任务在完成之前就被丢弃了（例如它没有保留任何唤醒器）。
+        if !self.state.done.load(Ordering::Acquire) {
+            self.shared.counters.record_abandoned();
+            self.finish();
+        }
+    }
+}
@@ -1,5 +1,16 @@
-#![cfg(test)]
+mod blocking;
+mod debug;
+pub mod fs;
+pub mod instrumented;
+mod metrics;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
 
+pub use blocking::{spawn_blocking, BlockingPool, JoinHandle};
+pub use debug::TaskReport;
+pub use metrics::{Histogram, Metrics};
+
+// 下面是书中的执行器，由 `instrumented` 包装后对外提供。
 // ANCHOR: imports
 use futures::{
     future::{BoxFuture, FutureExt},
@@ -99,6 +110,8 @@ impl Executor {
 }
 // ANCHOR_END: executor_run
 
+// `main` 只在测试 `run_main` 中运行。
+#[cfg_attr(not(test), allow(dead_code))]
 // ANCHOR: main
 fn main() {
     let (executor, spawner) = new_executor_and_spawner();
@@ -0,0 +1,271 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        OnceLock,
+    },
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
+};
+
+/// 轮询耗时直方图各个桶的上界（含），最后还有一个不设上界的桶。
+const POLL_BUCKETS: [Duration; 7] = [
+    Duration::from_micros(1),
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// 执行器、生成器和任务共享的计数器，由 `instrumented` 中的包装负责更新。
+///
+/// 所有计数都只用于观察，不参与调度，所以使用 `Relaxed` 就足够了。
+#[derive(Default)]
+pub(crate) struct Counters {
+    spawned: AtomicU64,
+    completed: AtomicU64,
+    pending: AtomicU64,
+    polls: AtomicU64,
+    local_wakes: AtomicU64,
+    remote_wakes: AtomicU64,
+    queue_depth: AtomicUsize,
+    queue_high_water: AtomicUsize,
+    poll_buckets: [AtomicU64; POLL_BUCKETS.len() + 1],
+    /// 所有轮询的总耗时（纳秒）。
+    poll_nanos: AtomicU64,
+    /// 轮询任务的线程，用于区分唤醒来自哪里。
+    executor_thread: OnceLock<ThreadId>,
+}
+
+impl Counters {
+    pub(crate) fn record_spawn(&self) {
+        self.spawned.fetch_add(1, Relaxed);
+        self.pending.fetch_add(1, Relaxed);
+        self.record_enqueue();
+    }
+
+    pub(crate) fn record_wake(&self) {
+        let local = self.executor_thread.get() == Some(&thread::current().id());
+        if local {
+            self.local_wakes.fetch_add(1, Relaxed);
+        } else {
+            self.remote_wakes.fetch_add(1, Relaxed);
+        }
+        self.record_enqueue();
+    }
+
+    fn record_enqueue(&self) {
+        let depth = self.queue_depth.fetch_add(1, Relaxed) + 1;
+        self.queue_high_water.fetch_max(depth, Relaxed);
+    }
+
+    /// 任务从队列中取出、即将被轮询时调用。
+    pub(crate) fn record_dequeue(&self) {
+        let _ = self.executor_thread.set(thread::current().id());
+        self.queue_depth.fetch_sub(1, Relaxed);
+    }
+
+    /// 任务已经完成，它还留在队列中的 `n` 个副本会被执行器直接跳过。
+    pub(crate) fn record_skipped(&self, n: usize) {
+        self.queue_depth.fetch_sub(n, Relaxed);
+    }
+
+    /// 对一次轮询计时。
+    pub(crate) fn time_poll<T>(&self, poll: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = poll();
+        let elapsed = started.elapsed();
+        let bucket = POLL_BUCKETS
+            .iter()
+            .position(|&bound| elapsed <= bound)
+            .unwrap_or(POLL_BUCKETS.len());
+        self.poll_buckets[bucket].fetch_add(1, Relaxed);
+        self.poll_nanos.fetch_add(elapsed.as_nanos() as u64, Relaxed);
+        self.polls.fetch_add(1, Relaxed);
+        result
+    }
+
+    pub(crate) fn record_completed(&self) {
+        self.completed.fetch_add(1, Relaxed);
+        self.pending.fetch_sub(1, Relaxed);
+    }
+
+    /// 任务在完成之前就被丢弃了（例如它没有保留任何唤醒器）。
+    pub(crate) fn record_abandoned(&self) {
+        self.pending.fetch_sub(1, Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            tasks_spawned: self.spawned.load(Relaxed),
+            tasks_completed: self.completed.load(Relaxed),
+            tasks_pending: self.pending.load(Relaxed),
+            polls: self.polls.load(Relaxed),
+            local_wakes: self.local_wakes.load(Relaxed),
+            remote_wakes: self.remote_wakes.load(Relaxed),
+            queue_depth_high_water: self.queue_high_water.load(Relaxed),
+            poll_durations: Histogram {
+                counts: self.poll_buckets.iter().map(|c| c.load(Relaxed)).collect(),
+                sum: Duration::from_nanos(self.poll_nanos.load(Relaxed)),
+            },
+        }
+    }
+}
+
+/// 执行器在某一时刻的运行指标快照。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metrics {
+    /// 生成过的任务总数。
+    pub tasks_spawned: u64,
+    /// 已经运行完成的任务数。
+    pub tasks_completed: u64,
+    /// 已生成、尚未完成且仍然存活的任务数。
+    pub tasks_pending: u64,
+    /// 轮询的总次数。
+    pub polls: u64,
+    /// 在执行器自己的线程上（通常是在轮询其他任务时）发生的唤醒次数。
+    pub local_wakes: u64,
+    /// 在其他线程上（例如计时器线程或阻塞线程池）发生的唤醒次数。
+    pub remote_wakes: u64,
+    /// 任务队列曾经达到的最大长度。
+    pub queue_depth_high_water: usize,
+    /// 单次轮询耗时的分布。
+    pub poll_durations: Histogram,
+}
+
+/// 轮询耗时直方图。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    sum: Duration,
+}
+
+impl Histogram {
+    /// 依次返回每个桶的上界和落入该桶的次数；最后一个桶没有上界。
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        POLL_BUCKETS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .zip(self.counts.iter().copied())
+    }
+
+    /// 所有样本的总和。
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
+
+/// 以 Prometheus 文本格式输出，使用这个执行器的程序可以把它放进自己的 HTTP 响应中供抓取。
+///
+/// 第 9 章的服务器运行在 async-std 上，并不使用这个执行器，所以它的 `/metrics`
+/// 中没有这些指标。
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# TYPE executor_tasks_spawned_total counter")?;
+        writeln!(f, "executor_tasks_spawned_total {}", self.tasks_spawned)?;
+        writeln!(f, "# TYPE executor_tasks_completed_total counter")?;
+        writeln!(f, "executor_tasks_completed_total {}", self.tasks_completed)?;
+        writeln!(f, "# TYPE executor_tasks_pending gauge")?;
+        writeln!(f, "executor_tasks_pending {}", self.tasks_pending)?;
+        writeln!(f, "# TYPE executor_polls_total counter")?;
+        writeln!(f, "executor_polls_total {}", self.polls)?;
+        writeln!(f, "# TYPE executor_wakes_total counter")?;
+        writeln!(f, "executor_wakes_total{{thread=\"executor\"}} {}", self.local_wakes)?;
+        writeln!(f, "executor_wakes_total{{thread=\"other\"}} {}", self.remote_wakes)?;
+        writeln!(f, "# TYPE executor_queue_depth_high_water gauge")?;
+        writeln!(f, "executor_queue_depth_high_water {}", self.queue_depth_high_water)?;
+        writeln!(f, "# TYPE executor_poll_duration_seconds histogram")?;
+        let mut cumulative = 0;
+        for (bound, count) in self.poll_durations.buckets() {
+            cumulative += count;
+            match bound {
+                Some(bound) => writeln!(
+                    f,
+                    "executor_poll_duration_seconds_bucket{{le=\"{}\"}} {cumulative}",
+                    bound.as_secs_f64()
+                )?,
+                None => writeln!(
+                    f,
+                    "executor_poll_duration_seconds_bucket{{le=\"+Inf\"}} {cumulative}"
+                )?,
+            }
+        }
+        writeln!(
+            f,
+            "executor_poll_duration_seconds_sum {}",
+            self.poll_durations.sum().as_secs_f64()
+        )?;
+        writeln!(f, "executor_poll_duration_seconds_count {cumulative}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{instrumented::new_executor_and_spawner, spawn_blocking};
+    use futures::channel::oneshot;
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn counts_tasks_polls_and_wakes() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (tx, rx) = oneshot::channel::<()>();
+        // 被同一线程上的另一个任务唤醒。
+        spawner.spawn(async {
+            let _ = rx.await;
+        });
+        spawner.spawn(async move {
+            let _ = tx.send(());
+        });
+        // 被阻塞线程池中的线程唤醒。
+        spawner.spawn(async {
+            spawn_blocking(|| thread::sleep(Duration::from_millis(50))).await;
+        });
+        drop(spawner);
+        executor.run();
+
+        let metrics = executor.metrics();
+        assert_eq!(metrics.tasks_spawned, 3);
+        assert_eq!(metrics.tasks_completed, 3);
+        assert_eq!(metrics.tasks_pending, 0);
+        assert_eq!(metrics.polls, 5);
+        assert_eq!(metrics.local_wakes, 1);
+        assert_eq!(metrics.remote_wakes, 1);
+        assert_eq!(metrics.queue_depth_high_water, 3);
+        let polls: u64 = metrics.poll_durations.buckets().map(|(_, count)| count).sum();
+        assert_eq!(polls, 5);
+    }
+
+    #[test]
+    fn abandoned_tasks_are_no_longer_pending() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(futures::future::pending());
+        let metrics = spawner.metrics();
+        assert_eq!(metrics.tasks_pending, 1);
+        drop(spawner);
+        executor.run();
+
+        let metrics = executor.metrics();
+        assert_eq!(metrics.tasks_pending, 0);
+        assert_eq!(metrics.tasks_completed, 0);
+    }
+
+    #[test]
+    fn renders_prometheus_text() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async {});
+        drop(spawner);
+        executor.run();
+
+        let text = executor.metrics().to_string();
+        assert!(text.contains("# TYPE executor_tasks_spawned_total counter\n"));
+        assert!(text.contains("executor_tasks_spawned_total 1\n"));
+        assert!(text.contains("# TYPE executor_poll_duration_seconds histogram\n"));
+        assert!(text.contains("\nexecutor_poll_duration_seconds_sum "));
+        assert!(text.contains("executor_poll_duration_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.ends_with("executor_poll_duration_seconds_count 1\n"));
+    }
+}
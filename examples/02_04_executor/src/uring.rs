@@ -0,0 +1,353 @@
+//! 基于 Linux io_uring 的完成式 I/O 驱动。
+//!
+//! 驱动在一个专门的线程上持有 `IoUring`，所有操作都由其他线程提交到共享状态里，
+//! 再通过 eventfd 叫醒驱动线程把它们推入提交队列。
+//!
+//! 与基于就绪通知的 epoll 不同，io_uring 的操作在完成前会一直使用调用者交出的缓冲区，
+//! 所以这里的操作都要求拥有缓冲区的所有权：缓冲区在操作完成时随结果一起还给调用者。
+//! 如果期物在操作完成前被丢弃，驱动会向内核提交取消请求，缓冲区则留在驱动里，
+//! 直到内核报告完成后才被释放。
+//!
+//! [`TcpListener`] 和 [`TcpStream`] 在这些操作之上提供了网络 I/O，
+//! 既有交出缓冲区所有权的 `read`/`write`，也实现了 `AsyncRead`/`AsyncWrite`，
+//! 可以直接替换 `09_*` 服务器中的 `async_std::net` 类型。
+
+mod net;
+
+pub use net::{Incoming, TcpListener, TcpStream};
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    fs::File,
+    future::Future,
+    io,
+    marker::PhantomData,
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    sync::{Arc, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+/// 提交队列的长度。
+const RING_ENTRIES: u32 = 256;
+
+/// 驱动线程自己在 eventfd 上发起的读操作所使用的 `user_data`。
+const EVENTFD_TOKEN: u64 = u64::MAX;
+
+/// 取消请求本身的 `user_data`，它们的完成事件会被直接忽略。
+const CANCEL_TOKEN: u64 = u64::MAX - 1;
+
+/// 所有操作共享的驱动，第一次提交操作时启动。
+struct Driver {
+    state: Mutex<State>,
+    /// 提交新操作后写入它来叫醒驱动线程。
+    eventfd: OwnedFd,
+}
+
+struct State {
+    next_id: u64,
+    ops: HashMap<u64, Slot>,
+    /// 已提交、但尚未被驱动线程推入提交队列的操作。
+    submissions: Vec<squeue::Entry>,
+}
+
+/// 一个正在进行中的操作。
+struct Slot {
+    result: Option<i32>,
+    waker: Option<Waker>,
+    /// 操作期间内核会访问的资源（缓冲区、文件等），必须活到操作完成为止。
+    resources: Box<dyn Any + Send>,
+    /// 对应的期物已被丢弃，完成后直接释放资源即可。
+    dropped: bool,
+    /// 操作成功时的结果是一个新的文件描述符（例如 `accept`）。
+    /// 如果没人来取，驱动要负责关闭它，否则它会泄漏。
+    returns_fd: bool,
+}
+
+fn driver() -> io::Result<&'static Driver> {
+    static DRIVER: OnceLock<Result<Driver, i32>> = OnceLock::new();
+    DRIVER
+        .get_or_init(|| Driver::start().map_err(|e| e.raw_os_error().unwrap_or(libc::EIO)))
+        .as_ref()
+        .map_err(|&code| io::Error::from_raw_os_error(code))
+}
+
+impl Driver {
+    fn start() -> io::Result<Driver> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let eventfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        thread::Builder::new()
+            .name("io-uring-driver".into())
+            .spawn(move || run(ring))?;
+        Ok(Driver {
+            state: Mutex::new(State {
+                next_id: 0,
+                ops: HashMap::new(),
+                submissions: Vec::new(),
+            }),
+            eventfd,
+        })
+    }
+
+    fn notify(&self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.eventfd.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+/// 驱动线程的主循环。
+fn run(mut ring: IoUring) {
+    // 本线程在 `Driver::start` 中启动，`driver()` 会阻塞到初始化完成为止。
+    let driver = driver().expect("io_uring driver failed to start");
+    let mut eventfd_buf = [0u8; 8];
+    let mut arm_eventfd = true;
+    loop {
+        if arm_eventfd {
+            let entry = opcode::Read::new(
+                types::Fd(driver.eventfd.as_raw_fd()),
+                eventfd_buf.as_mut_ptr(),
+                eventfd_buf.len() as u32,
+            )
+            .build()
+            .user_data(EVENTFD_TOKEN);
+            push(&mut ring, &entry);
+            arm_eventfd = false;
+        }
+
+        let submissions = mem::take(&mut driver.state.lock().unwrap().submissions);
+        for entry in &submissions {
+            push(&mut ring, entry);
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINTR | libc::EBUSY)) => {}
+            Err(e) => panic!("io_uring submit failed: {e}"),
+        }
+
+        let completions: Vec<_> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        let mut wakers = Vec::new();
+        let mut state = driver.state.lock().unwrap();
+        for (user_data, result) in completions {
+            match user_data {
+                EVENTFD_TOKEN => {
+                    arm_eventfd = true;
+                    continue;
+                }
+                CANCEL_TOKEN => continue,
+                _ => {}
+            }
+            let Some(slot) = state.ops.get_mut(&user_data) else {
+                continue;
+            };
+            if slot.dropped {
+                if slot.returns_fd && result >= 0 {
+                    // 取消请求来晚了一步，操作已经成功，关掉没人要的描述符。
+                    drop(unsafe { OwnedFd::from_raw_fd(result) });
+                }
+                state.ops.remove(&user_data);
+                continue;
+            }
+            slot.result = Some(result);
+            wakers.extend(slot.waker.take());
+        }
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+fn push(ring: &mut IoUring, entry: &squeue::Entry) {
+    // 提交队列满了就先提交一批，腾出空间。
+    while unsafe { ring.submission().push(entry) }.is_err() {
+        ring.submit().expect("io_uring submit failed");
+    }
+}
+
+/// 提交一个操作。
+///
+/// `build` 拿到的是已经装箱的资源，可以放心地把其中的指针交给内核：
+/// 在操作完成之前，这个盒子不会被移动或释放。
+pub(crate) fn submit<T, F>(resources: T, build: F) -> Op<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut T) -> squeue::Entry,
+{
+    submit_inner(resources, build, false)
+}
+
+/// 与 [`submit`] 相同，但操作成功时的结果是一个新的文件描述符。
+pub(crate) fn submit_returning_fd<T, F>(resources: T, build: F) -> Op<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut T) -> squeue::Entry,
+{
+    submit_inner(resources, build, true)
+}
+
+fn submit_inner<T, F>(resources: T, build: F, returns_fd: bool) -> Op<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut T) -> squeue::Entry,
+{
+    let driver = match driver() {
+        Ok(driver) => driver,
+        Err(e) => return Op::failed(e, resources),
+    };
+    let mut resources = Box::new(resources);
+    let entry = build(&mut resources);
+
+    let mut state = driver.state.lock().unwrap();
+    let id = state.next_id;
+    state.next_id += 1;
+    state.ops.insert(
+        id,
+        Slot {
+            result: None,
+            waker: None,
+            resources,
+            dropped: false,
+            returns_fd,
+        },
+    );
+    state.submissions.push(entry.user_data(id));
+    drop(state);
+    driver.notify();
+
+    Op {
+        id: Some(id),
+        failed: None,
+        _resources: PhantomData,
+    }
+}
+
+/// 一个已提交操作的期物，产出内核返回的结果和交还的资源。
+pub(crate) struct Op<T> {
+    id: Option<u64>,
+    /// 驱动无法启动时，错误和原样奉还的资源。
+    failed: Option<(io::Error, T)>,
+    _resources: PhantomData<T>,
+}
+
+impl<T> Op<T> {
+    fn failed(error: io::Error, resources: T) -> Self {
+        Op {
+            id: None,
+            failed: Some((error, resources)),
+            _resources: PhantomData,
+        }
+    }
+}
+
+impl<T> Unpin for Op<T> {}
+
+impl<T: 'static> Future for Op<T> {
+    type Output = (io::Result<u32>, T);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let Some(id) = self.id else {
+            let (error, resources) = self.failed.take().expect("`Op` polled after completion");
+            return Poll::Ready((Err(error), resources));
+        };
+        let driver = driver().expect("`Op` submitted without a driver");
+        let mut state = driver.state.lock().unwrap();
+        let slot = state.ops.get_mut(&id).expect("`Op` polled after completion");
+        let Some(result) = slot.result else {
+            slot.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+        let slot = state.ops.remove(&id).unwrap();
+        drop(state);
+        self.id = None;
+
+        let resources = *slot
+            .resources
+            .downcast::<T>()
+            .expect("io_uring resources have the wrong type");
+        let result = if result < 0 {
+            Err(io::Error::from_raw_os_error(-result))
+        } else {
+            Ok(result as u32)
+        };
+        Poll::Ready((result, resources))
+    }
+}
+
+impl<T> Drop for Op<T> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else { return };
+        let Ok(driver) = driver() else { return };
+        let mut state = driver.state.lock().unwrap();
+        match state.ops.get_mut(&id) {
+            // 已经完成但没人来取，直接释放；成功返回的描述符也要关掉，否则它会泄漏。
+            Some(slot) if slot.result.is_some() => {
+                let slot = state.ops.remove(&id).unwrap();
+                drop(state);
+                match slot.result {
+                    Some(fd) if slot.returns_fd && fd >= 0 => unsafe {
+                        libc::close(fd);
+                    },
+                    _ => {}
+                }
+            }
+            // 内核可能仍在使用资源：请求取消，资源交给驱动线程在完成时释放。
+            // 如果不取消，像 `accept` 这样的操作可能会在没人等待时“吞掉”下一个连接。
+            Some(slot) => {
+                slot.dropped = true;
+                let cancel = opcode::AsyncCancel::new(id).build().user_data(CANCEL_TOKEN);
+                state.submissions.push(cancel);
+                drop(state);
+                driver.notify();
+            }
+            None => {}
+        }
+    }
+}
+
+/// 编号为 `id` 的操作的状态：`None` 表示它已经不在驱动中了，
+/// 否则 `Some(completed)` 表示它是否已经完成。
+///
+/// 驱动由所有测试共享，所以测试只能观察自己提交的操作。
+#[cfg(test)]
+pub(crate) fn op_state(id: u64) -> Option<bool> {
+    let driver = driver().ok()?;
+    let state = driver.state.lock().unwrap();
+    state.ops.get(&id).map(|slot| slot.result.is_some())
+}
+
+/// 从 `file` 的 `offset` 处读取数据，最多填满 `buf`，返回读到的字节数和缓冲区。
+pub(crate) fn read_at(
+    file: Arc<File>,
+    buf: Vec<u8>,
+    offset: u64,
+) -> impl Future<Output = (io::Result<usize>, Vec<u8>)> {
+    // 文件也随缓冲区一起交给驱动，保证操作期间文件描述符不会被关闭后复用。
+    let op = submit((file, buf), |(file, buf)| {
+        opcode::Read::new(types::Fd(file.as_raw_fd()), buf.as_mut_ptr(), buf.len() as u32)
+            .offset(offset)
+            .build()
+    });
+    async move {
+        let (result, (_file, buf)) = op.await;
+        (result.map(|n| n as usize), buf)
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    future::Future,
+    io, mem,
+    net::{self, Shutdown, SocketAddr, ToSocketAddrs},
+    os::fd::{AsRawFd, FromRawFd},
+    pin::Pin,
+    ptr,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    Stream,
+};
+use io_uring::{opcode, types};
+
+use super::{submit, submit_returning_fd, Op};
+
+/// 通过 `AsyncRead` 读取时，单次至少向内核请求的字节数。
+const MIN_READ: usize = 4 * 1024;
+
+/// 由 io_uring 驱动的 TCP 监听器，接口与 `async_std::net::TcpListener` 保持一致。
+pub struct TcpListener {
+    std: Arc<net::TcpListener>,
+}
+
+impl TcpListener {
+    /// 绑定到 `addr` 并开始监听。
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<TcpListener> {
+        // 绑定本身不会阻塞，不必交给驱动。
+        let std = net::TcpListener::bind(addr)?;
+        Ok(TcpListener { std: Arc::new(std) })
+    }
+
+    /// 接管一个已经在监听的标准库监听器，例如从父进程继承来的套接字。
+    pub fn from_std(std: net::TcpListener) -> TcpListener {
+        TcpListener { std: Arc::new(std) }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.std.local_addr()
+    }
+
+    /// 接受一个新连接。
+    ///
+    /// 如果返回的期物在连接到来之前被丢弃，这次 `accept` 会被取消，
+    /// 下一个连接仍然会交给之后的 `accept` 调用。
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (result, _std) = accept_op(self.std.clone()).await;
+        let stream = TcpStream::from_fd(result?);
+        let addr = stream.peer_addr()?;
+        Ok((stream, addr))
+    }
+
+    /// 返回一个不断接受新连接的流。
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming {
+            listener: self,
+            accept: None,
+        }
+    }
+}
+
+type AcceptOp = Op<Arc<net::TcpListener>>;
+
+fn accept_op(listener: Arc<net::TcpListener>) -> AcceptOp {
+    // 监听器随操作一起交给驱动，保证操作期间描述符不会被关闭。
+    submit_returning_fd(listener, |listener| {
+        opcode::Accept::new(
+            types::Fd(listener.as_raw_fd()),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        )
+        .flags(libc::SOCK_CLOEXEC)
+        .build()
+    })
+}
+
+/// [`TcpListener::incoming`] 返回的流。
+pub struct Incoming<'a> {
+    listener: &'a TcpListener,
+    accept: Option<AcceptOp>,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let listener = self.listener.std.clone();
+        let accept = self.accept.get_or_insert_with(|| accept_op(listener));
+        let (result, _std) = ready!(Pin::new(accept).poll(cx));
+        self.accept = None;
+        Poll::Ready(Some(result.map(TcpStream::from_fd)))
+    }
+}
+
+type ReadOp = Op<(Arc<net::TcpStream>, Vec<u8>)>;
+type WriteOp = Op<(Arc<net::TcpStream>, Vec<u8>, usize)>;
+
+/// 由 io_uring 驱动的 TCP 连接。
+///
+/// 可以使用交出缓冲区所有权的 [`read`](TcpStream::read)/[`write`](TcpStream::write)，
+/// 也可以通过 `AsyncRead`/`AsyncWrite` 使用，后者在内部维护了一对缓冲区。
+pub struct TcpStream {
+    std: Arc<net::TcpStream>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    read_op: Option<ReadOp>,
+    write_buf: Vec<u8>,
+    write_op: Option<WriteOp>,
+}
+
+impl TcpStream {
+    /// 连接到 `addr`。
+    pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let std = crate::spawn_blocking(move || net::TcpStream::connect(&addrs[..])).await?;
+        Ok(TcpStream::from_std(std))
+    }
+
+    /// 接管 `accept` 返回的描述符。
+    fn from_fd(fd: u32) -> TcpStream {
+        TcpStream::from_std(unsafe { net::TcpStream::from_raw_fd(fd as i32) })
+    }
+
+    fn from_std(std: net::TcpStream) -> TcpStream {
+        TcpStream {
+            std: Arc::new(std),
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_op: None,
+            write_buf: Vec::new(),
+            write_op: None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.std.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.std.local_addr()
+    }
+
+    /// 读取数据到 `buf` 的空余容量中，返回读到的字节数和缓冲区。
+    ///
+    /// `buf` 没有空余容量时会先预留一些。如果期物在完成前被丢弃，
+    /// 读取会被取消，已经交出的缓冲区由驱动负责释放。
+    pub async fn read(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        let (result, (_std, mut buf)) = read_op(self.std.clone(), buf).await;
+        let result = finish_read(result, &mut buf);
+        (result, buf)
+    }
+
+    /// 写出 `buf` 中的数据，返回写出的字节数（可能少于 `buf.len()`）和缓冲区。
+    pub async fn write(&self, buf: Vec<u8>) -> (io::Result<usize>, Vec<u8>) {
+        let (result, (_std, buf, _)) = write_op(self.std.clone(), buf, 0).await;
+        (result.map(|n| n as usize), buf)
+    }
+
+    /// 写出 `buf` 中的全部数据。
+    pub async fn write_all(&self, buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        let mut start = 0;
+        let mut buf = buf;
+        while start < buf.len() {
+            let (result, (_std, returned, _)) = write_op(self.std.clone(), buf, start).await;
+            buf = returned;
+            match result {
+                Ok(0) => return (Err(io::ErrorKind::WriteZero.into()), buf),
+                Ok(n) => start += n as usize,
+                Err(e) => return (Err(e), buf),
+            }
+        }
+        (Ok(()), buf)
+    }
+
+    /// 驱动尚未完成的写入，直到内部缓冲区中的数据全部写出。
+    fn poll_write_op(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(op) = &mut self.write_op {
+            let (result, (_std, buf, start)) = ready!(Pin::new(op).poll(cx));
+            self.write_op = None;
+            match result {
+                Ok(n) if n > 0 && start + (n as usize) < buf.len() => {
+                    self.write_op = Some(write_op(self.std.clone(), buf, start + n as usize));
+                }
+                Ok(0) => {
+                    self.write_buf = buf;
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                Ok(_) => self.write_buf = buf,
+                Err(e) => {
+                    self.write_buf = buf;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn read_op(std: Arc<net::TcpStream>, mut buf: Vec<u8>) -> ReadOp {
+    if buf.capacity() == buf.len() {
+        buf.reserve(MIN_READ);
+    }
+    submit((std, buf), |(std, buf)| {
+        let spare = buf.spare_capacity_mut();
+        opcode::Recv::new(
+            types::Fd(std.as_raw_fd()),
+            spare.as_mut_ptr().cast(),
+            spare.len() as u32,
+        )
+        .build()
+    })
+}
+
+/// 读取完成后，把内核填入空余容量的数据计入 `buf` 的长度。
+fn finish_read(result: io::Result<u32>, buf: &mut Vec<u8>) -> io::Result<usize> {
+    let n = result? as usize;
+    // 内核已经初始化了空余容量中的前 `n` 个字节。
+    unsafe { buf.set_len(buf.len() + n) };
+    Ok(n)
+}
+
+fn write_op(std: Arc<net::TcpStream>, buf: Vec<u8>, start: usize) -> WriteOp {
+    submit((std, buf, start), |(std, buf, start)| {
+        let data = &buf[*start..];
+        opcode::Send::new(types::Fd(std.as_raw_fd()), data.as_ptr(), data.len() as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+    })
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if this.read_pos < this.read_buf.len() {
+                let n = out.len().min(this.read_buf.len() - this.read_pos);
+                out[..n].copy_from_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+                this.read_pos += n;
+                return Poll::Ready(Ok(n));
+            }
+
+            match &mut this.read_op {
+                None => {
+                    if out.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let mut buf = mem::take(&mut this.read_buf);
+                    buf.clear();
+                    buf.reserve(out.len().max(MIN_READ));
+                    this.read_pos = 0;
+                    this.read_op = Some(read_op(this.std.clone(), buf));
+                }
+                Some(op) => {
+                    let (result, (_std, mut buf)) = ready!(Pin::new(op).poll(cx));
+                    this.read_op = None;
+                    let result = finish_read(result, &mut buf);
+                    this.read_buf = buf;
+                    if result? == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // 同一时间只允许一次写入在进行中，先等上一次写完。
+        ready!(this.poll_write_op(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let mut buf = mem::take(&mut this.write_buf);
+        buf.clear();
+        buf.extend_from_slice(data);
+        this.write_op = Some(write_op(this.std.clone(), buf, 0));
+        // 数据已经交给了驱动，`poll_flush` 会等待它们真正写出。
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_op(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_op(cx))?;
+        Poll::Ready(this.std.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uring::op_state;
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+        StreamExt,
+    };
+    use std::{
+        io::{Read, Write},
+        thread,
+        time::Duration,
+    };
+
+    /// 等到编号为 `id` 的操作的状态满足 `done`。
+    fn wait_for(id: u64, done: impl Fn(Option<bool>) -> bool) {
+        for _ in 0..100 {
+            if done(op_state(id)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("operation {id} never reached the expected state");
+    }
+
+    /// 丢弃尚未完成的操作，并等待驱动处理完它的取消。
+    fn cancel<T: 'static>(mut op: Op<T>) {
+        let waker = futures::task::noop_waker();
+        assert!(Pin::new(&mut op)
+            .poll(&mut Context::from_waker(&waker))
+            .is_pending());
+        let id = op.id.unwrap();
+        drop(op);
+        wait_for(id, |state| state.is_none());
+    }
+
+    #[test]
+    fn owned_buffer_echo() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = thread::spawn(move || {
+                let mut stream = net::TcpStream::connect(addr).unwrap();
+                stream.write_all(b"ping").unwrap();
+                let mut reply = [0; 4];
+                stream.read_exact(&mut reply).unwrap();
+                reply
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let (n, buf) = stream.read(Vec::with_capacity(16)).await;
+            assert_eq!(n.unwrap(), 4);
+            assert_eq!(buf, b"ping");
+            let (result, _) = stream.write_all(b"pong".to_vec()).await;
+            result.unwrap();
+
+            assert_eq!(&client.join().unwrap(), b"pong");
+        });
+    }
+
+    #[test]
+    fn async_read_write_adapters() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = thread::spawn(move || {
+                let mut stream = net::TcpStream::connect(addr).unwrap();
+                stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let mut reply = Vec::new();
+                stream.read_to_end(&mut reply).unwrap();
+                reply
+            });
+
+            let mut stream = listener.incoming().next().await.unwrap().unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+            // 固有方法 `write_all` 交出的是缓冲区所有权，这里使用 `AsyncWriteExt` 的版本。
+            AsyncWriteExt::write_all(&mut stream, b"HTTP/1.1 200 OK\r\n\r\n")
+                .await
+                .unwrap();
+            stream.close().await.unwrap();
+
+            assert_eq!(client.join().unwrap(), b"HTTP/1.1 200 OK\r\n\r\n");
+        });
+    }
+
+    #[test]
+    fn dropped_accept_does_not_swallow_connection() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            cancel(accept_op(listener.std.clone()));
+
+            let client = thread::spawn(move || net::TcpStream::connect(addr).unwrap());
+            let (stream, _) = listener.accept().await.unwrap();
+            let client = client.join().unwrap();
+            assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    fn dropped_read_does_not_lose_data() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+
+            cancel(read_op(stream.std.clone(), Vec::with_capacity(16)));
+
+            client.write_all(b"hello").unwrap();
+            let (n, buf) = stream.read(Vec::with_capacity(16)).await;
+            assert_eq!(n.unwrap(), 5);
+            assert_eq!(buf, b"hello");
+        });
+    }
+
+    #[test]
+    fn unclaimed_accept_closes_the_connection() {
+        let listener = block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let accept = accept_op(listener.std.clone());
+        let id = accept.id.unwrap();
+        let mut client = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // 操作已经完成，但结果没人来取。
+        wait_for(id, |state| state == Some(true));
+        drop(accept);
+
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(client.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
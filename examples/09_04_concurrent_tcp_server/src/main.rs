@@ -3,12 +3,15 @@ use async_std::net::TcpListener;
 use async_std::net::TcpStream;
 use futures::stream::StreamExt;
 
+/// 同时处理的连接数上限。
+const MAX_CONNECTIONS: usize = 1024;
+
 #[async_std::main]
 async fn main() {
     let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
     listener
         .incoming()
-        .for_each_concurrent(/* 限制 */ None, |tcpstream| async move {
+        .for_each_concurrent(MAX_CONNECTIONS, |tcpstream| async move {
             let tcpstream = tcpstream.unwrap();
             handle_connection(tcpstream).await;
         })
@@ -0,0 +1,416 @@
+//! 流式的请求体。
+//!
+//! [`BodyReader`] 直接在连接上按照 `Content-Length` 或 `chunked` 分帧读出请求体，
+//! 读到请求体的末尾时返回 EOF，超过上限时报错。[`RequestBody`] 是交给处理函数的一端：
+//! 开启 [`Config::stream_bodies`](crate::connection::Config::stream_bodies) 后，
+//! [`serve`](crate::connection::serve) 一边从连接上读请求体，一边经由一个有界通道把数据块交给它，
+//! 处理函数读得慢时服务器也就不再从连接上读数据。
+
+use std::{
+    fmt, io, mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures::{
+    channel::mpsc,
+    io::{AsyncRead, AsyncReadExt},
+    sink::SinkExt,
+    stream::{Stream, StreamExt},
+};
+
+use crate::request::{BodyKind, ChunkedDecoder, ParseError};
+
+/// 每次从连接上读取的字节数。
+const READ_CHUNK: usize = 16 * 1024;
+
+/// 按照请求的分帧方式读取请求体，读完之后返回 EOF，连接上剩下的数据留给下一个请求。
+///
+/// 请求体格式有误或者超过上限时以 [`io::ErrorKind::InvalidData`] 失败，
+/// 错误中带有对应的 [`ParseError`]，可以用 [`parse_error`] 取出来。
+pub struct BodyReader<'a, S> {
+    stream: &'a mut S,
+    /// 读请求头时多读出来的数据，读请求体时也先放在这里。
+    buf: &'a mut Vec<u8>,
+    framing: Framing,
+    max: u64,
+    read: u64,
+}
+
+enum Framing {
+    Length { remaining: u64 },
+    Chunked(ChunkedDecoder),
+    Done,
+}
+
+impl<'a, S> BodyReader<'a, S> {
+    /// `buf` 是读取请求头时用的缓冲区，其中的数据从请求体开始。请求体最多 `max` 个字节。
+    pub fn new(stream: &'a mut S, buf: &'a mut Vec<u8>, kind: BodyKind, max: u64) -> Self {
+        let framing = match kind {
+            BodyKind::Empty => Framing::Done,
+            BodyKind::Length(remaining) => Framing::Length { remaining },
+            BodyKind::Chunked => Framing::Chunked(ChunkedDecoder::new()),
+        };
+        BodyReader {
+            stream,
+            buf,
+            framing,
+            max,
+            read: 0,
+        }
+    }
+
+    /// 请求体是否已经读完。
+    pub fn is_done(&self) -> bool {
+        matches!(self.framing, Framing::Done)
+    }
+}
+
+impl<S: AsyncRead + Unpin> BodyReader<'_, S> {
+    /// 从连接上再读一些数据追加到缓冲区末尾，连接关闭时报错。
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let len = self.buf.len();
+        self.buf.resize(len + READ_CHUNK, 0);
+        let result = Pin::new(&mut *self.stream).poll_read(cx, &mut self.buf[len..]);
+        let n = match &result {
+            Poll::Ready(Ok(n)) => *n,
+            _ => 0,
+        };
+        self.buf.truncate(len + n);
+        match ready!(result)? {
+            0 => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn count(&mut self, n: usize) -> io::Result<()> {
+        self.read += n as u64;
+        if self.read > self.max {
+            return Err(invalid_data(ParseError::BodyTooLarge));
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for BodyReader<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if out.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            match &mut this.framing {
+                Framing::Done => return Poll::Ready(Ok(0)),
+                Framing::Length { remaining } if *remaining > this.max => {
+                    return Poll::Ready(Err(invalid_data(ParseError::BodyTooLarge)));
+                }
+                Framing::Length { remaining: 0 } => this.framing = Framing::Done,
+                Framing::Length { remaining } if this.buf.is_empty() => {
+                    // 缓冲区里没有数据时直接读到调用者的缓冲区里，省去一次拷贝。
+                    let len = out.len().min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    let n = ready!(Pin::new(&mut *this.stream).poll_read(cx, &mut out[..len]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    *remaining -= n as u64;
+                    return Poll::Ready(this.count(n).map(|()| n));
+                }
+                Framing::Length { remaining } => {
+                    let n = out.len().min(this.buf.len());
+                    let n = n.min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                    out[..n].copy_from_slice(&this.buf[..n]);
+                    this.buf.drain(..n);
+                    *remaining -= n as u64;
+                    return Poll::Ready(this.count(n).map(|()| n));
+                }
+                Framing::Chunked(decoder) => {
+                    if decoder.is_done() {
+                        this.framing = Framing::Done;
+                        continue;
+                    }
+                    if this.buf.is_empty() {
+                        ready!(this.poll_fill(cx))?;
+                        continue;
+                    }
+                    // 只把能放进 `out` 的那么多输入交给解码器，解出的数据也就一定放得下。
+                    let input = &this.buf[..this.buf.len().min(out.len())];
+                    let decoded = decoder.decode(input).map_err(invalid_data)?;
+                    let n = decoded.data.len();
+                    out[..n].copy_from_slice(&input[decoded.data]);
+                    this.buf.drain(..decoded.consumed);
+                    if n > 0 {
+                        return Poll::Ready(this.count(n).map(|()| n));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn invalid_data(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// 取出读取请求体的错误中带有的 [`ParseError`]，处理函数可以据此选择响应的状态码。
+pub fn parse_error(e: &io::Error) -> Option<&ParseError> {
+    e.get_ref()?.downcast_ref()
+}
+
+/// 复制一个错误，其中的 [`ParseError`] 会被保留下来。
+fn duplicate(e: &io::Error) -> io::Error {
+    match parse_error(e) {
+        Some(parse) => io::Error::new(e.kind(), parse.clone()),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// 通道中的一项：`Ok(None)` 表示请求体已经完整读完。
+pub(crate) type Item = io::Result<Option<Vec<u8>>>;
+
+/// 交给处理函数的请求体，既是一个由数据块组成的 [`Stream`]，也实现了 [`AsyncRead`]。
+///
+/// 请求体没能完整读完时（格式有误、超过上限、连接断开或者超时），读取会以错误结束，
+/// 而不会像正常结束那样返回 `None` 或 EOF。
+///
+/// ```
+/// use final_tcp_server::body::RequestBody;
+/// use futures::{executor::block_on, io::AsyncReadExt};
+///
+/// let mut body = RequestBody::from(b"hello".to_vec());
+/// let mut text = String::new();
+/// block_on(body.read_to_string(&mut text)).unwrap();
+/// assert_eq!(text, "hello");
+/// ```
+pub struct RequestBody {
+    /// 还没有读完的数据块。
+    chunk: Vec<u8>,
+    pos: usize,
+    /// `Request` 需要是 `Sync` 的，这里不能用 `BoxStream`。
+    receiver: Option<Pin<Box<dyn Stream<Item = Item> + Send + Sync>>>,
+}
+
+impl RequestBody {
+    /// 空的请求体。
+    pub fn empty() -> RequestBody {
+        RequestBody::from(Vec::new())
+    }
+
+    /// 创建一个由通道另一端提供数据的请求体。
+    pub(crate) fn channel() -> (mpsc::Sender<Item>, RequestBody) {
+        let (sender, receiver) = mpsc::channel(1);
+        (sender, RequestBody::from_items(receiver))
+    }
+
+    /// 由 `items` 提供数据的请求体，`items` 必须以 `Ok(None)` 或者一个错误结束。
+    pub(crate) fn from_items(items: impl Stream<Item = Item> + Send + Sync + 'static) -> RequestBody {
+        RequestBody {
+            chunk: Vec::new(),
+            pos: 0,
+            receiver: Some(Box::pin(items)),
+        }
+    }
+
+    /// 读出整个请求体。
+    pub async fn to_vec(mut self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        while let Some(chunk) = self.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+        Ok(body)
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(body: Vec<u8>) -> Self {
+        RequestBody {
+            chunk: body,
+            pos: 0,
+            receiver: None,
+        }
+    }
+}
+
+impl fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("buffered", &(self.chunk.len() - self.pos))
+            .field("streaming", &self.receiver.is_some())
+            .finish()
+    }
+}
+
+impl Stream for RequestBody {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.pos < this.chunk.len() {
+            let mut chunk = mem::take(&mut this.chunk);
+            chunk.drain(..mem::take(&mut this.pos));
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        let Some(receiver) = &mut this.receiver else {
+            return Poll::Ready(None);
+        };
+        let item = ready!(receiver.poll_next_unpin(cx));
+        if !matches!(item, Some(Ok(Some(_)))) {
+            this.receiver = None;
+        }
+        Poll::Ready(match item {
+            Some(Ok(Some(chunk))) => Some(Ok(chunk)),
+            Some(Ok(None)) => None,
+            Some(Err(e)) => Some(Err(e)),
+            // 发送端没有说请求体已经读完就消失了，说明读取被中途放弃，通常是因为超时。
+            None => Some(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "request body was not read to the end",
+            ))),
+        })
+    }
+}
+
+impl AsyncRead for RequestBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pos == self.chunk.len() {
+            match ready!(self.as_mut().poll_next(cx)) {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let this = &mut *self;
+        let n = out.len().min(this.chunk.len() - this.pos);
+        out[..n].copy_from_slice(&this.chunk[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// 把 `reader` 读出的请求体经由 `sender` 交给处理函数。
+///
+/// 处理函数不再需要请求体时，剩下的部分会被读出来丢掉，这样连接还能继续处理下一个请求。
+/// 读取失败时错误会同时交给处理函数和调用者。
+pub(crate) async fn forward<S: AsyncRead + Unpin>(
+    mut reader: BodyReader<'_, S>,
+    mut sender: mpsc::Sender<Item>,
+) -> io::Result<()> {
+    loop {
+        let mut chunk = vec![0; READ_CHUNK];
+        let n = match reader.read(&mut chunk).await {
+            Ok(n) => n,
+            Err(e) => {
+                let _ = sender.send(Err(duplicate(&e))).await;
+                return Err(e);
+            }
+        };
+        if n == 0 {
+            let _ = sender.send(Ok(None)).await;
+            return Ok(());
+        }
+        chunk.truncate(n);
+        if sender.send(Ok(Some(chunk))).await.is_err() {
+            futures::io::copy(&mut reader, &mut futures::io::sink()).await?;
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future, io::Cursor};
+
+    fn read_all(input: &[u8], kind: BodyKind, max: u64) -> (io::Result<Vec<u8>>, Vec<u8>) {
+        let mut stream = Cursor::new(input.to_vec());
+        let mut buf = Vec::new();
+        let mut body = Vec::new();
+        // 每次只读 3 个字节，检查分帧在任意位置切开时都正确。
+        let result = block_on(async {
+            let mut reader = BodyReader::new(&mut stream, &mut buf, kind, max);
+            let mut piece = [0; 3];
+            loop {
+                match reader.read(&mut piece).await? {
+                    0 => break Ok(body),
+                    n => body.extend_from_slice(&piece[..n]),
+                }
+            }
+        });
+        let mut rest = buf;
+        rest.extend_from_slice(&stream.get_ref()[stream.position() as usize..]);
+        (result, rest)
+    }
+
+    #[test]
+    fn reads_framed_bodies() {
+        let (body, rest) = read_all(b"hello worldGET", BodyKind::Length(11), 100);
+        assert_eq!(body.unwrap(), b"hello world");
+        assert_eq!(rest, b"GET");
+
+        let input = b"5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nTrailer: x\r\n\r\nGET";
+        let (body, rest) = read_all(input, BodyKind::Chunked, 100);
+        assert_eq!(body.unwrap(), b"hello world");
+        assert_eq!(rest, b"GET");
+
+        let (body, _) = read_all(b"abc", BodyKind::Length(5), 100);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let (body, _) = read_all(b"5\r\nhel", BodyKind::Chunked, 100);
+        assert_eq!(body.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let (body, _) = read_all(b"z\r\n", BodyKind::Chunked, 100);
+        assert_eq!(parse_error(&body.unwrap_err()), Some(&ParseError::InvalidChunk));
+    }
+
+    #[test]
+    fn enforces_the_size_limit() {
+        let (body, rest) = read_all(b"hello world", BodyKind::Length(11), 10);
+        assert_eq!(parse_error(&body.unwrap_err()), Some(&ParseError::BodyTooLarge));
+        // 声明的长度超过上限时一个字节也不读。
+        assert_eq!(rest, b"hello world");
+
+        let input = b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        let (body, _) = read_all(input, BodyKind::Chunked, 10);
+        assert_eq!(parse_error(&body.unwrap_err()), Some(&ParseError::BodyTooLarge));
+    }
+
+    #[test]
+    fn forwards_chunks_and_errors() {
+        let mut stream = Cursor::new(b"hello".to_vec());
+        let mut buf = Vec::new();
+        let reader = BodyReader::new(&mut stream, &mut buf, BodyKind::Length(5), 100);
+        let (sender, body) = RequestBody::channel();
+        let (forwarded, body) = block_on(future::join(forward(reader, sender), body.to_vec()));
+        forwarded.unwrap();
+        assert_eq!(body.unwrap(), b"hello");
+
+        let mut stream = Cursor::new(b"hello".to_vec());
+        let reader = BodyReader::new(&mut stream, &mut buf, BodyKind::Length(5), 4);
+        let (sender, body) = RequestBody::channel();
+        let (forwarded, body) = block_on(future::join(forward(reader, sender), body.to_vec()));
+        assert!(parse_error(&forwarded.unwrap_err()).is_some());
+        assert_eq!(parse_error(&body.unwrap_err()), Some(&ParseError::BodyTooLarge));
+
+        // 处理函数不读请求体时，剩下的数据会被丢掉。
+        let mut stream = Cursor::new([b'x'; 100_000].to_vec());
+        let reader = BodyReader::new(&mut stream, &mut buf, BodyKind::Length(100_000), 1 << 20);
+        let (sender, body) = RequestBody::channel();
+        drop(body);
+        block_on(forward(reader, sender)).unwrap();
+        assert_eq!(stream.position(), 100_000);
+
+        // 发送端中途消失时，读取以错误结束。
+        let (sender, body) = RequestBody::channel();
+        drop(sender);
+        let error = block_on(body.to_vec()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted);
+    }
+}
@@ -0,0 +1,646 @@
+//! 与示例服务器配套的异步 HTTP/1.1 客户端。
+//!
+//! 客户端与服务器共用同一套报文解析代码：响应头的格式与请求头几乎相同，
+//! 响应体的分帧方式也和请求体一样由 `Content-Length` 或 `Transfer-Encoding` 决定。
+//! 一个响应读完之后，如果连接还能继续使用，就把它放回连接池，
+//! 之后发往同一个源（协议、主机和端口）的请求会优先复用它。
+
+use std::{
+    collections::HashMap,
+    error, fmt, io, str,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_std::net::TcpStream;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures_rustls::{
+    pki_types::ServerName,
+    rustls::{self, RootCertStore},
+    TlsConnector,
+};
+
+use crate::{
+    headers::Headers,
+    request::{
+        body_kind, fill, find, parse_header, read_body, split_crlf, Limits, Method,
+        ParseError, ReadError, Status, Version,
+    },
+    response::{Response, StatusCode},
+    time::{timeout, Elapsed},
+};
+
+/// 客户端的配置。
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 建立连接（包括 TLS 握手）的最长时间。
+    pub connect_timeout: Duration,
+    /// 从发出请求到读完响应的最长时间，包括建立连接的时间。
+    pub request_timeout: Duration,
+    /// 空闲连接在连接池中保留的最长时间。
+    pub idle_timeout: Duration,
+    /// 每个源最多保留的空闲连接数。
+    pub max_idle_per_host: usize,
+    /// 解析响应时的上限，其中 `max_body_bytes` 限制的是响应体。
+    pub limits: Limits,
+    /// 访问 `https` 地址时使用的 TLS 配置，默认信任 Mozilla 的根证书。
+    pub tls: Arc<rustls::ClientConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(30),
+            max_idle_per_host: 8,
+            limits: Limits {
+                max_body_bytes: 64 * 1024 * 1024,
+                ..Limits::default()
+            },
+            tls: default_tls(),
+        }
+    }
+}
+
+fn default_tls() -> Arc<rustls::ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("the ring provider supports the default protocol versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+/// 请求失败的原因。
+#[derive(Debug)]
+pub enum Error {
+    /// 无法解析的 URL，或者不是 `http`、`https` 地址。
+    InvalidUrl(String),
+    Io(io::Error),
+    /// 服务器返回的响应格式有误。
+    InvalidResponse(ParseError),
+    /// 响应体超过了 [`Limits::max_body_bytes`]。
+    BodyTooLarge,
+    Timeout(Elapsed),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUrl(url) => write!(f, "invalid URL: {url}"),
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::InvalidResponse(e) => write!(f, "invalid response: {e}"),
+            Error::BodyTooLarge => f.write_str("response body is too large"),
+            Error::Timeout(e) => write!(f, "timed out: {e}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::InvalidResponse(e) => Some(e),
+            Error::Timeout(e) => Some(e),
+            Error::InvalidUrl(_) | Error::BodyTooLarge => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::BodyTooLarge => Error::BodyTooLarge,
+            e => Error::InvalidResponse(e),
+        }
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => Error::Io(e),
+            ReadError::Parse(e) => e.into(),
+            ReadError::UnexpectedEof => Error::Io(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+impl From<Elapsed> for Error {
+    fn from(e: Elapsed) -> Self {
+        Error::Timeout(e)
+    }
+}
+
+/// 一个 HTTP/1.1 客户端。
+///
+/// 克隆出来的客户端共享同一个连接池。
+///
+/// ```no_run
+/// # async_std::task::block_on(async {
+/// use final_tcp_server::{client::Client, response::StatusCode};
+///
+/// let client = Client::new();
+/// let response = client.get("http://127.0.0.1:7878/").await.unwrap();
+/// assert_eq!(response.status(), StatusCode::OK);
+/// # });
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: Config,
+    pool: Mutex<HashMap<Origin, Vec<Idle>>>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("config", &self.inner.config)
+            .field("idle_connections", &self.idle_connections())
+            .finish()
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::with_config(Config::default())
+    }
+
+    pub fn with_config(config: Config) -> Client {
+        Client {
+            inner: Arc::new(Inner {
+                config,
+                pool: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Response, Error> {
+        self.request(Method::Get, url, Headers::new(), Vec::new())
+            .await
+    }
+
+    pub async fn post(
+        &self,
+        url: &str,
+        content_type: &str,
+        body: impl Into<Vec<u8>>,
+    ) -> Result<Response, Error> {
+        let mut headers = Headers::new();
+        headers.insert("Content-Type", content_type);
+        self.request(Method::Post, url, headers, body.into()).await
+    }
+
+    /// 发出一个请求并读出完整的响应。
+    ///
+    /// `Host` 和 `Content-Length` 会自动加上，`headers` 中的 `Content-Length`
+    /// 会被忽略。整个过程受 [`Config::request_timeout`] 的限制。
+    pub async fn request(
+        &self,
+        method: Method,
+        url: &str,
+        headers: Headers,
+        body: Vec<u8>,
+    ) -> Result<Response, Error> {
+        let url = Url::parse(url)?;
+        let request = encode_request(method, &url, &headers, &body);
+        let config = &self.inner.config;
+        timeout(config.request_timeout, self.send(method, &url.origin, &request)).await?
+    }
+
+    /// 连接池中的空闲连接数。
+    pub fn idle_connections(&self) -> usize {
+        self.inner.pool.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    async fn send(&self, method: Method, origin: &Origin, request: &[u8]) -> Result<Response, Error> {
+        loop {
+            let (mut conn, reused) = match self.checkout(origin) {
+                Some(conn) => (conn, true),
+                None => (self.connect(origin).await?, false),
+            };
+            let mut buf = Vec::new();
+            match self.exchange(&mut conn, &mut buf, method, request).await {
+                Ok((response, reusable)) => {
+                    if reusable {
+                        self.checkin(origin, conn);
+                    }
+                    return Ok(response);
+                }
+                // 服务器随时可能关闭空闲的连接。如果还没收到任何响应就失败了，
+                // 幂等的请求可以放心地换一个连接重试。
+                Err(Error::Io(_)) if reused && buf.is_empty() && method.is_idempotent() => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn connect(&self, origin: &Origin) -> Result<Conn, Error> {
+        let config = &self.inner.config;
+        let connect = async {
+            let tcp = TcpStream::connect((origin.host.as_str(), origin.port)).await?;
+            tcp.set_nodelay(true)?;
+            if !origin.https {
+                return Ok::<Conn, io::Error>(Box::new(tcp));
+            }
+            let name = ServerName::try_from(origin.host.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let tls = TlsConnector::from(config.tls.clone()).connect(name, tcp).await?;
+            Ok(Box::new(tls))
+        };
+        Ok(timeout(config.connect_timeout, connect).await??)
+    }
+
+    /// 在 `conn` 上发出请求并读出响应，同时返回连接能否继续使用。
+    ///
+    /// 读到的数据放在 `buf` 中，调用者可以据此判断失败之前有没有收到响应。
+    async fn exchange(
+        &self,
+        conn: &mut Conn,
+        buf: &mut Vec<u8>,
+        method: Method,
+        request: &[u8],
+    ) -> Result<(Response, bool), Error> {
+        let limits = &self.inner.config.limits;
+        conn.write_all(request).await?;
+        conn.flush().await?;
+
+        let head = loop {
+            let (head, len) = loop {
+                if let Status::Complete(parsed) = parse_response(buf, limits)? {
+                    break parsed;
+                }
+                if fill(conn, buf).await? == 0 {
+                    return Err(ReadError::UnexpectedEof.into());
+                }
+            };
+            buf.drain(..len);
+            // 跳过 `100 Continue` 之类的临时响应。
+            if head.status.as_u16() >= 200 {
+                break head;
+            }
+        };
+
+        let no_body = method == Method::Head
+            || matches!(head.status.as_u16(), 204 | 304)
+            || (method == Method::Connect && head.status.as_u16() < 300);
+        let framed = head.headers.contains("Content-Length") || head.headers.contains("Transfer-Encoding");
+        let (body, until_eof) = if no_body {
+            (Vec::new(), false)
+        } else if framed {
+            let kind = body_kind(head.headers.iter().map(|(n, v)| (n, v.as_bytes())))?;
+            (read_body(conn, buf, kind, limits).await?, false)
+        } else {
+            // 没有说明长度的响应体一直持续到连接关闭。
+            let mut body = std::mem::take(buf);
+            let max = limits.max_body_bytes as u64;
+            (&mut *conn).take(max + 1).read_to_end(&mut body).await?;
+            if body.len() > limits.max_body_bytes {
+                return Err(Error::BodyTooLarge);
+            }
+            (body, true)
+        };
+
+        let keep_alive = match head.version {
+            Version::Http11 | Version::Http2 => !head.headers.has_token("Connection", "close"),
+            Version::Http10 => head.headers.has_token("Connection", "keep-alive"),
+        };
+        let reusable = keep_alive && !until_eof && buf.is_empty();
+        let mut response = Response::new(head.status).with_body(body);
+        *response.headers_mut() = head.headers;
+        Ok((response, reusable))
+    }
+
+    fn checkout(&self, origin: &Origin) -> Option<Conn> {
+        let mut pool = self.inner.pool.lock().unwrap();
+        let idle = pool.get_mut(origin)?;
+        // 最近放回的连接最不可能已经被服务器关闭。
+        while let Some(entry) = idle.pop() {
+            if entry.since.elapsed() < self.inner.config.idle_timeout {
+                return Some(entry.conn);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, origin: &Origin, conn: Conn) {
+        let mut pool = self.inner.pool.lock().unwrap();
+        let idle = pool.entry(origin.clone()).or_default();
+        if idle.len() < self.inner.config.max_idle_per_host {
+            idle.push(Idle {
+                conn,
+                since: Instant::now(),
+            });
+        }
+    }
+}
+
+/// 可以在上面收发 HTTP 报文的连接，可能是明文的 TCP，也可能套了一层 TLS。
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+type Conn = Box<dyn Stream>;
+
+struct Idle {
+    conn: Conn,
+    since: Instant,
+}
+
+/// 可以共用连接的范围。
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Origin {
+    https: bool,
+    host: String,
+    port: u16,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    origin: Origin,
+    /// `Host` 头部的值。
+    authority: String,
+    /// 请求行中的请求目标，即路径加上查询字符串。
+    target: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Url, Error> {
+        let invalid = || Error::InvalidUrl(url.to_owned());
+        let (scheme, rest) = url.split_once("://").ok_or_else(invalid)?;
+        let https = match scheme.to_ascii_lowercase().as_str() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(invalid()),
+        };
+        let (authority, target) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        let target = target.split('#').next().unwrap_or_default();
+        let target = match target.chars().next() {
+            Some('/') => target.to_owned(),
+            _ => format!("/{target}"),
+        };
+        if authority.contains('@') || target.bytes().any(|b| b <= b' ') {
+            return Err(invalid());
+        }
+
+        // IPv6 地址写在方括号里，其中的冒号不是端口的分隔符。
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let default_port = if https { 443 } else { 80 };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let authority = match (host.contains(':'), port == default_port) {
+            (true, true) => format!("[{host}]"),
+            (true, false) => format!("[{host}]:{port}"),
+            (false, true) => host.to_owned(),
+            (false, false) => format!("{host}:{port}"),
+        };
+        Ok(Url {
+            origin: Origin {
+                https,
+                host: host.to_ascii_lowercase(),
+                port,
+            },
+            authority,
+            target,
+        })
+    }
+}
+
+fn encode_request(method: Method, url: &Url, headers: &Headers, body: &[u8]) -> Vec<u8> {
+    let mut headers = headers.clone();
+    if !headers.contains("Host") {
+        headers.insert("Host", url.authority.as_str());
+    }
+    headers.remove("Content-Length");
+    if !body.is_empty() || matches!(method, Method::Post | Method::Put | Method::Patch) {
+        headers.insert("Content-Length", body.len().to_string());
+    }
+    let mut request = format!("{method} {} HTTP/1.1\r\n{headers}\r\n", url.target).into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+/// 响应的状态行和头部。
+#[derive(Debug)]
+struct ResponseHead {
+    version: Version,
+    status: StatusCode,
+    headers: Headers,
+}
+
+/// 尝试从 `buf` 的开头解析出状态行和头部，成功时同时返回它们占用的字节数。
+fn parse_response(buf: &[u8], limits: &Limits) -> Result<Status<(ResponseHead, usize)>, ParseError> {
+    let Some(end) = find(buf, b"\r\n\r\n").map(|i| i + 4) else {
+        return if buf.len() > limits.max_head_bytes {
+            Err(ParseError::HeadTooLarge)
+        } else {
+            Ok(Status::Partial)
+        };
+    };
+    if end > limits.max_head_bytes {
+        return Err(ParseError::HeadTooLarge);
+    }
+
+    let mut lines = split_crlf(&buf[..end - 2]);
+    // 状态行形如 `HTTP/1.1 200 OK`，原因短语可以为空。
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.splitn(3, |&b| b == b' ');
+    let version = match parts.next() {
+        Some(b"HTTP/1.1") => Version::Http11,
+        Some(b"HTTP/1.0") => Version::Http10,
+        _ => return Err(ParseError::InvalidStatus),
+    };
+    let status = parts
+        .next()
+        .filter(|code| code.len() == 3 && code.iter().all(u8::is_ascii_digit))
+        .and_then(|code| str::from_utf8(code).ok()?.parse().ok())
+        .and_then(StatusCode::from_u16)
+        .ok_or(ParseError::InvalidStatus)?;
+
+    let mut headers = Headers::new();
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let header = parse_header(line)?;
+        headers.append(header.name, String::from_utf8_lossy(header.value));
+    }
+    let head = ResponseHead {
+        version,
+        status,
+        headers,
+    };
+    Ok(Status::Complete((head, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{connection, request::Request};
+    use async_std::{net::TcpListener, task};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 启动一个示例服务器，返回它的地址和已经接受的连接数。
+    async fn start_server() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                task::spawn(async move {
+                    let handler = |request: Request| async move {
+                        let body = format!("{} {} {}", request.method, request.path, request.body.len());
+                        Response::text(StatusCode::OK, body)
+                    };
+                    let _ = connection::serve(stream, &connection::Config::default(), handler).await;
+                });
+            }
+        });
+        (format!("http://{addr}"), accepted)
+    }
+
+    /// 启动一个服务器，对每个连接上的第一个请求原样回复 `response` 之后关闭连接。
+    async fn canned_server(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                while find(&request, b"\r\n\r\n").is_none() {
+                    if fill(&mut stream, &mut request).await.unwrap() == 0 {
+                        break;
+                    }
+                }
+                stream.write_all(response).await.unwrap();
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[async_std::test]
+    async fn reuses_keep_alive_connections() {
+        let (base, accepted) = start_server().await;
+        let client = Client::new();
+
+        let response = client.get(&format!("{base}/hello?x=1")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body().as_bytes(), Some(&b"GET /hello 0"[..]));
+        let response = client.post(&format!("{base}/upload"), "text/plain", "data").await.unwrap();
+        assert_eq!(response.body().as_bytes(), Some(&b"POST /upload 4"[..]));
+        let response = client.clone().get(&base).await.unwrap();
+        assert_eq!(response.body().as_bytes(), Some(&b"GET / 0"[..]));
+
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(client.idle_connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn reads_chunked_and_close_delimited_bodies() {
+        let client = Client::new();
+        let chunked = canned_server(
+            b"HTTP/1.1 100 Continue\r\n\r\n\
+              HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n",
+        )
+        .await;
+        let response = client.get(&chunked).await.unwrap();
+        assert_eq!(response.body().as_bytes(), Some(&b"hello world"[..]));
+        assert_eq!(client.idle_connections(), 1);
+
+        // 没有说明长度的响应体读到连接关闭为止，这样的连接不能再用。
+        let until_eof = canned_server(b"HTTP/1.0 404 Not Found\r\n\r\nmissing").await;
+        let response = client.get(&until_eof).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.body().as_bytes(), Some(&b"missing"[..]));
+        assert_eq!(client.idle_connections(), 1);
+    }
+
+    #[async_std::test]
+    async fn retries_idempotent_requests_on_stale_connections() {
+        // 服务器声称保持连接，实际上回复之后马上关闭。
+        let base = canned_server(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await;
+        let client = Client::new();
+        client.get(&base).await.unwrap();
+        assert_eq!(client.idle_connections(), 1);
+        // 等服务器那一端真正关闭。
+        task::sleep(Duration::from_millis(50)).await;
+
+        let response = client.get(&base).await.unwrap();
+        assert_eq!(response.body().as_bytes(), Some(&b"ok"[..]));
+        let err = client.post(&base, "text/plain", "x").await.unwrap_err();
+        assert!(matches!(err, Error::Io(_)), "{err}");
+    }
+
+    #[async_std::test]
+    async fn gives_up_after_the_request_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // 接受连接，但是从不回复。
+        let _server = task::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            futures::future::pending::<()>().await;
+        });
+        let client = Client::with_config(Config {
+            request_timeout: Duration::from_millis(200),
+            ..Config::default()
+        });
+        let err = client.get(&format!("http://{addr}/")).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(_)), "{err}");
+    }
+
+    #[test]
+    fn parses_urls() {
+        let url = Url::parse("http://Example.com:8080/a/b?q=1#frag").unwrap();
+        assert_eq!(url.origin, Origin { https: false, host: "example.com".into(), port: 8080 });
+        assert_eq!(url.authority, "Example.com:8080");
+        assert_eq!(url.target, "/a/b?q=1");
+
+        let url = Url::parse("https://[::1]?x").unwrap();
+        assert_eq!(url.origin, Origin { https: true, host: "::1".into(), port: 443 });
+        assert_eq!(url.authority, "[::1]");
+        assert_eq!(url.target, "/?x");
+
+        for invalid in ["ftp://host/", "example.com", "http://:80/", "http://host:port/", "http://u@host/"] {
+            assert!(matches!(Url::parse(invalid), Err(Error::InvalidUrl(_))), "{invalid}");
+        }
+    }
+}
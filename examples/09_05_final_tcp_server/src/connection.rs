@@ -0,0 +1,503 @@
+//! 在一个连接上依次处理多个请求（HTTP/1.1 keep-alive）。
+
+use std::{future::Future, io, sync::atomic::AtomicU64, time::Duration};
+
+use futures::{
+    future,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    body::{forward, parse_error, BodyReader, RequestBody},
+    deadline::{read_within, Counted, MinDataRate, StallGuard},
+    error::ServerError,
+    request::{fill, read_body, read_head, BodyKind, Limits, Method, Request},
+    response::{Response, StatusCode},
+    shutdown::Shutdown,
+    time::timeout,
+    upgrade::Upgraded,
+};
+
+/// 连接的配置。
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub limits: Limits,
+    /// 等待下一个请求的最长时间，超时后关闭连接。
+    pub idle_timeout: Duration,
+    /// 收到请求的第一个字节之后，读完请求头的最长时间。
+    pub header_timeout: Duration,
+    /// 读完请求体的最长时间。
+    pub body_timeout: Duration,
+    /// 为 `true` 时不再预先读完请求体，而是在处理函数运行的同时把请求体交给它，
+    /// 处理函数通过 [`Request::take_body`] 边读边处理，参见 [`body`](crate::body)。
+    ///
+    /// 请求体的上限仍然是 [`Limits::max_body_bytes`]，读取仍然受 [`Config::body_timeout`]
+    /// 和 [`Config::min_data_rate`] 的限制，处理函数读得太慢也会导致超时。
+    pub stream_bodies: bool,
+    /// 写回一个响应的最长时间。流式响应没有总的期限，而是要求每次写入在这个时间之内有进展。
+    pub write_timeout: Duration,
+    /// 读取请求时要求的最低速率，`None` 表示不限制。
+    pub min_data_rate: Option<MinDataRate>,
+    /// 一个连接最多处理的请求数，处理完最后一个请求后关闭连接。
+    pub max_requests: usize,
+    /// 收到关闭信号后，正在处理的请求仍会完成，但不再等待新的请求。
+    pub shutdown: Shutdown,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            limits: Limits::default(),
+            idle_timeout: Duration::from_secs(5),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            stream_bodies: false,
+            write_timeout: Duration::from_secs(30),
+            min_data_rate: Some(MinDataRate {
+                bytes_per_second: 240,
+                grace_period: Duration::from_secs(5),
+            }),
+            max_requests: 100,
+            shutdown: Shutdown::new(),
+        }
+    }
+}
+
+/// 在 `stream` 上不断读取请求、交给 `handler` 处理并写回响应，直到连接需要关闭。
+///
+/// 客户端可以不等响应就连续发出多个请求（流水线），多读出来的数据会留到下一轮，
+/// 响应则严格按照请求的顺序依次写回。以下情况会关闭连接：
+///
+/// - 客户端关闭了连接，或者在 [`Config::idle_timeout`] 之内没有发来下一个请求；
+/// - 请求或响应中带有 `Connection: close`，或者是没有要求保持连接的 HTTP/1.0 请求；
+/// - 已经处理了 [`Config::max_requests`] 个请求；
+/// - 收到了 [`Config::shutdown`] 信号；
+/// - 请求格式有误，此时会先回复一个对应的错误响应；
+/// - 请求没能在期限之内读完，或者读取速率太低，此时会先回复 `408 Request Timeout`；
+/// - 流式的请求体没能完整读完，此时处理函数会从请求体中读到错误，它的响应写回之后关闭连接；
+/// - 响应没能在 [`Config::write_timeout`] 之内写完；
+/// - 响应是附带了 [`OnUpgrade`](crate::upgrade::OnUpgrade) 的 `101 Switching Protocols`，
+///   此时连接交给升级回调继续使用，回调结束之后关闭。
+///
+/// 只有连接因为出错而结束时才返回错误：读写失败、请求格式有误，
+/// 或者读写超时。两个请求之间的空闲超时属于正常关闭。
+pub async fn serve<S, H, F>(stream: S, config: &Config, handler: H) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    H: FnMut(Request) -> F,
+    F: Future<Output = Response>,
+{
+    serve_buffered(stream, Vec::new(), config, handler).await
+}
+
+/// 与 [`serve`] 相同，只是 `buffer` 中是已经从 `stream` 中读出的数据，
+/// 例如为了判断是不是 HTTP/2 连接而读出的开头部分。
+pub async fn serve_buffered<S, H, F>(
+    mut stream: S,
+    mut buffer: Vec<u8>,
+    config: &Config,
+    mut handler: H,
+) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    H: FnMut(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let mut served = 0;
+    let received = AtomicU64::new(0);
+    loop {
+        // 两个请求之间的等待可以被关闭信号打断；一旦读到了请求的第一个字节，
+        // 就一定把这个请求处理完。
+        if buffer.is_empty() {
+            let first = config.shutdown.run_until(fill(&mut stream, &mut buffer));
+            match timeout(config.idle_timeout, first).await {
+                // 客户端关闭了连接、空闲太久，或者服务器正在关闭。
+                Err(_) | Ok(None) | Ok(Some(Ok(0))) => break,
+                Ok(Some(Ok(_))) => {}
+                Ok(Some(Err(e))) => return Err(e.into()),
+            }
+        }
+
+        let mut counted = Counted::new(&mut stream, &received);
+        let head = read_head(&mut counted, &mut buffer, &config.limits);
+        let head = read_within(config.header_timeout, config.min_data_rate, &received, head).await;
+        let (mut request, body_kind) = match head {
+            Ok(Ok(Some(head))) => head,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => return Err(fail(&mut stream, config, e.into()).await),
+            Err(e) => return Err(fail(&mut stream, config, e.into()).await),
+        };
+        // 没有请求体时就不必再为它计时了。
+        if body_kind != BodyKind::Empty && !config.stream_bodies {
+            let mut counted = Counted::new(&mut stream, &received);
+            let body = read_body(&mut counted, &mut buffer, body_kind, &config.limits);
+            let body =
+                read_within(config.body_timeout, config.min_data_rate, &received, body).await;
+            request.body = match body {
+                Ok(Ok(body)) => body,
+                Ok(Err(e)) => return Err(fail(&mut stream, config, e.into()).await),
+                Err(e) => return Err(fail(&mut stream, config, e.into()).await),
+            };
+        }
+        served += 1;
+
+        let keep_alive = request.keep_alive()
+            && served < config.max_requests
+            && !config.shutdown.is_triggered();
+        let method = request.method;
+        let version = request.version;
+        let (response, body_error) = if body_kind != BodyKind::Empty && config.stream_bodies {
+            let (sender, body) = RequestBody::channel();
+            request.set_body_stream(body);
+            let mut counted = Counted::new(&mut stream, &received);
+            let reader = BodyReader::new(
+                &mut counted,
+                &mut buffer,
+                body_kind,
+                config.limits.max_body_bytes as u64,
+            );
+            let forward = forward(reader, sender);
+            let forward = read_within(config.body_timeout, config.min_data_rate, &received, forward);
+            match future::join(handler(request), forward).await {
+                (response, Ok(Ok(()))) => (response, None),
+                (response, Ok(Err(e))) => (response, Some(body_error(e))),
+                (response, Err(e)) => (response, Some(e.into())),
+            }
+        } else {
+            (handler(request).await, None)
+        };
+        let mut response = response.for_version(version);
+        // 请求体没有读完，连接上剩下的数据已经无法分辨了，回复之后只能关闭连接。
+        if body_error.is_some() {
+            response = response.with_close();
+        }
+        let upgrade = match response.status() {
+            StatusCode::SWITCHING_PROTOCOLS => response.take_upgrade(),
+            _ => None,
+        };
+        // 升级响应中的 `Connection: Upgrade` 不能被替换掉。
+        if !keep_alive && upgrade.is_none() {
+            response = response.with_close();
+        }
+        let close = response.is_close();
+        if method != Method::Head && response.is_streaming() {
+            // 流式响应可能持续很久，这时只要求每次写入都在期限之内有进展。
+            response.write_to(&mut StallGuard::new(&mut stream, config.write_timeout)).await?;
+        } else {
+            let write = async {
+                match method {
+                    Method::Head => response.write_head_to(&mut stream).await,
+                    _ => response.write_to(&mut stream).await,
+                }
+            };
+            timeout(config.write_timeout, write).await??;
+        }
+        if let Some(upgrade) = upgrade {
+            // 升级之后的连接不再受 HTTP 的超时和请求数限制，由新协议自己管理。
+            upgrade.run(Upgraded::new(&mut stream, std::mem::take(&mut buffer))).await;
+            break;
+        }
+        if let Some(e) = body_error {
+            return Err(e);
+        }
+        if close {
+            break;
+        }
+    }
+    Ok(stream.close().await?)
+}
+
+/// 把读取流式请求体时的错误转换成 [`ServerError`]，请求体格式有误时保留其中的 [`ParseError`]。
+fn body_error(e: io::Error) -> ServerError {
+    match parse_error(&e) {
+        Some(parse) => ServerError::Parse(parse.clone()),
+        None => ServerError::Io(e),
+    }
+}
+
+/// 读取请求失败时，尽可能告诉客户端原因，然后原样返回 `error`。
+async fn fail<S>(stream: &mut S, config: &Config, error: ServerError) -> ServerError
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = match &error {
+        ServerError::Parse(e) => Response::text(e.status_code(), format!("{e}\n")),
+        ServerError::Timeout(_) => Response::text(StatusCode::REQUEST_TIMEOUT, "request timeout\n"),
+        // 连接本身出了问题，没有必要再写什么了。
+        ServerError::Io(_) => return error,
+    };
+    // 反正要关闭连接了，写不出去也无所谓。
+    let _ = timeout(config.write_timeout, response.with_close().write_to(stream)).await;
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        request::ParseError,
+        response::Body,
+        sse::{self, Event},
+    };
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+        StreamExt,
+    };
+    use mock_stream::MockStream;
+    use std::{io, time::Instant};
+
+    fn output(stream: &MockStream) -> String {
+        String::from_utf8(stream.written()).unwrap()
+    }
+
+    async fn echo_path(request: Request) -> Response {
+        Response::text(StatusCode::OK, request.path)
+    }
+
+    fn serve_input(input: &[u8], config: &Config) -> String {
+        let mut stream = MockStream::builder().read(input).build();
+        block_on(serve(&mut stream, config, echo_path)).unwrap();
+        output(&stream)
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let output = serve_input(
+            b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n",
+            &Config::default(),
+        );
+        let bodies: Vec<_> = output
+            .split("HTTP/1.1 200 OK\r\n")
+            .skip(1)
+            .map(|response| response.rsplit("\r\n\r\n").next().unwrap())
+            .collect();
+        assert_eq!(bodies, ["/a", "/b", "/c"]);
+        assert!(!output.contains("Connection: close"));
+    }
+
+    #[test]
+    fn connection_close_stops_the_loop() {
+        let output = serve_input(
+            b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n",
+            &Config::default(),
+        );
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+
+        let output = serve_input(b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n", &Config::default());
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn max_requests_per_connection() {
+        let config = Config {
+            max_requests: 2,
+            ..Config::default()
+        };
+        let output = serve_input(&b"GET / HTTP/1.1\r\n\r\n".repeat(3), &config);
+        let responses: Vec<_> = output.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 2);
+        assert!(!responses[0].contains("Connection: close"));
+        assert!(responses[1].contains("Connection: close"));
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        let config = Config {
+            idle_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        // 数据读完之后一直等待。
+        let (mut stream, _peer) = MockStream::builder().read(b"GET / HTTP/1.1\r\n\r\n").build_with_handle();
+        let started = Instant::now();
+        block_on(serve(&mut stream, &config, echo_path)).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(output(&stream).matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let config = Config::default();
+        // 数据读完之后一直等待。
+        let (mut stream, _peer) = MockStream::builder().read(b"GET / HTTP/1.1\r\n\r\n").build_with_handle();
+        let shutdown = config.shutdown.clone();
+        let started = Instant::now();
+        let trigger = async move {
+            timeout(Duration::from_millis(50), futures::future::pending::<()>())
+                .await
+                .unwrap_err();
+            shutdown.trigger();
+        };
+        let (result, ()) = block_on(futures::future::join(
+            serve(&mut stream, &config, echo_path),
+            trigger,
+        ));
+        result.unwrap();
+        assert!(started.elapsed() < config.idle_timeout);
+        assert_eq!(output(&stream).matches("HTTP/1.1 200 OK").count(), 1);
+
+        // 关闭之后处理的请求会带上 `Connection: close`。
+        let output = serve_input(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n", &config);
+        assert_eq!(output.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn slow_requests_get_408() {
+        let config = Config {
+            header_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        // 数据读完之后一直等待。
+        let (mut stream, _peer) = MockStream::builder().read(b"GET / HTTP/1.1\r\nHost: loc").build_with_handle();
+        let result = block_on(serve(&mut stream, &config, echo_path));
+        assert!(matches!(result, Err(ServerError::Timeout(_))));
+        assert!(output(&stream).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        // 请求体来得太慢，在期限之前就被最低速率限制断开。
+        let config = Config {
+            min_data_rate: Some(MinDataRate {
+                bytes_per_second: 1000,
+                grace_period: Duration::from_millis(50),
+            }),
+            ..Config::default()
+        };
+        // 数据读完之后一直等待。
+        let (mut stream, _peer) = MockStream::builder().read(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\nabc").build_with_handle();
+        let started = Instant::now();
+        let result = block_on(serve(&mut stream, &config, echo_path));
+        assert!(matches!(result, Err(ServerError::Timeout(_))));
+        assert!(started.elapsed() < config.body_timeout);
+        assert!(output(&stream).contains("408 Request Timeout"));
+    }
+
+    #[test]
+    fn stalled_writes_time_out() {
+        let config = Config {
+            write_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        let (stream, peer) = MockStream::builder()
+            .read(b"GET / HTTP/1.1\r\n\r\n")
+            .build_with_handle();
+        peer.pause_writes();
+        let result = block_on(serve(stream, &config, echo_path));
+        assert!(matches!(result, Err(ServerError::Timeout(_))));
+    }
+
+    #[test]
+    fn streaming_responses_only_time_out_when_stalled() {
+        let config = Config {
+            write_timeout: Duration::from_millis(50),
+            ..Config::default()
+        };
+        // 整个响应花的时间远远超过写入期限，但每一块都能及时写出。
+        let slow_chunks = |_| async {
+            let chunks = futures::stream::iter(0..4).then(|i| async move {
+                crate::time::sleep(Duration::from_millis(30)).await;
+                vec![b'0' + i]
+            });
+            Response::new(StatusCode::OK).with_body(Body::from_stream(chunks)).with_close()
+        };
+        let mut stream = MockStream::builder().read(b"GET / HTTP/1.1\r\n\r\n").build();
+        block_on(serve(&mut stream, &config, slow_chunks)).unwrap();
+        assert!(output(&stream).ends_with("\r\n\r\n1\r\n0\r\n1\r\n1\r\n1\r\n2\r\n1\r\n3\r\n0\r\n\r\n"));
+
+        let (stream, peer) = MockStream::builder()
+            .read(b"GET / HTTP/1.1\r\n\r\n")
+            .build_with_handle();
+        peer.pause_writes();
+        let result = block_on(serve(stream, &config, slow_chunks));
+        assert!(matches!(result, Err(ServerError::Io(e)) if e.kind() == io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn streams_to_http_10_clients_without_chunks() {
+        let events = |_| async { sse::response(futures::stream::iter([Event::data("hi")])) };
+        let mut stream = MockStream::builder().read(b"GET /events HTTP/1.0\r\n\r\n").build();
+        block_on(serve(&mut stream, &Config::default(), events)).unwrap();
+        let output = output(&stream);
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        assert!(!head.contains("Transfer-Encoding"), "{head}");
+        assert!(!head.contains("Content-Length"), "{head}");
+        assert!(head.contains("\r\nConnection: close"), "{head}");
+        // 响应体就是事件本身，没有块的长度，也没有表示结束的空块。
+        assert_eq!(body, "data: hi\n\n");
+        assert!(stream.is_closed());
+    }
+
+    #[test]
+    fn talks_to_a_client_over_a_duplex_pipe() {
+        // 缓冲区很小，客户端和服务器要交替运行好几轮才能交换完一个请求和响应。
+        let (mut client, server) = mock_stream::duplex(16);
+        let config = Config::default();
+        let client = async move {
+            let mut responses = Vec::new();
+            for path in ["/first", "/second"] {
+                let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+                client.write_all(request.as_bytes()).await.unwrap();
+                let mut expected = Vec::new();
+                Response::text(StatusCode::OK, path).write_to(&mut expected).await.unwrap();
+                let mut response = vec![0; expected.len()];
+                client.read_exact(&mut response).await.unwrap();
+                assert_eq!(response, expected);
+                responses.push(response);
+            }
+            // 客户端关闭连接之后，服务器也随之结束。
+            client.close().await.unwrap();
+            let mut rest = Vec::new();
+            client.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            responses.len()
+        };
+        let (result, served) = block_on(futures::future::join(
+            serve(server, &config, echo_path),
+            client,
+        ));
+        result.unwrap();
+        assert_eq!(served, 2);
+    }
+
+    #[test]
+    fn requests_split_across_reads() {
+        let mut stream = MockStream::builder()
+            .read(b"GET /a HT")
+            .pending()
+            .read(b"TP/1.1\r\n")
+            .pending()
+            .read(b"\r\nGET /b HTTP/1.1\r\n\r\n")
+            .build();
+        block_on(serve(&mut stream, &Config::default(), echo_path)).unwrap();
+        assert_eq!(output(&stream).matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(stream.is_closed());
+
+        // 连接在写回第一个响应的途中断开。
+        let mut stream = MockStream::builder()
+            .read(b"GET / HTTP/1.1\r\n\r\n")
+            .max_write(5)
+            .write_error_at(10, io::ErrorKind::ConnectionReset)
+            .build();
+        let result = block_on(serve(&mut stream, &Config::default(), echo_path));
+        assert!(matches!(result, Err(ServerError::Io(e)) if e.kind() == io::ErrorKind::ConnectionReset));
+        assert_eq!(stream.written(), b"HTTP/1.1 2");
+    }
+
+    #[test]
+    fn malformed_request_gets_an_error_response() {
+        let mut stream = MockStream::builder().read(b"GET / HTTP/1.1\r\n\r\nBREW / HTTP/1.1\r\n\r\n").build();
+        let result = block_on(serve(&mut stream, &Config::default(), echo_path));
+        assert!(matches!(result, Err(ServerError::Parse(ParseError::UnsupportedMethod))));
+        let output = output(&stream);
+        assert!(output.contains("HTTP/1.1 501 Not Implemented\r\n"));
+        assert!(output.ends_with("unsupported method\n"));
+
+        // 请求没发完连接就断了。
+        let mut stream = MockStream::builder().read(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").build();
+        let result = block_on(serve(&mut stream, &Config::default(), echo_path));
+        let Err(ServerError::Io(e)) = result else {
+            panic!("expected an I/O error, got {result:?}");
+        };
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
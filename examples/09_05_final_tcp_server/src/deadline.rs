@@ -0,0 +1,266 @@
+//! 读写期限与最低传输速率，防止慢速客户端（slowloris）长期占着连接。
+
+use std::{
+    future::Future,
+    io,
+    pin::{pin, Pin},
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{self, Either},
+    io::{AsyncRead, AsyncWrite},
+};
+
+use crate::time::{sleep, timeout, Elapsed, Sleep};
+
+/// 两次检查传输速率之间的间隔。
+const RATE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 读取请求时要求的最低平均速率。
+///
+/// 只给每个阶段设一个总的期限还不够：客户端可以每隔几秒发一个字节，
+/// 在期限之内勉强发完一个很大的请求体。设置了最低速率之后，
+/// 过了 `grace_period` 仍然达不到 `bytes_per_second` 的连接会被断开。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinDataRate {
+    pub bytes_per_second: u64,
+    /// 开始计算速率之前的宽限时间，用来容忍连接刚建立时的慢启动。
+    pub grace_period: Duration,
+}
+
+impl MinDataRate {
+    /// 直到 `counter` 的平均增长速率低于最低速率时才完成。
+    async fn violated(&self, counter: &AtomicU64) {
+        let started = Instant::now();
+        let start_count = counter.load(Ordering::Relaxed);
+        let mut timer = sleep(self.grace_period);
+        loop {
+            (&mut timer).await;
+            let elapsed = started.elapsed().as_secs_f64();
+            let received = counter.load(Ordering::Relaxed) - start_count;
+            if (received as f64) < self.bytes_per_second as f64 * elapsed {
+                return;
+            }
+            timer.reset(Instant::now() + RATE_CHECK_INTERVAL);
+        }
+    }
+}
+
+/// 运行读取数据的 `future`，它必须在 `limit` 之内完成，并且在此期间经由 `counter`
+/// 统计的读取速率不能低于 `rate`，否则放弃它并返回 [`Elapsed`]。
+pub async fn read_within<F: Future>(
+    limit: Duration,
+    rate: Option<MinDataRate>,
+    counter: &AtomicU64,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let Some(rate) = rate else {
+        return timeout(limit, future).await;
+    };
+    let guarded = async {
+        match future::select(pin!(future), pin!(rate.violated(counter))).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(((), _)) => Err(Elapsed),
+        }
+    };
+    timeout(limit, guarded).await?
+}
+
+/// 统计从流中读出的字节数，供 [`read_within`] 检查传输速率。写入原样转发。
+pub struct Counted<'a, S> {
+    stream: &'a mut S,
+    counter: &'a AtomicU64,
+}
+
+impl<'a, S> Counted<'a, S> {
+    pub fn new(stream: &'a mut S, counter: &'a AtomicU64) -> Self {
+        Counted { stream, counter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut *this.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().stream).poll_close(cx)
+    }
+}
+
+/// 写入停滞超过 `limit` 时以 [`io::ErrorKind::TimedOut`] 失败的写入端。
+///
+/// 持续很久的响应（例如事件流）无法给整个响应设期限，只能要求每次写入都有进展。
+/// 计时器在写不动的时候才开始计时，写出数据之后就停下，下次写不动时再重新设定期限，
+/// 整个写入端自始至终只用一个计时器。
+pub struct StallGuard<'a, S> {
+    stream: &'a mut S,
+    limit: Duration,
+    timer: Sleep,
+    /// 计时器是否正在计时，也就是上一次写入之后是否一直写不动。
+    armed: bool,
+}
+
+impl<'a, S> StallGuard<'a, S> {
+    pub fn new(stream: &'a mut S, limit: Duration) -> Self {
+        StallGuard {
+            stream,
+            limit,
+            timer: sleep(limit),
+            armed: false,
+        }
+    }
+
+    fn guard<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.armed = false;
+            return poll;
+        }
+        if !self.armed {
+            self.timer.reset(Instant::now() + self.limit);
+            self.armed = true;
+        }
+        match Pin::new(&mut self.timer).poll(cx) {
+            Poll::Ready(()) => {
+                self.armed = false;
+                Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, Elapsed)))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for StallGuard<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.stream).poll_write(cx, buf);
+        this.guard(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.stream).poll_flush(cx);
+        this.guard(cx, poll)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.stream).poll_close(cx);
+        this.guard(cx, poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+    };
+    use std::cell::Cell;
+
+    #[test]
+    fn slow_readers_are_cut_off() {
+        let counter = AtomicU64::new(0);
+        let rate = MinDataRate {
+            bytes_per_second: 1000,
+            grace_period: Duration::from_millis(50),
+        };
+        let started = Instant::now();
+        let result = block_on(read_within(
+            Duration::from_secs(5),
+            Some(rate),
+            &counter,
+            future::pending::<()>(),
+        ));
+        assert_eq!(result, Err(Elapsed));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let mut input: &[u8] = b"hello";
+        let mut buf = [0; 5];
+        let mut counted = Counted::new(&mut input, &counter);
+        let result = block_on(read_within(
+            Duration::from_secs(5),
+            Some(rate),
+            &counter,
+            counted.read_exact(&mut buf),
+        ));
+        result.unwrap().unwrap();
+        assert_eq!(counter.load(Ordering::Relaxed), 5);
+    }
+
+    /// `stalled` 为 `true` 时一直写不动、也不会唤醒任务的写入端。
+    struct Gate<'a> {
+        stalled: &'a Cell<bool>,
+    }
+
+    impl AsyncWrite for Gate<'_> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            match self.stalled.get() {
+                true => Poll::Pending,
+                false => Poll::Ready(Ok(buf.len())),
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn stalled_writes_time_out_and_progress_rearms_the_timer() {
+        let limit = Duration::from_millis(20);
+        let stalled = Cell::new(false);
+        let mut gate = Gate { stalled: &stalled };
+        let mut guard = StallGuard::new(&mut gate, limit);
+        for _ in 0..3 {
+            stalled.set(true);
+            let started = Instant::now();
+            let error = block_on(guard.write(b"x")).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+            // 每次卡住都要重新等满一个期限，而不是沿用上一次已经到期的期限。
+            assert!(started.elapsed() >= limit);
+
+            stalled.set(false);
+            assert_eq!(block_on(guard.write(b"x")).unwrap(), 1);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! 处理连接时可能出现的错误。
+
+use std::{error, fmt, io};
+
+use crate::{
+    request::{ParseError, ReadError},
+    time::Elapsed,
+};
+
+/// 处理一个连接时出现的错误。
+///
+/// 这些错误只影响出错的那个连接，服务器记录下来之后应当继续处理其他连接。
+#[derive(Debug)]
+pub enum ServerError {
+    /// 读写套接字失败，例如客户端重置了连接。
+    Io(io::Error),
+    /// 客户端发来的请求格式有误，此时已经回复了对应的错误响应。
+    Parse(ParseError),
+    /// 客户端在规定的时间内没有发完请求。
+    Timeout(Elapsed),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Io(e) => write!(f, "I/O error: {e}"),
+            ServerError::Parse(e) => write!(f, "malformed request: {e}"),
+            ServerError::Timeout(e) => write!(f, "timed out: {e}"),
+        }
+    }
+}
+
+impl error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ServerError::Io(e) => Some(e),
+            ServerError::Parse(e) => Some(e),
+            ServerError::Timeout(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for ServerError {
+    fn from(e: io::Error) -> Self {
+        ServerError::Io(e)
+    }
+}
+
+impl From<ParseError> for ServerError {
+    fn from(e: ParseError) -> Self {
+        ServerError::Parse(e)
+    }
+}
+
+impl From<Elapsed> for ServerError {
+    fn from(e: Elapsed) -> Self {
+        ServerError::Timeout(e)
+    }
+}
+
+impl From<ReadError> for ServerError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => ServerError::Io(e),
+            ReadError::Parse(e) => ServerError::Parse(e),
+            ReadError::UnexpectedEof => ServerError::Io(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+}
+
+/// 接受连接时遇到的错误是否是因为资源（通常是文件描述符）耗尽。
+///
+/// 这类错误不会因为立即重试而消失，反而会让接受循环空转，应当等一会儿再继续接受连接，
+/// 让正在处理的连接有机会结束并释放资源。
+pub fn is_resource_exhausted(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return matches!(code, libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM);
+    }
+    e.kind() == io::ErrorKind::OutOfMemory
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_errors() {
+        #[cfg(unix)]
+        assert!(is_resource_exhausted(&io::Error::from_raw_os_error(libc::EMFILE)));
+        assert!(!is_resource_exhausted(&io::ErrorKind::ConnectionAborted.into()));
+
+        let e = ServerError::from(ReadError::UnexpectedEof);
+        assert!(matches!(&e, ServerError::Io(e) if e.kind() == io::ErrorKind::UnexpectedEof));
+        let e = ServerError::from(ParseError::InvalidVersion);
+        assert!(error::Error::source(&e).is_some());
+        assert!(e.to_string().starts_with("malformed request"));
+    }
+}
@@ -0,0 +1,1714 @@
+//! HTTP/2（RFC 9113）的服务器端。
+//!
+//! 一个连接上可以同时进行多个请求，每个请求占用一个流。[`serve`] 在当前任务中读取帧，
+//! 维护 HPACK 和流量控制的状态；每个流的处理函数则通过调用者给出的 `spawn`
+//! 交给执行器，作为独立的任务运行，慢的请求不会挡住同一个连接上的其他请求。
+//! 所有要发送的帧都经由一个通道交给同一个写入循环，按顺序写到连接上。
+//!
+//! 处理函数用的仍然是 [`Request`] 和 [`Response`]：请求的 `version` 是 [`Version::Http2`]，
+//! `:authority` 会变成 `Host` 头部；响应中只对 HTTP/1.1 有意义的头部（例如 `Connection`）
+//! 会被去掉。服务器推送没有实现，`PRIORITY` 帧会被忽略。
+
+use std::{
+    collections::HashMap,
+    error, fmt,
+    future::Future,
+    io, mem,
+    pin::{pin, Pin},
+    sync::{Arc, Mutex, MutexGuard},
+    task::{ready, Context, Poll, Waker},
+};
+
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle, BoxFuture},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    stream::StreamExt,
+};
+
+use crate::{
+    body::{Item, RequestBody},
+    connection::Config,
+    deadline::StallGuard,
+    error::ServerError,
+    headers::Headers,
+    hpack,
+    request::{Method, ParseError, Request, Version},
+    response::{Body, Response, StatusCode},
+    time::{sleep, timeout, Sleep},
+};
+
+/// 客户端在连接开始时发送的前言。
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// 同时进行的流的上限，通过 `SETTINGS_MAX_CONCURRENT_STREAMS` 告诉客户端。
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// 每个流的接收窗口。处理函数不读请求体时，客户端最多只能再发这么多数据。
+const STREAM_WINDOW: u32 = 256 * 1024;
+/// 整个连接的接收窗口。收到数据之后立即归还，真正限制内存占用的是每个流的窗口。
+const CONNECTION_WINDOW: u32 = 1024 * 1024;
+/// 协议规定的初始窗口大小和最大帧长度，在对方的 `SETTINGS` 生效之前使用。
+const DEFAULT_WINDOW: u32 = 65_535;
+const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+/// 读取帧时每次从连接上读取的字节数。
+const READ_CHUNK: usize = 16 * 1024;
+
+mod frame {
+    pub const DATA: u8 = 0x0;
+    pub const HEADERS: u8 = 0x1;
+    pub const PRIORITY: u8 = 0x2;
+    pub const RST_STREAM: u8 = 0x3;
+    pub const SETTINGS: u8 = 0x4;
+    pub const PUSH_PROMISE: u8 = 0x5;
+    pub const PING: u8 = 0x6;
+    pub const GOAWAY: u8 = 0x7;
+    pub const WINDOW_UPDATE: u8 = 0x8;
+    pub const CONTINUATION: u8 = 0x9;
+}
+
+mod flag {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+mod setting {
+    pub const ENABLE_PUSH: u16 = 0x2;
+    pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+    pub const MAX_FRAME_SIZE: u16 = 0x5;
+    pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+}
+
+/// `RST_STREAM` 和 `GOAWAY` 中的错误码。
+pub mod error_code {
+    pub const NO_ERROR: u32 = 0x0;
+    pub const PROTOCOL_ERROR: u32 = 0x1;
+    pub const INTERNAL_ERROR: u32 = 0x2;
+    pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+    pub const STREAM_CLOSED: u32 = 0x5;
+    pub const FRAME_SIZE_ERROR: u32 = 0x6;
+    pub const REFUSED_STREAM: u32 = 0x7;
+    pub const CANCEL: u32 = 0x8;
+    pub const COMPRESSION_ERROR: u32 = 0x9;
+}
+
+use error_code::*;
+
+/// 需要关闭整个连接的协议错误。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolError {
+    pub code: u32,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP/2 protocol error {:#x}: {}", self.code, self.reason)
+    }
+}
+
+impl error::Error for ProtocolError {}
+
+impl From<ProtocolError> for ServerError {
+    fn from(e: ProtocolError) -> Self {
+        ServerError::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+fn protocol_error<T>(code: u32, reason: &'static str) -> Result<T, Error> {
+    Err(Error::Protocol(ProtocolError { code, reason }))
+}
+
+#[derive(Debug)]
+enum Error {
+    Io(io::Error),
+    Protocol(ProtocolError),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<Error> for ServerError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => ServerError::Io(e),
+            Error::Protocol(e) => e.into(),
+        }
+    }
+}
+
+/// 从 `stream` 中读取数据追加到 `buf` 中，直到可以判断客户端是否以 HTTP/2 的前言开头。
+///
+/// 用于没有经过 TLS 协商、需要按照“事先知道”（prior knowledge）的方式识别 h2c 的连接。
+/// 不论结果如何，读出的数据都留在 `buf` 中，应当原样交给 [`serve`] 或者
+/// [`connection::serve_buffered`](crate::connection::serve_buffered)。
+pub async fn is_preface(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> io::Result<bool> {
+    loop {
+        let n = buf.len().min(PREFACE.len());
+        if buf[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        if crate::request::fill(stream, buf).await? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+/// 在 `stream` 上提供 HTTP/2 服务，直到连接需要关闭。
+///
+/// `buf` 是已经从连接上读出的数据（例如 [`is_preface`] 读出的前言），可以为空。
+/// `handler` 在读取帧的任务中被调用，它返回的期物连同写回响应的过程一起交给 `spawn`。
+///
+/// [`Config`] 中以下几项同样适用于 HTTP/2：
+///
+/// - [`idle_timeout`](Config::idle_timeout)：没有进行中的流时等待新请求的最长时间；
+/// - [`header_timeout`](Config::header_timeout)：读完前言的最长时间；
+/// - [`write_timeout`](Config::write_timeout)：每次写入必须在这个时间之内有进展；
+/// - [`limits`](Config::limits)：头部和请求体的上限；
+/// - [`stream_bodies`](Config::stream_bodies)：为 `false` 时请求体收完之后才调用处理函数；
+/// - [`shutdown`](Config::shutdown)：收到关闭信号后发送 `GOAWAY`，等进行中的流结束后关闭连接。
+///
+/// 请求体的读取由流量控制限制，不再受 `body_timeout` 和 `min_data_rate` 的约束。
+pub async fn serve<S, H, F, E>(
+    stream: S,
+    buf: Vec<u8>,
+    config: &Config,
+    handler: H,
+    spawn: E,
+) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    H: FnMut(Request) -> F,
+    F: Future<Output = Response> + Send + 'static,
+    E: Fn(BoxFuture<'static, ()>),
+{
+    let (mut reader, mut writer) = stream.split();
+    let (outgoing, frames) = mpsc::unbounded();
+    let shared = Arc::new(Shared::default());
+    let connection = Connection::new(config, handler, spawn, shared.clone(), outgoing);
+    let read = pin!(connection.run(&mut reader, buf));
+    let write = pin!(write_frames(
+        StallGuard::new(&mut writer, config.write_timeout),
+        frames,
+        shared
+    ));
+    match future::select(read, write).await {
+        // 读取结束之后，写入循环会在写完剩下的帧（例如 `GOAWAY`）之后结束。
+        future::Either::Left((result, write)) => {
+            let written = write.await;
+            result?;
+            Ok(written?)
+        }
+        future::Either::Right((written, _)) => Ok(written?),
+    }
+}
+
+/// 帧头。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Head {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+}
+
+/// 一个收到的帧：帧头和负载。
+type Frame = (Head, Vec<u8>);
+
+/// 读出下一个完整的帧，连接在两个帧之间被关闭时返回 `None`。
+///
+/// 读取的过程中还要同时等待其他事件，所以这里直接轮询：没有读完的数据都留在 `buf` 中，
+/// 下一次调用时接着读。
+fn poll_frame(
+    reader: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Option<Frame>, Error>> {
+    let mut chunk = [0; READ_CHUNK];
+    loop {
+        if buf.len() >= 9 {
+            let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+            if len > DEFAULT_MAX_FRAME_SIZE {
+                return Poll::Ready(protocol_error(
+                    FRAME_SIZE_ERROR,
+                    "frame is larger than SETTINGS_MAX_FRAME_SIZE",
+                ));
+            }
+            if buf.len() >= 9 + len {
+                let head = Head {
+                    kind: buf[3],
+                    flags: buf[4],
+                    stream: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7fff_ffff,
+                };
+                let payload = buf[9..9 + len].to_vec();
+                buf.drain(..9 + len);
+                return Poll::Ready(Ok(Some((head, payload))));
+            }
+        }
+        match ready!(Pin::new(&mut *reader).poll_read(cx, &mut chunk)) {
+            Ok(0) if buf.is_empty() => return Poll::Ready(Ok(None)),
+            // 不少客户端关闭 HTTP/2 连接时不发送 TLS 的 `close_notify`，在两帧之间断开也算正常关闭。
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && buf.is_empty() => {
+                return Poll::Ready(Ok(None))
+            }
+            Ok(0) => return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) => return Poll::Ready(Err(e.into())),
+        }
+    }
+}
+
+/// 要写到连接上的一帧。
+#[derive(Debug)]
+enum Outgoing {
+    Settings(Vec<(u16, u32)>),
+    SettingsAck,
+    PingAck([u8; 8]),
+    GoAway {
+        last_stream: u32,
+        code: u32,
+    },
+    WindowUpdate {
+        stream: u32,
+        increment: u32,
+    },
+    Reset {
+        stream: u32,
+        code: u32,
+    },
+    /// 完整的头部块，放不进一帧时会拆成 `HEADERS` 和若干个 `CONTINUATION`。
+    Headers {
+        stream: u32,
+        block: Vec<u8>,
+        end_stream: bool,
+    },
+    /// 长度不超过对方的 `SETTINGS_MAX_FRAME_SIZE`，并且已经扣除了发送窗口。
+    Data {
+        stream: u32,
+        data: Vec<u8>,
+        end_stream: bool,
+    },
+}
+
+fn encode_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[kind, flags]);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+impl Outgoing {
+    fn encode(self, max_frame_size: usize, out: &mut Vec<u8>) {
+        match self {
+            Outgoing::Settings(settings) => {
+                let payload: Vec<u8> = settings
+                    .iter()
+                    .flat_map(|&(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()].concat())
+                    .collect();
+                encode_frame(out, frame::SETTINGS, 0, 0, &payload);
+            }
+            Outgoing::SettingsAck => encode_frame(out, frame::SETTINGS, flag::ACK, 0, &[]),
+            Outgoing::PingAck(data) => encode_frame(out, frame::PING, flag::ACK, 0, &data),
+            Outgoing::GoAway { last_stream, code } => {
+                let payload = [last_stream.to_be_bytes(), code.to_be_bytes()].concat();
+                encode_frame(out, frame::GOAWAY, 0, 0, &payload);
+            }
+            Outgoing::WindowUpdate { stream, increment } => {
+                encode_frame(
+                    out,
+                    frame::WINDOW_UPDATE,
+                    0,
+                    stream,
+                    &increment.to_be_bytes(),
+                );
+            }
+            Outgoing::Reset { stream, code } => {
+                encode_frame(out, frame::RST_STREAM, 0, stream, &code.to_be_bytes());
+            }
+            Outgoing::Headers {
+                stream,
+                block,
+                end_stream,
+            } => {
+                let mut chunks = block.chunks(max_frame_size).peekable();
+                let mut kind = frame::HEADERS;
+                let mut flags = if end_stream { flag::END_STREAM } else { 0 };
+                // 空的头部块也要发出一个 `HEADERS` 帧。
+                let first: &[u8] = chunks.next().unwrap_or_default();
+                let mut chunk = Some(first);
+                while let Some(payload) = chunk {
+                    chunk = chunks.next();
+                    if chunk.is_none() {
+                        flags |= flag::END_HEADERS;
+                    }
+                    encode_frame(out, kind, flags, stream, payload);
+                    (kind, flags) = (frame::CONTINUATION, 0);
+                }
+            }
+            Outgoing::Data {
+                stream,
+                data,
+                end_stream,
+            } => {
+                let flags = if end_stream { flag::END_STREAM } else { 0 };
+                encode_frame(out, frame::DATA, flags, stream, &data);
+            }
+        }
+    }
+}
+
+/// 把 `frames` 中的帧依次写到连接上，通道中暂时没有帧时才刷新，所有发送端都消失后关闭连接。
+async fn write_frames(
+    writer: impl AsyncWrite + Unpin,
+    mut frames: mpsc::UnboundedReceiver<Outgoing>,
+    shared: Arc<Shared>,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut encoded = Vec::new();
+    loop {
+        let frame = match frames.try_recv() {
+            Ok(frame) => frame,
+            Err(mpsc::TryRecvError::Closed) => break,
+            Err(mpsc::TryRecvError::Empty) => {
+                writer.flush().await?;
+                match frames.next().await {
+                    Some(frame) => frame,
+                    None => break,
+                }
+            }
+        };
+        encoded.clear();
+        frame.encode(shared.lock().max_frame_size, &mut encoded);
+        writer.write_all(&encoded).await?;
+    }
+    writer.flush().await?;
+    writer.close().await
+}
+
+/// 读取帧的任务和各个流的任务共享的发送状态。
+#[derive(Default)]
+struct Shared {
+    state: Mutex<SendState>,
+}
+
+struct SendState {
+    /// 连接的发送窗口。
+    window: i64,
+    /// 对方的 `SETTINGS_INITIAL_WINDOW_SIZE`，新流的发送窗口从这里开始。
+    initial_window: i64,
+    /// 对方的 `SETTINGS_MAX_FRAME_SIZE`。
+    max_frame_size: usize,
+    /// 任务还在运行的流。
+    streams: HashMap<u32, SendStream>,
+}
+
+struct SendStream {
+    window: i64,
+    /// 等待发送窗口的任务。
+    waker: Option<Waker>,
+    abort: AbortHandle,
+}
+
+impl Default for SendState {
+    fn default() -> Self {
+        SendState {
+            window: DEFAULT_WINDOW as i64,
+            initial_window: DEFAULT_WINDOW as i64,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            streams: HashMap::new(),
+        }
+    }
+}
+
+impl SendState {
+    fn wake_all(&mut self) {
+        for stream in self.streams.values_mut() {
+            if let Some(waker) = stream.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, SendState> {
+        self.state.lock().unwrap()
+    }
+
+    /// 为流 `id` 申请最多 `want` 个字节的发送窗口，返回 0 表示流已经不存在了。
+    fn poll_capacity(&self, id: u32, want: usize, cx: &mut Context<'_>) -> Poll<usize> {
+        let mut state = self.lock();
+        let max = state.window.min(state.max_frame_size as i64);
+        let Some(stream) = state.streams.get_mut(&id) else {
+            return Poll::Ready(0);
+        };
+        let n = max.min(stream.window).min(want as i64);
+        if n <= 0 {
+            stream.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        stream.window -= n;
+        state.window -= n;
+        Poll::Ready(n as usize)
+    }
+}
+
+/// 流的任务发给读取帧的任务的通知。
+enum Signal {
+    /// 处理函数从请求体中读走了这么多数据，可以归还给客户端了。
+    Consumed(u32, usize),
+    /// 流的任务结束了。
+    Finished(u32),
+}
+
+/// 请求还没有收完的流。
+struct RecvStream {
+    /// 接收窗口。
+    window: i64,
+    received: u64,
+    content_length: Option<u64>,
+    body: PendingBody,
+}
+
+enum PendingBody {
+    /// 请求体经由通道交给已经在运行的处理函数。
+    Streaming(mpsc::UnboundedSender<Item>),
+    /// 请求体收完之后再调用处理函数。
+    Buffering(Request),
+    /// 处理函数不再需要请求体，收到的数据直接丢掉。
+    Discarding,
+}
+
+/// 读取帧的一侧的连接状态。
+struct Connection<'c, H, E> {
+    config: &'c Config,
+    handler: H,
+    spawn: E,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    signals: mpsc::UnboundedSender<Signal>,
+    decoder: hpack::Decoder,
+    streams: HashMap<u32, RecvStream>,
+    /// 客户端打开过的最大的流。
+    last_stream: u32,
+    /// 连接的接收窗口。
+    window: i64,
+    /// 正在接收的头部块：所属的流、是否结束流，以及已经收到的部分。
+    continuation: Option<(u32, bool, Vec<u8>)>,
+    /// 已经发出或收到了 `GOAWAY`，不再接受新的流。
+    going_away: bool,
+}
+
+impl<'c, H, F, E> Connection<'c, H, E>
+where
+    H: FnMut(Request) -> F,
+    F: Future<Output = Response> + Send + 'static,
+    E: Fn(BoxFuture<'static, ()>),
+{
+    fn new(
+        config: &'c Config,
+        handler: H,
+        spawn: E,
+        shared: Arc<Shared>,
+        outgoing: mpsc::UnboundedSender<Outgoing>,
+    ) -> Self {
+        // 接收端只在 `run` 中使用，这里先放一个占位的发送端。
+        let (signals, _) = mpsc::unbounded();
+        Connection {
+            config,
+            handler,
+            spawn,
+            shared,
+            outgoing,
+            signals,
+            decoder: hpack::Decoder::default(),
+            streams: HashMap::new(),
+            last_stream: 0,
+            window: DEFAULT_WINDOW as i64,
+            continuation: None,
+            going_away: false,
+        }
+    }
+
+    fn send(&self, frame: Outgoing) {
+        // 写入循环已经结束时，`serve` 很快也会结束，这里不必处理。
+        let _ = self.outgoing.unbounded_send(frame);
+    }
+
+    /// 进行中的流的数量：任务还在运行的流，加上还在等待请求体的流。
+    fn active(&self) -> usize {
+        let buffering = self
+            .streams
+            .values()
+            .filter(|stream| matches!(stream.body, PendingBody::Buffering(_)))
+            .count();
+        self.shared.lock().streams.len() + buffering
+    }
+
+    fn go_away(&mut self, code: u32) {
+        if !self.going_away || code != NO_ERROR {
+            self.send(Outgoing::GoAway {
+                last_stream: self.last_stream,
+                code,
+            });
+        }
+        self.going_away = true;
+    }
+
+    async fn run(
+        mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        mut buf: Vec<u8>,
+    ) -> Result<(), Error> {
+        let result = self.run_inner(reader, &mut buf).await;
+        if let Err(Error::Protocol(e)) = &result {
+            self.go_away(e.code);
+        }
+        result
+    }
+
+    async fn run_inner(
+        &mut self,
+        reader: &mut (impl AsyncRead + Unpin),
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let preface = async {
+            while buf.len() < PREFACE.len() {
+                let len = buf.len();
+                buf.resize(PREFACE.len(), 0);
+                let result = reader.read(&mut buf[len..]).await;
+                buf.truncate(len + *result.as_ref().unwrap_or(&0));
+                if result? == 0 {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            Ok(())
+        };
+        match timeout(self.config.header_timeout, preface).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::TimedOut, e).into()),
+        }
+        if !buf.starts_with(PREFACE) {
+            return protocol_error(PROTOCOL_ERROR, "invalid connection preface");
+        }
+        buf.drain(..PREFACE.len());
+
+        self.send(Outgoing::Settings(vec![
+            (setting::ENABLE_PUSH, 0),
+            (
+                setting::MAX_CONCURRENT_STREAMS,
+                MAX_CONCURRENT_STREAMS as u32,
+            ),
+            (setting::INITIAL_WINDOW_SIZE, STREAM_WINDOW),
+            (
+                setting::MAX_HEADER_LIST_SIZE,
+                self.config.limits.max_head_bytes as u32,
+            ),
+        ]));
+        self.send(Outgoing::WindowUpdate {
+            stream: 0,
+            increment: CONNECTION_WINDOW - DEFAULT_WINDOW,
+        });
+        self.window = CONNECTION_WINDOW as i64;
+
+        let (signals, mut signal_receiver) = mpsc::unbounded();
+        self.signals = signals;
+        let mut shutdown = self.config.shutdown.wait();
+        let mut idle: Option<Sleep> = None;
+        let mut first = true;
+        loop {
+            let active = self.active();
+            if active > 0 {
+                idle = None;
+            } else if self.going_away {
+                return Ok(());
+            } else if idle.is_none() {
+                idle = Some(sleep(self.config.idle_timeout));
+            }
+
+            let going_away = self.going_away;
+            let event = future::poll_fn(|cx| {
+                if let Poll::Ready(frame) = poll_frame(reader, buf, cx) {
+                    return Poll::Ready(Event::Frame(frame));
+                }
+                if let Poll::Ready(signal) = signal_receiver.poll_next_unpin(cx) {
+                    return Poll::Ready(Event::Signal(signal));
+                }
+                if !going_away && Pin::new(&mut shutdown).poll(cx).is_ready() {
+                    return Poll::Ready(Event::Shutdown);
+                }
+                match idle.as_mut().map(|idle| Pin::new(idle).poll(cx)) {
+                    Some(Poll::Ready(())) => Poll::Ready(Event::Idle),
+                    _ => Poll::Pending,
+                }
+            })
+            .await;
+
+            match event {
+                Event::Frame(frame) => {
+                    let Some((head, payload)) = frame? else {
+                        return Ok(());
+                    };
+                    if mem::take(&mut first)
+                        && (head.kind != frame::SETTINGS || head.flags & flag::ACK != 0)
+                    {
+                        return protocol_error(PROTOCOL_ERROR, "the first frame must be SETTINGS");
+                    }
+                    self.on_frame(head, payload)?;
+                }
+                Event::Signal(Some(Signal::Consumed(id, n))) => {
+                    if let Some(stream) = self.streams.get_mut(&id) {
+                        stream.window += n as i64;
+                        self.send(Outgoing::WindowUpdate {
+                            stream: id,
+                            increment: n as u32,
+                        });
+                    }
+                }
+                Event::Signal(Some(Signal::Finished(id))) => {
+                    // 响应已经发完了，客户端却还在发送请求体，告诉它不必再发了。
+                    if self.streams.remove(&id).is_some() {
+                        self.send(Outgoing::Reset {
+                            stream: id,
+                            code: NO_ERROR,
+                        });
+                    }
+                }
+                Event::Signal(None) => {}
+                Event::Shutdown => self.go_away(NO_ERROR),
+                Event::Idle => {
+                    self.go_away(NO_ERROR);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn on_frame(&mut self, head: Head, payload: Vec<u8>) -> Result<(), Error> {
+        if let Some((id, ..)) = &self.continuation {
+            if head.kind != frame::CONTINUATION || head.stream != *id {
+                return protocol_error(PROTOCOL_ERROR, "expected CONTINUATION");
+            }
+        }
+        match head.kind {
+            frame::DATA => self.on_data(head, payload),
+            frame::HEADERS => self.on_headers(head, payload),
+            frame::CONTINUATION => {
+                let Some((id, end_stream, mut block)) = self.continuation.take() else {
+                    return protocol_error(PROTOCOL_ERROR, "unexpected CONTINUATION");
+                };
+                block.extend_from_slice(&payload);
+                self.on_header_fragment(id, end_stream, block, head.flags)
+            }
+            frame::PRIORITY if payload.len() != 5 => {
+                protocol_error(FRAME_SIZE_ERROR, "invalid PRIORITY frame")
+            }
+            frame::RST_STREAM => {
+                if payload.len() != 4 {
+                    return protocol_error(FRAME_SIZE_ERROR, "invalid RST_STREAM frame");
+                }
+                if head.stream == 0 || head.stream > self.last_stream {
+                    return protocol_error(PROTOCOL_ERROR, "RST_STREAM on an idle stream");
+                }
+                self.close_stream(head.stream);
+                Ok(())
+            }
+            frame::SETTINGS => self.on_settings(head, &payload),
+            frame::PUSH_PROMISE => protocol_error(PROTOCOL_ERROR, "clients cannot push"),
+            frame::PING => {
+                if payload.len() != 8 || head.stream != 0 {
+                    return protocol_error(FRAME_SIZE_ERROR, "invalid PING frame");
+                }
+                if head.flags & flag::ACK == 0 {
+                    self.send(Outgoing::PingAck(payload.try_into().unwrap()));
+                }
+                Ok(())
+            }
+            frame::GOAWAY => {
+                // 客户端不再打开新的流，已有的流照常处理完。
+                self.going_away = true;
+                Ok(())
+            }
+            frame::WINDOW_UPDATE => self.on_window_update(head, &payload),
+            // 未知的帧和 `PRIORITY` 一样忽略。
+            _ => Ok(()),
+        }
+    }
+
+    fn on_settings(&mut self, head: Head, payload: &[u8]) -> Result<(), Error> {
+        if head.stream != 0 {
+            return protocol_error(PROTOCOL_ERROR, "SETTINGS on a stream");
+        }
+        if head.flags & flag::ACK != 0 {
+            return match payload.is_empty() {
+                true => Ok(()),
+                false => protocol_error(FRAME_SIZE_ERROR, "SETTINGS ack with a payload"),
+            };
+        }
+        if !payload.len().is_multiple_of(6) {
+            return protocol_error(FRAME_SIZE_ERROR, "invalid SETTINGS frame");
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                setting::ENABLE_PUSH if value > 1 => {
+                    return protocol_error(PROTOCOL_ERROR, "invalid SETTINGS_ENABLE_PUSH");
+                }
+                setting::INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return protocol_error(
+                            FLOW_CONTROL_ERROR,
+                            "invalid SETTINGS_INITIAL_WINDOW_SIZE",
+                        );
+                    }
+                    // 已有的流的窗口按照差值调整，可能因此变成负数。
+                    let mut state = self.shared.lock();
+                    let delta = value as i64 - state.initial_window;
+                    state.initial_window = value as i64;
+                    for stream in state.streams.values_mut() {
+                        stream.window += delta;
+                        if stream.window > MAX_WINDOW {
+                            return protocol_error(FLOW_CONTROL_ERROR, "stream window overflow");
+                        }
+                    }
+                    state.wake_all();
+                }
+                setting::MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                        return protocol_error(PROTOCOL_ERROR, "invalid SETTINGS_MAX_FRAME_SIZE");
+                    }
+                    self.shared.lock().max_frame_size = value as usize;
+                }
+                // 我们只引用静态表，不关心对方的动态表大小；其余的设置也都用不上。
+                _ => {}
+            }
+        }
+        self.send(Outgoing::SettingsAck);
+        Ok(())
+    }
+
+    fn on_window_update(&mut self, head: Head, payload: &[u8]) -> Result<(), Error> {
+        let Ok(payload) = <[u8; 4]>::try_from(payload) else {
+            return protocol_error(FRAME_SIZE_ERROR, "invalid WINDOW_UPDATE frame");
+        };
+        let increment = (u32::from_be_bytes(payload) & 0x7fff_ffff) as i64;
+        let mut state = self.shared.lock();
+        if head.stream == 0 {
+            if increment == 0 {
+                return protocol_error(PROTOCOL_ERROR, "zero WINDOW_UPDATE");
+            }
+            state.window += increment;
+            if state.window > MAX_WINDOW {
+                return protocol_error(FLOW_CONTROL_ERROR, "connection window overflow");
+            }
+            state.wake_all();
+            return Ok(());
+        }
+        // 任务已经结束的流还可能收到窗口更新，直接忽略。
+        let Some(stream) = state.streams.get_mut(&head.stream) else {
+            return Ok(());
+        };
+        stream.window += increment;
+        if increment == 0 || stream.window > MAX_WINDOW {
+            drop(state);
+            let code = if increment == 0 {
+                PROTOCOL_ERROR
+            } else {
+                FLOW_CONTROL_ERROR
+            };
+            self.reset(head.stream, code);
+            return Ok(());
+        }
+        if let Some(waker) = stream.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self, head: Head, payload: Vec<u8>) -> Result<(), Error> {
+        if head.stream == 0 {
+            return protocol_error(PROTOCOL_ERROR, "HEADERS on stream 0");
+        }
+        let mut block = strip_padding(head.flags, &payload)?;
+        if head.flags & flag::PRIORITY != 0 {
+            block = block.get(5..).ok_or(Error::Protocol(ProtocolError {
+                code: PROTOCOL_ERROR,
+                reason: "invalid HEADERS frame",
+            }))?;
+        }
+        let end_stream = head.flags & flag::END_STREAM != 0;
+        self.on_header_fragment(head.stream, end_stream, block.to_vec(), head.flags)
+    }
+
+    /// 收到了头部块的一部分，`END_HEADERS` 之前先攒起来。
+    fn on_header_fragment(
+        &mut self,
+        id: u32,
+        end_stream: bool,
+        block: Vec<u8>,
+        flags: u8,
+    ) -> Result<(), Error> {
+        if flags & flag::END_HEADERS == 0 {
+            // 头部块必须解码完才能保持 HPACK 的状态一致，所以超过上限的头部块只能关闭连接。
+            if block.len() > 4 * self.config.limits.max_head_bytes {
+                return protocol_error(PROTOCOL_ERROR, "header block is too large");
+            }
+            self.continuation = Some((id, end_stream, block));
+            return Ok(());
+        }
+        let fields = match self
+            .decoder
+            .decode(&block, self.config.limits.max_head_bytes)
+        {
+            Ok(fields) => Ok(fields),
+            Err(hpack::DecodeError::ListTooLarge) => Err(()),
+            Err(_) => return protocol_error(COMPRESSION_ERROR, "invalid header block"),
+        };
+
+        if self.streams.contains_key(&id) {
+            // 请求体之后的尾部，内容用不上，但它意味着请求结束了。
+            if !end_stream {
+                self.reset(id, PROTOCOL_ERROR);
+                return Ok(());
+            }
+            self.end_body(id);
+            return Ok(());
+        }
+        if id.is_multiple_of(2) || id <= self.last_stream {
+            return protocol_error(PROTOCOL_ERROR, "invalid stream identifier");
+        }
+        if self.going_away {
+            return Ok(());
+        }
+        self.last_stream = id;
+        if self.active() >= MAX_CONCURRENT_STREAMS {
+            self.send(Outgoing::Reset {
+                stream: id,
+                code: REFUSED_STREAM,
+            });
+            return Ok(());
+        }
+
+        let Ok(fields) = fields else {
+            self.reject(id, end_stream, StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+            return Ok(());
+        };
+        let Some(mut request) = to_request(fields) else {
+            self.send(Outgoing::Reset {
+                stream: id,
+                code: PROTOCOL_ERROR,
+            });
+            return Ok(());
+        };
+        let content_length = match request.headers.get("content-length") {
+            Some(value) => match value.parse::<u64>() {
+                Ok(len) => Some(len),
+                Err(_) => {
+                    self.send(Outgoing::Reset {
+                        stream: id,
+                        code: PROTOCOL_ERROR,
+                    });
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        if content_length.is_some_and(|len| len > self.config.limits.max_body_bytes as u64) {
+            self.reject(id, end_stream, StatusCode::PAYLOAD_TOO_LARGE);
+            return Ok(());
+        }
+        if end_stream {
+            if content_length.is_some_and(|len| len > 0) {
+                self.send(Outgoing::Reset {
+                    stream: id,
+                    code: PROTOCOL_ERROR,
+                });
+            } else {
+                self.spawn_stream(id, request);
+            }
+            return Ok(());
+        }
+
+        let body = if self.config.stream_bodies {
+            let (sender, receiver) = mpsc::unbounded();
+            // 处理函数读走数据时才把接收窗口还给客户端，这样它读得慢时客户端也就发不动了。
+            let signals = self.signals.clone();
+            let receiver = receiver.inspect(move |item: &Item| {
+                if let Ok(Some(data)) = item {
+                    let _ = signals.unbounded_send(Signal::Consumed(id, data.len()));
+                }
+            });
+            request.set_body_stream(RequestBody::from_items(receiver));
+            self.spawn_stream(id, request);
+            PendingBody::Streaming(sender)
+        } else {
+            PendingBody::Buffering(request)
+        };
+        self.streams.insert(
+            id,
+            RecvStream {
+                window: STREAM_WINDOW as i64,
+                received: 0,
+                content_length,
+                body,
+            },
+        );
+        Ok(())
+    }
+
+    fn on_data(&mut self, head: Head, payload: Vec<u8>) -> Result<(), Error> {
+        if head.stream == 0 {
+            return protocol_error(PROTOCOL_ERROR, "DATA on stream 0");
+        }
+        // 连接的窗口立即归还，包括填充在内。
+        let len = payload.len();
+        self.window -= len as i64;
+        if self.window < 0 {
+            return protocol_error(FLOW_CONTROL_ERROR, "connection window exceeded");
+        }
+        if len > 0 {
+            self.window += len as i64;
+            self.send(Outgoing::WindowUpdate {
+                stream: 0,
+                increment: len as u32,
+            });
+        }
+        let data = strip_padding(head.flags, &payload)?;
+        let id = head.stream;
+        let end_stream = head.flags & flag::END_STREAM != 0;
+
+        let max_body = self.config.limits.max_body_bytes as u64;
+        let Some(stream) = self.streams.get_mut(&id) else {
+            if id > self.last_stream {
+                return protocol_error(PROTOCOL_ERROR, "DATA on an idle stream");
+            }
+            self.send(Outgoing::Reset {
+                stream: id,
+                code: STREAM_CLOSED,
+            });
+            return Ok(());
+        };
+        stream.window -= len as i64;
+        if stream.window < 0 {
+            self.reset(id, FLOW_CONTROL_ERROR);
+            return Ok(());
+        }
+        stream.received += data.len() as u64;
+        if stream
+            .content_length
+            .is_some_and(|expected| stream.received > expected)
+        {
+            self.fail_body(id, ParseError::InvalidContentLength);
+            return Ok(());
+        }
+        if stream.received > max_body {
+            self.fail_body(id, ParseError::BodyTooLarge);
+            if end_stream {
+                self.streams.remove(&id);
+            }
+            return Ok(());
+        }
+
+        // 除了交给处理函数的数据，其余的（填充、缓存或丢掉的数据）都立即归还。
+        let mut consumed = len - data.len();
+        match &mut stream.body {
+            PendingBody::Streaming(sender) => {
+                if !data.is_empty() && sender.unbounded_send(Ok(Some(data.to_vec()))).is_err() {
+                    stream.body = PendingBody::Discarding;
+                    consumed = len;
+                }
+            }
+            PendingBody::Buffering(request) => {
+                request.body.extend_from_slice(data);
+                consumed = len;
+            }
+            PendingBody::Discarding => consumed = len,
+        }
+        if consumed > 0 && !end_stream {
+            stream.window += consumed as i64;
+            self.send(Outgoing::WindowUpdate {
+                stream: id,
+                increment: consumed as u32,
+            });
+        }
+        if end_stream {
+            self.end_body(id);
+        }
+        Ok(())
+    }
+
+    /// 请求收完了。
+    fn end_body(&mut self, id: u32) {
+        let Some(stream) = self.streams.remove(&id) else {
+            return;
+        };
+        if stream
+            .content_length
+            .is_some_and(|expected| stream.received != expected)
+        {
+            self.streams.insert(id, stream);
+            self.fail_body(id, ParseError::InvalidContentLength);
+            self.streams.remove(&id);
+            return;
+        }
+        match stream.body {
+            PendingBody::Streaming(sender) => {
+                let _ = sender.unbounded_send(Ok(None));
+            }
+            PendingBody::Buffering(request) => self.spawn_stream(id, request),
+            PendingBody::Discarding => {}
+        }
+    }
+
+    /// 请求体有误。已经开始处理的请求会从请求体中读到错误，由处理函数决定如何回应；
+    /// 还没有开始处理的请求直接用对应的状态码回应。之后收到的数据都会被丢掉。
+    fn fail_body(&mut self, id: u32, error: ParseError) {
+        let Some(stream) = self.streams.get_mut(&id) else {
+            return;
+        };
+        let status = error.status_code();
+        match mem::replace(&mut stream.body, PendingBody::Discarding) {
+            PendingBody::Streaming(sender) => {
+                let error = io::Error::new(io::ErrorKind::InvalidData, error);
+                let _ = sender.unbounded_send(Err(error));
+            }
+            PendingBody::Buffering(_) => self.reject(id, false, status),
+            PendingBody::Discarding => {}
+        }
+    }
+
+    /// 不调用处理函数，直接回复一个没有响应体的错误响应。
+    fn reject(&mut self, id: u32, end_stream: bool, status: StatusCode) {
+        let mut block = Vec::new();
+        let status = status.as_u16().to_string();
+        hpack::encode([(":status", status.as_str())], &mut block);
+        self.send(Outgoing::Headers {
+            stream: id,
+            block,
+            end_stream: true,
+        });
+        // 响应已经完整了，请求剩下的部分不用再发了。
+        if !end_stream {
+            self.send(Outgoing::Reset {
+                stream: id,
+                code: NO_ERROR,
+            });
+            self.streams.remove(&id);
+        }
+    }
+
+    /// 因为错误关闭一个流。
+    fn reset(&mut self, id: u32, code: u32) {
+        self.close_stream(id);
+        self.send(Outgoing::Reset { stream: id, code });
+    }
+
+    /// 不再接收流上的数据，并取消它的任务。
+    fn close_stream(&mut self, id: u32) {
+        // 丢掉发送端，处理函数会从请求体中读到错误。
+        self.streams.remove(&id);
+        if let Some(stream) = self.shared.lock().streams.get(&id) {
+            stream.abort.abort();
+        }
+    }
+
+    fn spawn_stream(&mut self, id: u32, request: Request) {
+        let head = request.method == Method::Head;
+        let response = (self.handler)(request);
+        let (task, abort) = future::abortable(respond(
+            id,
+            response,
+            head,
+            self.shared.clone(),
+            self.outgoing.clone(),
+        ));
+        let mut state = self.shared.lock();
+        let window = state.initial_window;
+        state.streams.insert(
+            id,
+            SendStream {
+                window,
+                waker: None,
+                abort,
+            },
+        );
+        drop(state);
+        let shared = self.shared.clone();
+        let signals = self.signals.clone();
+        (self.spawn)(Box::pin(async move {
+            let _ = task.await;
+            shared.lock().streams.remove(&id);
+            let _ = signals.unbounded_send(Signal::Finished(id));
+        }));
+    }
+}
+
+impl<H, E> Drop for Connection<'_, H, E> {
+    fn drop(&mut self) {
+        // 连接要关闭了，还在运行的流都没有必要继续。`serve` 的期物被中途丢弃时也会走到这里，
+        // 否则等待发送窗口的流会永远等下去。
+        for stream in self.shared.lock().streams.values() {
+            stream.abort.abort();
+        }
+    }
+}
+
+enum Event {
+    Frame(Result<Option<Frame>, Error>),
+    Signal(Option<Signal>),
+    Shutdown,
+    Idle,
+}
+
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], Error> {
+    if flags & flag::PADDED == 0 {
+        return Ok(payload);
+    }
+    match payload.split_first() {
+        Some((&pad, rest)) if (pad as usize) <= rest.len() => {
+            Ok(&rest[..rest.len() - pad as usize])
+        }
+        _ => protocol_error(PROTOCOL_ERROR, "invalid padding"),
+    }
+}
+
+/// 只在 HTTP/1.1 的一个连接之内有意义的头部，HTTP/2 中不允许出现。
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// 把解码出的头部转换成请求，格式有误时返回 `None`。
+fn to_request(fields: Vec<hpack::Field>) -> Option<Request> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+    for (name, value) in fields {
+        let value = String::from_utf8_lossy(&value).into_owned();
+        if value.contains(['\r', '\n', '\0']) {
+            return None;
+        }
+        let pseudo = match name.as_slice() {
+            b":method" => &mut method,
+            b":scheme" => &mut scheme,
+            b":path" => &mut path,
+            b":authority" => &mut authority,
+            name if name.starts_with(b":") => return None,
+            _ => {
+                // 名字必须是小写。
+                let name = std::str::from_utf8(&name).ok()?;
+                if name.is_empty()
+                    || name
+                        .bytes()
+                        .any(|b| b.is_ascii_uppercase() || b <= b' ' || b == b':')
+                {
+                    return None;
+                }
+                if CONNECTION_HEADERS.contains(&name) || name == "te" && value != "trailers" {
+                    return None;
+                }
+                // 拆开发送的 Cookie 要重新合并起来。
+                match name {
+                    "cookie" => cookies.push(value),
+                    _ => headers.append(name, value),
+                }
+                continue;
+            }
+        };
+        // 伪头部不能重复，并且必须出现在普通头部之前。
+        if pseudo.is_some() || !headers.is_empty() || !cookies.is_empty() {
+            return None;
+        }
+        *pseudo = Some(value);
+    }
+
+    let method = Method::from_bytes(method?.as_bytes())?;
+    // `CONNECT` 的格式不同，这里不支持。
+    if method == Method::Connect {
+        return None;
+    }
+    scheme?;
+    let path = path?;
+    if !(path.starts_with('/') || path == "*" && method == Method::Options) {
+        return None;
+    }
+    let mut request = Request::new(method, &path);
+    request.version = Version::Http2;
+    if !cookies.is_empty() {
+        headers.append("cookie", cookies.join("; "));
+    }
+    if let Some(authority) = authority {
+        if !headers.contains("host") {
+            headers.append("host", authority);
+        }
+    }
+    request.headers = headers;
+    Some(request)
+}
+
+/// 运行处理函数并写回响应。
+async fn respond(
+    id: u32,
+    response: impl Future<Output = Response>,
+    head: bool,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+) {
+    let mut response = response.await;
+    // HTTP/2 中没有协议升级，`101` 不能作为最终的响应。
+    if response.status().as_u16() < 200 {
+        response = Response::text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "protocol upgrades are not supported over HTTP/2\n",
+        );
+    }
+    let forbids_body = response.status().forbids_body();
+    let mut block = Vec::new();
+    encode_head(&response, &mut block);
+    let body = response.into_body();
+    let no_body = head || forbids_body || body.is_empty();
+    let headers = Outgoing::Headers {
+        stream: id,
+        block,
+        end_stream: no_body,
+    };
+    if outgoing.unbounded_send(headers).is_err() || no_body {
+        return;
+    }
+
+    let sender = DataSender {
+        id,
+        shared,
+        outgoing,
+    };
+    match body {
+        Body::Bytes(bytes) => {
+            sender.send(&bytes, true).await;
+        }
+        Body::Reader { reader, len } => {
+            let mut reader = reader.take(len);
+            let mut sent = 0;
+            let mut chunk = vec![0; READ_CHUNK];
+            loop {
+                let n = match reader.read(&mut chunk).await {
+                    Ok(0) | Err(_) => {
+                        // 已经发出的 `content-length` 无法收回，只能重置这个流。
+                        sender.reset(INTERNAL_ERROR);
+                        return;
+                    }
+                    Ok(n) => n,
+                };
+                sent += n as u64;
+                if !sender.send(&chunk[..n], sent == len).await || sent == len {
+                    return;
+                }
+            }
+        }
+        Body::Stream(mut chunks) => {
+            while let Some(chunk) = chunks.next().await {
+                if !sender.send(&chunk, false).await {
+                    return;
+                }
+            }
+            sender.send(&[], true).await;
+        }
+    }
+}
+
+/// 状态行之外的头部转换成 HTTP/2 的格式：名字改成小写，去掉只对 HTTP/1.1 有意义的头部，
+/// `content-length` 根据响应体重新计算。
+fn encode_head(response: &Response, out: &mut Vec<u8>) {
+    let mut fields = vec![(":status".to_owned(), response.status().as_u16().to_string())];
+    for (name, value) in response.headers().iter() {
+        let name = name.to_ascii_lowercase();
+        if !CONNECTION_HEADERS.contains(&name.as_str()) && name != "content-length" {
+            fields.push((name, value.to_owned()));
+        }
+    }
+    if !response.status().forbids_body() {
+        if let Some(len) = response.body().len() {
+            fields.push(("content-length".to_owned(), len.to_string()));
+        }
+    }
+    hpack::encode(fields.iter().map(|(n, v)| (n.as_str(), v.as_str())), out);
+}
+
+/// 在流量控制的限制之内发送一个流的数据。
+struct DataSender {
+    id: u32,
+    shared: Arc<Shared>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl DataSender {
+    /// 发送 `data`，`end_stream` 表示这是最后的数据。流或者连接已经关闭时返回 `false`。
+    async fn send(&self, mut data: &[u8], end_stream: bool) -> bool {
+        if data.is_empty() {
+            if !end_stream {
+                return true;
+            }
+            let frame = Outgoing::Data {
+                stream: self.id,
+                data: Vec::new(),
+                end_stream,
+            };
+            return self.outgoing.unbounded_send(frame).is_ok();
+        }
+        while !data.is_empty() {
+            let n = future::poll_fn(|cx| self.shared.poll_capacity(self.id, data.len(), cx)).await;
+            if n == 0 {
+                return false;
+            }
+            let (piece, rest) = data.split_at(n);
+            data = rest;
+            let frame = Outgoing::Data {
+                stream: self.id,
+                data: piece.to_vec(),
+                end_stream: end_stream && data.is_empty(),
+            };
+            if self.outgoing.unbounded_send(frame).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn reset(&self, code: u32) {
+        let _ = self.outgoing.unbounded_send(Outgoing::Reset {
+            stream: self.id,
+            code,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::timeout;
+    use futures::channel::oneshot;
+    use mock_stream::{duplex, DuplexStream, MockStream};
+    use std::time::Duration;
+
+    /// 直接收发帧的测试客户端。
+    struct Peer {
+        stream: DuplexStream,
+        buf: Vec<u8>,
+        decoder: hpack::Decoder,
+    }
+
+    impl Peer {
+        /// 在后台任务中运行服务器，发送前言和 `settings`，并等到服务器的 `SETTINGS`。
+        async fn start<H, F>(config: Config, handler: H, settings: &[(u16, u32)]) -> Peer
+        where
+            H: FnMut(Request) -> F + Send + 'static,
+            F: Future<Output = Response> + Send + 'static,
+        {
+            let (client, server) = duplex(1024);
+            async_std::task::spawn(async move {
+                let spawn = |task| {
+                    async_std::task::spawn(task);
+                };
+                serve(server, Vec::new(), &config, handler, spawn).await
+            });
+            Peer::connect(client, settings).await
+        }
+
+        /// 在已经连上服务器的 `client` 上发送前言和 `settings`，并等到服务器的 `SETTINGS`。
+        async fn connect(client: DuplexStream, settings: &[(u16, u32)]) -> Peer {
+            let mut peer = Peer {
+                stream: client,
+                buf: Vec::new(),
+                decoder: hpack::Decoder::default(),
+            };
+            peer.stream.write_all(PREFACE).await.unwrap();
+            let mut frame = Vec::new();
+            Outgoing::Settings(settings.to_vec()).encode(DEFAULT_MAX_FRAME_SIZE, &mut frame);
+            peer.stream.write_all(&frame).await.unwrap();
+            let (head, _) = peer.recv().await;
+            assert_eq!((head.kind, head.flags), (frame::SETTINGS, 0));
+            peer
+        }
+
+        async fn send(&mut self, frame: Outgoing) {
+            let mut encoded = Vec::new();
+            frame.encode(DEFAULT_MAX_FRAME_SIZE, &mut encoded);
+            self.stream.write_all(&encoded).await.unwrap();
+        }
+
+        async fn request(&mut self, stream: u32, fields: &[(&str, &str)], end_stream: bool) {
+            let mut block = Vec::new();
+            hpack::encode(fields.iter().copied(), &mut block);
+            self.send(Outgoing::Headers {
+                stream,
+                block,
+                end_stream,
+            })
+            .await;
+        }
+
+        async fn get(&mut self, stream: u32, path: &str) {
+            let fields = [(":method", "GET"), (":scheme", "http"), (":path", path)];
+            self.request(stream, &fields, true).await;
+        }
+
+        async fn recv(&mut self) -> Frame {
+            let frame = future::poll_fn(|cx| poll_frame(&mut self.stream, &mut self.buf, cx));
+            frame.await.unwrap().expect("connection closed")
+        }
+
+        /// 读出 `n` 个完整的响应，按照完成的顺序返回流、状态码和响应体。
+        async fn responses(&mut self, n: usize) -> Vec<(u32, String, Vec<u8>)> {
+            let mut pending = HashMap::new();
+            let mut done = Vec::new();
+            while done.len() < n {
+                let (head, payload) = self.recv().await;
+                let end_stream = head.flags & flag::END_STREAM != 0;
+                match head.kind {
+                    frame::HEADERS => {
+                        assert_ne!(head.flags & flag::END_HEADERS, 0);
+                        let fields = self.decoder.decode(&payload, 4096).unwrap();
+                        assert_eq!(fields[0].0, b":status");
+                        let status = String::from_utf8(fields[0].1.clone()).unwrap();
+                        pending.insert(head.stream, (status, Vec::new()));
+                    }
+                    frame::DATA => pending.get_mut(&head.stream).unwrap().1.extend(payload),
+                    _ => continue,
+                }
+                if end_stream {
+                    let (status, body) = pending.remove(&head.stream).unwrap();
+                    done.push((head.stream, status, body));
+                }
+            }
+            done
+        }
+    }
+
+    #[async_std::test]
+    async fn multiplexes_streams() {
+        // 第一个请求要等第二个请求开始处理之后才能完成，只有两者并发执行时才不会卡住。
+        let (sender, receiver) = oneshot::channel::<()>();
+        let mut slots = (Some(sender), Some(receiver));
+        let handler = move |request: Request| {
+            let (sender, receiver) = match request.path.as_str() {
+                "/slow" => (None, slots.1.take()),
+                _ => (slots.0.take(), None),
+            };
+            async move {
+                match (sender, receiver) {
+                    (Some(sender), _) => sender.send(()).unwrap(),
+                    (_, Some(receiver)) => receiver.await.unwrap(),
+                    _ => unreachable!(),
+                }
+                assert_eq!(request.version, Version::Http2);
+                let host = request.headers.get("Host").unwrap_or_default();
+                Response::text(StatusCode::OK, format!("{} {host}", request.path))
+            }
+        };
+        let mut peer = Peer::start(Config::default(), handler, &[]).await;
+        peer.get(1, "/slow").await;
+        let fields = [
+            (":method", "GET"),
+            (":scheme", "http"),
+            (":path", "/fast"),
+            (":authority", "example.com"),
+        ];
+        peer.request(3, &fields, true).await;
+        let mut responses = peer.responses(2).await;
+        responses.sort();
+        assert_eq!(
+            responses,
+            [
+                (1, "200".to_owned(), b"/slow ".to_vec()),
+                (3, "200".to_owned(), b"/fast example.com".to_vec()),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn waits_for_window_updates() {
+        let handler = |_| async { Response::text(StatusCode::OK, "x".repeat(25)) };
+        let settings = [(setting::INITIAL_WINDOW_SIZE, 10)];
+        let mut peer = Peer::start(Config::default(), handler, &settings).await;
+        peer.get(1, "/").await;
+        let mut data = Vec::new();
+        while data.len() < 10 {
+            let (head, payload) = peer.recv().await;
+            if head.kind == frame::DATA {
+                assert_eq!(head.flags & flag::END_STREAM, 0);
+                data.extend(payload);
+            }
+        }
+        assert_eq!(data.len(), 10);
+
+        // 窗口用完之后，服务器在收到窗口更新之前不会再发数据，下一帧就是 PING 的应答。
+        let ping = [0, 0, 8, frame::PING, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+        peer.stream.write_all(&ping).await.unwrap();
+        let (head, payload) = peer.recv().await;
+        assert_eq!((head.kind, head.flags), (frame::PING, flag::ACK));
+        assert_eq!(payload, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        peer.send(Outgoing::WindowUpdate {
+            stream: 1,
+            increment: 100,
+        })
+        .await;
+        let (head, payload) = peer.recv().await;
+        assert_eq!((head.kind, head.flags), (frame::DATA, flag::END_STREAM));
+        assert_eq!(payload.len(), 15);
+    }
+
+    #[async_std::test]
+    async fn dropping_the_connection_aborts_waiting_streams() {
+        let (client, server) = duplex(1024);
+        let (finished, stream_finished) = oneshot::channel();
+        let finished = Mutex::new(Some(finished));
+        let handler = |_| async { Response::text(StatusCode::OK, "x".repeat(25)) };
+        let (connection, abort) = future::abortable(async move {
+            let spawn = move |task: BoxFuture<'static, ()>| {
+                let finished = finished.lock().unwrap().take();
+                async_std::task::spawn(async move {
+                    task.await;
+                    if let Some(finished) = finished {
+                        let _ = finished.send(());
+                    }
+                });
+            };
+            serve(server, Vec::new(), &Config::default(), handler, spawn).await
+        });
+        async_std::task::spawn(connection);
+        let settings = [(setting::INITIAL_WINDOW_SIZE, 10)];
+        let mut peer = Peer::connect(client, &settings).await;
+        peer.get(1, "/").await;
+        while peer.recv().await.0.kind != frame::DATA {}
+
+        // 流的任务正在等待发送窗口，这时丢掉整个连接，它也要跟着结束。
+        abort.abort();
+        let finished = timeout(Duration::from_secs(5), stream_finished).await;
+        assert_eq!(finished, Ok(Ok(())));
+    }
+
+    #[async_std::test]
+    async fn receives_request_bodies() {
+        for stream_bodies in [true, false] {
+            let handler = move |mut request: Request| async move {
+                let body = match stream_bodies {
+                    true => request.take_body().to_vec().await.unwrap(),
+                    false => mem::take(&mut request.body),
+                };
+                Response::text(StatusCode::OK, body)
+            };
+            let config = Config {
+                stream_bodies,
+                ..Config::default()
+            };
+            let mut peer = Peer::start(config, handler, &[]).await;
+            let fields = [
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", "/"),
+                ("content-length", "11"),
+            ];
+            peer.request(1, &fields, false).await;
+            for (data, end_stream) in [("hello ", false), ("world", true)] {
+                let data = data.as_bytes().to_vec();
+                peer.send(Outgoing::Data {
+                    stream: 1,
+                    data,
+                    end_stream,
+                })
+                .await;
+            }
+            let responses = peer.responses(1).await;
+            assert_eq!(responses, [(1, "200".to_owned(), b"hello world".to_vec())]);
+        }
+    }
+
+    #[async_std::test]
+    async fn rejects_oversized_bodies_before_calling_the_handler() {
+        let handler = |_| async { unreachable!("handler should not run") };
+        let config = Config {
+            limits: crate::request::Limits {
+                max_body_bytes: 4,
+                ..Default::default()
+            },
+            ..Config::default()
+        };
+        let mut peer = Peer::start(config, handler, &[]).await;
+        let fields = [(":method", "POST"), (":scheme", "http"), (":path", "/")];
+        peer.request(1, &fields, false).await;
+        let data = b"too large".to_vec();
+        peer.send(Outgoing::Data {
+            stream: 1,
+            data,
+            end_stream: true,
+        })
+        .await;
+        assert_eq!(peer.responses(1).await, [(1, "413".to_owned(), Vec::new())]);
+    }
+
+    #[test]
+    fn rejects_an_invalid_preface() {
+        let stream = MockStream::builder()
+            .read(b"PRI * HTTP/2.0\r\n\r\nXX\r\n\r\n")
+            .build();
+        let result = futures::executor::block_on(serve(
+            stream,
+            Vec::new(),
+            &Config::default(),
+            |_| async { Response::new(StatusCode::OK) },
+            |_| unreachable!(),
+        ));
+        assert!(
+            matches!(result, Err(ServerError::Io(e)) if e.kind() == io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn validates_request_headers() {
+        let fields = |list: &[(&str, &str)]| {
+            list.iter()
+                .map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        let base = [(":method", "GET"), (":scheme", "https"), (":path", "/a?b")];
+        let request = to_request(fields(
+            &[
+                &base[..],
+                &[("cookie", "a=1"), ("accept", "*/*"), ("cookie", "b=2")],
+            ]
+            .concat(),
+        ))
+        .unwrap();
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.headers.get("Cookie"), Some("a=1; b=2"));
+        assert_eq!(request.headers.get("Accept"), Some("*/*"));
+
+        for invalid in [
+            vec![(":method", "GET"), (":path", "/")],
+            [&base[..], &[("Accept", "*/*")]].concat(),
+            [&base[..], &[("connection", "close")]].concat(),
+            [&base[..], &[("te", "gzip")]].concat(),
+            [&[("accept", "*/*")], &base[..]].concat(),
+            [&base[..], &[(":path", "/again")]].concat(),
+        ] {
+            assert!(to_request(fields(&invalid)).is_none(), "{invalid:?}");
+        }
+    }
+}
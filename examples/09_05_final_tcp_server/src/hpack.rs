@@ -0,0 +1,807 @@
+//! HPACK（RFC 7541）：HTTP/2 的头部压缩。
+//!
+//! [`Decoder`] 完整地实现了解码，包括动态表和 Huffman 编码，因为对方可以任意使用它们。
+//! 编码一侧则由我们自己决定：[`encode`] 只引用静态表，不往动态表中添加任何条目，
+//! 也不做 Huffman 编码。这样压缩率差一些，但编码没有任何状态，
+//! 每个流的任务都可以独立地编码自己的头部，不必和连接上的其他流排队。
+
+use std::{collections::VecDeque, error, fmt, sync::OnceLock};
+
+/// 动态表默认的（也是我们允许对方使用的）最大字节数。
+pub const DEFAULT_TABLE_SIZE: usize = 4096;
+
+/// 解码失败的原因。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// 头部块在一个字段的中间结束了。
+    Truncated,
+    IntegerOverflow,
+    /// 引用了静态表和动态表中都不存在的条目。
+    InvalidIndex,
+    InvalidHuffman,
+    /// 动态表大小的更新超过了上限，或者没有出现在头部块的开头。
+    InvalidTableSizeUpdate,
+    /// 解码出的头部超过了调用者给出的上限。此时整个头部块仍然解码完毕，
+    /// 动态表的状态是正确的，连接可以继续使用。
+    ListTooLarge,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            DecodeError::Truncated => "truncated header block",
+            DecodeError::IntegerOverflow => "integer overflow",
+            DecodeError::InvalidIndex => "invalid table index",
+            DecodeError::InvalidHuffman => "invalid Huffman code",
+            DecodeError::InvalidTableSizeUpdate => "invalid dynamic table size update",
+            DecodeError::ListTooLarge => "header list is too large",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// 一个解码出的头部，名字和值都是原始的字节。
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// 条目在表中占用的大小：名字和值的长度再加上 32 个字节的开销。
+fn entry_size(name: &[u8], value: &[u8]) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// 头部块的解码器，一个连接上只有一个，按照头部块到达的顺序依次使用。
+#[derive(Debug)]
+pub struct Decoder {
+    /// 最新加入的条目在最前面。
+    dynamic: VecDeque<Field>,
+    size: usize,
+    /// 对方通过大小更新选定的上限。
+    max_size: usize,
+    /// 我们通过 `SETTINGS_HEADER_TABLE_SIZE` 允许的上限。
+    limit: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Decoder {
+    pub fn new(limit: usize) -> Decoder {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: limit,
+            limit,
+        }
+    }
+
+    /// 解码一个完整的头部块。解码出的头部（按照名字和值的长度加上 32 计算）
+    /// 超过 `max_list_size` 时返回 [`DecodeError::ListTooLarge`]。
+    ///
+    /// 除了 `ListTooLarge` 以外的错误都意味着动态表已经无法和对方保持一致，
+    /// 调用者必须关闭连接。
+    pub fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list_size: usize,
+    ) -> Result<Vec<Field>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        let mut too_large = false;
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                // 已索引的头部。
+                let index = decode_integer(&mut block, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                // 带增量索引的字面值，解码之后加入动态表。
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                if !fields.is_empty() {
+                    return Err(DecodeError::InvalidTableSizeUpdate);
+                }
+                let size = decode_integer(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(DecodeError::InvalidTableSizeUpdate);
+                }
+                self.max_size = size;
+                self.evict();
+                continue;
+            } else {
+                // 不加入动态表的字面值（`0000` 和 `0001` 两种），对解码来说没有区别。
+                self.decode_literal(&mut block, 4)?
+            };
+            list_size += entry_size(&field.0, &field.1);
+            too_large |= list_size > max_list_size;
+            if !too_large {
+                fields.push(field);
+            }
+        }
+        match too_large {
+            true => Err(DecodeError::ListTooLarge),
+            false => Ok(fields),
+        }
+    }
+
+    fn decode_literal(&mut self, block: &mut &[u8], prefix: u8) -> Result<Field, DecodeError> {
+        let index = decode_integer(block, prefix)?;
+        let name = match index {
+            0 => decode_string(block)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, decode_string(block)?))
+    }
+
+    /// 按照索引取出一个条目，1 到 61 是静态表，之后是动态表。
+    fn get(&self, index: usize) -> Result<Field, DecodeError> {
+        match index {
+            0 => Err(DecodeError::InvalidIndex),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self
+                .dynamic
+                .get(index - 62)
+                .cloned()
+                .ok_or(DecodeError::InvalidIndex),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        self.size += entry_size(&field.0, &field.1);
+        self.dynamic.push_front(field);
+        // 比整个表还大的条目会把表清空，自己也放不进去。
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            let (name, value) = self.dynamic.pop_back().unwrap();
+            self.size -= entry_size(&name, &value);
+        }
+    }
+}
+
+/// 解码一个带有 `prefix` 位前缀的整数。
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, rest) = block.split_first().ok_or(DecodeError::Truncated)?;
+    *block = rest;
+    let mask = (1u8 << prefix) - 1;
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(DecodeError::Truncated)?;
+        *block = rest;
+        // 合理的长度和索引都远远小于 2^28。
+        if shift > 21 {
+            return Err(DecodeError::IntegerOverflow);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = block.first().ok_or(DecodeError::Truncated)? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+    if block.len() < len {
+        return Err(DecodeError::Truncated);
+    }
+    let (data, rest) = block.split_at(len);
+    *block = rest;
+    match huffman {
+        true => huffman_decode(data),
+        false => Ok(data.to_vec()),
+    }
+}
+
+/// Huffman 解码树的节点，两个子节点分别对应 0 和 1。
+///
+/// 子节点的最高位为 1 时表示叶子，低位是符号；否则是下一个节点的下标。
+type Node = [u16; 2];
+
+const LEAF: u16 = 0x8000;
+const EOS: u16 = 256;
+
+fn huffman_tree() -> &'static [Node] {
+    static TREE: OnceLock<Vec<Node>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![[0; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = LEAF | symbol as u16;
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = (tree.len() - 1) as u16;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    })
+}
+
+fn huffman_decode(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let mut node = 0;
+    // 上一个符号之后读过的位数，以及它们是否全是 1。
+    let (mut pending, mut ones) = (0, true);
+    for byte in data {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            let child = tree[node][bit as usize];
+            if child & LEAF != 0 {
+                let symbol = child & !LEAF;
+                if symbol == EOS {
+                    return Err(DecodeError::InvalidHuffman);
+                }
+                out.push(symbol as u8);
+                (node, pending, ones) = (0, 0, true);
+            } else {
+                (node, pending, ones) = (child as usize, pending + 1, ones && bit == 1);
+            }
+        }
+    }
+    // 末尾只能用 EOS 的前缀（全是 1）补齐，并且不能超过 7 位。
+    if pending > 7 || !ones {
+        return Err(DecodeError::InvalidHuffman);
+    }
+    Ok(out)
+}
+
+/// 把 `fields` 编码成一个头部块，追加到 `out` 末尾。名字必须已经是小写。
+pub fn encode<'a>(fields: impl IntoIterator<Item = (&'a str, &'a str)>, out: &mut Vec<u8>) {
+    for (name, value) in fields {
+        let mut name_index = None;
+        let mut full_index = None;
+        for (i, &(n, v)) in STATIC_TABLE.iter().enumerate() {
+            if n == name {
+                name_index.get_or_insert(i + 1);
+                if v == value {
+                    full_index = Some(i + 1);
+                    break;
+                }
+            }
+        }
+        match (full_index, name_index) {
+            (Some(index), _) => encode_integer(index, 7, 0x80, out),
+            // 不加入动态表的字面值。
+            (None, Some(index)) => {
+                encode_integer(index, 4, 0, out);
+                encode_string(value.as_bytes(), out);
+            }
+            (None, None) => {
+                out.push(0);
+                encode_string(name.as_bytes(), out);
+                encode_string(value.as_bytes(), out);
+            }
+        }
+    }
+}
+
+fn encode_integer(value: usize, prefix: u8, flags: u8, out: &mut Vec<u8>) {
+    let mask = (1usize << prefix) - 1;
+    if value < mask {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | mask as u8);
+    let mut rest = value - mask;
+    while rest >= 0x80 {
+        out.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.push(rest as u8);
+}
+
+fn encode_string(data: &[u8], out: &mut Vec<u8>) {
+    encode_integer(data.len(), 7, 0, out);
+    out.extend_from_slice(data);
+}
+
+/// 静态表（RFC 7541 附录 A），索引从 1 开始。
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// 每个符号（0 到 255，以及表示结束的 256）的 Huffman 编码和位数（RFC 7541 附录 B）。
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn fields(list: &[(&str, &str)]) -> Vec<Field> {
+        list.iter()
+            .map(|(n, v)| (n.as_bytes().to_vec(), v.as_bytes().to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_rfc_examples_with_huffman() {
+        // RFC 7541 C.4：同一个连接上的三个请求，后面的请求引用了前面加入动态表的条目。
+        let mut decoder = Decoder::default();
+        let first = decoder
+            .decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"), 4096)
+            .unwrap();
+        assert_eq!(
+            first,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com")
+            ])
+        );
+        let second = decoder
+            .decode(&hex("828684be5886a8eb10649cbf"), 4096)
+            .unwrap();
+        assert_eq!(
+            second[3..],
+            fields(&[
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache")
+            ])
+        );
+        let third = decoder
+            .decode(
+                &hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"),
+                4096,
+            )
+            .unwrap();
+        assert_eq!(
+            third,
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+        assert_eq!(decoder.size, 164);
+    }
+
+    #[test]
+    fn evicts_entries_and_applies_size_updates() {
+        let mut decoder = Decoder::new(100);
+        // 两个各占 32 + 2 + 40 个字节的条目，第二个加入时第一个被挤出去。
+        let mut block = Vec::new();
+        for name in ["a1", "a2"] {
+            block.push(0x40);
+            encode_string(name.as_bytes(), &mut block);
+            encode_string(&[b'x'; 40], &mut block);
+        }
+        decoder.decode(&block, 4096).unwrap();
+        assert_eq!(decoder.dynamic.len(), 1);
+        assert_eq!(decoder.get(62).unwrap().0, b"a2");
+
+        // 大小更新只能出现在开头，并且不能超过我们允许的上限。
+        assert_eq!(
+            decoder.decode(&[0x20, 0x82], 4096).unwrap(),
+            fields(&[(":method", "GET")])
+        );
+        assert!(decoder.dynamic.is_empty());
+        assert_eq!(
+            decoder.decode(&[0x82, 0x20], 4096),
+            Err(DecodeError::InvalidTableSizeUpdate)
+        );
+        assert_eq!(
+            decoder.decode(&[0x3f, 0x46], 4096),
+            Err(DecodeError::InvalidTableSizeUpdate)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let mut decoder = Decoder::default();
+        assert_eq!(
+            decoder.decode(&[0x80], 4096),
+            Err(DecodeError::InvalidIndex)
+        );
+        assert_eq!(
+            decoder.decode(&[0xbe], 4096),
+            Err(DecodeError::InvalidIndex)
+        );
+        assert_eq!(
+            decoder.decode(&[0x04, 0x05, b'a'], 4096),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            decoder.decode(&[0xff, 0xff, 0xff, 0xff, 0xff], 4096),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(
+            decoder.decode(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x7f], 4096),
+            Err(DecodeError::IntegerOverflow)
+        );
+        // 'a' 的编码是 00011，后面用 0 而不是 1 补齐。
+        assert_eq!(
+            decoder.decode(&[0x04, 0x81, 0x18], 4096),
+            Err(DecodeError::InvalidHuffman)
+        );
+        // 超过 7 位的补齐。
+        assert_eq!(
+            decoder.decode(&[0x04, 0x82, 0x1f, 0xff], 4096),
+            Err(DecodeError::InvalidHuffman)
+        );
+        assert_eq!(
+            decoder.decode(&[0x04, 0x81, 0x1f], 4096).unwrap(),
+            fields(&[(":path", "a")])
+        );
+    }
+
+    #[test]
+    fn oversized_lists_keep_the_table_in_sync() {
+        let mut decoder = Decoder::default();
+        let mut block = vec![0x40];
+        encode_string(b"big", &mut block);
+        encode_string(&[b'x'; 100], &mut block);
+        assert_eq!(decoder.decode(&block, 50), Err(DecodeError::ListTooLarge));
+        assert_eq!(decoder.decode(&[0xbe], 4096).unwrap()[0].0, b"big");
+    }
+
+    #[test]
+    fn encodes_with_the_static_table_only() {
+        let mut out = Vec::new();
+        encode_integer(1337, 5, 0, &mut out);
+        assert_eq!(out, [0x1f, 0x9a, 0x0a]);
+
+        let list = [
+            (":status", "200"),
+            (":status", "302"),
+            ("content-type", "text/html"),
+            ("x-custom", "ünïcode"),
+            ("x-long", &"y".repeat(300)),
+        ];
+        let mut block = Vec::new();
+        encode(list.iter().map(|&(n, v)| (n, v)), &mut block);
+        assert_eq!(block[0], 0x88);
+        let mut decoder = Decoder::default();
+        assert_eq!(decoder.decode(&block, 4096).unwrap(), fields(&list));
+        assert!(decoder.dynamic.is_empty());
+    }
+}
@@ -0,0 +1,26 @@
+//! 最终的 TCP 服务器中与具体运行时无关的部分：HTTP 报文的解析与生成，连接的处理以及路由。
+
+pub mod body;
+pub mod client;
+pub mod connection;
+pub mod deadline;
+pub mod error;
+pub mod h2;
+pub mod headers;
+pub mod hpack;
+pub mod limit;
+pub mod listener;
+pub mod metrics;
+pub mod middleware;
+pub mod multipart;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod semaphore;
+pub mod shutdown;
+pub mod sse;
+pub mod static_files;
+pub mod time;
+pub mod tls;
+pub mod upgrade;
+pub mod websocket;
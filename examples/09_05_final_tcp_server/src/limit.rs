@@ -0,0 +1,270 @@
+//! 限制同时处理的连接数。
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{atomic::Ordering::Relaxed, Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use futures::{
+    future,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    metrics::ServerMetrics,
+    response::{Response, StatusCode},
+    semaphore::{Permit, Semaphore},
+};
+
+/// 连接数达到上限时如何处理新连接。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    /// 暂停接受新连接，直到有连接处理完毕。
+    ///
+    /// 新连接会留在操作系统的等待队列（backlog）中，队列满了之后由操作系统拒绝。
+    Wait,
+    /// 继续接受新连接，但立即以 `503 Service Unavailable` 回应并关闭它们。
+    Reject,
+}
+
+/// 用信号量限制同时处理的连接数。
+pub struct ConnectionLimiter {
+    permits: Semaphore,
+    max_connections: usize,
+    overload: Overload,
+    metrics: Arc<ServerMetrics>,
+    tasks: Arc<Mutex<Tasks>>,
+}
+
+/// 连接交给单独的任务去做的工作，见 [`ConnectionLimiter::track`]。
+#[derive(Default)]
+struct Tasks {
+    running: usize,
+    /// 等待这些任务全部结束的 [`ConnectionLimiter::wait_idle`]。
+    idle: Vec<Waker>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, overload: Overload, metrics: Arc<ServerMetrics>) -> Self {
+        ConnectionLimiter {
+            permits: Semaphore::new(max_connections),
+            max_connections,
+            overload,
+            metrics,
+            tasks: Arc::default(),
+        }
+    }
+
+    /// 为一个新连接申请名额。
+    ///
+    /// 在 [`Overload::Wait`] 下会一直等到有空闲的名额；在 [`Overload::Reject`] 下，
+    /// 没有空闲名额时立即返回 `None`，调用者应当用 [`reject`] 回应这个连接。
+    /// 返回的 [`ConnectionGuard`] 应当与连接一同保留，连接处理完毕后丢弃它以归还名额。
+    pub async fn admit(&self) -> Option<ConnectionGuard> {
+        let permit = match self.overload {
+            Overload::Wait => self.permits.acquire().await,
+            Overload::Reject => match self.permits.try_acquire() {
+                Some(permit) => permit,
+                None => {
+                    self.metrics.rejected.fetch_add(1, Relaxed);
+                    return None;
+                }
+            },
+        };
+        self.metrics.accepted.fetch_add(1, Relaxed);
+        self.metrics.active.fetch_add(1, Relaxed);
+        Some(ConnectionGuard {
+            _permit: permit,
+            metrics: self.metrics.clone(),
+        })
+    }
+
+    /// 把连接派生出来的任务（例如 HTTP/2 的每个流）也算作连接的一部分，
+    /// [`wait_idle`](Self::wait_idle) 会等到它们结束。这些任务不占用名额。
+    ///
+    /// 调用时就开始计数，所以应当在交给执行器之前调用。
+    pub fn track<F: Future>(&self, task: F) -> impl Future<Output = F::Output> {
+        self.tasks.lock().unwrap().running += 1;
+        let guard = TaskGuard(self.tasks.clone());
+        async move {
+            let _guard = guard;
+            task.await
+        }
+    }
+
+    /// 等待所有连接都处理完毕，即所有的名额都被归还，并且 [`track`](Self::track)
+    /// 过的任务都已经结束。
+    ///
+    /// 通常在停止接受新连接之后调用，否则新连接可能让它一直等下去。
+    pub async fn wait_idle(&self) {
+        let mut permits = Vec::with_capacity(self.max_connections);
+        for _ in 0..self.max_connections {
+            permits.push(self.permits.acquire().await);
+        }
+        future::poll_fn(|cx| {
+            let mut tasks = self.tasks.lock().unwrap();
+            if tasks.running == 0 {
+                return Poll::Ready(());
+            }
+            if !tasks.idle.iter().any(|waker| waker.will_wake(cx.waker())) {
+                tasks.idle.push(cx.waker().clone());
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// 一个连接占用的名额，被丢弃时归还。
+pub struct ConnectionGuard {
+    _permit: Permit,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.active.fetch_sub(1, Relaxed);
+    }
+}
+
+/// [`ConnectionLimiter::track`] 过的任务结束（或者被丢弃）时减少计数。
+struct TaskGuard(Arc<Mutex<Tasks>>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut tasks = self.0.lock().unwrap();
+        tasks.running -= 1;
+        if tasks.running == 0 {
+            for waker in tasks.idle.drain(..) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// 拿到了名额的连接，名额随连接一起移动，连接被丢弃时归还。
+pub struct Admitted<S> {
+    stream: S,
+    _guard: ConnectionGuard,
+}
+
+impl<S> Admitted<S> {
+    pub fn new(stream: S, guard: ConnectionGuard) -> Self {
+        Admitted {
+            stream,
+            _guard: guard,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Admitted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Admitted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+/// 以 `503 Service Unavailable` 回应一个因为过载而被拒绝的连接。
+///
+/// 这里不读取请求：客户端一般会先读到这个响应，再发现连接已经关闭。
+pub async fn reject(mut stream: impl AsyncWrite + Unpin) -> io::Result<()> {
+    Response::text(StatusCode::SERVICE_UNAVAILABLE, "server is busy\n")
+        .with_header("Retry-After", "1")
+        .with_close()
+        .write_to(&mut stream)
+        .await?;
+    stream.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, task::noop_waker_ref, FutureExt};
+    use std::task::Context;
+
+    #[test]
+    fn reject_policy_counts_rejections() {
+        let metrics = Arc::new(ServerMetrics::new());
+        let limiter = ConnectionLimiter::new(1, Overload::Reject, metrics.clone());
+        let guard = block_on(limiter.admit()).unwrap();
+        assert!(block_on(limiter.admit()).is_none());
+        assert_eq!(metrics.connections_active(), 1);
+        drop(guard);
+        assert!(block_on(limiter.admit()).is_some());
+        assert_eq!(metrics.connections_accepted(), 2);
+        assert_eq!(metrics.connections_rejected(), 1);
+        assert_eq!(metrics.connections_active(), 0);
+        assert!(metrics.to_string().contains("server_connections_rejected_total 1\n"));
+    }
+
+    #[test]
+    fn wait_policy_waits_for_a_slot() {
+        let limiter = ConnectionLimiter::new(1, Overload::Wait, Arc::new(ServerMetrics::new()));
+        let guard = block_on(limiter.admit()).unwrap();
+        let mut waiting = limiter.admit().boxed();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(waiting.poll_unpin(&mut cx).is_pending());
+        drop(guard);
+        assert!(matches!(waiting.poll_unpin(&mut cx), std::task::Poll::Ready(Some(_))));
+    }
+
+    #[test]
+    fn wait_idle_waits_for_every_connection() {
+        let limiter = ConnectionLimiter::new(2, Overload::Wait, Arc::new(ServerMetrics::new()));
+        let guards = [block_on(limiter.admit()), block_on(limiter.admit())];
+        let mut idle = limiter.wait_idle().boxed();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let [first, second] = guards;
+        drop(first);
+        assert!(idle.poll_unpin(&mut cx).is_pending());
+        drop(second);
+        assert!(idle.poll_unpin(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn wait_idle_waits_for_tracked_tasks() {
+        let limiter = ConnectionLimiter::new(1, Overload::Wait, Arc::new(ServerMetrics::new()));
+        let guard = block_on(limiter.admit()).unwrap();
+        let task = limiter.track(async {});
+        // 还没有开始运行的任务也要等。
+        drop(guard);
+        let mut idle = limiter.wait_idle().boxed();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(idle.poll_unpin(&mut cx).is_pending());
+        block_on(task);
+        assert!(idle.poll_unpin(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn rejection_response() {
+        let mut out = Vec::new();
+        block_on(reject(&mut out)).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(out.contains("Connection: close\r\n"));
+    }
+}
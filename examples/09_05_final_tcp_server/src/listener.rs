@@ -0,0 +1,401 @@
+//! 接受连接的监听器。
+//!
+//! 服务器的接受循环只依赖 [`Listener`] 特征，具体监听在哪里由 [`Bind`] 决定：
+//! TCP 地址和端口、Unix 域套接字的路径，或者由 systemd 这类进程管理器按照
+//! `LISTEN_FDS` 协议（socket activation）传下来的套接字。[`Bind::listen`] 返回的
+//! [`AnyListener`] 可以是其中任何一种，它产生的连接统一是 [`AnyStream`]。
+//!
+//! ```
+//! use final_tcp_server::listener::Bind;
+//!
+//! assert!(matches!("0.0.0.0:8080".parse(), Ok(Bind::Tcp(_))));
+//! assert!(matches!("unix:/run/server.sock".parse(), Ok(Bind::Unix(_))));
+//! assert!(matches!("systemd".parse(), Ok(Bind::Inherited)));
+//! ```
+
+use std::{
+    env, fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+#[cfg(not(feature = "io-uring"))]
+use async_std::net::{TcpListener, TcpStream};
+// 开启 `io-uring` 特性后，TCP 连接的接受和读写都改由 io_uring 驱动完成。
+#[cfg(feature = "io-uring")]
+use executor::uring::{TcpListener, TcpStream};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    io::{AsyncRead, AsyncWrite},
+    stream::{self, BoxStream, StreamExt},
+};
+
+/// 没有配置时监听的地址。
+pub const DEFAULT_BIND: &str = "127.0.0.1:7878";
+
+/// 能够不断接受新连接的监听器。
+pub trait Listener: Send + Sync {
+    /// 接受到的连接。
+    type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+    /// 等待并接受下一个连接。
+    fn accept(&self) -> BoxFuture<'_, io::Result<Self::Stream>>;
+
+    /// 不断接受新连接的流。接受失败不会让流结束，错误会原样交给调用者处理。
+    fn incoming(&self) -> BoxStream<'_, io::Result<Self::Stream>>
+    where
+        Self: Sized,
+    {
+        stream::unfold(self, |listener| async move {
+            Some((listener.accept().await, listener))
+        })
+        .boxed()
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> BoxFuture<'_, io::Result<TcpStream>> {
+        async move { Ok(TcpListener::accept(self).await?.0) }.boxed()
+    }
+}
+
+#[cfg(unix)]
+impl Listener for async_std::os::unix::net::UnixListener {
+    type Stream = async_std::os::unix::net::UnixStream;
+
+    fn accept(&self) -> BoxFuture<'_, io::Result<Self::Stream>> {
+        async move {
+            Ok(async_std::os::unix::net::UnixListener::accept(self)
+                .await?
+                .0)
+        }
+        .boxed()
+    }
+}
+
+/// 监听的位置，通常来自配置。
+///
+/// 可以从字符串解析：`unix:` 开头的是 Unix 域套接字的路径，`systemd` 表示使用继承来的套接字，
+/// 其余的都当作 TCP 的“地址:端口”。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bind {
+    /// TCP 的地址和端口，例如 `127.0.0.1:7878` 或 `[::]:8080`。
+    Tcp(String),
+    /// Unix 域套接字的路径。
+    Unix(PathBuf),
+    /// 按照 `LISTEN_FDS` 协议从父进程继承来的第一个套接字。
+    Inherited,
+}
+
+impl Bind {
+    /// 从环境变量 `var` 中读取监听的位置。
+    ///
+    /// 没有设置 `var` 时，如果进程是通过 socket activation 启动的（设置了 `LISTEN_FDS`），
+    /// 就使用继承来的套接字，否则监听 [`DEFAULT_BIND`]。
+    pub fn from_env(var: &str) -> Result<Bind, InvalidBind> {
+        match env::var(var) {
+            Ok(value) => value.parse(),
+            Err(_) if listen_fds().is_some() => Ok(Bind::Inherited),
+            Err(_) => Ok(Bind::Tcp(DEFAULT_BIND.to_owned())),
+        }
+    }
+
+    /// 开始监听。
+    pub async fn listen(&self) -> io::Result<AnyListener> {
+        match self {
+            Bind::Tcp(addr) => Ok(AnyListener::Tcp(TcpListener::bind(addr.as_str()).await?)),
+            #[cfg(unix)]
+            Bind::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = async_std::os::unix::net::UnixListener::bind(path).await?;
+                Ok(AnyListener::Unix(listener))
+            }
+            #[cfg(unix)]
+            Bind::Inherited => inherited()?.into_iter().next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no sockets were passed in LISTEN_FDS",
+                )
+            }),
+            #[cfg(not(unix))]
+            Bind::Unix(_) | Bind::Inherited => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+impl FromStr for Bind {
+    type Err = InvalidBind;
+
+    fn from_str(s: &str) -> Result<Bind, InvalidBind> {
+        match s.trim() {
+            "" => Err(InvalidBind(s.to_owned())),
+            "systemd" => Ok(Bind::Inherited),
+            s => match s.strip_prefix("unix:") {
+                Some("") => Err(InvalidBind(s.to_owned())),
+                Some(path) => Ok(Bind::Unix(path.into())),
+                // 端口必须写出来，地址和端口的解析留给 `bind`，这样主机名也可以使用。
+                None if s
+                    .rsplit_once(':')
+                    .is_some_and(|(_, port)| port.parse::<u16>().is_ok()) =>
+                {
+                    Ok(Bind::Tcp(s.to_owned()))
+                }
+                None => Err(InvalidBind(s.to_owned())),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bind::Tcp(addr) => write!(f, "{addr}"),
+            Bind::Unix(path) => write!(f, "unix:{}", path.display()),
+            Bind::Inherited => write!(f, "systemd"),
+        }
+    }
+}
+
+/// 无法解析的监听位置。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidBind(String);
+
+impl fmt::Display for InvalidBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid listen address {:?}: expected HOST:PORT, unix:PATH or systemd",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidBind {}
+
+/// 上一次运行留下的套接字文件会让 `bind` 失败。文件还在、却已经没有进程在上面监听时，把它删掉。
+#[cfg(unix)]
+fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => match UnixStream::connect(path) {
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+            // 还有进程在监听，交给 `bind` 报告地址已被占用。
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// 传给当前进程的套接字的个数。
+///
+/// 按照 `sd_listen_fds(3)` 的约定，只有 `LISTEN_PID` 是当前进程时 `LISTEN_FDS` 才有效；
+/// 这些变量会被原样传给子进程，子进程靠 `LISTEN_PID` 知道它们不是给自己的。
+fn listen_fds() -> Option<i32> {
+    let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let count = env::var("LISTEN_FDS").ok()?.parse::<i32>().ok()?;
+    (pid == std::process::id() && count > 0).then_some(count)
+}
+
+/// 从父进程继承来的套接字，从描述符 3 开始，一共 `LISTEN_FDS` 个。
+///
+/// 环境变量保持不变：进程里已经有其他线程时修改环境变量是不安全的，
+/// 而子进程会因为 `LISTEN_PID` 不符而忽略它们。这些描述符只能被接管一次，
+/// 再次调用以及没有传下套接字时都返回空列表。
+#[cfg(unix)]
+pub fn inherited() -> io::Result<Vec<AnyListener>> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// 第一个继承来的描述符。
+    const FIRST_FD: i32 = 3;
+    static CLAIMED: AtomicBool = AtomicBool::new(false);
+
+    match listen_fds() {
+        Some(count) if !CLAIMED.swap(true, Ordering::Relaxed) => (FIRST_FD..FIRST_FD + count)
+            .map(|fd| unsafe { from_raw_fd(fd) })
+            .collect(),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// 接管一个正在监听的描述符，根据它的地址族决定是 TCP 还是 Unix 域套接字。
+///
+/// # Safety
+///
+/// `fd` 必须是一个打开的、正在监听的流式套接字，并且此后不再由别处使用。
+#[cfg(unix)]
+unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> io::Result<AnyListener> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    let unix = std::os::unix::net::UnixListener::from_raw_fd(fd);
+    // 地址族不是 `AF_UNIX` 时，`local_addr` 会报错。
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(AnyListener::Unix(unix.into()));
+    }
+    let tcp = std::net::TcpListener::from_raw_fd(unix.into_raw_fd());
+    if let Err(e) = tcp.local_addr() {
+        let message = format!("inherited descriptor {fd} is neither a TCP nor a Unix socket: {e}");
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    #[cfg(not(feature = "io-uring"))]
+    {
+        tcp.set_nonblocking(true)?;
+        Ok(AnyListener::Tcp(tcp.into()))
+    }
+    // io_uring 直接在内核中完成 `accept`，套接字保持阻塞模式即可。
+    #[cfg(feature = "io-uring")]
+    Ok(AnyListener::Tcp(TcpListener::from_std(tcp)))
+}
+
+/// [`Bind::listen`] 返回的监听器。
+pub enum AnyListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(async_std::os::unix::net::UnixListener),
+}
+
+impl Listener for AnyListener {
+    type Stream = AnyStream;
+
+    fn accept(&self) -> BoxFuture<'_, io::Result<AnyStream>> {
+        match self {
+            AnyListener::Tcp(listener) => Listener::accept(listener)
+                .map(|s| s.map(AnyStream::Tcp))
+                .boxed(),
+            #[cfg(unix)]
+            AnyListener::Unix(listener) => Listener::accept(listener)
+                .map(|s| s.map(AnyStream::Unix))
+                .boxed(),
+        }
+    }
+}
+
+/// [`AnyListener`] 接受的连接。
+pub enum AnyStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(async_std::os::unix::net::UnixStream),
+}
+
+/// 把调用转发给内部的连接。
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $call:expr) => {
+        match $self.get_mut() {
+            AnyStream::Tcp($stream) => $call,
+            #[cfg(unix)]
+            AnyStream::Unix($stream) => $call,
+        }
+    };
+}
+
+impl AsyncRead for AnyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for AnyStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_close(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// 通过 `listener` 接受一个连接，并确认数据能在两端之间往返。
+    async fn round_trip<C>(listener: &AnyListener, connect: C)
+    where
+        C: std::future::Future<Output = io::Result<AnyStream>>,
+    {
+        let mut incoming = listener.incoming();
+        let (client, server) = futures::join!(connect, incoming.next());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap().unwrap());
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&buf).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[test]
+    fn parses_bind_addresses() {
+        assert_eq!("[::1]:80".parse(), Ok(Bind::Tcp("[::1]:80".to_owned())));
+        assert_eq!(
+            "localhost:7878".parse(),
+            Ok(Bind::Tcp("localhost:7878".to_owned()))
+        );
+        assert_eq!("unix:./s.sock".parse(), Ok(Bind::Unix("./s.sock".into())));
+        for invalid in ["", "127.0.0.1", "localhost:http", "unix:"] {
+            assert!(invalid.parse::<Bind>().is_err(), "{invalid:?}");
+        }
+        let bind = Bind::Unix("/run/s.sock".into());
+        assert_eq!(bind.to_string().parse(), Ok(bind));
+    }
+
+    #[async_std::test]
+    async fn accepts_tcp_connections() {
+        let listener = Bind::Tcp("127.0.0.1:0".to_owned()).listen().await.unwrap();
+        let AnyListener::Tcp(tcp) = &listener else {
+            unreachable!()
+        };
+        let addr = tcp.local_addr().unwrap();
+        round_trip(&listener, async {
+            TcpStream::connect(addr).await.map(AnyStream::Tcp)
+        })
+        .await;
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn accepts_unix_connections_and_replaces_stale_sockets() {
+        use async_std::os::unix::net::UnixStream;
+
+        let path = env::temp_dir().join(format!("final_tcp_server-{}.sock", std::process::id()));
+        // 上一次运行留下的套接字文件。
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Bind::Unix(path.clone()).listen().await.unwrap();
+        let connect = async { UnixStream::connect(&path).await.map(AnyStream::Unix) };
+        round_trip(&listener, connect).await;
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recognizes_inherited_socket_families() {
+        use std::os::fd::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = unsafe { from_raw_fd(tcp.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, AnyListener::Tcp(_)));
+
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let unix = std::os::unix::net::UnixListener::from(std::os::fd::OwnedFd::from(unix));
+        let listener = unsafe { from_raw_fd(unix.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, AnyListener::Unix(_)));
+    }
+}
@@ -1,19 +1,20 @@
-use std::fs;
+use executor::fs;
+use futures::{
+    channel::mpsc,
+    future,
+    sink::SinkExt,
+    stream::{self, Stream, StreamExt},
+};
 
-use futures::stream::StreamExt;
-
-use async_std::net::TcpListener;
-use async_std::prelude::*;
 // ANCHOR: main_func
 use async_std::task::spawn;
 
 #[async_std::main]
 async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
+    let listener = listen().await;
     listener
         .incoming()
         .for_each_concurrent(/* 限制 */ None, |stream| async move {
-            let stream = stream.unwrap();
             spawn(handle_connection(stream));
         })
         .await;
@@ -21,24 +22,299 @@ async fn main() {
 // ANCHOR_END: main_func
 
 use async_std::io::{Read, Write};
+use final_tcp_server::{
+    connection::{serve_buffered, Config},
+    error::{is_resource_exhausted, ServerError},
+    h2,
+    limit::{reject, Admitted, ConnectionLimiter, Overload},
+    listener::{AnyListener, AnyStream, Bind, Listener},
+    metrics::ServerMetrics,
+    middleware::{CatchPanic, Compression, Logger},
+    multipart::{Multipart, MultipartError},
+    request::{Limits, Request},
+    response::{Response, StatusCode},
+    router::Router,
+    shutdown::Shutdown,
+    sse::{self, Event},
+    static_files::StaticFiles,
+    time::{sleep, timeout},
+    tls::{alpn_protocol, TlsAcceptor},
+    upgrade::Upgraded,
+    websocket::{self, WebSocket},
+};
+use std::{
+    io,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+/// 监听的位置，例如 `0.0.0.0:8080`、`unix:/run/server.sock` 或 `systemd`，默认是 `127.0.0.1:7878`。
+static BIND: LazyLock<Bind> =
+    LazyLock::new(|| Bind::from_env("BIND_ADDR").unwrap_or_else(|e| panic!("{e}")));
+
+/// 同时处理的连接数上限。
+const MAX_CONNECTIONS: usize = 1024;
+/// 达到上限之后的处理方式，改为 `Overload::Reject` 可以让多出来的连接立即收到 503。
+const OVERLOAD: Overload = Overload::Wait;
+
+/// 文件描述符耗尽时，暂停接受新连接的时间。
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// 请求体的上限。
+const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+/// 完成 TLS 握手的最长时间。
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 收到关闭信号后，等待已有连接处理完毕的最长时间。
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 停止接受新连接，并让已有的连接处理完当前的请求后关闭。
+static SHUTDOWN: LazyLock<Shutdown> = LazyLock::new(Shutdown::new);
+/// 取消所有仍在进行的连接。
+static CANCEL: LazyLock<Shutdown> = LazyLock::new(Shutdown::new);
+
+static CONFIG: LazyLock<Config> = LazyLock::new(|| Config {
+    // 上传的文件边收边处理，不需要整个放进内存，上限也就可以放宽一些。
+    limits: Limits {
+        max_body_bytes: MAX_UPLOAD_BYTES,
+        ..Limits::default()
+    },
+    stream_bodies: true,
+    shutdown: SHUTDOWN.clone(),
+    ..Config::default()
+});
+
+static METRICS: LazyLock<Arc<ServerMetrics>> = LazyLock::new(Default::default);
+
+static LIMITER: LazyLock<ConnectionLimiter> =
+    LazyLock::new(|| ConnectionLimiter::new(MAX_CONNECTIONS, OVERLOAD, METRICS.clone()));
+
+/// 设置了环境变量 `TLS_CERT` 和 `TLS_KEY`（PEM 文件的路径）时改为提供 HTTPS。
+static TLS: LazyLock<Option<TlsAcceptor>> = LazyLock::new(|| {
+    let cert = std::env::var_os("TLS_CERT")?;
+    let key = std::env::var_os("TLS_KEY")?;
+    let acceptor = TlsAcceptor::from_pem_files(&cert, &key, &[b"h2", b"http/1.1"]);
+    Some(acceptor.unwrap_or_else(|e| panic!("无法加载 TLS 证书或私钥：{e}")))
+});
+
+/// 所有连接共用的路由表。
+static ROUTER: LazyLock<Router> = LazyLock::new(|| {
+    Router::new()
+        .get("/", |_| page(StatusCode::OK, "hello.html"))
+        .get("/sleep", |_| async {
+            async_std::task::sleep(Duration::from_secs(5)).await;
+            page(StatusCode::OK, "hello.html").await
+        })
+        // 静态文件的根目录可以通过环境变量 `STATIC_ROOT` 指定。
+        .get(
+            "/static/*path",
+            StaticFiles::new(std::env::var_os("STATIC_ROOT").unwrap_or("static".into()))
+                .into_handler(),
+        )
+        .get("/metrics", |_| async {
+            Response::text(StatusCode::OK, METRICS.to_string())
+        })
+        .get("/events", |_| async { sse::response(countdown(10)) })
+        .post("/upload", upload)
+        .get("/ws", |request| async move {
+            websocket::upgrade(&request, |ws| Box::pin(echo(ws)))
+        })
+        .fallback(|_| page(StatusCode::NOT_FOUND, "404.html"))
+        .layer(CatchPanic)
+        .layer(Logger::new())
+        .layer(Compression::new())
+});
+
+/// 按照配置开始监听，并在收到关闭信号时停止接受新连接。
+async fn listen() -> Server {
+    // 证书有问题时在启动阶段就报错。
+    LazyLock::force(&TLS);
+    // 监听的位置来自环境变量 `BIND_ADDR`，可以是 TCP 地址、Unix 域套接字或者 systemd 传下来的套接字。
+    let listener = BIND.listen().await.unwrap_or_else(|e| panic!("无法监听 {}：{e}", *BIND));
+    #[cfg(unix)]
+    SHUTDOWN.trigger_on_signals().unwrap();
+    Server { listener }
+}
+
+/// 书中的 `TcpListener` 之外，服务器在接受连接时还要处理的事情：
+///
+/// - 接受连接出错时记录下来，然后继续接受下一个连接；
+/// - 为每个连接申请名额。`for_each_concurrent` 的限制管不到 `spawn` 出去的任务，
+///   所以名额随连接一起交给处理它的任务；
+/// - 收到关闭信号后不再接受新连接，等已有的连接处理完毕之后才结束。
+struct Server {
+    listener: AnyListener,
+}
+
+impl Server {
+    fn incoming(&self) -> impl Stream<Item = Admitted<AnyStream>> + '_ {
+        let admitted = self
+            .listener
+            .incoming()
+            .take_until(SHUTDOWN.wait())
+            .filter_map(|stream| async {
+                match stream {
+                    Ok(stream) => admit(stream).await,
+                    Err(e) => {
+                        accept_error(e).await;
+                        None
+                    }
+                }
+            });
+        // 排空之后流才结束，`main` 返回时所有连接都已经结束或者被取消了。
+        admitted.chain(stream::once(drain()).filter_map(|()| future::ready(None)))
+    }
+}
+
+/// 为新连接申请名额；在 [`Overload::Reject`] 下没有名额时以 503 回应并丢掉它。
+async fn admit(stream: AnyStream) -> Option<Admitted<AnyStream>> {
+    match LIMITER.admit().await {
+        Some(guard) => Some(Admitted::new(stream, guard)),
+        None => {
+            spawn(async move {
+                let _ = reject(stream).await;
+            });
+            None
+        }
+    }
+}
+
+async fn handle_connection(stream: impl Read + Write + Send + Unpin) -> Result<(), ServerError> {
+    // 超过排空期限仍未结束的连接会在这里被取消。
+    let result = CANCEL.run_until(serve_connection(stream)).await.unwrap_or(Ok(()));
+    if let Err(e) = &result {
+        eprintln!("连接出错：{e}");
+    }
+    result
+}
+
+/// 需要时先完成 TLS 握手，再按协议分派给 HTTP/1.1 或 HTTP/2。
+async fn serve_connection(stream: impl Read + Write + Send + Unpin) -> Result<(), ServerError> {
+    match &*TLS {
+        Some(acceptor) => {
+            let stream = timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await??;
+            // 通过 ALPN 协商好了 HTTP/2 时，不必再看前言。
+            match alpn_protocol(&stream) {
+                Some(b"h2") => serve_h2(stream, Vec::new()).await,
+                _ => serve_any(stream).await,
+            }
+        }
+        None => serve_any(stream).await,
+    }
+}
+
+/// 根据客户端发来的前几个字节决定使用 HTTP/1.1 还是 HTTP/2（h2c prior knowledge）。
+async fn serve_any(mut stream: impl Read + Write + Send + Unpin) -> Result<(), ServerError> {
+    let mut buffer = Vec::new();
+    let is_h2 = timeout(CONFIG.idle_timeout, h2::is_preface(&mut stream, &mut buffer)).await;
+    match is_h2 {
+        // 空闲太久的连接直接关闭，和两个请求之间的空闲超时一样。
+        Err(_) => Ok(()),
+        Ok(Err(e)) => Err(e.into()),
+        Ok(Ok(true)) => serve_h2(stream, buffer).await,
+        Ok(Ok(false)) => {
+            serve_buffered(stream, buffer, &CONFIG, |request| ROUTER.handle(request)).await
+        }
+    }
+}
 
-async fn handle_connection(mut stream: impl Read + Write + Unpin) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
-    let get = b"GET / HTTP/1.1\r\n";
-    let (status_line, filename) = if buffer.starts_with(get) {
-        ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
-    } else {
-        ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
+/// 每个流的处理函数作为单独的任务交给执行器。
+///
+/// 连接结束时这些任务会被取消，但要等到下一次被轮询时才真正结束，所以排空时也要等它们。
+async fn serve_h2(
+    stream: impl Read + Write + Send + Unpin,
+    buffer: Vec<u8>,
+) -> Result<(), ServerError> {
+    let spawn = |task| {
+        spawn(LIMITER.track(task));
     };
-    let contents = fs::read_to_string(filename).unwrap();
-    let response = format!("{status_line}{contents}");
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+    h2::serve(stream, buffer, &CONFIG, |request| ROUTER.handle(request), spawn).await
 }
 
-#[cfg(test)]
+/// 每秒推送一个事件，从 `from` 倒数到 0。
+///
+/// 事件由一个单独的任务产生，经过容量为 1 的通道交给响应；
+/// 客户端读得慢时，`send` 会一直等到前一个事件被取走。
+fn countdown(from: u32) -> mpsc::Receiver<Event> {
+    let (mut sender, receiver) = mpsc::channel(1);
+    spawn(async move {
+        for n in (0..=from).rev() {
+            // 客户端断开之后接收端被丢弃，发送就会失败。
+            if sender.send(Event::data(n.to_string()).with_id(n.to_string())).await.is_err() {
+                break;
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    });
+    receiver
+}
+
+/// WebSocket 回显：把收到的文本和二进制消息原样发回去。
+async fn echo(mut ws: WebSocket<Upgraded<'_>>) {
+    while let Some(message) = ws.next().await {
+        if message.is_data() && ws.send(message).await.is_err() {
+            break;
+        }
+    }
+    let _ = ws.close().await;
+}
 
+/// 接收一个 `multipart/form-data` 表单，回复每个字段的名字和大小。
+///
+/// 数据读出来之后直接丢掉，无论上传的文件有多大，占用的内存都不会超过几个数据块。
+async fn upload(mut request: Request) -> Response {
+    let Some(mut multipart) = Multipart::from_request(&mut request) else {
+        return Response::text(StatusCode::UNSUPPORTED_MEDIA_TYPE, "expected multipart/form-data\n");
+    };
+    let mut report = String::new();
+    let result = async {
+        while let Some(mut field) = multipart.next_field().await? {
+            let name = field.name().unwrap_or_default().to_owned();
+            let mut size = 0;
+            while let Some(chunk) = field.chunk().await? {
+                size += chunk.len();
+            }
+            report += &format!("{name}: {size} bytes\n");
+        }
+        Ok::<_, MultipartError>(())
+    };
+    match result.await {
+        Ok(()) => Response::text(StatusCode::OK, report),
+        Err(e) => Response::text(e.status_code(), format!("{e}\n")),
+    }
+}
+
+/// 记录接受连接时出现的错误，然后继续接受下一个连接。
+async fn accept_error(e: io::Error) {
+    eprintln!("接受连接失败：{e}");
+    // 立即重试只会让循环空转，等已有的连接释放一些资源再说。
+    if is_resource_exhausted(&e) {
+        sleep(ACCEPT_BACKOFF).await;
+    }
+}
+
+/// 等待正在处理的连接结束，超过 [`DRAIN_TIMEOUT`] 之后取消剩下的连接。
+async fn drain() {
+    if timeout(DRAIN_TIMEOUT, LIMITER.wait_idle()).await.is_err() {
+        eprintln!("仍有 {} 个连接没有结束，取消它们", METRICS.connections_active());
+        CANCEL.trigger();
+        LIMITER.wait_idle().await;
+    }
+}
+
+/// 以 `status` 返回一个 HTML 文件。
+async fn page(status: StatusCode, filename: &str) -> Response {
+    match fs::read(filename).await {
+        Ok(contents) => Response::html(status, contents),
+        Err(e) => {
+            eprintln!("无法读取 {filename}：{e}");
+            Response::text(StatusCode::INTERNAL_SERVER_ERROR, "internal server error\n")
+        }
+    }
+}
+
+#[cfg(test)]
 mod tests {
     // ANCHOR: mock_read
     use super::*;
@@ -97,7 +373,7 @@ mod tests {
 
     #[async_std::test]
     async fn test_handle_connection() {
-        let input_bytes = b"GET / HTTP/1.1\r\n";
+        let input_bytes = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
         let mut contents = vec![0u8; 1024];
         contents[..input_bytes.len()].clone_from_slice(input_bytes);
         let mut stream = MockTcpStream {
@@ -105,11 +381,18 @@ mod tests {
             write_data: Vec::new(),
         };
 
-        handle_connection(&mut stream).await;
+        handle_connection(&mut stream).await.unwrap();
 
         let expected_contents = fs::read_to_string("hello.html").unwrap();
-        let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
-        assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+        let expected_response = format!(
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/html; charset=utf-8\r\n\
+             Connection: close\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            expected_contents.len(),
+            expected_contents
+        );
+        assert_eq!(stream.write_data, expected_response.as_bytes());
     }
     // ANCHOR_END: test
 }
@@ -0,0 +1,46 @@
+//! 服务器的运行指标。
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+/// 服务器各处共享的计数器。
+///
+/// 所有计数都只用于观察，所以使用 `Relaxed` 就足够了。
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    pub(crate) accepted: AtomicU64,
+    pub(crate) rejected: AtomicU64,
+    pub(crate) active: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub fn new() -> ServerMetrics {
+        ServerMetrics::default()
+    }
+
+    /// 开始处理的连接总数。
+    pub fn connections_accepted(&self) -> u64 {
+        self.accepted.load(Relaxed)
+    }
+
+    /// 因为连接数达到上限而被拒绝的连接总数。
+    pub fn connections_rejected(&self) -> u64 {
+        self.rejected.load(Relaxed)
+    }
+
+    /// 正在处理中的连接数。
+    pub fn connections_active(&self) -> u64 {
+        self.active.load(Relaxed)
+    }
+}
+
+/// 以 Prometheus 文本格式输出。
+impl fmt::Display for ServerMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "server_connections_accepted_total {}", self.connections_accepted())?;
+        writeln!(f, "server_connections_rejected_total {}", self.connections_rejected())?;
+        writeln!(f, "server_connections_active {}", self.connections_active())
+    }
+}
@@ -0,0 +1,640 @@
+//! 中间件：包裹在处理函数外面，可以在请求到达处理函数之前和响应返回之后做些事情。
+//!
+//! 中间件通过 [`Router::layer`](crate::router::Router::layer) 添加，先添加的在外层。
+//! 每个中间件拿到请求和一个 [`Next`]，调用 `next.run(request).await`
+//! 把请求交给内层（下一个中间件或者路由本身），也可以不调用它而直接返回响应。
+//!
+//! ```
+//! use final_tcp_server::{
+//!     middleware::{Middleware, Next},
+//!     request::{Method, Request},
+//!     response::{Response, StatusCode},
+//!     router::Router,
+//! };
+//! use futures::future::{BoxFuture, FutureExt};
+//!
+//! /// 没有带上令牌的请求一律返回 401。
+//! struct RequireToken(&'static str);
+//!
+//! impl Middleware for RequireToken {
+//!     fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+//!         async move {
+//!             if request.headers.get("Authorization") != Some(self.0) {
+//!                 return Response::text(StatusCode::from_u16(401).unwrap(), "unauthorized\n");
+//!             }
+//!             next.run(request).await
+//!         }
+//!         .boxed()
+//!     }
+//! }
+//!
+//! let router = Router::new()
+//!     .get("/", |_| async { Response::text(StatusCode::OK, "secret") })
+//!     .layer(RequireToken("Bearer 42"));
+//! let response = futures::executor::block_on(router.handle(Request::new(Method::Get, "/")));
+//! assert_eq!(response.status().as_u16(), 401);
+//! ```
+
+use std::{
+    any::Any,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io::Write,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+    time::{Duration, Instant},
+};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression as Level,
+};
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::{
+    request::{Method, Request},
+    response::{Response, StatusCode},
+};
+
+/// 中间件特征。
+pub trait Middleware: Send + Sync + 'static {
+    /// 处理一个请求，需要内层处理时调用 `next.run(request)`。
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response>;
+}
+
+/// 中间件链中剩下的部分。
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    endpoint: &'a (dyn Fn(Request) -> BoxFuture<'a, Response> + Send + Sync),
+}
+
+impl<'a> Next<'a> {
+    /// 依次经过 `middleware` 之后，最终由 `endpoint` 处理请求。
+    pub fn new(
+        middleware: &'a [Box<dyn Middleware>],
+        endpoint: &'a (dyn Fn(Request) -> BoxFuture<'a, Response> + Send + Sync),
+    ) -> Next<'a> {
+        Next {
+            middleware,
+            endpoint,
+        }
+    }
+
+    /// 把请求交给内层处理。
+    pub async fn run(self, request: Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(request, Next::new(rest, self.endpoint)).await,
+            None => (self.endpoint)(request).await,
+        }
+    }
+}
+
+/// 每处理完一个请求输出一行日志：请求编号（如果有）、方法、路径、状态码和耗时。
+pub struct Logger {
+    sink: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Logger::new()
+    }
+}
+
+impl Logger {
+    /// 输出到标准错误。
+    pub fn new() -> Logger {
+        Logger::with_sink(|line| eprintln!("{line}"))
+    }
+
+    /// 把每一行日志交给 `sink`。
+    pub fn with_sink(sink: impl Fn(&str) + Send + Sync + 'static) -> Logger {
+        Logger {
+            sink: Box::new(sink),
+        }
+    }
+}
+
+impl Middleware for Logger {
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        async move {
+            let started = Instant::now();
+            let method = request.method;
+            let path = request.path.clone();
+            let id = request.headers.get(REQUEST_ID).map(str::to_owned);
+            let response = next.run(request).await;
+            let elapsed = started.elapsed();
+            let line = match id {
+                Some(id) => format!("[{id}] {method} {path} {} {elapsed:?}", response.status().as_u16()),
+                None => format!("{method} {path} {} {elapsed:?}", response.status().as_u16()),
+            };
+            (self.sink)(&line);
+            response
+        }
+        .boxed()
+    }
+}
+
+/// 请求编号所在的头部。
+pub const REQUEST_ID: &str = "X-Request-Id";
+
+/// 为每个请求分配一个编号，放在请求和响应的 `X-Request-Id` 头部中。
+///
+/// 客户端（或者前面的代理）已经带上了编号时沿用它，这样一个请求经过的各个服务的日志可以对应起来。
+/// 放在 [`Logger`] 外层，日志中就会带上编号。
+#[derive(Debug, Default)]
+pub struct RequestId {
+    next: AtomicU64,
+}
+
+impl RequestId {
+    pub fn new() -> RequestId {
+        RequestId::default()
+    }
+
+    fn generate(&self) -> String {
+        // 进程启动时随机选一个前缀，重启之后的编号就不会与之前的重复。
+        static PREFIX: LazyLock<u32> =
+            LazyLock::new(|| RandomState::new().build_hasher().finish() as u32);
+        format!("{:08x}-{}", *PREFIX, self.next.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// 沿用外来的编号之前检查一下，不要把奇怪的东西写进日志。
+fn valid_request_id(id: &str) -> bool {
+    (1..=64).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+impl Middleware for RequestId {
+    fn handle<'a>(&'a self, mut request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        async move {
+            let id = match request.headers.get(REQUEST_ID) {
+                Some(id) if valid_request_id(id) => id.to_owned(),
+                _ => self.generate(),
+            };
+            request.headers.insert(REQUEST_ID, id.as_str());
+            next.run(request).await.with_header(REQUEST_ID, id)
+        }
+        .boxed()
+    }
+}
+
+/// 按照 `Accept-Encoding` 用 gzip 或 deflate 压缩响应体。
+///
+/// 只压缩已经在内存中的、足够大的文本类响应体；文件和流式的响应体原样发送。
+#[derive(Debug, Clone)]
+pub struct Compression {
+    /// 小于这个长度的响应体不值得压缩。
+    pub min_size: usize,
+    pub level: u32,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            min_size: 256,
+            level: 6,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Compression {
+        Compression::default()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, data: &[u8], level: u32) -> Vec<u8> {
+        let level = Level::new(level);
+        // 写入 `Vec` 不会失败。
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            // HTTP 中的 deflate 指的是带 zlib 头部的格式。
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+}
+
+/// 从 `Accept-Encoding` 中选出客户端最想要的编码，同样想要时优先 gzip。
+fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut best = None::<(Encoding, f32)>;
+    for item in accept.split(',') {
+        let mut parts = item.split(';').map(str::trim);
+        let coding = parts.next().unwrap_or_default();
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let encoding = if coding.eq_ignore_ascii_case("gzip") || coding == "*" {
+            Encoding::Gzip
+        } else if coding.eq_ignore_ascii_case("deflate") {
+            Encoding::Deflate
+        } else {
+            continue;
+        };
+        let better = match best {
+            None => true,
+            Some((current, best_q)) => {
+                q > best_q || (q == best_q && encoding == Encoding::Gzip && current != Encoding::Gzip)
+            }
+        };
+        if q > 0.0 && better {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// 文本类的内容压缩效果好，图片、压缩包之类本来就压缩过了。
+fn compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    let mime = mime.to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
+
+impl Middleware for Compression {
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        async move {
+            let encoding = request.headers.get("Accept-Encoding").and_then(negotiate);
+            let mut response = next.run(request).await;
+            let Some(encoding) = encoding else {
+                return response;
+            };
+            let headers = response.headers();
+            let eligible = !headers.contains("Content-Encoding")
+                && headers.get("Content-Type").is_some_and(compressible)
+                && !matches!(
+                    response.status(),
+                    StatusCode::PARTIAL_CONTENT | StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                )
+                && response.body().as_bytes().is_some_and(|body| body.len() >= self.min_size);
+            if !eligible {
+                return response;
+            }
+            let compressed = encoding.encode(response.body().as_bytes().unwrap(), self.level);
+            let headers = response.headers_mut();
+            headers.insert("Content-Encoding", encoding.as_str());
+            headers.append("Vary", "Accept-Encoding");
+            response.with_body(compressed)
+        }
+        .boxed()
+    }
+}
+
+/// 跨域资源共享。
+///
+/// 对预检请求（带有 `Access-Control-Request-Method` 的 `OPTIONS` 请求）直接回复，
+/// 不再交给内层；对其他带有 `Origin` 的请求，在响应中加上允许跨域的头部。
+#[derive(Debug, Clone)]
+pub struct Cors {
+    /// 允许的来源，为空表示允许任何来源。
+    origins: Vec<String>,
+    methods: Vec<Method>,
+    headers: Vec<String>,
+    max_age: Option<Duration>,
+    credentials: bool,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors::new()
+    }
+}
+
+impl Cors {
+    /// 允许任何来源发出 `GET`、`HEAD` 和 `POST` 请求。
+    pub fn new() -> Cors {
+        Cors {
+            origins: Vec::new(),
+            methods: vec![Method::Get, Method::Head, Method::Post],
+            headers: Vec::new(),
+            max_age: None,
+            credentials: false,
+        }
+    }
+
+    /// 只允许列出的来源，例如 `https://example.com`，可以多次调用。
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Cors {
+        self.origins.push(origin.into());
+        self
+    }
+
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Cors {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// 允许客户端在跨域请求中带上的头部。
+    pub fn allow_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Cors {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// 浏览器可以缓存预检结果的时间。
+    pub fn max_age(mut self, max_age: Duration) -> Cors {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// 允许带上 cookie 等凭据。这时响应中必须写明具体的来源，而不能用 `*`。
+    pub fn allow_credentials(mut self) -> Cors {
+        self.credentials = true;
+        self
+    }
+
+    /// 允许 `origin` 时，返回 `Access-Control-Allow-Origin` 的值。
+    fn allowed_origin(&self, origin: &str) -> Option<String> {
+        if self.origins.is_empty() {
+            Some(if self.credentials { origin } else { "*" }.to_owned())
+        } else {
+            self.origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+                .then(|| origin.to_owned())
+        }
+    }
+
+    fn add_headers(&self, response: &mut Response, allow_origin: String) {
+        let headers = response.headers_mut();
+        if allow_origin != "*" {
+            headers.append("Vary", "Origin");
+        }
+        headers.insert("Access-Control-Allow-Origin", allow_origin);
+        if self.credentials {
+            headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+    }
+}
+
+impl Middleware for Cors {
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        async move {
+            let Some(origin) = request.headers.get("Origin").map(str::to_owned) else {
+                return next.run(request).await;
+            };
+            let allowed = self.allowed_origin(&origin);
+            let preflight = request.method == Method::Options
+                && request.headers.contains("Access-Control-Request-Method");
+            if !preflight {
+                let mut response = next.run(request).await;
+                if let Some(allow_origin) = allowed {
+                    self.add_headers(&mut response, allow_origin);
+                }
+                return response;
+            }
+
+            // 不允许的来源只得到一个不带 CORS 头部的响应，浏览器会据此拒绝真正的请求。
+            let mut response = Response::new(StatusCode::NO_CONTENT);
+            let Some(allow_origin) = allowed else {
+                return response;
+            };
+            self.add_headers(&mut response, allow_origin);
+            let methods: Vec<_> = self.methods.iter().map(Method::as_str).collect();
+            let headers = response.headers_mut();
+            headers.insert("Access-Control-Allow-Methods", methods.join(", "));
+            if !self.headers.is_empty() {
+                headers.insert("Access-Control-Allow-Headers", self.headers.join(", "));
+            }
+            if let Some(max_age) = self.max_age {
+                headers.insert("Access-Control-Max-Age", max_age.as_secs().to_string());
+            }
+            response
+        }
+        .boxed()
+    }
+}
+
+/// 捕获处理函数中的恐慌，改为返回 `500 Internal Server Error`。
+///
+/// 没有它的话，恐慌会让处理这个连接的任务直接结束，客户端什么响应也收不到。
+#[derive(Debug, Default)]
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        async move {
+            let method = request.method;
+            let path = request.path.clone();
+            match AssertUnwindSafe(next.run(request)).catch_unwind().await {
+                Ok(response) => response,
+                Err(panic) => {
+                    eprintln!("处理 {method} {path} 时发生恐慌：{}", panic_message(&*panic));
+                    Response::text(StatusCode::INTERNAL_SERVER_ERROR, "internal server error\n")
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::Router;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use futures::executor::block_on;
+    use std::{
+        io::Read,
+        sync::{Arc, Mutex},
+    };
+
+    fn get(router: &Router, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new(Method::Get, path);
+        for (name, value) in headers {
+            request.headers.insert(*name, *value);
+        }
+        block_on(router.handle(request))
+    }
+
+    /// 记录经过顺序的中间件。
+    struct Trace(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Middleware for Trace {
+        fn handle<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+            async move {
+                self.1.lock().unwrap().push(format!("{} in", self.0));
+                let response = next.run(request).await;
+                self.1.lock().unwrap().push(format!("{} out", self.0));
+                response
+            }
+            .boxed()
+        }
+    }
+
+    #[test]
+    fn runs_layers_outside_in() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let router = Router::new()
+            .get("/", |_| async { Response::text(StatusCode::OK, "hi") })
+            .layer(Trace("outer", trace.clone()))
+            .layer(Trace("inner", trace.clone()));
+        assert_eq!(get(&router, "/", &[]).status(), StatusCode::OK);
+        // 没有匹配的路由时同样会经过中间件。
+        assert_eq!(get(&router, "/missing", &[]).status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            trace.lock().unwrap()[..4],
+            ["outer in", "inner in", "inner out", "outer out"]
+        );
+        assert_eq!(trace.lock().unwrap().len(), 8);
+    }
+
+    #[test]
+    fn assigns_request_ids_and_logs() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let router = Router::new()
+            .get("/", |request: Request| async move {
+                let id = request.headers.get(REQUEST_ID).unwrap_or_default().to_owned();
+                Response::text(StatusCode::OK, id)
+            })
+            .layer(RequestId::new())
+            .layer(Logger::with_sink(move |line| sink.lock().unwrap().push(line.to_owned())));
+
+        let first = get(&router, "/", &[]);
+        let second = get(&router, "/", &[]);
+        let id = first.headers().get(REQUEST_ID).unwrap();
+        assert_eq!(first.body().as_bytes(), Some(id.as_bytes()));
+        assert_ne!(second.headers().get(REQUEST_ID), Some(id));
+
+        let forwarded = get(&router, "/", &[(REQUEST_ID, "upstream-7")]);
+        assert_eq!(forwarded.headers().get(REQUEST_ID), Some("upstream-7"));
+        let bogus = get(&router, "/", &[(REQUEST_ID, "has spaces")]);
+        assert_ne!(bogus.headers().get(REQUEST_ID), Some("has spaces"));
+
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[2].starts_with("[upstream-7] GET / 200 "), "{}", lines[2]);
+    }
+
+    #[test]
+    fn compresses_text_bodies() {
+        let text = "compress me please ".repeat(50);
+        let body = text.clone();
+        let router = Router::new()
+            .get("/text", move |_| {
+                let body = body.clone();
+                async move { Response::text(StatusCode::OK, body) }
+            })
+            .get("/tiny", |_| async { Response::text(StatusCode::OK, "tiny") })
+            .get("/png", |_| async {
+                Response::new(StatusCode::OK)
+                    .with_header("Content-Type", "image/png")
+                    .with_body(vec![0; 1024])
+            })
+            .layer(Compression::new());
+
+        let gzip = get(&router, "/text", &[("Accept-Encoding", "deflate;q=0.5, gzip")]);
+        assert_eq!(gzip.headers().get("Content-Encoding"), Some("gzip"));
+        assert_eq!(gzip.headers().get("Vary"), Some("Accept-Encoding"));
+        let compressed = gzip.body().as_bytes().unwrap();
+        assert!(compressed.len() < text.len() / 4);
+        let mut decoded = String::new();
+        GzDecoder::new(compressed).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        let deflate = get(&router, "/text", &[("Accept-Encoding", "gzip;q=0, deflate")]);
+        assert_eq!(deflate.headers().get("Content-Encoding"), Some("deflate"));
+        let mut decoded = String::new();
+        ZlibDecoder::new(deflate.body().as_bytes().unwrap()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, text);
+
+        for (path, accept) in [("/text", "br"), ("/tiny", "gzip"), ("/png", "gzip")] {
+            let response = get(&router, path, &[("Accept-Encoding", accept)]);
+            assert!(!response.headers().contains("Content-Encoding"), "{path} {accept}");
+        }
+        assert!(!get(&router, "/text", &[]).headers().contains("Content-Encoding"));
+    }
+
+    #[test]
+    fn answers_cors_preflights() {
+        let router = Router::new()
+            .post("/api", |_| async { Response::text(StatusCode::OK, "done") })
+            .layer(
+                Cors::new()
+                    .allow_origin("https://app.example")
+                    .allow_headers(["Content-Type"])
+                    .max_age(Duration::from_secs(600)),
+            );
+
+        let mut preflight = Request::new(Method::Options, "/api");
+        preflight.headers.insert("Origin", "https://app.example");
+        preflight.headers.insert("Access-Control-Request-Method", "POST");
+        let response = block_on(router.handle(preflight));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(headers.get("Access-Control-Allow-Origin"), Some("https://app.example"));
+        assert_eq!(headers.get("Access-Control-Allow-Methods"), Some("GET, HEAD, POST"));
+        assert_eq!(headers.get("Access-Control-Allow-Headers"), Some("Content-Type"));
+        assert_eq!(headers.get("Access-Control-Max-Age"), Some("600"));
+
+        let mut request = Request::new(Method::Post, "/api");
+        request.headers.insert("Origin", "https://app.example");
+        let response = block_on(router.handle(request));
+        assert_eq!(response.body().as_bytes(), Some(&b"done"[..]));
+        assert_eq!(response.headers().get("Vary"), Some("Origin"));
+
+        let mut foreign = Request::new(Method::Post, "/api");
+        foreign.headers.insert("Origin", "https://evil.example");
+        let response = block_on(router.handle(foreign));
+        assert!(!response.headers().contains("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn turns_panics_into_500() {
+        let router = Router::new()
+            .get("/boom", |_| async { panic!("handler exploded") })
+            .layer(CatchPanic);
+        let response = get(&router, "/boom", &[]);
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        assert_eq!(negotiate("gzip, deflate"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate;q=1, gzip;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("*"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity, br"), None);
+        assert_eq!(negotiate("gzip;q=0"), None);
+    }
+}
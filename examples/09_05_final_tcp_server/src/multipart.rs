@@ -0,0 +1,497 @@
+//! 流式的 `multipart/form-data` 解析器。
+//!
+//! [`Multipart`] 从任意的 [`AsyncRead`]（通常是 [`RequestBody`]）中依次读出各个部分，
+//! 每个部分的数据以数据块的形式交出，上传的文件不需要整个放进内存。
+//!
+//! ```
+//! use final_tcp_server::multipart::Multipart;
+//! use futures::{executor::block_on, io::Cursor};
+//!
+//! let body = "--XyZ\r\n\
+//!             Content-Disposition: form-data; name=\"title\"\r\n\
+//!             \r\n\
+//!             hello\r\n\
+//!             --XyZ--\r\n";
+//! let mut multipart = Multipart::new(Cursor::new(body), "XyZ");
+//! block_on(async {
+//!     let field = multipart.next_field().await.unwrap().unwrap();
+//!     assert_eq!(field.name(), Some("title"));
+//!     assert_eq!(field.text().await.unwrap(), "hello");
+//!     assert!(multipart.next_field().await.unwrap().is_none());
+//! });
+//! ```
+
+use std::{error, fmt, io};
+
+use futures::io::AsyncRead;
+
+use crate::{
+    body::{parse_error, RequestBody},
+    headers::Headers,
+    request::{fill, find, parse_header, split_crlf, Request},
+    response::StatusCode,
+};
+
+/// 每个部分的头部最多占用的字节数。
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+
+/// 解析失败的原因。
+#[derive(Debug)]
+pub enum MultipartError {
+    /// 读取数据失败，其中可能带有请求体的 [`ParseError`](crate::request::ParseError)。
+    Io(io::Error),
+    /// 某个部分的头部超过了上限。
+    HeadersTooLarge,
+    /// 格式有误，例如数据在结束分隔符之前就结束了。
+    Malformed(&'static str),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MultipartError::Io(e) => write!(f, "I/O error: {e}"),
+            MultipartError::HeadersTooLarge => f.write_str("multipart headers are too large"),
+            MultipartError::Malformed(msg) => write!(f, "malformed multipart body: {msg}"),
+        }
+    }
+}
+
+impl error::Error for MultipartError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            MultipartError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MultipartError {
+    fn from(e: io::Error) -> Self {
+        MultipartError::Io(e)
+    }
+}
+
+impl MultipartError {
+    /// 回应这个错误时应当使用的状态码。
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            MultipartError::Io(e) => match parse_error(e) {
+                Some(e) => e.status_code(),
+                None => StatusCode::BAD_REQUEST,
+            },
+            MultipartError::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            MultipartError::Malformed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// 从 `Content-Type` 中取出分隔符，类型不是 `multipart/form-data` 时返回 `None`。
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';').unwrap_or((content_type, ""));
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = param(params, "boundary")?;
+    // RFC 2046：分隔符由 1 到 70 个字符组成。
+    (1..=70).contains(&boundary.len()).then_some(boundary)
+}
+
+/// 在 `; key=value; key="value"` 形式的参数中查找 `key`，值可以是带有转义的引号字符串。
+fn param(params: &str, key: &str) -> Option<String> {
+    let mut rest = params;
+    loop {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        if rest.is_empty() {
+            return None;
+        }
+        let (name, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (i, '"') => break i + 1,
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(';').unwrap_or(after.len());
+                (after[..end].trim().to_owned(), &after[end..])
+            }
+        };
+        if name.trim().eq_ignore_ascii_case(key) {
+            return Some(value);
+        }
+        rest = after;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// 正在读前言或者某个部分的数据。
+    Data,
+    /// 缓冲区以分隔符开头。
+    Delimiter,
+    /// 已经读到了结束分隔符。
+    Done,
+}
+
+/// `multipart/form-data` 请求体的解析器。
+pub struct Multipart<R> {
+    reader: R,
+    /// `\r\n--` 加上分隔符。
+    delimiter: Vec<u8>,
+    buf: Vec<u8>,
+    state: State,
+}
+
+impl Multipart<RequestBody> {
+    /// 取走请求的请求体并开始解析，请求不是 `multipart/form-data` 时返回 `None`。
+    pub fn from_request(request: &mut Request) -> Option<Multipart<RequestBody>> {
+        let boundary = boundary(request.headers.get("Content-Type")?)?;
+        Some(Multipart::new(request.take_body(), &boundary))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Multipart<R> {
+        Multipart {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // 第一个分隔符前面不一定有换行，补上一个之后就可以和其他分隔符一样查找了。
+            buf: b"\r\n".to_vec(),
+            state: State::Data,
+        }
+    }
+
+    /// 读出下一个部分的头部，没有更多部分时返回 `None`。
+    ///
+    /// 上一个部分没有读完的数据会被跳过。
+    pub async fn next_field(&mut self) -> Result<Option<Field<'_, R>>, MultipartError> {
+        while self.state == State::Data {
+            self.next_chunk().await?;
+        }
+        if self.state == State::Done {
+            return Ok(None);
+        }
+
+        let start = self.delimiter.len();
+        while self.buf.len() < start + 2 {
+            self.fill().await?;
+        }
+        if &self.buf[start..start + 2] == b"--" {
+            // 结束分隔符之后的内容没有意义，不再读取。
+            self.state = State::Done;
+            return Ok(None);
+        }
+        // 分隔符所在的行剩下的部分只能是空白，之后是头部和一个空行。
+        let end = loop {
+            if let Some(i) = find(&self.buf[start..], b"\r\n\r\n") {
+                break start + i + 2;
+            }
+            if self.buf.len() > start + MAX_HEADER_BYTES {
+                return Err(MultipartError::HeadersTooLarge);
+            }
+            self.fill().await?;
+        };
+        if end - start > MAX_HEADER_BYTES {
+            return Err(MultipartError::HeadersTooLarge);
+        }
+        let mut lines = split_crlf(&self.buf[start..end]);
+        if !lines.next().unwrap().iter().all(|&b| b == b' ' || b == b'\t') {
+            return Err(MultipartError::Malformed("invalid boundary line"));
+        }
+        let mut headers = Headers::new();
+        for line in lines {
+            let header = parse_header(line).map_err(|_| MultipartError::Malformed("invalid header"))?;
+            headers.append(header.name, String::from_utf8_lossy(header.value));
+        }
+        self.buf.drain(..end + 2);
+        self.state = State::Data;
+
+        let disposition = headers.get("Content-Disposition").unwrap_or_default();
+        let params = disposition.split_once(';').map_or("", |(_, params)| params);
+        Ok(Some(Field {
+            name: param(params, "name"),
+            file_name: param(params, "filename"),
+            headers,
+            multipart: self,
+        }))
+    }
+
+    /// 读出当前部分的下一块数据，读到分隔符时返回 `None`。
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, MultipartError> {
+        if self.state != State::Data {
+            return Ok(None);
+        }
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                if i == 0 {
+                    self.state = State::Delimiter;
+                    return Ok(None);
+                }
+                return Ok(Some(self.buf.drain(..i).collect()));
+            }
+            // 末尾可能是分隔符的前一部分，先留着，其余的都可以交出去。
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let n = self.buf.len() - keep;
+                return Ok(Some(self.buf.drain(..n).collect()));
+            }
+            self.fill().await?;
+        }
+    }
+
+    async fn fill(&mut self) -> Result<(), MultipartError> {
+        match fill(&mut self.reader, &mut self.buf).await? {
+            0 => Err(MultipartError::Malformed("missing closing boundary")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 表单中的一个部分。
+pub struct Field<'m, R> {
+    multipart: &'m mut Multipart<R>,
+    headers: Headers,
+    name: Option<String>,
+    file_name: Option<String>,
+}
+
+impl<R: AsyncRead + Unpin> Field<'_, R> {
+    /// 表单字段的名字，来自 `Content-Disposition` 的 `name` 参数。
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// 上传的文件名，来自 `Content-Disposition` 的 `filename` 参数。
+    ///
+    /// 这是客户端提供的名字，用作本地路径之前一定要检查。
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers.get("Content-Type")
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// 读出下一块数据，这个部分读完时返回 `None`。
+    pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>, MultipartError> {
+        self.multipart.next_chunk().await
+    }
+
+    /// 读出这个部分剩下的全部数据。
+    pub async fn bytes(mut self) -> Result<Vec<u8>, MultipartError> {
+        let mut data = Vec::new();
+        while let Some(chunk) = self.chunk().await? {
+            data.extend_from_slice(&chunk);
+        }
+        Ok(data)
+    }
+
+    /// 读出这个部分剩下的全部数据，它必须是 UTF-8 文本。
+    pub async fn text(self) -> Result<String, MultipartError> {
+        String::from_utf8(self.bytes().await?).map_err(|_| MultipartError::Malformed("field is not UTF-8"))
+    }
+}
+
+impl<R> fmt::Debug for Field<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Field")
+            .field("name", &self.name)
+            .field("file_name", &self.file_name)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{serve, Config},
+        request::Method,
+        response::Response,
+    };
+    use futures::{executor::block_on, io::Cursor};
+    use mock_stream::MockStream;
+
+    const FORM: &str = "preamble is ignored\r\n\
+        --AaB03x\r\n\
+        Content-Disposition: form-data; name=\"submit-name\"\r\n\
+        \r\n\
+        Larry\r\n\
+        --AaB03x  \r\n\
+        Content-Disposition: form-data; name=\"files\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n--AaB03 is not a boundary\r\n\
+        --AaB03x--\r\n\
+        epilogue";
+
+    /// 读出所有部分的名字、文件名和数据。
+    async fn collect<R: AsyncRead + Unpin>(
+        mut multipart: Multipart<R>,
+    ) -> Result<Vec<(Option<String>, Option<String>, Vec<u8>)>, MultipartError> {
+        let mut fields = Vec::new();
+        while let Some(field) = multipart.next_field().await? {
+            let (name, file_name) = (field.name.clone(), field.file_name.clone());
+            fields.push((name, file_name, field.bytes().await?));
+        }
+        Ok(fields)
+    }
+
+    /// 每次只读出一个字节的读取端，检查分隔符在任意位置被切开时都能找到。
+    struct Trickle<'a>(&'a [u8]);
+
+    impl AsyncRead for Trickle<'_> {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut [u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            let n = self.0.len().min(buf.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            std::task::Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn parses_fields() {
+        let expected = vec![
+            (Some("submit-name".to_owned()), None, b"Larry".to_vec()),
+            (
+                Some("files".to_owned()),
+                Some("a \"b\".txt".to_owned()),
+                b"line one\r\n--AaB03 is not a boundary".to_vec(),
+            ),
+        ];
+        let fields = block_on(collect(Multipart::new(Cursor::new(FORM), "AaB03x"))).unwrap();
+        assert_eq!(fields, expected);
+        let fields = block_on(collect(Multipart::new(Trickle(FORM.as_bytes()), "AaB03x"))).unwrap();
+        assert_eq!(fields, expected);
+
+        // 没有读的部分会被跳过。
+        let mut multipart = Multipart::new(Cursor::new(FORM), "AaB03x");
+        block_on(async {
+            multipart.next_field().await.unwrap().unwrap();
+            let field = multipart.next_field().await.unwrap().unwrap();
+            assert_eq!(field.content_type(), Some("text/plain"));
+            assert!(multipart.next_field().await.unwrap().is_none());
+            assert!(multipart.next_field().await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_malformed_bodies() {
+        let truncated = &FORM[..FORM.find("--AaB03x--").unwrap()];
+        let error = block_on(collect(Multipart::new(Cursor::new(truncated), "AaB03x"))).unwrap_err();
+        assert!(matches!(error, MultipartError::Malformed(_)));
+
+        let garbage = "--AaB03x garbage\r\n\r\nx\r\n--AaB03x--";
+        let error = block_on(collect(Multipart::new(Cursor::new(garbage), "AaB03x"))).unwrap_err();
+        assert!(matches!(error, MultipartError::Malformed(_)));
+
+        let huge = format!("--AaB03x\r\nX: {}\r\n\r\n", "y".repeat(MAX_HEADER_BYTES));
+        let error = block_on(collect(Multipart::new(Cursor::new(huge), "AaB03x"))).unwrap_err();
+        assert!(matches!(error, MultipartError::HeadersTooLarge));
+        assert_eq!(error.status_code(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[test]
+    fn finds_the_boundary() {
+        assert_eq!(boundary("multipart/form-data; boundary=AaB03x").as_deref(), Some("AaB03x"));
+        assert_eq!(
+            boundary("Multipart/Form-Data; charset=utf-8; boundary=\"a b;c\"").as_deref(),
+            Some("a b;c")
+        );
+        assert_eq!(boundary("multipart/mixed; boundary=AaB03x"), None);
+        assert_eq!(boundary("multipart/form-data"), None);
+        assert_eq!(boundary("multipart/form-data; boundary=\"\""), None);
+    }
+
+    #[test]
+    fn streams_uploads_through_serve() {
+        async fn upload(mut request: Request) -> Response {
+            assert!(request.body.is_empty());
+            let Some(mut multipart) = Multipart::from_request(&mut request) else {
+                return Response::text(StatusCode::UNSUPPORTED_MEDIA_TYPE, "not a form");
+            };
+            let mut report = String::new();
+            loop {
+                let mut field = match multipart.next_field().await {
+                    Ok(Some(field)) => field,
+                    Ok(None) => break,
+                    Err(e) => return Response::text(e.status_code(), e.to_string()),
+                };
+                let name = field.name().unwrap_or_default().to_owned();
+                let (mut chunks, mut size) = (0, 0);
+                loop {
+                    match field.chunk().await {
+                        Ok(Some(chunk)) => (chunks, size) = (chunks + 1, size + chunk.len()),
+                        Ok(None) => break,
+                        Err(e) => return Response::text(e.status_code(), e.to_string()),
+                    }
+                }
+                report += &format!("{name}={size} ");
+                assert!(chunks > 1 || size < 100_000);
+            }
+            Response::text(StatusCode::OK, report)
+        }
+
+        let file = "x".repeat(300_000);
+        let form = format!(
+            "--b\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n1\r\n\
+             --b\r\nContent-Disposition: form-data; name=\"f\"; filename=\"x\"\r\n\r\n{file}\r\n--b--\r\n"
+        );
+        let request = |len: usize| {
+            format!(
+                "POST /upload HTTP/1.1\r\nContent-Type: multipart/form-data; boundary=b\r\n\
+                 Content-Length: {len}\r\n\r\n"
+            )
+        };
+        let config = Config {
+            stream_bodies: true,
+            ..Config::default()
+        };
+
+        // 上传完之后同一个连接还能继续处理请求。
+        let input = [request(form.len()).as_bytes(), form.as_bytes(), b"GET / HTTP/1.1\r\n\r\n"].concat();
+        let mut stream = MockStream::builder().read(&input).build();
+        let mut methods = Vec::new();
+        let handler = |request: Request| {
+            methods.push(request.method);
+            upload(request)
+        };
+        block_on(serve(&mut stream, &config, handler)).unwrap();
+        assert_eq!(methods, [Method::Post, Method::Get]);
+        let output = String::from_utf8(stream.written()).unwrap();
+        assert!(output.contains("\r\n\r\na=1 f=300000 "), "{output}");
+        assert!(output.contains("415 Unsupported Media Type"));
+
+        // 超过上限的请求体在处理函数中表现为错误，回复之后关闭连接。
+        let config = Config {
+            limits: crate::request::Limits {
+                max_body_bytes: 1000,
+                ..Default::default()
+            },
+            ..config
+        };
+        let input = [request(form.len()).as_bytes(), form.as_bytes()].concat();
+        let mut stream = MockStream::builder().read(&input).build();
+        let result = block_on(serve(&mut stream, &config, upload));
+        assert!(matches!(result, Err(crate::error::ServerError::Parse(_))));
+        let output = String::from_utf8(stream.written()).unwrap();
+        assert!(output.starts_with("HTTP/1.1 413 Content Too Large\r\n"), "{output}");
+        assert!(output.contains("Connection: close\r\n"));
+    }
+}
@@ -0,0 +1,997 @@
+//! HTTP/1.1 请求解析。
+//!
+//! [`parse`] 是一个不做任何拷贝的增量解析器：它直接在读缓冲区上工作，
+//! 数据不够时返回 [`Status::Partial`]，调用者读到更多数据后再试一次即可。
+//! [`read_request`] 在它之上从流中读出一个完整的、带有请求体的 [`Request`]。
+
+use std::{error, fmt, io, ops::Range, str};
+
+use futures::io::{AsyncRead, AsyncReadExt};
+
+use crate::{body::RequestBody, headers::Headers, response::StatusCode};
+
+/// 解析请求时的各种上限。
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// 请求行加上所有头部最多占用的字节数。
+    pub max_head_bytes: usize,
+    /// 最多允许的头部数量。
+    pub max_headers: usize,
+    /// 请求体最多允许的字节数。
+    pub max_body_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_head_bytes: 8 * 1024,
+            max_headers: 64,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// 请求方法。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub(crate) fn from_bytes(token: &[u8]) -> Option<Method> {
+        Some(match token {
+            b"GET" => Method::Get,
+            b"HEAD" => Method::Head,
+            b"POST" => Method::Post,
+            b"PUT" => Method::Put,
+            b"DELETE" => Method::Delete,
+            b"CONNECT" => Method::Connect,
+            b"OPTIONS" => Method::Options,
+            b"TRACE" => Method::Trace,
+            b"PATCH" => Method::Patch,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+
+    /// 重复执行多次和执行一次效果相同的方法，这样的请求失败后可以安全地重试。
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Method::Post | Method::Connect | Method::Patch)
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// HTTP 版本。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    Http10,
+    Http11,
+    Http2,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+            Version::Http2 => "HTTP/2",
+        }
+    }
+}
+
+/// 请求体的分帧方式。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    /// 没有请求体。
+    Empty,
+    /// 由 `Content-Length` 给出长度。
+    Length(u64),
+    /// `Transfer-Encoding: chunked`。
+    Chunked,
+}
+
+/// 解析失败的原因。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// 请求行和头部超过了 [`Limits::max_head_bytes`]。
+    HeadTooLarge,
+    /// 头部数量超过了 [`Limits::max_headers`]。
+    TooManyHeaders,
+    /// 请求体超过了 [`Limits::max_body_bytes`]。
+    BodyTooLarge,
+    InvalidMethod,
+    /// 不认识的请求方法。
+    UnsupportedMethod,
+    InvalidTarget,
+    InvalidVersion,
+    /// 格式正确、但不是 HTTP/1.0 或 HTTP/1.1 的版本。
+    UnsupportedVersion,
+    InvalidHeader,
+    InvalidContentLength,
+    /// 同时出现了 `Content-Length` 和 `Transfer-Encoding`。
+    ConflictingLength,
+    /// `Transfer-Encoding` 的最后一项不是 `chunked`。
+    UnsupportedTransferEncoding,
+    /// HTTP/1.0 的请求带有 `Transfer-Encoding`，无法确定请求体的边界。
+    TransferEncodingOnHttp10,
+    InvalidChunk,
+    /// 响应的状态行格式有误，只会在客户端解析响应时出现。
+    InvalidStatus,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseError::HeadTooLarge => "request head is too large",
+            ParseError::TooManyHeaders => "too many headers",
+            ParseError::BodyTooLarge => "request body is too large",
+            ParseError::InvalidMethod => "invalid method",
+            ParseError::UnsupportedMethod => "unsupported method",
+            ParseError::InvalidTarget => "invalid request target",
+            ParseError::InvalidVersion => "invalid HTTP version",
+            ParseError::UnsupportedVersion => "unsupported HTTP version",
+            ParseError::InvalidHeader => "invalid header",
+            ParseError::InvalidContentLength => "invalid Content-Length",
+            ParseError::ConflictingLength => "both Content-Length and Transfer-Encoding are present",
+            ParseError::UnsupportedTransferEncoding => "unsupported Transfer-Encoding",
+            ParseError::TransferEncodingOnHttp10 => "Transfer-Encoding in an HTTP/1.0 request",
+            ParseError::InvalidChunk => "invalid chunked encoding",
+            ParseError::InvalidStatus => "invalid status line",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl ParseError {
+    /// 回应这个错误时应当使用的状态码。
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ParseError::HeadTooLarge | ParseError::TooManyHeaders => {
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+            }
+            ParseError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ParseError::UnsupportedMethod | ParseError::UnsupportedTransferEncoding => {
+                StatusCode::NOT_IMPLEMENTED
+            }
+            ParseError::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// 从流中读取请求时的错误。
+#[derive(Debug)]
+pub enum ReadError {
+    Io(io::Error),
+    Parse(ParseError),
+    /// 连接在请求读完之前被关闭了。
+    UnexpectedEof,
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "I/O error: {e}"),
+            ReadError::Parse(e) => write!(f, "malformed request: {e}"),
+            ReadError::UnexpectedEof => f.write_str("connection closed in the middle of a request"),
+        }
+    }
+}
+
+impl error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Parse(e) => Some(e),
+            ReadError::UnexpectedEof => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// 增量解析的结果。
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status<T> {
+    Complete(T),
+    /// 数据还不完整，需要读到更多数据后再试。
+    Partial,
+}
+
+/// 一个头部，直接借用自读缓冲区。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawHeader<'b> {
+    pub name: &'b str,
+    pub value: &'b [u8],
+}
+
+/// 请求行和头部，所有字段都直接借用自读缓冲区。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead<'b> {
+    pub method: Method,
+    /// 请求目标中的路径部分，未经百分号解码。
+    pub path: &'b str,
+    /// `?` 之后的查询字符串。
+    pub query: Option<&'b str>,
+    pub version: Version,
+    pub headers: Vec<RawHeader<'b>>,
+}
+
+impl<'b> RequestHead<'b> {
+    /// 返回第一个名为 `name` 的头部的值。
+    pub fn header(&self, name: &str) -> Option<&'b [u8]> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+
+    /// 根据 `Content-Length` 和 `Transfer-Encoding` 确定请求体的分帧方式。
+    ///
+    /// HTTP/1.0 没有传输编码，带着 `Transfer-Encoding` 的 HTTP/1.0 请求多半经过了不可靠的中间人，
+    /// 按照 RFC 9112 第 6.1 节把它当作分帧错误拒绝。
+    pub fn body_kind(&self) -> Result<BodyKind, ParseError> {
+        if self.version == Version::Http10 && self.header("Transfer-Encoding").is_some() {
+            return Err(ParseError::TransferEncodingOnHttp10);
+        }
+        body_kind(self.headers.iter().map(|h| (h.name, h.value)))
+    }
+
+    /// 拷贝出一个不再借用缓冲区的 [`Request`]，请求体为空。
+    pub fn to_request(&self) -> Request {
+        let mut headers = Headers::new();
+        for h in &self.headers {
+            // 头部的值中偶尔会出现非 UTF-8 的字节，这里直接做有损转换。
+            headers.append(h.name, String::from_utf8_lossy(h.value));
+        }
+        Request {
+            method: self.method,
+            path: self.path.to_owned(),
+            query: self.query.map(str::to_owned),
+            version: self.version,
+            headers,
+            body: Vec::new(),
+            params: Vec::new(),
+            stream: None,
+        }
+    }
+}
+
+/// 一个完整的 HTTP 请求。
+#[derive(Debug)]
+pub struct Request {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub version: Version,
+    pub headers: Headers,
+    /// 预先读完的请求体。开启了 [`Config::stream_bodies`](crate::connection::Config::stream_bodies)
+    /// 时它总是空的，请求体要通过 [`take_body`](Request::take_body) 读取。
+    pub body: Vec<u8>,
+    /// 路由从路径中捕获到的参数，参见 [`Router`](crate::router::Router)。
+    pub params: Vec<(String, String)>,
+    stream: Option<RequestBody>,
+}
+
+impl Request {
+    /// 对方是否希望在这个请求之后继续使用同一个连接。
+    ///
+    /// HTTP/1.1 默认保持连接，除非带有 `Connection: close`；
+    /// HTTP/1.0 默认关闭连接，除非带有 `Connection: keep-alive`；
+    /// HTTP/2 的连接不由单个请求决定。
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.has_token("Connection", "close"),
+            Version::Http10 => self.headers.has_token("Connection", "keep-alive"),
+            Version::Http2 => true,
+        }
+    }
+
+    /// 返回路由捕获到的名为 `name` 的参数。
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// 取走请求体，之后 [`body`](Request::body) 为空。
+    ///
+    /// 不论请求体是预先读完的还是流式的，都可以用这个方法边读边处理。
+    pub fn take_body(&mut self) -> RequestBody {
+        match self.stream.take() {
+            Some(stream) => stream,
+            None => RequestBody::from(std::mem::take(&mut self.body)),
+        }
+    }
+
+    /// 把请求体换成一个流式的请求体。
+    pub(crate) fn set_body_stream(&mut self, stream: RequestBody) {
+        self.body.clear();
+        self.stream = Some(stream);
+    }
+
+    /// 构造一个没有头部和请求体的请求，主要用于测试。
+    pub fn new(method: Method, target: &str) -> Request {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_owned())),
+            None => (target, None),
+        };
+        Request {
+            method,
+            path: path.to_owned(),
+            query,
+            version: Version::Http11,
+            headers: Headers::new(),
+            body: Vec::new(),
+            params: Vec::new(),
+            stream: None,
+        }
+    }
+}
+
+pub(crate) fn body_kind<'a>(headers: impl Iterator<Item = (&'a str, &'a [u8])>) -> Result<BodyKind, ParseError> {
+    let mut length: Option<u64> = None;
+    let mut chunked = None;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("content-length") {
+            // 多个 `Content-Length`（或逗号分隔的多个值）必须完全一致。
+            for part in value.split(|&b| b == b',') {
+                let n = parse_content_length(part.trim_ascii())?;
+                match length {
+                    Some(existing) if existing != n => return Err(ParseError::InvalidContentLength),
+                    _ => length = Some(n),
+                }
+            }
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            let last = value
+                .split(|&b| b == b',')
+                .map(<[u8]>::trim_ascii)
+                .rfind(|coding| !coding.is_empty());
+            chunked = Some(matches!(last, Some(coding) if coding.eq_ignore_ascii_case(b"chunked")));
+        }
+    }
+    match (length, chunked) {
+        (Some(_), Some(_)) => Err(ParseError::ConflictingLength),
+        (_, Some(true)) => Ok(BodyKind::Chunked),
+        (_, Some(false)) => Err(ParseError::UnsupportedTransferEncoding),
+        (Some(0), None) | (None, None) => Ok(BodyKind::Empty),
+        (Some(n), None) => Ok(BodyKind::Length(n)),
+    }
+}
+
+fn parse_content_length(value: &[u8]) -> Result<u64, ParseError> {
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return Err(ParseError::InvalidContentLength);
+    }
+    str::from_utf8(value)
+        .unwrap()
+        .parse()
+        .map_err(|_| ParseError::InvalidContentLength)
+}
+
+/// 尝试从 `buf` 的开头解析出请求行和头部。
+///
+/// 成功时同时返回请求头占用的字节数，请求体（如果有）紧随其后。
+pub fn parse<'b>(
+    buf: &'b [u8],
+    limits: &Limits,
+) -> Result<Status<(RequestHead<'b>, usize)>, ParseError> {
+    // 按照 RFC 9112，服务器应当忽略请求行之前的空行。
+    let mut start = 0;
+    while buf[start..].starts_with(b"\r\n") {
+        start += 2;
+    }
+
+    let Some(end) = find(&buf[start..], b"\r\n\r\n").map(|i| start + i + 4) else {
+        return if buf.len() > limits.max_head_bytes {
+            Err(ParseError::HeadTooLarge)
+        } else {
+            Ok(Status::Partial)
+        };
+    };
+    if end > limits.max_head_bytes {
+        return Err(ParseError::HeadTooLarge);
+    }
+
+    // 头部的每一行都以 CRLF 结尾，单独出现的 LF 一律视为格式错误。
+    let head = &buf[start..end - 2];
+    if head.iter().enumerate().any(|(i, &b)| b == b'\n' && (i == 0 || head[i - 1] != b'\r')) {
+        return Err(ParseError::InvalidHeader);
+    }
+    let mut lines = split_crlf(head);
+    let request_line = lines.next().unwrap_or_default();
+    let (method, path, query, version) = parse_request_line(request_line)?;
+
+    let mut headers = Vec::new();
+    for line in lines {
+        if headers.len() == limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        headers.push(parse_header(line)?);
+    }
+
+    let head = RequestHead {
+        method,
+        path,
+        query,
+        version,
+        headers,
+    };
+    Ok(Status::Complete((head, end)))
+}
+
+/// 按 CRLF 切分，`head` 本身以 CRLF 结尾，最后不会产生空行。
+pub(crate) fn split_crlf(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = head;
+    std::iter::from_fn(move || {
+        let i = find(rest, b"\r\n")?;
+        let line = &rest[..i];
+        rest = &rest[i + 2..];
+        Some(line)
+    })
+}
+
+fn parse_request_line(line: &[u8]) -> Result<(Method, &str, Option<&str>, Version), ParseError> {
+    let mut parts = line.split(|&b| b == b' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::InvalidTarget);
+    };
+
+    if method.is_empty() || !method.iter().copied().all(is_token) {
+        return Err(ParseError::InvalidMethod);
+    }
+    let method = Method::from_bytes(method).ok_or(ParseError::UnsupportedMethod)?;
+
+    if target.is_empty() || !target.iter().all(|&b| b.is_ascii_graphic()) {
+        return Err(ParseError::InvalidTarget);
+    }
+    // 所有字节都是可见的 ASCII 字符，一定是合法的 UTF-8。
+    let target = str::from_utf8(target).unwrap();
+    let target = match target {
+        "*" if method == Method::Options => "*",
+        t if t.starts_with('/') => t,
+        // 绝对形式（通常来自代理），只保留路径部分，路径为空时留到下面按 `/` 处理。
+        t => {
+            let rest = t
+                .strip_prefix("http://")
+                .or_else(|| t.strip_prefix("https://"))
+                .ok_or(ParseError::InvalidTarget)?;
+            match rest.find(['/', '?']) {
+                Some(0) => return Err(ParseError::InvalidTarget),
+                Some(i) => &rest[i..],
+                None if rest.is_empty() => return Err(ParseError::InvalidTarget),
+                None => "",
+            }
+        }
+    };
+    if target.contains('#') {
+        return Err(ParseError::InvalidTarget);
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    // 只有绝对形式会走到这里：RFC 9112 第 3.2.2 节规定空路径等同于 `/`。
+    let path = if path.is_empty() { "/" } else { path };
+
+    let version = match version {
+        b"HTTP/1.1" => Version::Http11,
+        b"HTTP/1.0" => Version::Http10,
+        [b'H', b'T', b'T', b'P', b'/', major, b'.', minor]
+            if major.is_ascii_digit() && minor.is_ascii_digit() =>
+        {
+            return Err(ParseError::UnsupportedVersion)
+        }
+        _ => return Err(ParseError::InvalidVersion),
+    };
+    Ok((method, path, query, version))
+}
+
+pub(crate) fn parse_header(line: &[u8]) -> Result<RawHeader<'_>, ParseError> {
+    let colon = line
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or(ParseError::InvalidHeader)?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    // 名字和冒号之间不允许有空白；以空白开头的行是已经废弃的折行写法，同样拒绝。
+    if name.is_empty() || !name.iter().copied().all(is_token) {
+        return Err(ParseError::InvalidHeader);
+    }
+    let value = value.trim_ascii();
+    if !value
+        .iter()
+        .all(|&b| b == b'\t' || b == b' ' || b.is_ascii_graphic() || b >= 0x80)
+    {
+        return Err(ParseError::InvalidHeader);
+    }
+    Ok(RawHeader {
+        name: str::from_utf8(name).unwrap(),
+        value,
+    })
+}
+
+/// RFC 9110 中的 `tchar`。
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+pub(crate) fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// `Transfer-Encoding: chunked` 的增量解码器。
+///
+/// 每次调用 [`decode`](ChunkedDecoder::decode) 都会消耗一部分输入，
+/// 并最多返回一段数据在输入中的位置，数据本身不会被拷贝。
+#[derive(Debug, Clone)]
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    /// 已经读过的尾部（trailer）字节数。
+    trailer_bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Size { size: u64, digits: usize },
+    Extension { size: u64 },
+    SizeLf { size: u64 },
+    Data { remaining: u64 },
+    DataCr,
+    DataLf,
+    TrailerStart,
+    TrailerLine,
+    TrailerLf,
+    FinalLf,
+    Done,
+}
+
+/// [`ChunkedDecoder::decode`] 的一次结果。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decoded {
+    /// 消耗掉的输入字节数。
+    pub consumed: usize,
+    /// 解出的数据在输入中的位置，可能为空。
+    pub data: Range<usize>,
+}
+
+/// 尾部最多允许的字节数。
+const MAX_TRAILER_BYTES: usize = 8 * 1024;
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        ChunkedDecoder {
+            state: ChunkState::Size { size: 0, digits: 0 },
+            trailer_bytes: 0,
+        }
+    }
+}
+
+impl ChunkedDecoder {
+    pub fn new() -> Self {
+        ChunkedDecoder::default()
+    }
+
+    /// 最后一个块和尾部都已经读完。
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    pub fn decode(&mut self, input: &[u8]) -> Result<Decoded, ParseError> {
+        use ChunkState::*;
+
+        let mut i = 0;
+        while i < input.len() {
+            let b = input[i];
+            self.state = match self.state {
+                Size { size, digits } => match (b as char).to_digit(16) {
+                    Some(d) => {
+                        let size = size
+                            .checked_mul(16)
+                            .and_then(|s| s.checked_add(d as u64))
+                            .ok_or(ParseError::InvalidChunk)?;
+                        Size { size, digits: digits + 1 }
+                    }
+                    None if digits == 0 => return Err(ParseError::InvalidChunk),
+                    None if b == b'\r' => SizeLf { size },
+                    None if b == b';' || b == b' ' || b == b'\t' => Extension { size },
+                    None => return Err(ParseError::InvalidChunk),
+                },
+                Extension { size } => match b {
+                    b'\r' => SizeLf { size },
+                    b'\n' => return Err(ParseError::InvalidChunk),
+                    _ => Extension { size },
+                },
+                SizeLf { size } => match b {
+                    b'\n' if size == 0 => TrailerStart,
+                    b'\n' => Data { remaining: size },
+                    _ => return Err(ParseError::InvalidChunk),
+                },
+                Data { remaining } => {
+                    let n = remaining.min((input.len() - i) as u64) as usize;
+                    let remaining = remaining - n as u64;
+                    self.state = if remaining == 0 { DataCr } else { Data { remaining } };
+                    return Ok(Decoded {
+                        consumed: i + n,
+                        data: i..i + n,
+                    });
+                }
+                DataCr if b == b'\r' => DataLf,
+                DataLf if b == b'\n' => Size { size: 0, digits: 0 },
+                DataCr | DataLf => return Err(ParseError::InvalidChunk),
+                TrailerStart if b == b'\r' => FinalLf,
+                TrailerStart | TrailerLine => {
+                    self.trailer_bytes += 1;
+                    if self.trailer_bytes > MAX_TRAILER_BYTES {
+                        return Err(ParseError::HeadTooLarge);
+                    }
+                    if b == b'\r' { TrailerLf } else { TrailerLine }
+                }
+                TrailerLf if b == b'\n' => TrailerStart,
+                FinalLf if b == b'\n' => {
+                    self.state = Done;
+                    return Ok(Decoded {
+                        consumed: i + 1,
+                        data: i + 1..i + 1,
+                    });
+                }
+                TrailerLf | FinalLf => return Err(ParseError::InvalidChunk),
+                Done => break,
+            };
+            i += 1;
+        }
+        Ok(Decoded {
+            consumed: i,
+            data: i..i,
+        })
+    }
+}
+
+/// 每次从流中读取的字节数。
+const READ_CHUNK: usize = 1024;
+
+/// 从 `stream` 中读取下一个完整的请求，包括请求体。
+///
+/// `buf` 保存着上一次调用之后多读出来的数据（例如流水线中的下一个请求），
+/// 在同一个连接上反复调用时应传入同一个缓冲区。如果连接在新请求的第一个字节到来之前
+/// 就被正常关闭，返回 `Ok(None)`。
+pub async fn read_request(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Option<Request>, ReadError> {
+    let Some((mut request, body_kind)) = read_head(stream, buf, limits).await? else {
+        return Ok(None);
+    };
+    request.body = read_body(stream, buf, body_kind, limits).await?;
+    Ok(Some(request))
+}
+
+/// 读取下一个请求的请求行和头部，返回的请求的 `body` 为空，请求体的格式由 [`BodyKind`] 给出。
+///
+/// `buf` 的用法和返回 `Ok(None)` 的情况与 [`read_request`] 相同。返回之后 `buf`
+/// 中剩下的数据从请求体开始。
+pub async fn read_head(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<Option<(Request, BodyKind)>, ReadError> {
+    let (request, head_len, body_kind) = loop {
+        if let Status::Complete((head, len)) = parse(buf, limits)? {
+            let kind = head.body_kind()?;
+            break (head.to_request(), len, kind);
+        }
+        if fill(stream, buf).await? == 0 {
+            return if buf.iter().all(|&b| b == b'\r' || b == b'\n') {
+                Ok(None)
+            } else {
+                Err(ReadError::UnexpectedEof)
+            };
+        }
+    };
+    buf.drain(..head_len);
+    Ok(Some((request, body_kind)))
+}
+
+/// 紧接着 [`read_head`] 读取格式为 `kind` 的请求体。
+pub async fn read_body(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    kind: BodyKind,
+    limits: &Limits,
+) -> Result<Vec<u8>, ReadError> {
+    Ok(match kind {
+        BodyKind::Empty => Vec::new(),
+        BodyKind::Length(len) => {
+            if len > limits.max_body_bytes as u64 {
+                return Err(ParseError::BodyTooLarge.into());
+            }
+            let len = len as usize;
+            while buf.len() < len {
+                if fill(stream, buf).await? == 0 {
+                    return Err(ReadError::UnexpectedEof);
+                }
+            }
+            buf.drain(..len).collect()
+        }
+        BodyKind::Chunked => {
+            let mut decoder = ChunkedDecoder::new();
+            let mut body = Vec::new();
+            loop {
+                let decoded = decoder.decode(buf)?;
+                body.extend_from_slice(&buf[decoded.data]);
+                buf.drain(..decoded.consumed);
+                if body.len() > limits.max_body_bytes {
+                    return Err(ParseError::BodyTooLarge.into());
+                }
+                if decoder.is_done() {
+                    break body;
+                }
+                if buf.is_empty() && fill(stream, buf).await? == 0 {
+                    return Err(ReadError::UnexpectedEof);
+                }
+            }
+        }
+    })
+}
+
+/// 从流中再读一些数据追加到 `buf` 末尾，返回读到的字节数。
+pub(crate) async fn fill(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> io::Result<usize> {
+    let len = buf.len();
+    buf.resize(len + READ_CHUNK, 0);
+    let result = stream.read(&mut buf[len..]).await;
+    buf.truncate(len + *result.as_ref().unwrap_or(&0));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    fn parse_complete(input: &[u8]) -> (RequestHead<'_>, usize) {
+        match parse(input, &Limits::default()).unwrap() {
+            Status::Complete(result) => result,
+            Status::Partial => panic!("request should be complete"),
+        }
+    }
+
+    #[test]
+    fn parses_request_line_and_headers() {
+        let input = b"GET /search?q=rust HTTP/1.1\r\nHost: localhost\r\nAccept:  */* \r\n\r\nrest";
+        let (head, len) = parse_complete(input);
+        assert_eq!(len, input.len() - 4);
+        assert_eq!(head.method, Method::Get);
+        assert_eq!(head.path, "/search");
+        assert_eq!(head.query, Some("q=rust"));
+        assert_eq!(head.version, Version::Http11);
+        assert_eq!(head.header("host"), Some(&b"localhost"[..]));
+        assert_eq!(head.header("ACCEPT"), Some(&b"*/*"[..]));
+        assert_eq!(head.body_kind(), Ok(BodyKind::Empty));
+    }
+
+    #[test]
+    fn partial_input_at_every_split_point() {
+        let input = b"POST /upload HTTP/1.0\r\nContent-Length: 5\r\n\r\n";
+        for split in 0..input.len() {
+            assert_eq!(parse(&input[..split], &Limits::default()), Ok(Status::Partial));
+        }
+        let (head, _) = parse_complete(input);
+        assert_eq!(head.method, Method::Post);
+        assert_eq!(head.version, Version::Http10);
+        assert_eq!(head.body_kind(), Ok(BodyKind::Length(5)));
+    }
+
+    #[test]
+    fn request_without_headers() {
+        let (head, len) = parse_complete(b"\r\nGET / HTTP/1.1\r\n\r\n");
+        assert_eq!(len, 20);
+        assert_eq!(head.path, "/");
+        assert!(head.headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let cases: &[(&[u8], ParseError)] = &[
+            (b"G@T / HTTP/1.1\r\n\r\n", ParseError::InvalidMethod),
+            (b"BREW / HTTP/1.1\r\n\r\n", ParseError::UnsupportedMethod),
+            (b"GET foo HTTP/1.1\r\n\r\n", ParseError::InvalidTarget),
+            (b"GET / HTTP/2.0\r\n\r\n", ParseError::UnsupportedVersion),
+            (b"GET / HTTX/1.1\r\n\r\n", ParseError::InvalidVersion),
+            (b"GET / HTTP/1.1\r\nHost : x\r\n\r\n", ParseError::InvalidHeader),
+            (b"GET / HTTP/1.1\r\n folded\r\n\r\n", ParseError::InvalidHeader),
+            (b"GET / HTTP/1.1\nHost: x\r\n\r\n", ParseError::InvalidHeader),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                parse(input, &Limits::default()).as_ref().err(),
+                Some(expected),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn absolute_form_target() {
+        let (head, _) = parse_complete(b"GET http://example.com/a?b HTTP/1.1\r\n\r\n");
+        assert_eq!(head.path, "/a");
+        assert_eq!(head.query, Some("b"));
+
+        let (head, _) = parse_complete(b"GET http://example.com HTTP/1.1\r\n\r\n");
+        assert_eq!(head.path, "/");
+        assert_eq!(head.query, None);
+        let (head, _) = parse_complete(b"GET http://example.com?b HTTP/1.1\r\n\r\n");
+        assert_eq!(head.path, "/");
+        assert_eq!(head.query, Some("b"));
+        for target in ["http://", "http:///a"] {
+            let input = format!("GET {target} HTTP/1.1\r\n\r\n");
+            assert_eq!(
+                parse(input.as_bytes(), &Limits::default()).err(),
+                Some(ParseError::InvalidTarget)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_on_http_10() {
+        let (head, _) =
+            parse_complete(b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n");
+        assert_eq!(head.body_kind(), Err(ParseError::TransferEncodingOnHttp10));
+        assert_eq!(head.body_kind().unwrap_err().status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn enforces_limits() {
+        let limits = Limits {
+            max_head_bytes: 32,
+            max_headers: 1,
+            ..Limits::default()
+        };
+        let long = b"GET /aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa HTTP/1.1\r\n";
+        assert_eq!(parse(long, &limits), Err(ParseError::HeadTooLarge));
+        let many = b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert_eq!(
+            parse(many, &Limits { max_headers: 1, ..Limits::default() }),
+            Err(ParseError::TooManyHeaders)
+        );
+    }
+
+    #[test]
+    fn body_framing() {
+        let kind = |headers: &[(&str, &str)]| {
+            body_kind(headers.iter().map(|(n, v)| (*n, v.as_bytes())))
+        };
+        assert_eq!(kind(&[("Content-Length", "10")]), Ok(BodyKind::Length(10)));
+        assert_eq!(kind(&[("Content-Length", "10, 10")]), Ok(BodyKind::Length(10)));
+        assert_eq!(
+            kind(&[("Content-Length", "10"), ("content-length", "11")]),
+            Err(ParseError::InvalidContentLength)
+        );
+        assert_eq!(kind(&[("Content-Length", "-1")]), Err(ParseError::InvalidContentLength));
+        assert_eq!(kind(&[("Transfer-Encoding", "gzip, chunked")]), Ok(BodyKind::Chunked));
+        assert_eq!(
+            kind(&[("Transfer-Encoding", "chunked, gzip")]),
+            Err(ParseError::UnsupportedTransferEncoding)
+        );
+        assert_eq!(
+            kind(&[("Transfer-Encoding", "chunked"), ("Content-Length", "3")]),
+            Err(ParseError::ConflictingLength)
+        );
+    }
+
+    #[test]
+    fn chunked_decoding_byte_by_byte() {
+        let input = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\nNEXT";
+        let mut decoder = ChunkedDecoder::new();
+        let mut body = Vec::new();
+        let mut pos = 0;
+        while !decoder.is_done() {
+            let decoded = decoder.decode(&input[pos..pos + 1]).unwrap();
+            body.extend_from_slice(&input[pos..pos + 1][decoded.data]);
+            pos += decoded.consumed;
+        }
+        assert_eq!(body, b"hello world");
+        assert_eq!(&input[pos..], b"NEXT");
+    }
+
+    #[test]
+    fn chunked_rejects_garbage() {
+        let mut decoder = ChunkedDecoder::new();
+        assert_eq!(decoder.decode(b"zz\r\n"), Err(ParseError::InvalidChunk));
+        let mut decoder = ChunkedDecoder::new();
+        assert_eq!(decoder.decode(b"1\r\nab"), Ok(Decoded { consumed: 4, data: 3..4 }));
+        assert_eq!(decoder.decode(b"b"), Err(ParseError::InvalidChunk));
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version() {
+        let mut request = Request::new(Method::Get, "/");
+        assert!(request.keep_alive());
+        request.headers.append("Connection", "Upgrade, close");
+        assert!(!request.keep_alive());
+
+        let mut request = Request::new(Method::Get, "/");
+        request.version = Version::Http10;
+        assert!(!request.keep_alive());
+        request.headers.append("connection", "Keep-Alive");
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn reads_pipelined_requests_with_bodies() {
+        let input = b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+                      POST /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n0\r\n\r\n\
+                      GET /c HTTP/1.1\r\n\r\n";
+        let mut stream = Cursor::new(&input[..]);
+        let mut buf = Vec::new();
+        let limits = Limits::default();
+        block_on(async {
+            let a = read_request(&mut stream, &mut buf, &limits).await.unwrap().unwrap();
+            assert_eq!((a.path.as_str(), &a.body[..]), ("/a", &b"abc"[..]));
+            let b = read_request(&mut stream, &mut buf, &limits).await.unwrap().unwrap();
+            assert_eq!((b.path.as_str(), &b.body[..]), ("/b", &b"de"[..]));
+            let c = read_request(&mut stream, &mut buf, &limits).await.unwrap().unwrap();
+            assert_eq!((c.method, c.path.as_str()), (Method::Get, "/c"));
+            assert!(read_request(&mut stream, &mut buf, &limits).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn truncated_request_is_an_error() {
+        let mut stream = Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc"[..]);
+        let result = block_on(read_request(&mut stream, &mut Vec::new(), &Limits::default()));
+        assert!(matches!(result, Err(ReadError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn body_limit() {
+        let limits = Limits {
+            max_body_bytes: 2,
+            ..Limits::default()
+        };
+        let mut stream = Cursor::new(&b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc"[..]);
+        let result = block_on(read_request(&mut stream, &mut Vec::new(), &limits));
+        assert!(matches!(result, Err(ReadError::Parse(ParseError::BodyTooLarge))));
+    }
+}
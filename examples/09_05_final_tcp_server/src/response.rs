@@ -0,0 +1,450 @@
+//! HTTP/1.1 响应的构造与序列化。
+
+use std::fmt;
+
+use futures::{
+    io::{self as io, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::{BoxStream, Stream, StreamExt},
+};
+
+use crate::{headers::Headers, request::Version, upgrade::OnUpgrade};
+
+/// 响应状态码。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StatusCode(u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const PARTIAL_CONTENT: StatusCode = StatusCode(206);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const REQUEST_TIMEOUT: StatusCode = StatusCode(408);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const RANGE_NOT_SATISFIABLE: StatusCode = StatusCode(416);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+    pub const HTTP_VERSION_NOT_SUPPORTED: StatusCode = StatusCode(505);
+
+    /// 从数字构造状态码，只接受 100 到 999 之间的值。
+    pub fn from_u16(code: u16) -> Option<StatusCode> {
+        (100..1000).contains(&code).then_some(StatusCode(code))
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// 状态码对应的标准原因短语，不认识的状态码返回空字符串。
+    pub fn canonical_reason(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            206 => "Partial Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            411 => "Length Required",
+            413 => "Content Too Large",
+            415 => "Unsupported Media Type",
+            416 => "Range Not Satisfiable",
+            426 => "Upgrade Required",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    /// 这类响应按规定不能带有响应体。
+    pub(crate) fn forbids_body(&self) -> bool {
+        self.0 < 200 || self.0 == 204 || self.0 == 304
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.0, self.canonical_reason())
+    }
+}
+
+/// 响应体。
+pub enum Body {
+    /// 已经完整放在内存中的数据。
+    Bytes(Vec<u8>),
+    /// 长度已知、在发送时才从 `reader` 中逐块读出的数据，例如一个很大的文件。
+    Reader {
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        len: u64,
+    },
+    /// 长度事先未知、由流逐块产生的数据。HTTP/1.1 中以 `Transfer-Encoding: chunked` 发送；
+    /// HTTP/1.0 客户端不认识 chunked，发给它们时要先调用 [`Response::for_version`]，
+    /// 数据原样写出，以关闭连接表示结束；HTTP/2 中每一块作为 `DATA` 帧发送。
+    Stream(BoxStream<'static, Vec<u8>>),
+}
+
+impl Body {
+    /// 从 `reader` 中读出 `len` 个字节作为响应体。
+    pub fn from_reader(reader: impl AsyncRead + Send + Unpin + 'static, len: u64) -> Body {
+        Body::Reader {
+            reader: Box::new(reader),
+            len,
+        }
+    }
+
+    /// 把 `stream` 产生的每一块数据依次发送出去。
+    pub fn from_stream(stream: impl Stream<Item = Vec<u8>> + Send + 'static) -> Body {
+        Body::Stream(stream.boxed())
+    }
+
+    /// 响应体的长度，流式的响应体返回 `None`。
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { len, .. } => Some(*len),
+            Body::Stream(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// 如果响应体已经在内存中，返回它的内容。
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Reader { .. } | Body::Stream(_) => None,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Body {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Body {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Reader { len, .. } => f.debug_struct("Reader").field("len", len).finish(),
+            Body::Stream(_) => f.write_str("Stream"),
+        }
+    }
+}
+
+/// 一个 HTTP 响应。
+///
+/// `Content-Length` 由响应体自动计算，不需要手动设置：
+///
+/// ```
+/// use final_tcp_server::response::{Response, StatusCode};
+///
+/// let response = Response::new(StatusCode::OK)
+///     .with_header("Content-Type", "text/plain; charset=utf-8")
+///     .with_body("hello")
+///     .with_close();
+/// let mut out = Vec::new();
+/// futures::executor::block_on(response.write_to(&mut out)).unwrap();
+/// assert_eq!(
+///     out,
+///     b"HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+///       Connection: close\r\nContent-Length: 5\r\n\r\nhello"
+/// );
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    status: StatusCode,
+    headers: Headers,
+    body: Body,
+    upgrade: Option<OnUpgrade>,
+    /// 流式的响应体是否使用 chunked 编码，见 [`for_version`](Response::for_version)。
+    chunked: bool,
+}
+
+impl Response {
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Bytes(Vec::new()),
+            upgrade: None,
+            chunked: true,
+        }
+    }
+
+    /// 一个 `text/html` 响应。
+    pub fn html(status: StatusCode, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/html; charset=utf-8")
+            .with_body(body)
+    }
+
+    /// 一个 `text/plain` 响应。
+    pub fn text(status: StatusCode, body: impl Into<Body>) -> Response {
+        Response::new(status)
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+            .with_body(body)
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Response {
+        self.status = status;
+        self
+    }
+
+    /// 设置一个头部，替换掉已有的同名头部。
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Response {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Body>) -> Response {
+        self.body = body.into();
+        self
+    }
+
+    /// 发送完这个响应后关闭连接，并通过 `Connection: close` 告知对方。
+    pub fn with_close(self) -> Response {
+        self.with_header("Connection", "close")
+    }
+
+    /// 让响应适合回复 `version` 的请求。
+    ///
+    /// HTTP/1.0 没有 chunked 编码，流式的响应体只能原样写出，写完之后关闭连接，
+    /// 客户端读到连接关闭就知道响应体结束了。
+    pub fn for_version(mut self, version: Version) -> Response {
+        if version == Version::Http10 && self.is_streaming() {
+            self.chunked = false;
+            return self.with_close();
+        }
+        self
+    }
+
+    /// 发送完这个 `101 Switching Protocols` 响应之后，把连接交给 `on_upgrade`。
+    pub fn with_upgrade(mut self, on_upgrade: OnUpgrade) -> Response {
+        self.upgrade = Some(on_upgrade);
+        self
+    }
+
+    /// 取出响应上附带的升级回调。
+    pub fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade.take()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Body {
+        self.body
+    }
+
+    /// 发送完这个响应后是否应当关闭连接。
+    pub fn is_close(&self) -> bool {
+        self.headers.has_token("Connection", "close")
+    }
+
+    /// 响应体是否是流式的，需要用 chunked 编码持续写出。
+    pub fn is_streaming(&self) -> bool {
+        matches!(self.body, Body::Stream(_)) && !self.status.forbids_body()
+    }
+
+    /// 序列化状态行和头部，`Content-Length` 总是根据当前的响应体重新计算，
+    /// 流式的响应体则改用 `Transfer-Encoding: chunked`（回复 HTTP/1.0 请求时两者都没有）。
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.headers.iter() {
+            if !name.eq_ignore_ascii_case("content-length")
+                && !name.eq_ignore_ascii_case("transfer-encoding")
+            {
+                head.push_str(&format!("{name}: {value}\r\n"));
+            }
+        }
+        if !self.status.forbids_body() {
+            match self.body.len() {
+                Some(len) => head.push_str(&format!("Content-Length: {len}\r\n")),
+                None if self.chunked => head.push_str("Transfer-Encoding: chunked\r\n"),
+                None => {}
+            }
+        }
+        head.push_str("\r\n");
+        head.into_bytes()
+    }
+
+    /// 把整个响应写入 `stream` 并刷新。
+    ///
+    /// 单次 `write` 可能只写出一部分数据，因此这里使用 `write_all`。
+    pub async fn write_to(self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        let mut head = self.head_bytes();
+        match self.body {
+            _ if self.status.forbids_body() => stream.write_all(&head).await?,
+            // 小的响应体和头部一起写出，省掉一次写操作。
+            Body::Bytes(bytes) => {
+                head.extend_from_slice(&bytes);
+                stream.write_all(&head).await?;
+            }
+            Body::Reader { reader, len } => {
+                stream.write_all(&head).await?;
+                let copied = io::copy(reader.take(len), stream).await?;
+                if copied < len {
+                    // 已经发出的 `Content-Length` 无法收回，只能让调用者关闭连接。
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response body ended before Content-Length bytes were written",
+                    ));
+                }
+            }
+            Body::Stream(mut chunks) => {
+                stream.write_all(&head).await?;
+                // 每一块写完并刷新之后才去取下一块，套接字写不动时就不再从流中取数据，
+                // 背压由此传回给产生数据的一方。
+                while let Some(chunk) = chunks.next().await {
+                    // 长度为 0 的块表示响应体结束，不能用来发送空数据。
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    if !self.chunked {
+                        stream.write_all(&chunk).await?;
+                        stream.flush().await?;
+                        continue;
+                    }
+                    let mut frame = format!("{:X}\r\n", chunk.len()).into_bytes();
+                    frame.extend_from_slice(&chunk);
+                    frame.extend_from_slice(b"\r\n");
+                    stream.write_all(&frame).await?;
+                    stream.flush().await?;
+                }
+                if self.chunked {
+                    stream.write_all(b"0\r\n\r\n").await?;
+                }
+            }
+        }
+        stream.flush().await
+    }
+
+    /// 只写出状态行和头部，用于回应 `HEAD` 请求。
+    pub async fn write_head_to(self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        stream.write_all(&self.head_bytes()).await?;
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+    use mock_stream::MockStream;
+
+    /// 每次最多只接受 3 个字节的写入端。
+    fn short_writer() -> MockStream {
+        MockStream::builder().max_write(3).build()
+    }
+
+    #[test]
+    fn writes_everything_despite_short_writes() {
+        let response = Response::html(StatusCode::NOT_FOUND, "<h1>Oops!</h1>");
+        let mut writer = short_writer();
+        block_on(response.write_to(&mut writer)).unwrap();
+        assert_eq!(
+            writer.written(),
+            b"HTTP/1.1 404 Not Found\r\nContent-Type: text/html; charset=utf-8\r\n\
+              Content-Length: 14\r\n\r\n<h1>Oops!</h1>"
+        );
+    }
+
+    fn to_bytes(response: Response) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(response.write_to(&mut out)).unwrap();
+        out
+    }
+
+    #[test]
+    fn content_length_follows_the_body() {
+        let response = Response::new(StatusCode::OK)
+            .with_header("content-length", "999")
+            .with_body(vec![0; 3]);
+        assert!(!response.is_close());
+        let response = response.with_close();
+        assert!(response.is_close());
+        assert!(to_bytes(response).ends_with(b"\r\nContent-Length: 3\r\n\r\n\0\0\0"));
+    }
+
+    #[test]
+    fn no_body_for_204_and_304() {
+        let response = Response::new(StatusCode::NOT_MODIFIED).with_body("ignored");
+        assert_eq!(to_bytes(response), b"HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn head_only() {
+        let response = Response::text(StatusCode::OK, "abc");
+        let mut writer = short_writer();
+        block_on(response.write_head_to(&mut writer)).unwrap();
+        assert!(writer.written().ends_with(b"Content-Length: 3\r\n\r\n"));
+    }
+
+    #[test]
+    fn streams_reader_bodies() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let body = Body::from_reader(Cursor::new(data.clone()), 60_000);
+        let mut writer = short_writer();
+        block_on(Response::new(StatusCode::OK).with_body(body).write_to(&mut writer)).unwrap();
+        assert!(writer.written().starts_with(b"HTTP/1.1 200 OK\r\nContent-Length: 60000\r\n\r\n"));
+        assert!(writer.written().ends_with(&data[..60_000]));
+
+        // 数据比声明的长度短。
+        let body = Body::from_reader(Cursor::new(vec![1, 2, 3]), 10);
+        let err = block_on(Response::new(StatusCode::OK).with_body(body).write_to(&mut Vec::new()));
+        assert_eq!(err.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,318 @@
+//! 按照请求方法和路径把请求分发给不同的处理函数。
+
+use std::future::Future;
+
+use futures::future::{BoxFuture, FutureExt};
+
+use crate::{
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::{Response, StatusCode},
+};
+
+type Handler = Box<dyn Fn(Request) -> BoxFuture<'static, Response> + Send + Sync>;
+
+/// 路由表。
+///
+/// 路径模式按 `/` 分段，每一段可以是：
+///
+/// - 普通文本，必须与请求路径中对应的段完全相同；
+/// - `:name`，匹配任意一个非空的段，并以 `name` 为名保存下来；
+/// - `*` 或 `*name`，只能出现在最后，匹配剩下的所有段（可以为空）。
+///
+/// 捕获到的参数可以通过 [`Request::param`] 取得。路由按照注册的顺序依次尝试，
+/// 第一个匹配的路由生效。
+///
+/// ```
+/// use final_tcp_server::{
+///     request::{Method, Request},
+///     response::{Response, StatusCode},
+///     router::Router,
+/// };
+///
+/// let router = Router::new().get("/users/:id", |request: Request| async move {
+///     let id = request.param("id").unwrap().to_owned();
+///     Response::text(StatusCode::OK, id)
+/// });
+/// let response = futures::executor::block_on(router.handle(Request::new(Method::Get, "/users/42")));
+/// assert_eq!(response.body().as_bytes(), Some(&b"42"[..]));
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Handler>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(Option<String>),
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: None,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// 注册一个路由。
+    ///
+    /// # Panics
+    ///
+    /// 如果 `pattern` 不以 `/` 开头，或者通配符不在最后一段，就会恐慌。
+    pub fn route<H, F>(mut self, method: Method, pattern: &str, handler: H) -> Router
+    where
+        H: Fn(Request) -> F + Send + Sync + 'static,
+        F: Future<Output = Response> + Send + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: parse_pattern(pattern),
+            handler: Box::new(move |request| handler(request).boxed()),
+        });
+        self
+    }
+
+    /// 注册一个 `GET` 路由，它同时也会响应 `HEAD` 请求。
+    pub fn get<H, F>(self, pattern: &str, handler: H) -> Router
+    where
+        H: Fn(Request) -> F + Send + Sync + 'static,
+        F: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    /// 注册一个 `POST` 路由。
+    pub fn post<H, F>(self, pattern: &str, handler: H) -> Router
+    where
+        H: Fn(Request) -> F + Send + Sync + 'static,
+        F: Future<Output = Response> + Send + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// 设置没有任何路由匹配时使用的处理函数，默认返回一个简单的 404 响应。
+    pub fn fallback<H, F>(mut self, handler: H) -> Router
+    where
+        H: Fn(Request) -> F + Send + Sync + 'static,
+        F: Future<Output = Response> + Send + 'static,
+    {
+        self.fallback = Some(Box::new(move |request| handler(request).boxed()));
+        self
+    }
+
+    /// 添加一个中间件，它会包裹所有的路由（包括 [`fallback`](Router::fallback)）。
+    /// 先添加的中间件在外层，最先看到请求、最后看到响应。
+    pub fn layer(mut self, middleware: impl Middleware) -> Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// 让请求依次经过各个中间件，再交给匹配的处理函数。
+    pub async fn handle(&self, request: Request) -> Response {
+        let dispatch = |request| self.dispatch(request).boxed();
+        Next::new(&self.middleware, &dispatch).run(request).await
+    }
+
+    /// 把请求交给匹配的处理函数。
+    ///
+    /// 如果路径匹配、但没有对应请求方法的路由，返回 `405 Method Not Allowed`，
+    /// 并在 `Allow` 头部中列出允许的方法。
+    async fn dispatch(&self, mut request: Request) -> Response {
+        let mut allowed = Vec::new();
+        let mut found = None;
+        for route in &self.routes {
+            let Some(params) = match_path(&route.pattern, &request.path) else {
+                continue;
+            };
+            let method_matches = route.method == request.method
+                || (route.method == Method::Get && request.method == Method::Head);
+            if method_matches {
+                found = Some((route, params));
+                break;
+            }
+            allowed.push(route.method);
+            if route.method == Method::Get {
+                allowed.push(Method::Head);
+            }
+        }
+
+        if let Some((route, params)) = found {
+            request.params = params;
+            return (route.handler)(request).await;
+        }
+        if !allowed.is_empty() {
+            allowed.sort_by_key(Method::as_str);
+            allowed.dedup();
+            let allow: Vec<_> = allowed.iter().map(Method::as_str).collect();
+            return Response::text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n")
+                .with_header("Allow", allow.join(", "));
+        }
+        match &self.fallback {
+            Some(fallback) => fallback(request).await,
+            None => Response::text(StatusCode::NOT_FOUND, "not found\n"),
+        }
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let rest = pattern
+        .strip_prefix('/')
+        .unwrap_or_else(|| panic!("route pattern {pattern:?} must start with '/'"));
+    let segments: Vec<_> = rest
+        .split('/')
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_owned())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Wildcard((!name.is_empty()).then(|| name.to_owned()))
+            } else {
+                Segment::Literal(segment.to_owned())
+            }
+        })
+        .collect();
+    let misplaced = segments[..segments.len() - 1]
+        .iter()
+        .any(|segment| matches!(segment, Segment::Wildcard(_)));
+    assert!(
+        !misplaced,
+        "wildcard in route pattern {pattern:?} must be the last segment"
+    );
+    segments
+}
+
+/// 用 `pattern` 匹配 `path`，成功时返回捕获到的参数。
+fn match_path(pattern: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = path.strip_prefix('/')?;
+    let mut params = Vec::new();
+    for (i, segment) in pattern.iter().enumerate() {
+        if let Segment::Wildcard(name) = segment {
+            if let Some(name) = name {
+                params.push((name.clone(), rest.to_owned()));
+            }
+            return Some(params);
+        }
+        let (part, remaining) = match rest.split_once('/') {
+            Some((part, remaining)) => (part, Some(remaining)),
+            None => (rest, None),
+        };
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Param(name) if !part.is_empty() => params.push((name.clone(), part.to_owned())),
+            _ => return None,
+        }
+        match remaining {
+            Some(remaining) => rest = remaining,
+            // 路径已经用完，模式必须也恰好结束（或者只剩一个通配符）。
+            None => {
+                return match &pattern[i + 1..] {
+                    [] => Some(params),
+                    [Segment::Wildcard(name)] => {
+                        if let Some(name) = name {
+                            params.push((name.clone(), String::new()));
+                        }
+                        Some(params)
+                    }
+                    _ => None,
+                };
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn matches(pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        match_path(&parse_pattern(pattern), path)
+    }
+
+    fn param(name: &str, value: &str) -> (String, String) {
+        (name.to_owned(), value.to_owned())
+    }
+
+    #[test]
+    fn literal_and_param_segments() {
+        assert_eq!(matches("/", "/"), Some(vec![]));
+        assert_eq!(matches("/", "/a"), None);
+        assert_eq!(matches("/sleep", "/sleep"), Some(vec![]));
+        assert_eq!(matches("/sleep", "/sleep/"), None);
+        assert_eq!(
+            matches("/users/:id/posts/:post", "/users/7/posts/hello"),
+            Some(vec![param("id", "7"), param("post", "hello")])
+        );
+        assert_eq!(matches("/users/:id", "/users/"), None);
+        assert_eq!(matches("/users/:id", "/users/7/extra"), None);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert_eq!(
+            matches("/static/*path", "/static/css/site.css"),
+            Some(vec![param("path", "css/site.css")])
+        );
+        assert_eq!(matches("/static/*path", "/static"), Some(vec![param("path", "")]));
+        assert_eq!(matches("/*", "/anything/at/all"), Some(vec![]));
+        assert_eq!(matches("/static/*", "/other"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be the last segment")]
+    fn wildcard_must_be_last() {
+        parse_pattern("/*/a");
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_| async { Response::text(StatusCode::OK, "index") })
+            .post("/items/:id", |request: Request| async move {
+                let id = request.param("id").unwrap().to_owned();
+                Response::text(StatusCode::OK, format!("posted {id}"))
+            })
+    }
+
+    #[test]
+    fn dispatches_by_method_and_path() {
+        let router = router();
+        let response = block_on(router.handle(Request::new(Method::Get, "/")));
+        assert_eq!(response.body().as_bytes(), Some(&b"index"[..]));
+        let response = block_on(router.handle(Request::new(Method::Head, "/")));
+        assert_eq!(response.body().as_bytes(), Some(&b"index"[..]));
+        let response = block_on(router.handle(Request::new(Method::Post, "/items/3")));
+        assert_eq!(response.body().as_bytes(), Some(&b"posted 3"[..]));
+    }
+
+    #[test]
+    fn not_found_and_method_not_allowed() {
+        let router = router();
+        let response = block_on(router.handle(Request::new(Method::Get, "/missing")));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = block_on(router.handle(Request::new(Method::Delete, "/")));
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers().get("Allow"), Some("GET, HEAD"));
+
+        let router = router.fallback(|_| async { Response::text(StatusCode::NOT_FOUND, "custom") });
+        let response = block_on(router.handle(Request::new(Method::Get, "/missing")));
+        assert_eq!(response.body().as_bytes(), Some(&b"custom"[..]));
+    }
+}
@@ -0,0 +1,230 @@
+//! 异步信号量。
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// 一个异步信号量，用来限制同时进行的某种操作的数量。
+///
+/// 与 [`std::sync::Mutex`] 之类的阻塞原语不同，许可不足时 [`acquire`](Semaphore::acquire)
+/// 返回的期物会让出执行权，等到有许可被归还时再被唤醒。
+#[derive(Clone)]
+pub struct Semaphore {
+    inner: Arc<Mutex<State>>,
+}
+
+struct State {
+    permits: usize,
+    /// 正在等待许可的期物，按照开始等待的顺序排列。
+    waiters: VecDeque<(u64, Waker)>,
+    next_id: u64,
+}
+
+impl State {
+    /// 有空闲许可时，取出排在最前面的等待者的唤醒器。
+    ///
+    /// 调用者应当在释放锁之后再唤醒它，以免被唤醒的任务立刻在另一个线程上争抢这把锁。
+    fn next_waiter(&mut self) -> Option<Waker> {
+        if self.permits == 0 {
+            return None;
+        }
+        self.waiters.pop_front().map(|(_, waker)| waker)
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            inner: Arc::new(Mutex::new(State {
+                permits,
+                waiters: VecDeque::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// 等待并取得一个许可。
+    pub fn acquire(&self) -> Acquire {
+        Acquire {
+            semaphore: self.clone(),
+            id: None,
+        }
+    }
+
+    /// 如果现在就有空闲的许可，取得它；否则立即返回 `None`。
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let mut state = self.inner.lock().unwrap();
+        if state.permits == 0 {
+            return None;
+        }
+        state.permits -= 1;
+        Some(Permit {
+            semaphore: self.clone(),
+        })
+    }
+
+    /// 当前空闲的许可数。
+    pub fn available_permits(&self) -> usize {
+        self.inner.lock().unwrap().permits
+    }
+}
+
+/// [`Semaphore::acquire`] 返回的期物。
+pub struct Acquire {
+    semaphore: Semaphore,
+    /// 已经登记在等待队列中时，队列里对应的编号。
+    id: Option<u64>,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let inner = self.semaphore.inner.clone();
+        let mut state = inner.lock().unwrap();
+        if state.permits > 0 {
+            state.permits -= 1;
+            if let Some(id) = self.id.take() {
+                state.waiters.retain(|(waiter, _)| *waiter != id);
+            }
+            return Poll::Ready(Permit {
+                semaphore: self.semaphore.clone(),
+            });
+        }
+
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                self.id = Some(id);
+                id
+            }
+        };
+        match state.waiters.iter_mut().find(|(waiter, _)| *waiter == id) {
+            Some((_, waker)) => waker.clone_from(cx.waker()),
+            None => state.waiters.push_back((id, cx.waker().clone())),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let mut state = self.semaphore.inner.lock().unwrap();
+        let len = state.waiters.len();
+        state.waiters.retain(|(waiter, _)| *waiter != id);
+        // 已经被唤醒、却没来得及取走许可就被丢弃了，把这次唤醒转交给下一个等待者。
+        let next = (state.waiters.len() == len)
+            .then(|| state.next_waiter())
+            .flatten();
+        drop(state);
+        if let Some(waker) = next {
+            waker.wake();
+        }
+    }
+}
+
+/// 一个许可，被丢弃时自动归还给信号量。
+pub struct Permit {
+    semaphore: Semaphore,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.semaphore.inner.lock().unwrap();
+        state.permits += 1;
+        let next = state.next_waiter();
+        drop(state);
+        if let Some(waker) = next {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        future,
+        task::{noop_waker_ref, waker, ArcWake},
+        FutureExt,
+    };
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// 记录自己是否被唤醒过的唤醒器。
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl ArcWake for Flag {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn limits_concurrent_holders() {
+        let semaphore = Semaphore::new(2);
+        let a = semaphore.try_acquire().unwrap();
+        let _b = block_on(semaphore.acquire());
+        assert!(semaphore.try_acquire().is_none());
+
+        let mut waiting = semaphore.acquire();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(waiting.poll_unpin(&mut cx).is_pending());
+        drop(a);
+        let Poll::Ready(_c) = waiting.poll_unpin(&mut cx) else {
+            panic!("a permit should be available");
+        };
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn released_permits_wake_waiters_in_order() {
+        let semaphore = Semaphore::new(1);
+        let held = semaphore.try_acquire().unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let waiters = (0..3).map(|i| {
+            let (semaphore, order) = (semaphore.clone(), order.clone());
+            async move {
+                let _permit = semaphore.acquire().await;
+                order.lock().unwrap().push(i);
+            }
+        });
+        let all = future::join_all(waiters);
+        // `join` 先轮询所有等待者，让它们都登记之后才会归还许可。
+        let release = async move { drop(held) };
+        block_on(future::join(all, release));
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2]);
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn dropped_waiter_passes_the_wakeup_on() {
+        let semaphore = Semaphore::new(1);
+        let held = semaphore.try_acquire().unwrap();
+        let (first_flag, second_flag) = (Arc::new(Flag::default()), Arc::new(Flag::default()));
+        let (first_waker, second_waker) = (waker(first_flag.clone()), waker(second_flag.clone()));
+        let mut first = semaphore.acquire();
+        let mut second = semaphore.acquire();
+        assert!(first.poll_unpin(&mut Context::from_waker(&first_waker)).is_pending());
+        assert!(second.poll_unpin(&mut Context::from_waker(&second_waker)).is_pending());
+
+        // 许可归还时唤醒了 `first`，但它在被轮询之前就被丢弃了。
+        drop(held);
+        assert!(first_flag.0.load(Ordering::SeqCst));
+        assert!(!second_flag.0.load(Ordering::SeqCst));
+        drop(first);
+        assert!(second_flag.0.load(Ordering::SeqCst));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(second.poll_unpin(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,122 @@
+//! 优雅关闭。
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, Either, FutureExt, Shared},
+};
+
+/// 一个可以被多处等待的一次性关闭信号。
+///
+/// 克隆出来的 `Shutdown` 共享同一个信号，任何一个副本调用 [`trigger`](Shutdown::trigger)
+/// 之后，所有副本上的 [`wait`](Shutdown::wait) 都会完成。
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    receiver: Shared<oneshot::Receiver<()>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown::new()
+    }
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("triggered", &self.is_triggered())
+            .finish()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = oneshot::channel();
+        Shutdown {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver: receiver.shared(),
+        }
+    }
+
+    /// 发出关闭信号，重复调用没有额外效果。
+    pub fn trigger(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.sender.lock().unwrap().is_none()
+    }
+
+    /// 等待关闭信号。
+    pub fn wait(&self) -> impl Future<Output = ()> + Send + Unpin + 'static {
+        // 发送端只会在发出信号时被取走，所以这里不会出现 `Canceled`。
+        self.receiver.clone().map(|_| ())
+    }
+
+    /// 运行 `future`，如果在它完成之前收到了关闭信号，就放弃它并返回 `None`。
+    pub async fn run_until<F: Future>(&self, future: F) -> Option<F::Output> {
+        match future::select(std::pin::pin!(future), self.wait()).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(((), _)) => None,
+        }
+    }
+
+    /// 收到 SIGINT 或 SIGTERM 时发出关闭信号。
+    ///
+    /// 信号由一个单独的线程接收。再次收到信号时不再等待，直接退出进程。
+    #[cfg(unix)]
+    pub fn trigger_on_signals(&self) -> std::io::Result<()> {
+        use signal_hook::{
+            consts::{SIGINT, SIGTERM},
+            iterator::Signals,
+        };
+
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let shutdown = self.clone();
+        std::thread::spawn(move || {
+            let mut signals = signals.forever();
+            if let Some(signal) = signals.next() {
+                eprintln!("收到信号 {signal}，正在关闭服务器……");
+                shutdown.trigger();
+            }
+            if let Some(signal) = signals.next() {
+                eprintln!("再次收到信号 {signal}，立即退出");
+                std::process::exit(128 + signal);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, task::noop_waker_ref};
+    use std::task::Context;
+
+    #[test]
+    fn all_clones_observe_the_trigger() {
+        let shutdown = Shutdown::new();
+        let clone = shutdown.clone();
+        let mut waiting = clone.wait();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(waiting.poll_unpin(&mut cx).is_pending());
+        assert!(!clone.is_triggered());
+
+        shutdown.trigger();
+        shutdown.trigger();
+        assert!(clone.is_triggered());
+        assert!(waiting.poll_unpin(&mut cx).is_ready());
+        block_on(Shutdown::new().run_until(async {}))
+            .expect("futures that finish first are not cancelled");
+        assert_eq!(block_on(clone.run_until(future::pending::<()>())), None);
+    }
+}
@@ -0,0 +1,212 @@
+//! Server-Sent Events：把一个事件流以 `text/event-stream` 格式持续推送给客户端。
+//!
+//! 处理函数通常用 [`mpsc::channel`](futures::channel::mpsc::channel) 产生事件：
+//! 把接收端交给 [`response`]，发送端交给一个后台任务。通道是有界的，
+//! 客户端读得慢时套接字会写不动，服务器不再从通道中取事件，发送端的 `send` 也就随之等待。
+
+use std::time::{Duration, Instant};
+
+use futures::{
+    future::{self, Either},
+    stream::{self, Stream, StreamExt},
+};
+
+use crate::{
+    response::{Body, Response, StatusCode},
+    time::sleep,
+};
+
+/// 默认的心跳间隔。
+///
+/// 长时间没有数据的连接可能被中间的代理当作空闲连接断开，定期发送一个注释行可以避免这种情况。
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// 一个事件。
+///
+/// ```
+/// use final_tcp_server::sse::Event;
+///
+/// let event = Event::data("line one\nline two").with_event("update").with_id("7");
+/// assert_eq!(
+///     event.to_string(),
+///     "event: update\nid: 7\ndata: line one\ndata: line two\n\n"
+/// );
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl Event {
+    /// 只带数据的事件。数据可以有多行，每一行会作为一个 `data` 字段发送。
+    pub fn data(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// 事件的类型，客户端用 `addEventListener(name, ...)` 接收。换行符会被去掉。
+    pub fn with_event(mut self, event: impl Into<String>) -> Event {
+        self.event = Some(single_line(event.into()));
+        self
+    }
+
+    /// 事件的编号，客户端重连时会通过 `Last-Event-ID` 头部带回来。换行符会被去掉。
+    pub fn with_id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(single_line(id.into()));
+        self
+    }
+
+    /// 要求客户端断线之后等待多久再重连。
+    pub fn with_retry(mut self, retry: Duration) -> Event {
+        self.retry = Some(retry);
+        self
+    }
+}
+
+fn single_line(mut value: String) -> String {
+    value.retain(|c| c != '\r' && c != '\n');
+    value
+}
+
+/// 按照 `text/event-stream` 的格式输出，以一个空行结尾。
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {event}")?;
+        }
+        if let Some(id) = &self.id {
+            writeln!(f, "id: {id}")?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        // `lines` 会把 `\r\n` 和 `\n` 都当作换行，单独的 `\r` 也要拆开。
+        for line in self.data.lines().flat_map(|line| line.split('\r')) {
+            writeln!(f, "data: {line}")?;
+        }
+        if self.data.is_empty() {
+            writeln!(f, "data:")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// 用默认的心跳间隔生成事件流响应，参见 [`response_with_heartbeat`]。
+pub fn response(events: impl Stream<Item = Event> + Send + 'static) -> Response {
+    response_with_heartbeat(events, DEFAULT_HEARTBEAT)
+}
+
+/// 生成一个 `text/event-stream` 响应，`events` 结束时响应也随之结束。
+///
+/// 每隔 `heartbeat` 会额外发送一个注释行，客户端会忽略它。
+pub fn response_with_heartbeat(
+    events: impl Stream<Item = Event> + Send + 'static,
+    heartbeat: Duration,
+) -> Response {
+    Response::new(StatusCode::OK)
+        .with_header("Content-Type", "text/event-stream")
+        .with_header("Cache-Control", "no-cache")
+        .with_body(Body::from_stream(with_heartbeat(events, heartbeat)))
+}
+
+/// 把事件编码成数据块，并在其中穿插心跳。
+///
+/// 每个流只用一个计时器：中间发送了事件也不会打断计时，每次心跳之后再把期限往后推。
+fn with_heartbeat(
+    events: impl Stream<Item = Event> + Send + 'static,
+    heartbeat: Duration,
+) -> impl Stream<Item = Vec<u8>> + Send + 'static {
+    let state = (events.boxed(), sleep(heartbeat));
+    stream::unfold(state, move |(mut events, mut timer)| async move {
+        let next = match future::select(events.next(), &mut timer).await {
+            Either::Left((event, _)) => Some(event),
+            Either::Right(((), _)) => None,
+        };
+        match next {
+            Some(Some(event)) => Some((event.to_string().into_bytes(), (events, timer))),
+            Some(None) => None,
+            None => {
+                timer.reset(Instant::now() + heartbeat);
+                Some((b": heartbeat\n\n".to_vec(), (events, timer)))
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{serve, Config},
+        request::Request,
+    };
+    use futures::{channel::mpsc, executor::block_on, SinkExt};
+    use mock_stream::MockStream;
+
+    #[test]
+    fn encodes_all_fields() {
+        let event = Event::data("a\r\nb\rc")
+            .with_event("multi\nline")
+            .with_id("1")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "event: multiline\nid: 1\nretry: 3000\ndata: a\ndata: b\ndata: c\n\n"
+        );
+        assert_eq!(Event::default().to_string(), "data:\n\n");
+    }
+
+    #[test]
+    fn streams_events_as_chunks() {
+        let (mut events, receiver) = mpsc::channel(1);
+        let producer = async move {
+            for i in 0..3 {
+                events.send(Event::data(i.to_string())).await.unwrap();
+            }
+        };
+        let mut receiver = Some(receiver);
+        let handler = move |_: Request| {
+            let events = response(receiver.take().unwrap());
+            async move { events }
+        };
+        let mut stream = MockStream::builder()
+            .read(b"GET /events HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .build();
+        let (result, ()) = block_on(future::join(
+            serve(&mut stream, &Config::default(), handler),
+            producer,
+        ));
+        result.unwrap();
+
+        let output = String::from_utf8(stream.written()).unwrap();
+        let (head, body) = output.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains("\r\nContent-Type: text/event-stream\r\n"));
+        assert!(head.ends_with("\r\nTransfer-Encoding: chunked"));
+        assert!(!head.contains("Content-Length"));
+        assert_eq!(
+            body,
+            "9\r\ndata: 0\n\n\r\n9\r\ndata: 1\n\n\r\n9\r\ndata: 2\n\n\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn sends_heartbeats_while_idle() {
+        let (mut events, receiver) = mpsc::channel(1);
+        let chunks = with_heartbeat(receiver, Duration::from_millis(20));
+        let collect = chunks.collect::<Vec<_>>();
+        let produce = async move {
+            crate::time::sleep(Duration::from_millis(70)).await;
+            events.send(Event::data("late")).await.unwrap();
+        };
+        let (chunks, ()) = block_on(future::join(collect, produce));
+        let (last, heartbeats) = chunks.split_last().unwrap();
+        assert_eq!(last, b"data: late\n\n");
+        assert!(heartbeats.len() >= 2, "{heartbeats:?}");
+        assert!(heartbeats.iter().all(|chunk| chunk == b": heartbeat\n\n"));
+    }
+}
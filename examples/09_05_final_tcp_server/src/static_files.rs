@@ -0,0 +1,500 @@
+//! 提供某个目录下的静态文件。
+
+use std::{
+    fs::Metadata,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use executor::{fs::File, spawn_blocking};
+use futures::{
+    future::{BoxFuture, FutureExt},
+    io::AsyncSeekExt,
+};
+
+use crate::{
+    request::{Method, Request},
+    response::{Body, Response, StatusCode},
+};
+
+/// 以某个目录为根提供静态文件的处理函数。
+///
+/// 请求路径会先做百分号解码，含有 `..` 等可能跳出根目录的路径会被拒绝；
+/// 即使路径本身没有问题，解析符号链接之后落在根目录之外的文件也不会被提供。
+/// 文件内容在发送时逐块读取，不会一次性读进内存。
+///
+/// 支持 `ETag`/`If-None-Match`、`Last-Modified`/`If-Modified-Since` 条件请求，
+/// 以及单个区间的 `Range` 请求。
+#[derive(Debug, Clone)]
+pub struct StaticFiles {
+    root: Arc<PathBuf>,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> StaticFiles {
+        StaticFiles {
+            root: Arc::new(root.into()),
+        }
+    }
+
+    /// 转换成可以注册到 [`Router`](crate::router::Router) 中的处理函数。
+    ///
+    /// 文件的路径取自路由中名为 `path` 的参数，例如 `/static/*path`。
+    pub fn into_handler(self) -> impl Fn(Request) -> BoxFuture<'static, Response> + Send + Sync {
+        move |request| {
+            let files = self.clone();
+            async move {
+                let path = request.param("path").unwrap_or_default().to_owned();
+                files.serve(&request, &path).await
+            }
+            .boxed()
+        }
+    }
+
+    /// 用根目录下的 `path` 文件回应 `request`。
+    pub async fn serve(&self, request: &Request, path: &str) -> Response {
+        if !matches!(request.method, Method::Get | Method::Head) {
+            return Response::text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed\n")
+                .with_header("Allow", "GET, HEAD");
+        }
+        let Some(relative) = sanitize(path) else {
+            return not_found();
+        };
+        let root = self.root.clone();
+        let resolved = spawn_blocking(move || resolve(&root, &relative)).await;
+        let (path, metadata) = match resolved {
+            Ok(Some(resolved)) => resolved,
+            Ok(None) => return not_found(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return not_found(),
+            Err(e) => return internal_error(e),
+        };
+        match serve_file(request, &path, &metadata).await {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => not_found(),
+            Err(e) => internal_error(e),
+        }
+    }
+}
+
+fn not_found() -> Response {
+    Response::text(StatusCode::NOT_FOUND, "not found\n")
+}
+
+fn internal_error(e: io::Error) -> Response {
+    eprintln!("无法读取静态文件：{e}");
+    Response::text(StatusCode::INTERNAL_SERVER_ERROR, "internal server error\n")
+}
+
+/// 解码请求路径，并拒绝任何可能跳出根目录的写法。
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(path)?;
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            s if s.contains(['\\', '\0', ':']) => return None,
+            s => relative.push(s),
+        }
+    }
+    Some(relative)
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// 找到要提供的文件及其元数据：目录会改用其中的 `index.html`，
+/// 解析符号链接后必须仍在根目录下。
+fn resolve(root: &Path, relative: &Path) -> io::Result<Option<(PathBuf, Metadata)>> {
+    let root = root.canonicalize()?;
+    let mut path = root.join(relative).canonicalize()?;
+    let mut metadata = path.metadata()?;
+    if metadata.is_dir() {
+        path = path.join("index.html").canonicalize()?;
+        metadata = path.metadata()?;
+    }
+    Ok((path.starts_with(&root) && metadata.is_file()).then_some((path, metadata)))
+}
+
+async fn serve_file(request: &Request, path: &Path, metadata: &Metadata) -> io::Result<Response> {
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = etag(len, modified);
+
+    let mut response = Response::new(StatusCode::OK)
+        .with_header("Content-Type", mime_type(path))
+        .with_header("Accept-Ranges", "bytes")
+        .with_header("ETag", etag.clone());
+    if let Some(modified) = modified {
+        response = response.with_header("Last-Modified", http_date(modified));
+    }
+
+    if not_modified(request, &etag, modified) {
+        let mut not_modified = Response::new(StatusCode::NOT_MODIFIED);
+        for (name, value) in response.headers().iter() {
+            if !name.eq_ignore_ascii_case("content-type") {
+                not_modified.headers_mut().append(name, value);
+            }
+        }
+        return Ok(not_modified);
+    }
+
+    let range = match request.headers.get("Range") {
+        Some(range) if if_range_matches(request, &etag, modified) => parse_range(range, len),
+        _ => None,
+    };
+    let mut file = File::open(path).await?;
+    match range {
+        None => Ok(response.with_body(Body::from_reader(file, len))),
+        Some(Err(Unsatisfiable)) => Ok(Response::new(StatusCode::RANGE_NOT_SATISFIABLE)
+            .with_header("Content-Range", format!("bytes */{len}"))),
+        Some(Ok((start, end))) => {
+            file.seek(SeekFrom::Start(start)).await?;
+            Ok(response
+                .with_status(StatusCode::PARTIAL_CONTENT)
+                .with_header("Content-Range", format!("bytes {start}-{end}/{len}"))
+                .with_body(Body::from_reader(file, end - start + 1)))
+        }
+    }
+}
+
+/// 由文件长度和修改时间组成的实体标签。
+fn etag(len: u64, modified: Option<SystemTime>) -> String {
+    let modified = modified
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", len, modified.as_nanos())
+}
+
+/// 根据条件请求头部判断客户端缓存的版本是否仍然有效。
+fn not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    // 同时出现时 `If-None-Match` 优先。
+    if let Some(tags) = request.headers.get("If-None-Match") {
+        return tags.trim() == "*"
+            || tags
+                .split(',')
+                .any(|tag| tag.trim().trim_start_matches("W/") == etag);
+    }
+    match (request.headers.get("If-Modified-Since"), modified) {
+        (Some(since), Some(modified)) => match parse_http_date(since) {
+            // `Last-Modified` 只精确到秒。
+            Some(since) => truncate_to_secs(modified) <= since,
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// 没有 `If-Range`，或者 `If-Range` 与当前版本一致时，才按 `Range` 只返回一部分。
+fn if_range_matches(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    match request.headers.get("If-Range") {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => match (parse_http_date(value), modified) {
+            (Some(date), Some(modified)) => truncate_to_secs(modified) == date,
+            _ => false,
+        },
+    }
+}
+
+struct Unsatisfiable;
+
+/// 解析 `Range` 头部，返回闭区间 `(start, end)`。
+///
+/// 只支持单个区间；格式不对或者包含多个区间时返回 `None`，此时按照规范忽略它、
+/// 返回整个文件。
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), Unsatisfiable>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // `bytes=-n` 表示最后 n 个字节。
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(Unsatisfiable));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: u64 = start.parse().ok()?;
+        let end = match end {
+            "" => u64::MAX,
+            end => end.parse().ok()?,
+        };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(Unsatisfiable));
+        }
+        (start, end.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+fn mime_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "mp4" => "video/mp4",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// 按照 IMF-fixdate 格式输出时间，例如 `Sun, 06 Nov 1994 08:49:37 GMT`。
+fn http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// 解析 IMF-fixdate 格式的时间，不支持已经废弃的另外两种格式。
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let rest = value.trim().split_once(", ")?.1;
+    let mut parts = rest.split(' ');
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|&m| m == month)? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut clock = parts.next()?.split(':').map(|n| n.parse::<u64>().ok());
+    let (h, m, s) = (clock.next()??, clock.next()??, clock.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() || h > 23 || m > 59 || s > 60 {
+        return None;
+    }
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + h * 3600 + m * 60 + s))
+}
+
+/// 把 1970-01-01 以来的天数换算成公历日期，算法来自 Howard Hinnant 的
+/// [chrono-Compatible Low-Level Date Algorithms](https://howardhinnant.github.io/date_algorithms.html)。
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// [`civil_from_days`] 的逆运算。
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = i64::from((month + 9) % 12);
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::AsyncReadExt};
+    use std::fs;
+
+    /// 测试用的临时根目录，离开作用域时删除。
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(name: &str) -> TempRoot {
+            let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), name));
+            fs::create_dir_all(path.join("public/docs")).unwrap();
+            fs::write(path.join("public/hello.txt"), "hello, world").unwrap();
+            fs::write(path.join("public/docs/index.html"), "<h1>docs</h1>").unwrap();
+            fs::write(path.join("secret.txt"), "top secret").unwrap();
+            TempRoot(path)
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.0.join("public"))
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(files: &StaticFiles, path: &str, headers: &[(&str, &str)]) -> Response {
+        let mut request = Request::new(Method::Get, path);
+        for (name, value) in headers {
+            request.headers.append(*name, *value);
+        }
+        block_on(files.serve(&request, path))
+    }
+
+    fn body(response: Response) -> String {
+        let mut out = Vec::new();
+        block_on(response.write_to(&mut out)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        text.split_once("\r\n\r\n").unwrap().1.to_owned()
+    }
+
+    #[test]
+    fn serves_files_with_metadata() {
+        let root = TempRoot::new("serves_files_with_metadata");
+        let response = get(&root.files(), "hello.txt", &[]);
+        assert_eq!(response.status(), StatusCode::OK);
+        let headers = response.headers();
+        assert_eq!(headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(headers.get("Accept-Ranges"), Some("bytes"));
+        assert!(headers.get("ETag").unwrap().starts_with("\"c-"));
+        assert!(headers.get("Last-Modified").unwrap().ends_with(" GMT"));
+        assert_eq!(body(response), "hello, world");
+
+        let response = get(&root.files(), "docs/", &[]);
+        assert_eq!(response.headers().get("Content-Type"), Some("text/html; charset=utf-8"));
+        assert_eq!(body(response), "<h1>docs</h1>");
+    }
+
+    #[test]
+    fn rejects_path_traversal() {
+        let root = TempRoot::new("rejects_path_traversal");
+        let files = root.files();
+        for path in ["../secret.txt", "docs/../../secret.txt", "%2e%2e/secret.txt", "missing"] {
+            assert_eq!(get(&files, path, &[]).status(), StatusCode::NOT_FOUND, "{path}");
+        }
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.0.join("secret.txt"), root.0.join("public/link"))
+                .unwrap();
+            assert_eq!(get(&files, "link", &[]).status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let root = TempRoot::new("conditional_requests");
+        let files = root.files();
+        let response = get(&files, "hello.txt", &[]);
+        let etag = response.headers().get("ETag").unwrap().to_owned();
+        let modified = response.headers().get("Last-Modified").unwrap().to_owned();
+
+        let response = get(&files, "hello.txt", &[("If-None-Match", &format!("\"x\", {etag}"))]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get("ETag"), Some(etag.as_str()));
+        let response = get(&files, "hello.txt", &[("If-Modified-Since", &modified)]);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = get(&files, "hello.txt", &[("If-None-Match", "\"other\"")]);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let root = TempRoot::new("byte_ranges");
+        let files = root.files();
+        let response = get(&files, "hello.txt", &[("Range", "bytes=7-")]);
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers().get("Content-Range"), Some("bytes 7-11/12"));
+        assert_eq!(body(response), "world");
+
+        let response = get(&files, "hello.txt", &[("Range", "bytes=-5")]);
+        assert_eq!(body(response), "world");
+        let response = get(&files, "hello.txt", &[("Range", "bytes=0-4")]);
+        assert_eq!(body(response), "hello");
+
+        let response = get(&files, "hello.txt", &[("Range", "bytes=20-")]);
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers().get("Content-Range"), Some("bytes */12"));
+
+        // 多个区间以及 `If-Range` 不匹配时都返回整个文件。
+        let response = get(&files, "hello.txt", &[("Range", "bytes=0-1,3-4")]);
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get(&files, "hello.txt", &[("Range", "bytes=0-1"), ("If-Range", "\"old\"")]);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn streams_large_files() {
+        let root = TempRoot::new("streams_large_files");
+        let contents: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+        fs::write(root.0.join("public/big.bin"), &contents).unwrap();
+        let response = get(&root.files(), "big.bin", &[]);
+        let Body::Reader { mut reader, len } = response.into_body() else {
+            panic!("large files should be streamed");
+        };
+        assert_eq!(len, 300_000);
+        let mut read = Vec::new();
+        block_on(reader.read_to_end(&mut read)).unwrap();
+        assert_eq!(read, contents);
+    }
+
+    #[test]
+    fn http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        let leap = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(http_date(leap), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(parse_http_date(&http_date(leap)), Some(leap));
+    }
+}
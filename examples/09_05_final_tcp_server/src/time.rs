@@ -0,0 +1,276 @@
+//! 计时器和超时。
+//!
+//! 书中的 `TimerFuture` 为每个计时器启动一个线程，睡满整段时间；服务器的每个连接都要
+//! 同时用到好几个计时器，这样的开销承受不起。这里的所有计时器共享同一个线程：
+//! 它按期限把计时器放在一个最小堆里，只睡到最早的那个期限，到期后唤醒对应的任务。
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    error, fmt,
+    future::Future,
+    pin::{pin, Pin},
+    sync::{Condvar, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+use futures::future::{self, Either};
+
+/// 期物没能在规定的时间内完成。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl error::Error for Elapsed {}
+
+/// 运行 `future`，如果它在 `duration` 之内没有完成就放弃它并返回 [`Elapsed`]。
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    match future::select(pin!(future), sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
+
+/// 等待 `duration`。
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// 等到 `deadline`。
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, id: None }
+}
+
+/// [`sleep`] 返回的期物，到期后就绪。
+///
+/// 第一次被轮询时才向计时器线程登记，被丢弃时注销。
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    /// 在计时器线程中登记的编号。
+    id: Option<u64>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// 把期限改为 `deadline`，可以在到期之后重新使用同一个计时器。
+    /// 新的期限在下一次轮询时生效。
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            if let Some(id) = self.id.take() {
+                timers().cancel(id);
+            }
+            return Poll::Ready(());
+        }
+        let deadline = self.deadline;
+        timers().register(&mut self.id, deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            timers().cancel(id);
+        }
+    }
+}
+
+/// 所有计时器共享的状态，由计时器线程负责到期唤醒。
+struct Timers {
+    state: Mutex<State>,
+    /// 有更早的期限登记进来时叫醒计时器线程。
+    condvar: Condvar,
+}
+
+struct State {
+    next_id: u64,
+    timers: HashMap<u64, Timer>,
+    /// 按期限排序的 `(期限, 编号)`。计时器的期限推后时不会立刻更新这里，
+    /// 而是等旧的一项到期时再按新的期限重新排队；已经注销的计时器的项到期时直接跳过。
+    queue: BinaryHeap<Reverse<(Instant, u64)>>,
+}
+
+struct Timer {
+    deadline: Instant,
+    /// 这个计时器在 `queue` 中最早的一项的期限。
+    queued: Instant,
+    waker: Waker,
+}
+
+fn timers() -> &'static Timers {
+    static TIMERS: OnceLock<Timers> = OnceLock::new();
+    TIMERS.get_or_init(|| {
+        thread::Builder::new()
+            .name("timer".into())
+            .spawn(run)
+            .expect("failed to spawn timer thread");
+        Timers {
+            state: Mutex::new(State {
+                next_id: 0,
+                timers: HashMap::new(),
+                queue: BinaryHeap::new(),
+            }),
+            condvar: Condvar::new(),
+        }
+    })
+}
+
+impl Timers {
+    fn register(&self, id: &mut Option<u64>, deadline: Instant, waker: &Waker) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let earliest = state.queue.peek().map(|&Reverse((at, _))| at);
+        match id.and_then(|id| state.timers.get_mut(&id)) {
+            Some(timer) => {
+                timer.deadline = deadline;
+                if !timer.waker.will_wake(waker) {
+                    timer.waker = waker.clone();
+                }
+                if deadline >= timer.queued {
+                    // 队列中已经有一项会更早到期，到时候再重新排队。
+                    return;
+                }
+                timer.queued = deadline;
+                state.queue.push(Reverse((deadline, id.unwrap())));
+            }
+            None => {
+                let new_id = state.next_id;
+                state.next_id += 1;
+                *id = Some(new_id);
+                state.timers.insert(
+                    new_id,
+                    Timer {
+                        deadline,
+                        queued: deadline,
+                        waker: waker.clone(),
+                    },
+                );
+                state.queue.push(Reverse((deadline, new_id)));
+            }
+        }
+        if earliest.is_none_or(|earliest| deadline < earliest) {
+            self.condvar.notify_one();
+        }
+    }
+
+    fn cancel(&self, id: u64) {
+        self.state.lock().unwrap().timers.remove(&id);
+    }
+}
+
+/// 计时器线程的主循环。
+fn run() {
+    // 本线程在 `timers()` 的初始化过程中启动，`timers()` 会阻塞到初始化完成为止。
+    let timers = timers();
+    let mut state = timers.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        while let Some(&Reverse((at, id))) = state.queue.peek() {
+            if at > now {
+                break;
+            }
+            state.queue.pop();
+            let Some(timer) = state.timers.get_mut(&id) else {
+                continue;
+            };
+            if timer.queued != at {
+                // 这一项已经被更早的一项取代了。
+                continue;
+            }
+            if timer.deadline > now {
+                // 期限推后了，按新的期限重新排队。
+                timer.queued = timer.deadline;
+                let deadline = timer.deadline;
+                state.queue.push(Reverse((deadline, id)));
+                continue;
+            }
+            expired.extend(state.timers.remove(&id).map(|timer| timer.waker));
+        }
+
+        if !expired.is_empty() {
+            drop(state);
+            for waker in expired {
+                waker.wake();
+            }
+            state = timers.state.lock().unwrap();
+            continue;
+        }
+
+        state = match state.queue.peek() {
+            Some(&Reverse((at, _))) => {
+                timers
+                    .condvar
+                    .wait_timeout(state, at.saturating_duration_since(now))
+                    .unwrap()
+                    .0
+            }
+            None => timers.condvar.wait(state).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future::join_all, task::noop_waker_ref};
+
+    #[test]
+    fn concurrent_sleeps_never_wake_early() {
+        let start = Instant::now();
+        let sleeps = [30, 10, 20].map(|ms| async move {
+            let duration = Duration::from_millis(ms);
+            sleep(duration).await;
+            (duration, start.elapsed())
+        });
+        // 机器繁忙时几个计时器可能同时到期，所以只检查没有提前醒来，不检查先后顺序。
+        for (duration, elapsed) in block_on(join_all(sleeps)) {
+            assert!(elapsed >= duration, "{elapsed:?} < {duration:?}");
+        }
+    }
+
+    #[test]
+    fn reset_moves_the_deadline_both_ways() {
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        let start = Instant::now();
+        let mut timer = sleep(Duration::from_secs(60));
+        assert!(Pin::new(&mut timer).poll(cx).is_pending());
+
+        // 提前：计时器线程要按新的期限醒来。
+        timer.reset(start + Duration::from_millis(20));
+        block_on(&mut timer);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // 到期之后还可以推后再用。
+        timer.reset(Instant::now() + Duration::from_millis(20));
+        assert!(Pin::new(&mut timer).poll(cx).is_pending());
+        block_on(&mut timer);
+    }
+
+    #[test]
+    fn timeout_gives_up_on_slow_futures() {
+        let slow = block_on(timeout(Duration::from_millis(10), future::pending::<()>()));
+        assert_eq!(slow, Err(Elapsed));
+        let fast = block_on(timeout(Duration::from_secs(60), async { 42 }));
+        assert_eq!(fast, Ok(42));
+    }
+}
@@ -0,0 +1,168 @@
+//! 基于 rustls 的 TLS，让服务器可以提供 HTTPS。
+
+use std::{fs, io, path::Path, sync::Arc};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use futures_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig,
+};
+
+pub use futures_rustls::server::TlsStream;
+
+/// 在任意 `AsyncRead + AsyncWrite + Unpin` 的流上完成 TLS 握手。
+///
+/// 握手之后得到的 [`TlsStream`] 同样实现了这两个特征，可以直接交给
+/// [`serve`](crate::connection::serve)。
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    inner: futures_rustls::TlsAcceptor,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: Arc<ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor {
+            inner: config.into(),
+        }
+    }
+
+    /// 用 PEM 格式的证书链和私钥创建，`alpn` 是按优先级排列的、服务器支持的应用层协议，
+    /// 例如 `b"http/1.1"`。
+    pub fn from_pem(cert_chain: &[u8], key: &[u8], alpn: &[&[u8]]) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_slice_iter(cert_chain)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_data)?;
+        if certs.is_empty() {
+            return Err(invalid_data("no certificate found in PEM data"));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(invalid_data)?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(invalid_data)?;
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        Ok(TlsAcceptor::new(Arc::new(config)))
+    }
+
+    /// 从 PEM 文件中读取证书链和私钥，参见 [`from_pem`](TlsAcceptor::from_pem)。
+    ///
+    /// 这里使用阻塞的文件读取，应当在服务器启动时调用。
+    pub fn from_pem_files(
+        cert_chain: impl AsRef<Path>,
+        key: impl AsRef<Path>,
+        alpn: &[&[u8]],
+    ) -> io::Result<TlsAcceptor> {
+        TlsAcceptor::from_pem(&fs::read(cert_chain)?, &fs::read(key)?, alpn)
+    }
+
+    /// 作为服务器一方完成握手。
+    pub async fn accept<S>(&self, stream: S) -> io::Result<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.accept(stream).await
+    }
+}
+
+/// 握手时与客户端协商出的应用层协议。
+pub fn alpn_protocol<S>(stream: &TlsStream<S>) -> Option<&[u8]> {
+    stream.get_ref().1.alpn_protocol()
+}
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        connection::{serve, Config},
+        request::Request,
+        response::{Response, StatusCode},
+    };
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures_rustls::{
+        pki_types::ServerName,
+        rustls::{ClientConfig, RootCertStore},
+        TlsConnector,
+    };
+
+    /// 为 `localhost` 生成一张自签名证书，返回 PEM 格式的证书和私钥。
+    fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        (cert.cert.pem(), cert.key_pair.serialize_pem())
+    }
+
+    fn connector(cert_pem: &str, alpn: &[&[u8]]) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(cert_pem.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[async_std::test]
+    async fn serves_https_and_negotiates_alpn() {
+        let (cert, key) = self_signed();
+        let acceptor = TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes(), &[b"http/1.1"]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            assert_eq!(alpn_protocol(&stream), Some(&b"http/1.1"[..]));
+            let handler = |request: Request| async move {
+                Response::text(StatusCode::OK, format!("secure {}", request.path))
+            };
+            serve(stream, &Config::default(), handler).await.unwrap();
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector(&cert, &[b"h2", b"http/1.1"])
+            .connect(name, stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"http/1.1"[..]));
+        stream
+            .write_all(b"GET /hello HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nsecure /hello"));
+        server.await;
+    }
+
+    #[test]
+    fn loads_pem_files() {
+        let (cert, key) = self_signed();
+        let dir = std::env::temp_dir().join(format!("tls-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, &cert).unwrap();
+        fs::write(&key_path, &key).unwrap();
+
+        let loaded = TlsAcceptor::from_pem_files(&cert_path, &key_path, &[]);
+        let swapped = TlsAcceptor::from_pem_files(&key_path, &cert_path, &[]);
+        fs::remove_dir_all(&dir).unwrap();
+        loaded.unwrap();
+        assert_eq!(swapped.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,109 @@
+//! 协议升级：发出 `101 Switching Protocols` 响应之后，把连接交给另一个协议继续使用。
+//!
+//! 处理函数在响应上附带一个 [`OnUpgrade`]，[`serve`](crate::connection::serve)
+//! 写完响应后不再把连接当作 HTTP 处理，而是把它包装成 [`Upgraded`] 交给这个回调，
+//! 回调结束时连接随之关闭。
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    future::BoxFuture,
+    io::{AsyncRead, AsyncWrite},
+};
+
+/// 升级时对连接的要求。
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// 升级之后的连接。
+///
+/// 读取请求时可能已经多读了一些属于新协议的数据，这些数据会先于连接上的数据被读出。
+pub struct Upgraded<'a> {
+    io: Box<dyn Io + 'a>,
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> Upgraded<'a> {
+    pub fn new(io: impl Io + 'a, buffered: Vec<u8>) -> Upgraded<'a> {
+        Upgraded {
+            io: Box::new(io),
+            buffered,
+            pos: 0,
+        }
+    }
+}
+
+impl fmt::Debug for Upgraded<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgraded")
+            .field("buffered", &(self.buffered.len() - self.pos))
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for Upgraded<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pos < this.buffered.len() {
+            let rest = &this.buffered[this.pos..];
+            let n = rest.len().min(buf.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            this.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_close(cx)
+    }
+}
+
+/// 升级完成之后要执行的回调。
+///
+/// 回调拿到的连接借用自 [`serve`](crate::connection::serve)，
+/// 所以它返回的期物只能在这次借用期间运行。
+pub struct OnUpgrade(Box<dyn for<'a> FnOnce(Upgraded<'a>) -> BoxFuture<'a, ()> + Send>);
+
+impl OnUpgrade {
+    pub fn new<F>(callback: F) -> OnUpgrade
+    where
+        F: for<'a> FnOnce(Upgraded<'a>) -> BoxFuture<'a, ()> + Send + 'static,
+    {
+        OnUpgrade(Box::new(callback))
+    }
+
+    pub async fn run(self, io: Upgraded<'_>) {
+        (self.0)(io).await
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
@@ -0,0 +1,818 @@
+//! WebSocket（RFC 6455）：握手，以及握手之后按帧收发消息。
+//!
+//! [`upgrade`] 检查握手请求并生成 `101 Switching Protocols` 响应，
+//! 连接升级之后交给处理函数的是一个 [`WebSocket`]。它既是消息的 [`Stream`]，
+//! 也是消息的 [`Sink`]：
+//!
+//! - 收到的分片消息会被拼接完整之后再交出；发送的消息超过
+//!   [`Config::fragment_size`] 时会被拆成多个分片。
+//! - 收到 ping 时自动回复 pong，ping 本身仍会交给调用者。回复来不及发出时只回复最近的一个 ping。
+//! - 收到 close 时自动回复 close，交出这个 close 消息之后流就结束了；
+//!   对方违反协议时，会以对应的错误码发出 close，再把这个 close 交给调用者。
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{
+    future::BoxFuture,
+    io::{AsyncRead, AsyncWrite},
+    Sink, Stream,
+};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    request::{Method, Request},
+    response::{Response, StatusCode},
+    upgrade::{OnUpgrade, Upgraded},
+};
+
+/// 计算 `Sec-WebSocket-Accept` 时附加在客户端密钥之后的固定字符串。
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// 写缓冲区中积压的数据超过这个数量时，发送新消息之前要先等它写出去。
+const WRITE_BUFFER: usize = 64 * 1024;
+
+/// 常用的关闭状态码。
+pub mod close_code {
+    /// 正常关闭。
+    pub const NORMAL: u16 = 1000;
+    /// 对方违反了协议。
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    /// 文本消息不是合法的 UTF-8。
+    pub const INVALID_DATA: u16 = 1007;
+    /// 消息超过了 [`Config::max_message_size`](super::Config::max_message_size)。
+    pub const TOO_BIG: u16 = 1009;
+}
+
+/// 一条完整的消息。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// 关闭连接，可以带上状态码和原因。
+    Close(Option<CloseFrame>),
+}
+
+impl Message {
+    /// 是否是文本或二进制消息。
+    pub fn is_data(&self) -> bool {
+        matches!(self, Message::Text(_) | Message::Binary(_))
+    }
+}
+
+/// close 消息中的状态码和原因。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    fn new(code: u16, reason: &str) -> CloseFrame {
+        CloseFrame {
+            code,
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+/// 连接中的哪一方。客户端发出的帧必须加掩码，服务器发出的帧不能加掩码。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Server,
+    Client,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// 收到的一条消息（拼接所有分片之后）的最大长度。
+    pub max_message_size: usize,
+    /// 发送时每个帧最多携带的数据，更长的消息会被拆成多个分片。
+    pub fragment_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_message_size: 16 * 1024 * 1024,
+            fragment_size: 64 * 1024,
+        }
+    }
+}
+
+/// 根据客户端的 `Sec-WebSocket-Key` 计算 `Sec-WebSocket-Accept`。
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+/// 请求是否要求升级到 WebSocket。
+pub fn is_upgrade_request(request: &Request) -> bool {
+    request.headers.has_token("Connection", "upgrade")
+        && request.headers.has_token("Upgrade", "websocket")
+}
+
+/// 用默认配置完成握手，参见 [`upgrade_with_config`]。
+pub fn upgrade<H>(request: &Request, handler: H) -> Response
+where
+    H: for<'a> FnOnce(WebSocket<Upgraded<'a>>) -> BoxFuture<'a, ()> + Send + 'static,
+{
+    upgrade_with_config(request, Config::default(), handler)
+}
+
+/// 检查握手请求。请求合法时返回 `101 Switching Protocols`，
+/// 响应发出之后用升级得到的 [`WebSocket`] 调用 `handler`；否则返回对应的错误响应。
+///
+/// ```
+/// use final_tcp_server::{router::Router, upgrade::Upgraded, websocket::{self, WebSocket}};
+/// use futures::{SinkExt, StreamExt};
+///
+/// async fn echo(mut ws: WebSocket<Upgraded<'_>>) {
+///     while let Some(message) = ws.next().await {
+///         if message.is_data() && ws.send(message).await.is_err() {
+///             break;
+///         }
+///     }
+///     let _ = ws.close().await;
+/// }
+///
+/// let router = Router::new().get("/ws", |request| async move {
+///     websocket::upgrade(&request, |ws| Box::pin(echo(ws)))
+/// });
+/// ```
+pub fn upgrade_with_config<H>(request: &Request, config: Config, handler: H) -> Response
+where
+    H: for<'a> FnOnce(WebSocket<Upgraded<'a>>) -> BoxFuture<'a, ()> + Send + 'static,
+{
+    if request.method != Method::Get || !is_upgrade_request(request) {
+        return Response::text(StatusCode::UPGRADE_REQUIRED, "expected a WebSocket handshake\n")
+            .with_header("Upgrade", "websocket");
+    }
+    if request.headers.get("Sec-WebSocket-Version") != Some("13") {
+        return Response::text(StatusCode::UPGRADE_REQUIRED, "unsupported WebSocket version\n")
+            .with_header("Sec-WebSocket-Version", "13");
+    }
+    // 密钥是 16 个随机字节的 base64 编码。
+    let key = match request.headers.get("Sec-WebSocket-Key") {
+        Some(key) if STANDARD.decode(key).is_ok_and(|key| key.len() == 16) => key,
+        _ => return Response::text(StatusCode::BAD_REQUEST, "invalid Sec-WebSocket-Key\n"),
+    };
+
+    Response::new(StatusCode::SWITCHING_PROTOCOLS)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", accept_key(key))
+        .with_upgrade(OnUpgrade::new(move |io| {
+            handler(WebSocket::with_config(io, Role::Server, config))
+        }))
+}
+
+/// 帧的操作码。
+mod opcode {
+    pub const CONTINUATION: u8 = 0x0;
+    pub const TEXT: u8 = 0x1;
+    pub const BINARY: u8 = 0x2;
+    pub const CLOSE: u8 = 0x8;
+    pub const PING: u8 = 0x9;
+    pub const PONG: u8 = 0xA;
+}
+
+/// 解析出的一个帧，数据已经去掉了掩码。
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// 尝试从 `buf` 的开头解析出一个帧，成功时同时返回它占用的字节数。
+fn parse_frame(buf: &[u8], role: Role, max_size: usize) -> Result<Option<(Frame, usize)>, CloseFrame> {
+    let protocol_error = |reason| CloseFrame::new(close_code::PROTOCOL_ERROR, reason);
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    let fin = first & 0x80 != 0;
+    let opcode = first & 0x0F;
+    if first & 0x70 != 0 {
+        return Err(protocol_error("reserved bits are set"));
+    }
+    match opcode {
+        opcode::CONTINUATION | opcode::TEXT | opcode::BINARY => {}
+        opcode::CLOSE | opcode::PING | opcode::PONG => {
+            // 控制帧不能分片，最多带 125 字节的数据。
+            if !fin || second & 0x7F > 125 {
+                return Err(protocol_error("invalid control frame"));
+            }
+        }
+        _ => return Err(protocol_error("unknown opcode")),
+    }
+    let masked = second & 0x80 != 0;
+    if masked != (role == Role::Server) {
+        return Err(protocol_error(match role {
+            Role::Server => "client frames must be masked",
+            Role::Client => "server frames must not be masked",
+        }));
+    }
+
+    let (len, mut pos) = match second & 0x7F {
+        126 => match buf.get(2..4) {
+            Some(len) => (u16::from_be_bytes([len[0], len[1]]) as u64, 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(len) => (u64::from_be_bytes(len.try_into().unwrap()), 10),
+            None => return Ok(None),
+        },
+        len => (len as u64, 2),
+    };
+    if len > max_size as u64 {
+        return Err(CloseFrame::new(close_code::TOO_BIG, "message is too big"));
+    }
+    let mask = if masked {
+        let Some(mask) = buf.get(pos..pos + 4) else {
+            return Ok(None);
+        };
+        pos += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let end = pos + len as usize;
+    let Some(payload) = buf.get(pos..end) else {
+        return Ok(None);
+    };
+    let mut payload = payload.to_vec();
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// 一个已经完成握手的 WebSocket 连接。
+///
+/// 读取通过 [`Stream`] 进行，流结束表示连接已经关闭；写入通过 [`Sink`] 进行。
+/// 关闭时调用 [`SinkExt::close`](futures::SinkExt::close)，它会发出 close 消息（如果还没有发过），
+/// 之后继续读取直到流结束，就完成了关闭握手。
+#[derive(Debug)]
+pub struct WebSocket<S> {
+    stream: S,
+    role: Role,
+    config: Config,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    /// 还没有放进写缓冲区的自动回复的 pong，见 [`poll_write_buf`](Self::poll_write_buf)。
+    pending_pong: Option<Vec<u8>>,
+    /// 正在拼接的分片消息：第一个分片的操作码和已经收到的数据。
+    fragments: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    /// 收到了 close、读到了 EOF 或者出了错，之后不会再交出消息。
+    finished: bool,
+    /// 读取时遇到的 I/O 错误。
+    error: Option<io::Error>,
+    /// 生成掩码用的随机数状态，只有客户端需要。
+    mask_state: u64,
+}
+
+impl<S> WebSocket<S> {
+    pub fn new(stream: S, role: Role) -> WebSocket<S> {
+        WebSocket::with_config(stream, role, Config::default())
+    }
+
+    pub fn with_config(stream: S, role: Role, config: Config) -> WebSocket<S> {
+        WebSocket {
+            stream,
+            role,
+            config,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            pending_pong: None,
+            fragments: None,
+            close_sent: false,
+            finished: false,
+            error: None,
+            mask_state: RandomState::new().build_hasher().finish() | 1,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    /// 取出读取时遇到的 I/O 错误。流因为这样的错误提前结束时，可以用它查看原因。
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+
+    /// 把一个帧追加到写缓冲区中。
+    fn encode_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) {
+        let mask = match self.role {
+            Role::Client => Some(self.next_mask()),
+            Role::Server => None,
+        };
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        let buf = &mut self.write_buf;
+        buf.push(if fin { 0x80 } else { 0 } | opcode);
+        match payload.len() {
+            len if len < 126 => buf.push(mask_bit | len as u8),
+            len if len <= u16::MAX as usize => {
+                buf.push(mask_bit | 126);
+                buf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                buf.push(mask_bit | 127);
+                buf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        let start = buf.len();
+        match mask {
+            Some(mask) => {
+                buf.extend_from_slice(&mask);
+                buf.extend_from_slice(payload);
+                apply_mask(&mut buf[start + 4..], mask);
+            }
+            None => buf.extend_from_slice(payload),
+        }
+    }
+
+    fn encode_close(&mut self, frame: Option<&CloseFrame>) {
+        // close 之后不能再发任何帧，还没有发出的 pong 要抢在它前面。
+        if let Some(payload) = self.pending_pong.take() {
+            self.encode_frame(true, opcode::PONG, &payload);
+        }
+        let mut payload = Vec::new();
+        if let Some(frame) = frame {
+            payload.extend_from_slice(&frame.code.to_be_bytes());
+            payload.extend_from_slice(frame.reason.as_bytes());
+        }
+        self.encode_frame(true, opcode::CLOSE, &payload);
+        self.close_sent = true;
+    }
+
+    /// xorshift64，掩码只是为了防止中间代理的缓存投毒，不需要密码学强度的随机数。
+    fn next_mask(&mut self) -> [u8; 4] {
+        let mut x = self.mask_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.mask_state = x;
+        (x as u32).to_be_bytes()
+    }
+
+    /// 对方违反了协议：以 `close` 中的状态码关闭连接，并把这个 close 交给调用者。
+    fn fail(&mut self, close: CloseFrame) -> Message {
+        self.finished = true;
+        if !self.close_sent {
+            self.encode_close(Some(&close));
+        }
+        Message::Close(Some(close))
+    }
+
+    /// 处理一个帧，得到完整的消息时返回它。
+    fn on_frame(&mut self, frame: Frame) -> Option<Message> {
+        let opcode = match frame.opcode {
+            opcode::PING => {
+                if !self.close_sent {
+                    self.pending_pong = Some(frame.payload.clone());
+                }
+                return Some(Message::Ping(frame.payload));
+            }
+            opcode::PONG => return Some(Message::Pong(frame.payload)),
+            opcode::CLOSE => return Some(self.on_close(frame.payload)),
+            opcode::CONTINUATION => {
+                let Some((opcode, mut data)) = self.fragments.take() else {
+                    let close = CloseFrame::new(close_code::PROTOCOL_ERROR, "unexpected continuation frame");
+                    return Some(self.fail(close));
+                };
+                if data.len() + frame.payload.len() > self.config.max_message_size {
+                    return Some(self.fail(CloseFrame::new(close_code::TOO_BIG, "message is too big")));
+                }
+                data.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    self.fragments = Some((opcode, data));
+                    return None;
+                }
+                return Some(self.complete(opcode, data));
+            }
+            opcode => opcode,
+        };
+        if self.fragments.is_some() {
+            let close = CloseFrame::new(close_code::PROTOCOL_ERROR, "expected a continuation frame");
+            return Some(self.fail(close));
+        }
+        if !frame.fin {
+            self.fragments = Some((opcode, frame.payload));
+            return None;
+        }
+        Some(self.complete(opcode, frame.payload))
+    }
+
+    fn complete(&mut self, opcode: u8, data: Vec<u8>) -> Message {
+        if opcode == opcode::BINARY {
+            return Message::Binary(data);
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Message::Text(text),
+            Err(_) => self.fail(CloseFrame::new(close_code::INVALID_DATA, "text is not valid UTF-8")),
+        }
+    }
+
+    fn on_close(&mut self, payload: Vec<u8>) -> Message {
+        let frame = match payload.len() {
+            0 => None,
+            1 => {
+                let close = CloseFrame::new(close_code::PROTOCOL_ERROR, "truncated close frame");
+                return self.fail(close);
+            }
+            _ => {
+                let code = u16::from_be_bytes([payload[0], payload[1]]);
+                if !is_valid_close_code(code) {
+                    let close = CloseFrame::new(close_code::PROTOCOL_ERROR, "invalid close code");
+                    return self.fail(close);
+                }
+                let Ok(reason) = String::from_utf8(payload[2..].to_vec()) else {
+                    let close = CloseFrame::new(close_code::INVALID_DATA, "close reason is not valid UTF-8");
+                    return self.fail(close);
+                };
+                Some(CloseFrame { code, reason })
+            }
+        };
+        self.finished = true;
+        // 回复对方的 close 来完成关闭握手，状态码原样奉还。
+        if !self.close_sent {
+            self.encode_close(frame.as_ref());
+        }
+        Message::Close(frame)
+    }
+}
+
+/// 对方可以在 close 帧中使用的状态码（RFC 6455 第 7.4 节）。
+///
+/// 1004、1005、1006 和 1015 是保留的，1005 和 1006 只用来在本地表示没有状态码或者连接异常断开，
+/// 不能出现在帧中；1016 到 2999 留给以后的协议扩展；1000 以下不使用。
+fn is_valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+impl<S: AsyncWrite + Unpin> WebSocket<S> {
+    /// 把写缓冲区中的数据全部写出去并刷新。
+    ///
+    /// 自动回复的 pong 等写缓冲区空了才放进去，在那之前又收到 ping 时，
+    /// 只回复最近的一个（RFC 6455 第 5.5.3 节）。这样对方只发 ping 不读数据时，
+    /// 写缓冲区也不会无限增长。
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.is_empty() && self.pending_pong.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if self.write_buf.is_empty() {
+                let Some(payload) = self.pending_pong.take() else {
+                    break;
+                };
+                self.encode_frame(true, opcode::PONG, &payload);
+            }
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for WebSocket<S> {
+    type Item = Message;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let this = self.get_mut();
+        loop {
+            // 顺便把自动回复的 pong 和 close 发出去。写不动时不影响读取，
+            // 但连接已经结束时要等它们发完。
+            match this.poll_write_buf(cx) {
+                Poll::Ready(Err(e)) => {
+                    this.finished = true;
+                    this.error.get_or_insert(e);
+                    return Poll::Ready(None);
+                }
+                Poll::Pending if this.finished => return Poll::Pending,
+                _ => {}
+            }
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            match parse_frame(&this.read_buf, this.role, this.config.max_message_size) {
+                Ok(Some((frame, len))) => {
+                    this.read_buf.drain(..len);
+                    if let Some(message) = this.on_frame(frame) {
+                        return Poll::Ready(Some(message));
+                    }
+                }
+                Ok(None) => {
+                    let mut chunk = [0; 4096];
+                    match ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk)) {
+                        Ok(0) => {
+                            // 对方没有发 close 就断开了连接。
+                            this.finished = true;
+                            return Poll::Ready(None);
+                        }
+                        Ok(n) => this.read_buf.extend_from_slice(&chunk[..n]),
+                        Err(e) => {
+                            this.finished = true;
+                            this.error = Some(e);
+                            return Poll::Ready(None);
+                        }
+                    }
+                }
+                Err(close) => return Poll::Ready(Some(this.fail(close))),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Sink<Message> for WebSocket<S> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.write_buf.len() >= WRITE_BUFFER {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: Message) -> io::Result<()> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket is closing"));
+        }
+        let (opcode, data) = match message {
+            Message::Text(text) => (opcode::TEXT, text.into_bytes()),
+            Message::Binary(data) => (opcode::BINARY, data),
+            Message::Ping(data) | Message::Pong(data) if data.len() > 125 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "control frame payload is longer than 125 bytes",
+                ));
+            }
+            Message::Ping(data) => (opcode::PING, data),
+            Message::Pong(data) => (opcode::PONG, data),
+            Message::Close(frame) => {
+                this.encode_close(frame.as_ref());
+                return Ok(());
+            }
+        };
+        if data.is_empty() {
+            this.encode_frame(true, opcode, &[]);
+            return Ok(());
+        }
+        let size = this.config.fragment_size.max(1);
+        let count = data.len().div_ceil(size);
+        for (i, chunk) in data.chunks(size).enumerate() {
+            let opcode = if i == 0 { opcode } else { opcode::CONTINUATION };
+            this.encode_frame(i + 1 == count, opcode, chunk);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    /// 发出 close（如果还没有发过），写完缓冲区后关闭底层连接的写入端。
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.encode_close(Some(&CloseFrame::new(close_code::NORMAL, "")));
+        }
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{serve, Config as ConnectionConfig};
+    use futures::{
+        executor::block_on,
+        future,
+        io::{AsyncReadExt, AsyncWriteExt},
+        SinkExt, StreamExt,
+    };
+    use mock_stream::{duplex, MockStream};
+
+    /// 把收到的数据消息原样发回去，直到连接关闭。
+    async fn echo<S: AsyncRead + AsyncWrite + Unpin>(mut ws: WebSocket<S>) {
+        while let Some(message) = ws.next().await {
+            if message.is_data() {
+                ws.send(message).await.unwrap();
+            }
+        }
+        ws.close().await.unwrap();
+    }
+
+    fn handshake_request(key: &str, version: &str) -> Request {
+        let mut request = Request::new(Method::Get, "/ws");
+        request.headers.insert("Connection", "keep-alive, Upgrade");
+        request.headers.insert("Upgrade", "websocket");
+        request.headers.insert("Sec-WebSocket-Version", version);
+        request.headers.insert("Sec-WebSocket-Key", key);
+        request
+    }
+
+    #[test]
+    fn computes_the_accept_key() {
+        // RFC 6455 第 1.3 节中的例子。
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn validates_handshakes() {
+        let ok = upgrade(&handshake_request("dGhlIHNhbXBsZSBub25jZQ==", "13"), |ws| Box::pin(echo(ws)));
+        assert_eq!(ok.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(ok.headers().get("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let old = upgrade(&handshake_request("dGhlIHNhbXBsZSBub25jZQ==", "8"), |ws| Box::pin(echo(ws)));
+        assert_eq!(old.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(old.headers().get("Sec-WebSocket-Version"), Some("13"));
+        let short_key = upgrade(&handshake_request("c2hvcnQ=", "13"), |ws| Box::pin(echo(ws)));
+        assert_eq!(short_key.status(), StatusCode::BAD_REQUEST);
+        let plain = upgrade(&Request::new(Method::Get, "/ws"), |ws| Box::pin(echo(ws)));
+        assert_eq!(plain.status(), StatusCode::UPGRADE_REQUIRED);
+    }
+
+    #[test]
+    fn exchanges_fragmented_messages_pings_and_close() {
+        let (client, server) = duplex(64);
+        let small_frames = Config {
+            fragment_size: 4,
+            ..Config::default()
+        };
+        let mut client = WebSocket::with_config(client, Role::Client, small_frames);
+        let server = WebSocket::new(server, Role::Server);
+
+        let talk = async move {
+            let long = "a message split into many fragments".repeat(10);
+            client.send(Message::Text(long.clone())).await.unwrap();
+            client.send(Message::Binary(vec![1, 2, 3, 4, 5])).await.unwrap();
+            client.send(Message::Ping(b"ping".to_vec())).await.unwrap();
+
+            assert_eq!(client.next().await, Some(Message::Text(long)));
+            assert_eq!(client.next().await, Some(Message::Binary(vec![1, 2, 3, 4, 5])));
+            assert_eq!(client.next().await, Some(Message::Pong(b"ping".to_vec())));
+
+            // 发出 close 之后继续读，直到收到对方的回复。
+            client.close().await.unwrap();
+            let reply = client.next().await;
+            assert_eq!(reply, Some(Message::Close(Some(CloseFrame::new(close_code::NORMAL, "")))));
+            assert_eq!(client.next().await, None);
+        };
+        block_on(future::join(echo(server), talk));
+    }
+
+    #[test]
+    fn upgrades_a_connection_served_over_http() {
+        let (mut client, server) = duplex(256);
+        let config = ConnectionConfig::default();
+        let handler = |request: Request| async move { upgrade(&request, |ws| Box::pin(echo(ws))) };
+
+        let talk = async move {
+            // 握手请求后面紧跟着第一帧，服务器读请求时会把它一起读进缓冲区。
+            let mut first = WebSocket::new(MockStream::builder().build(), Role::Client);
+            first.send(Message::Text("early".into())).await.unwrap();
+            let mut bytes = b"GET /ws HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\n\
+                Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                .to_vec();
+            bytes.extend_from_slice(&first.get_ref().written());
+            client.write_all(&bytes).await.unwrap();
+
+            // 逐个字节读出响应头，不要读到后面的帧。
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                client.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{head}");
+            assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            assert!(!head.contains("Content-Length"));
+
+            let mut ws = WebSocket::new(client, Role::Client);
+            assert_eq!(ws.next().await, Some(Message::Text("early".into())));
+            ws.send(Message::Text("late".into())).await.unwrap();
+            assert_eq!(ws.next().await, Some(Message::Text("late".into())));
+            ws.close().await.unwrap();
+            assert!(matches!(ws.next().await, Some(Message::Close(_))));
+            assert_eq!(ws.next().await, None);
+        };
+        let (result, ()) = block_on(future::join(serve(server, &config, handler), talk));
+        result.unwrap();
+    }
+
+    #[test]
+    fn rejects_unmasked_client_frames() {
+        let stream = MockStream::builder().read([0x81, 0x02, b'h', b'i']).build();
+        let mut ws = WebSocket::new(stream, Role::Server);
+        let close = block_on(ws.next()).unwrap();
+        assert!(matches!(
+            close,
+            Message::Close(Some(CloseFrame { code: close_code::PROTOCOL_ERROR, .. }))
+        ));
+        assert_eq!(block_on(ws.next()), None);
+        // 回复的 close 帧带着同样的状态码。
+        let written = ws.get_ref().written();
+        assert_eq!(&written[..4], &[0x88, written[1], 0x03, 0xEA]);
+    }
+
+    #[test]
+    fn answers_invalid_close_codes_with_a_protocol_error() {
+        let close = |code: u16| {
+            // 客户端的帧必须带掩码，全零的掩码不改变数据。
+            let mut frame = vec![0x88, 0x82, 0, 0, 0, 0];
+            frame.extend_from_slice(&code.to_be_bytes());
+            let mut ws = WebSocket::new(MockStream::builder().read(frame).build(), Role::Server);
+            let message = block_on(ws.next());
+            assert_eq!(block_on(ws.next()), None);
+            let written = ws.get_ref().written();
+            (message, u16::from_be_bytes([written[2], written[3]]))
+        };
+        for code in [0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            let (message, reply) = close(code);
+            assert!(
+                matches!(
+                    message,
+                    Some(Message::Close(Some(CloseFrame { code: close_code::PROTOCOL_ERROR, .. })))
+                ),
+                "{code}: {message:?}"
+            );
+            assert_eq!(reply, close_code::PROTOCOL_ERROR, "{code}");
+        }
+        for code in [1000, 1003, 1007, 1011, 3000, 4999] {
+            let (message, reply) = close(code);
+            assert_eq!(message, Some(Message::Close(Some(CloseFrame::new(code, "")))));
+            assert_eq!(reply, code);
+        }
+    }
+
+    #[test]
+    fn answers_only_the_latest_ping_while_writes_are_blocked() {
+        // 客户端只发 ping 不读数据，服务器写不出去的 pong 不能越攒越多。
+        let (client, server) = duplex(64);
+        let mut client = WebSocket::new(client, Role::Client);
+        let mut server = WebSocket::new(server, Role::Server);
+        block_on(async {
+            for i in 0..100u32 {
+                let ping = i.to_be_bytes().to_vec();
+                client.send(Message::Ping(ping.clone())).await.unwrap();
+                assert_eq!(server.next().await, Some(Message::Ping(ping)));
+            }
+        });
+        assert!(server.write_buf.len() <= 6, "{}", server.write_buf.len());
+
+        let read_pongs = async {
+            let mut pongs = 0;
+            loop {
+                match client.next().await {
+                    Some(Message::Pong(payload)) => {
+                        pongs += 1;
+                        if payload == 99u32.to_be_bytes() {
+                            return pongs;
+                        }
+                    }
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+        };
+        let (flushed, pongs) = block_on(future::join(server.flush(), read_pongs));
+        flushed.unwrap();
+        assert!(pongs < 100, "{pongs}");
+    }
+}
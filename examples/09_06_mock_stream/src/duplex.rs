@@ -0,0 +1,230 @@
+//! 内存中的双向管道。
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+/// 创建一对相互连接的端点，写入一端的数据可以从另一端读出。
+///
+/// 每个方向最多缓存 `capacity` 个字节，缓冲区满了之后写入会等待对端读走数据，
+/// 这样就能测试到真实连接中的背压。关闭或丢弃一端之后，另一端读完剩下的数据会得到 EOF，
+/// 再写入则会得到 `BrokenPipe` 错误。
+///
+/// ```
+/// use example_09_06_mock_stream::duplex;
+/// use futures::{executor::block_on, future, io::{AsyncReadExt, AsyncWriteExt}};
+///
+/// let (mut client, mut server) = duplex(4);
+/// let echo = async move {
+///     let mut buf = [0; 16];
+///     let n = server.read(&mut buf).await.unwrap();
+///     server.write_all(&buf[..n]).await.unwrap();
+/// };
+/// let talk = async move {
+///     client.write_all(b"ping").await.unwrap();
+///     let mut reply = String::new();
+///     client.read_to_string(&mut reply).await.unwrap();
+///     reply
+/// };
+/// let ((), reply) = block_on(future::join(echo, talk));
+/// assert_eq!(reply, "ping");
+/// ```
+pub fn duplex(capacity: usize) -> (DuplexStream, DuplexStream) {
+    assert!(capacity > 0, "capacity must be positive");
+    let a = Arc::new(Mutex::new(Pipe::new(capacity)));
+    let b = Arc::new(Mutex::new(Pipe::new(capacity)));
+    (
+        DuplexStream {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        DuplexStream { read: b, write: a },
+    )
+}
+
+/// [`duplex`] 创建的一个端点。
+#[derive(Debug)]
+pub struct DuplexStream {
+    /// 对端写入、本端读取的方向。
+    read: Arc<Mutex<Pipe>>,
+    /// 本端写入、对端读取的方向。
+    write: Arc<Mutex<Pipe>>,
+}
+
+/// 一个方向上的缓冲区。
+#[derive(Debug)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    /// 写入的一端已经关闭，读完缓冲区之后就是 EOF。
+    write_closed: bool,
+    /// 读取的一端已经被丢弃，再写入也没有人读了。
+    read_closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Pipe {
+    fn new(capacity: usize) -> Pipe {
+        Pipe {
+            buffer: VecDeque::new(),
+            capacity,
+            write_closed: false,
+            read_closed: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+}
+
+fn lock(pipe: &Mutex<Pipe>) -> MutexGuard<'_, Pipe> {
+    pipe.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn wake(waker: Option<Waker>) {
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl AsyncRead for DuplexStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.read);
+        if pipe.buffer.is_empty() {
+            if pipe.write_closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = pipe.buffer.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buffer.drain(..n)) {
+            *dst = src;
+        }
+        // 腾出了空间，让等待的写入者继续。
+        let waker = pipe.write_waker.take();
+        drop(pipe);
+        wake(waker);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for DuplexStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.write);
+        if pipe.write_closed || pipe.read_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let room = pipe.capacity - pipe.buffer.len();
+        if room == 0 {
+            pipe.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        pipe.buffer.extend(&buf[..n]);
+        let waker = pipe.read_waker.take();
+        drop(pipe);
+        wake(waker);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut pipe = lock(&self.write);
+        pipe.write_closed = true;
+        let waker = pipe.read_waker.take();
+        drop(pipe);
+        wake(waker);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for DuplexStream {
+    fn drop(&mut self) {
+        let mut pipe = lock(&self.write);
+        pipe.write_closed = true;
+        let reader = pipe.read_waker.take();
+        drop(pipe);
+        wake(reader);
+
+        let mut pipe = lock(&self.read);
+        pipe.read_closed = true;
+        let writer = pipe.write_waker.take();
+        drop(pipe);
+        wake(writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        future,
+        io::{AsyncReadExt, AsyncWriteExt},
+        task::noop_waker_ref,
+    };
+
+    #[test]
+    fn small_buffers_apply_backpressure() {
+        let (mut a, mut b) = duplex(3);
+        let data: Vec<u8> = (0..=255).collect();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        assert!(matches!(Pin::new(&mut a).poll_write(&mut cx, &data), Poll::Ready(Ok(3))));
+        assert!(Pin::new(&mut a).poll_write(&mut cx, &data[3..]).is_pending());
+
+        let expected = data.clone();
+        let reader = async move {
+            let mut received = Vec::new();
+            b.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, expected);
+        };
+        let writer = async move {
+            a.write_all(&data[3..]).await.unwrap();
+            a.close().await.unwrap();
+        };
+        block_on(future::join(writer, reader));
+    }
+
+    #[test]
+    fn dropping_one_end_closes_both_directions() {
+        let (mut a, b) = duplex(8);
+        block_on(a.write_all(b"left")).unwrap();
+        drop(b);
+        let e = block_on(a.write(b"x")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(block_on(a.read(&mut [0; 4])).unwrap(), 0);
+
+        // 对端只是关闭了写入的一侧时，仍然可以读到它之前写入的数据。
+        let (mut a, mut b) = duplex(8);
+        block_on(async {
+            b.write_all(b"bye").await.unwrap();
+            b.close().await.unwrap();
+            let mut rest = Vec::new();
+            a.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"bye");
+            a.write_all(b"still").await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,490 @@
+//! 用于测试的模拟连接。
+//!
+//! 第 9 章测试中的 `MockTcpStream` 为了简单，忽略了 `Context`，每次读取都返回同样的数据，
+//! 每次写入都覆盖之前写入的内容。[`MockStream`] 则按照预先写好的脚本行事：
+//!
+//! - 读取的数据按块给出，读完一块再读下一块，全部读完之后返回 EOF；
+//! - 可以在任意两块数据之间插入一次 `Poll::Pending`，并且在返回 `Pending`
+//!   之前安排好唤醒，不会让任务永远睡下去；
+//! - 可以在读取脚本的某个位置、或者写入到某个偏移量时注入错误；
+//! - 写入的数据会累积起来，可以限制每次写入接受的字节数来模拟“短写”。
+//!
+//! 需要在测试运行过程中继续提供数据时，用 [`Builder::build_with_handle`] 同时拿到一个
+//! [`Handle`]。如果想让一个真正的客户端和服务器对话，可以用 [`duplex`] 创建一对
+//! 在内存中相互连接的端点。
+//!
+//! ```
+//! use example_09_06_mock_stream::MockStream;
+//! use futures::{executor::block_on, io::{AsyncReadExt, AsyncWriteExt}};
+//!
+//! let mut stream = MockStream::builder()
+//!     .read(b"ping")
+//!     .pending()
+//!     .read(b"\n")
+//!     .max_write(2)
+//!     .build();
+//! block_on(async {
+//!     let mut line = String::new();
+//!     stream.read_to_string(&mut line).await.unwrap();
+//!     assert_eq!(line, "ping\n");
+//!     stream.write_all(b"pong\n").await.unwrap();
+//! });
+//! assert_eq!(stream.written(), b"pong\n");
+//! ```
+
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+mod duplex;
+
+pub use duplex::{duplex, DuplexStream};
+
+/// 读取脚本中的一步。
+#[derive(Debug)]
+enum Action {
+    Data(Vec<u8>),
+    /// 返回一次 `Pending`。
+    Pending,
+    Error(io::ErrorKind),
+}
+
+#[derive(Debug)]
+struct State {
+    reads: VecDeque<Action>,
+    /// 脚本读完之后，还有没有可能通过 [`Handle`] 得到更多的数据。
+    open: bool,
+    read_waker: Option<Waker>,
+
+    written: Vec<u8>,
+    max_write: usize,
+    /// 写到这个偏移量时返回错误。
+    write_error: Option<(usize, io::ErrorKind)>,
+    /// 接下来还要返回几次 `Pending`。
+    write_pending: usize,
+    writes_paused: bool,
+    write_waker: Option<Waker>,
+    closed: bool,
+}
+
+fn injected(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "injected by MockStream")
+}
+
+/// 按照脚本读写的模拟连接，由 [`Builder`] 创建。
+#[derive(Debug)]
+pub struct MockStream {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockStream {
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    /// 到目前为止写入的全部数据。
+    pub fn written(&self) -> Vec<u8> {
+        lock(&self.state).written.clone()
+    }
+
+    /// 是否已经被关闭（调用过 `poll_close`）。
+    pub fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    // 测试中的断言失败会让持有锁的线程恐慌，这时仍然允许查看数据。
+    state.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = lock(&self.state);
+        loop {
+            match state.reads.pop_front() {
+                // 空的数据块会被当成 EOF，直接跳过。
+                Some(Action::Data(data)) if data.is_empty() => continue,
+                Some(Action::Data(mut data)) => {
+                    let n = data.len().min(buf.len());
+                    buf[..n].copy_from_slice(&data[..n]);
+                    if n < data.len() {
+                        data.drain(..n);
+                        state.reads.push_front(Action::Data(data));
+                    }
+                    return Poll::Ready(Ok(n));
+                }
+                Some(Action::Pending) => {
+                    // 模拟数据稍后到达：先安排唤醒，下一次轮询时继续执行脚本。
+                    cx.waker().wake_by_ref();
+                    return Poll::Pending;
+                }
+                Some(Action::Error(kind)) => return Poll::Ready(Err(injected(kind))),
+                None if state.open => {
+                    state.read_waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = lock(&self.state);
+        if state.closed {
+            return Poll::Ready(Err(injected(io::ErrorKind::BrokenPipe)));
+        }
+        if state.writes_paused {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        if state.write_pending > 0 {
+            state.write_pending -= 1;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        let mut n = buf.len().min(state.max_write);
+        if let Some((offset, kind)) = state.write_error {
+            let room = offset.saturating_sub(state.written.len());
+            if room == 0 && !buf.is_empty() {
+                return Poll::Ready(Err(injected(kind)));
+            }
+            // 先写到出错的位置为止，下一次写入再返回错误。
+            n = n.min(room);
+        }
+        state.written.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        lock(&self.state).closed = true;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// 编写 [`MockStream`] 的脚本。
+#[derive(Debug)]
+pub struct Builder {
+    reads: VecDeque<Action>,
+    max_write: usize,
+    write_error: Option<(usize, io::ErrorKind)>,
+    write_pending: usize,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Builder {
+            reads: VecDeque::new(),
+            max_write: usize::MAX,
+            write_error: None,
+            write_pending: 0,
+        }
+    }
+}
+
+impl Builder {
+    /// 接下来可以读到 `data`。
+    ///
+    /// 每一块数据至少要用一次读取来取走，读取的缓冲区比数据块小时则需要多次，
+    /// 所以可以用多个数据块来模拟分成几个 TCP 包到达的请求。
+    pub fn read(mut self, data: impl AsRef<[u8]>) -> Self {
+        self.reads.push_back(Action::Data(data.as_ref().to_vec()));
+        self
+    }
+
+    /// 接下来的一次读取返回 `Pending`，并且立即唤醒任务。
+    pub fn pending(mut self) -> Self {
+        self.reads.push_back(Action::Pending);
+        self
+    }
+
+    /// 读完前面的数据之后，下一次读取返回 `kind` 类型的错误。
+    pub fn read_error(mut self, kind: io::ErrorKind) -> Self {
+        self.reads.push_back(Action::Error(kind));
+        self
+    }
+
+    /// 每次写入最多接受 `max` 个字节。
+    pub fn max_write(mut self, max: usize) -> Self {
+        assert!(max > 0, "a write that accepts nothing means EOF");
+        self.max_write = max;
+        self
+    }
+
+    /// 累计写入 `offset` 个字节之后，再写入就返回 `kind` 类型的错误。
+    pub fn write_error_at(mut self, offset: usize, kind: io::ErrorKind) -> Self {
+        self.write_error = Some((offset, kind));
+        self
+    }
+
+    /// 接下来的 `times` 次写入返回 `Pending`，每次都会立即唤醒任务。
+    pub fn write_pending(mut self, times: usize) -> Self {
+        self.write_pending = times;
+        self
+    }
+
+    /// 创建模拟连接，脚本中的数据读完之后返回 EOF。
+    pub fn build(self) -> MockStream {
+        MockStream {
+            state: Arc::new(Mutex::new(self.into_state(false))),
+        }
+    }
+
+    /// 创建模拟连接和控制它的 [`Handle`]。
+    ///
+    /// 脚本中的数据读完之后，读取会一直等待，直到通过 `Handle` 提供更多数据，
+    /// 或者 `Handle` 被关闭或丢弃。
+    pub fn build_with_handle(self) -> (MockStream, Handle) {
+        let state = Arc::new(Mutex::new(self.into_state(true)));
+        (
+            MockStream {
+                state: state.clone(),
+            },
+            Handle { state },
+        )
+    }
+
+    fn into_state(self, open: bool) -> State {
+        State {
+            reads: self.reads,
+            open,
+            read_waker: None,
+            written: Vec::new(),
+            max_write: self.max_write,
+            write_error: self.write_error,
+            write_pending: self.write_pending,
+            writes_paused: false,
+            write_waker: None,
+            closed: false,
+        }
+    }
+}
+
+/// 在测试运行过程中控制 [`MockStream`]，相当于连接的另一端。
+///
+/// 被丢弃时会调用 [`close`](Handle::close)。
+#[derive(Debug)]
+pub struct Handle {
+    state: Arc<Mutex<State>>,
+}
+
+impl Handle {
+    /// 提供更多可以读取的数据，并唤醒正在等待数据的任务。
+    pub fn read(&self, data: impl AsRef<[u8]>) {
+        self.push(Action::Data(data.as_ref().to_vec()));
+    }
+
+    /// 让下一次读取返回错误，并唤醒正在等待数据的任务。
+    pub fn read_error(&self, kind: io::ErrorKind) {
+        self.push(Action::Error(kind));
+    }
+
+    /// 读完已经提供的数据之后返回 EOF，相当于对端关闭了写入的一侧。
+    pub fn close(&self) {
+        let mut state = lock(&self.state);
+        state.open = false;
+        let waker = state.read_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// 暂停接受写入，写入会一直返回 `Pending`，相当于对端不再读取数据。
+    pub fn pause_writes(&self) {
+        lock(&self.state).writes_paused = true;
+    }
+
+    /// 恢复接受写入，并唤醒正在等待写入的任务。
+    pub fn resume_writes(&self) {
+        let mut state = lock(&self.state);
+        state.writes_paused = false;
+        let waker = state.write_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// 到目前为止写入的全部数据。
+    pub fn written(&self) -> Vec<u8> {
+        lock(&self.state).written.clone()
+    }
+
+    /// 取出到目前为止写入的数据，之后的写入从头开始累积。
+    pub fn take_written(&self) -> Vec<u8> {
+        let mut state = lock(&self.state);
+        // 写入错误的偏移量是相对于全部写入的数据的，这里要跟着调整。
+        let taken = state.written.len();
+        if let Some((offset, _)) = &mut state.write_error {
+            *offset = offset.saturating_sub(taken);
+        }
+        std::mem::take(&mut state.written)
+    }
+
+    /// 模拟连接是否已经被关闭。
+    pub fn is_closed(&self) -> bool {
+        lock(&self.state).closed
+    }
+
+    fn push(&self, action: Action) {
+        let mut state = lock(&self.state);
+        state.reads.push_back(action);
+        let waker = state.read_waker.take();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::block_on,
+        io::{AsyncReadExt, AsyncWriteExt},
+        task::{noop_waker_ref, waker, ArcWake},
+        FutureExt,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// 记录被唤醒次数的唤醒器。
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(arc_self: &Arc<Self>) {
+            arc_self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn reads_follow_the_script() {
+        let mut stream = MockStream::builder()
+            .read(b"hello")
+            .read(b"")
+            .read(b" world")
+            .read_error(io::ErrorKind::ConnectionReset)
+            .read(b"!")
+            .build();
+        let mut buf = [0; 3];
+        let mut read = |stream: &mut MockStream| block_on(stream.read(&mut buf)).map(|n| buf[..n].to_vec());
+        assert_eq!(read(&mut stream).unwrap(), b"hel");
+        assert_eq!(read(&mut stream).unwrap(), b"lo");
+        assert_eq!(read(&mut stream).unwrap(), b" wo");
+        assert_eq!(read(&mut stream).unwrap(), b"rld");
+        let e = read(&mut stream).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(read(&mut stream).unwrap(), b"!");
+        assert_eq!(read(&mut stream).unwrap(), b"");
+        assert_eq!(read(&mut stream).unwrap(), b"");
+    }
+
+    #[test]
+    fn pending_wakes_the_task() {
+        let counter = Arc::new(Counter::default());
+        let waker = waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let mut stream = MockStream::builder().pending().read(b"x").build();
+        let mut buf = [0; 1];
+
+        let mut read = stream.read(&mut buf);
+        assert!(read.poll_unpin(&mut cx).is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(read.poll_unpin(&mut cx), Poll::Ready(Ok(1))));
+    }
+
+    #[test]
+    fn handle_feeds_data_later() {
+        let counter = Arc::new(Counter::default());
+        let waker = waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let (mut stream, handle) = MockStream::builder().read(b"a").build_with_handle();
+        let mut buf = [0; 4];
+
+        assert!(matches!(stream.read(&mut buf).poll_unpin(&mut cx), Poll::Ready(Ok(1))));
+        assert!(stream.read(&mut buf).poll_unpin(&mut cx).is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+        handle.read(b"bc");
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(stream.read(&mut buf).poll_unpin(&mut cx), Poll::Ready(Ok(2))));
+
+        assert!(stream.read(&mut buf).poll_unpin(&mut cx).is_pending());
+        drop(handle);
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+        assert!(matches!(stream.read(&mut buf).poll_unpin(&mut cx), Poll::Ready(Ok(0))));
+    }
+
+    #[test]
+    fn writes_accumulate_in_short_pieces() {
+        let mut stream = MockStream::builder().max_write(2).write_pending(3).build();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(matches!(Pin::new(&mut stream).poll_write(&mut cx, b"abc"), Poll::Pending));
+
+        block_on(async {
+            stream.write_all(b"hello ").await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+            stream.close().await.unwrap();
+        });
+        assert_eq!(stream.written(), b"hello world");
+        assert!(stream.is_closed());
+        let e = block_on(stream.write(b"more")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn write_errors_at_offset() {
+        let (mut stream, handle) = MockStream::builder()
+            .write_error_at(4, io::ErrorKind::ConnectionReset)
+            .build_with_handle();
+        assert_eq!(block_on(stream.write(b"abc")).unwrap(), 3);
+        assert_eq!(block_on(stream.write(b"def")).unwrap(), 1);
+        let e = block_on(stream.write(b"ef")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(handle.take_written(), b"abcd");
+        assert_eq!(block_on(stream.write(b"")).unwrap(), 0);
+    }
+
+    #[test]
+    fn paused_writes_wait_for_resume() {
+        let counter = Arc::new(Counter::default());
+        let waker = waker(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let (mut stream, handle) = MockStream::builder().build_with_handle();
+        handle.pause_writes();
+        assert!(Pin::new(&mut stream).poll_write(&mut cx, b"x").is_pending());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+        handle.resume_writes();
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert!(matches!(Pin::new(&mut stream).poll_write(&mut cx, b"x"), Poll::Ready(Ok(1))));
+        assert_eq!(handle.written(), b"x");
+    }
+}
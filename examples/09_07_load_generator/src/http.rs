@@ -0,0 +1,280 @@
+//! 发送请求、读出响应所需的最少的 HTTP/1.1。
+//!
+//! 第 9 章前面几个版本的服务器并不规范：响应没有 `Content-Length`，写完就关闭连接，
+//! `09_04` 甚至只回一个 `hello`。所以这里的读取很宽容：能按 `Content-Length` 或 `chunked`
+//! 确定响应的结尾就照做，否则一直读到连接关闭；开头不是 `HTTP/` 的响应也照样读完，
+//! 只是没有状态码。
+
+use std::io;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// 响应头部的上限，超过之后认为对方发来的不是 HTTP 响应。
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+/// 每次从连接上读取的字节数。
+const READ_CHUNK: usize = 16 * 1024;
+
+/// 读完的一个响应。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// 状态码，响应不是 HTTP 格式时为 `None`。
+    pub status: Option<u16>,
+    /// 响应体的字节数。
+    pub body_len: usize,
+    /// 这个连接能否继续发送下一个请求。
+    pub reusable: bool,
+}
+
+/// 生成一个 `GET` 请求。
+pub fn request(host: &str, path: &str, keep_alive: bool) -> Vec<u8> {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: {connection}\r\n\r\n").into_bytes()
+}
+
+/// 在 `stream` 上发送 `request` 并读出完整的响应。
+///
+/// `buf` 中是上一个响应之后多读出来的数据，读完之后剩下的数据仍然留在里面。
+/// 连接在收到任何数据之前就被关闭时返回 `UnexpectedEof` 错误，
+/// 这通常说明服务器关闭了一个空闲的保持连接，换一个新连接重试即可。
+pub async fn exchange(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    buf: &mut Vec<u8>,
+    request: &[u8],
+) -> io::Result<Response> {
+    stream.write_all(request).await?;
+    stream.flush().await?;
+    read_response(stream, buf).await
+}
+
+/// 读出一个响应。
+pub async fn read_response(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> io::Result<Response> {
+    let head_len = loop {
+        if !buf.is_empty() && !b"HTTP/".starts_with(&buf[..buf.len().min(5)]) {
+            // 不是 HTTP 响应，只能读到连接关闭为止。
+            let body_len = buf.len() + read_to_eof(stream).await?;
+            buf.clear();
+            return Ok(Response {
+                status: None,
+                body_len,
+                reusable: false,
+            });
+        }
+        if let Some(pos) = find(buf, b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(invalid("response head is too large"));
+        }
+        if fill(stream, buf).await? == 0 {
+            // 没有头部结束标记，就把已经收到的数据当作整个响应。
+            return match buf.is_empty() {
+                true => Err(io::ErrorKind::UnexpectedEof.into()),
+                false => Ok(Response {
+                    status: None,
+                    body_len: discard(buf),
+                    reusable: false,
+                }),
+            };
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    buf.drain(..head_len);
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let mut parts = status_line.split(' ');
+    let version = parts.next().unwrap_or_default();
+    let status = parts.next().and_then(|code| code.parse().ok());
+    let (mut content_length, mut chunked, mut close) = (None, false, version == "HTTP/1.0");
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = Some(value.parse().map_err(|_| invalid("bad Content-Length"))?)
+            }
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+
+    let no_body = matches!(status, Some(100..=199 | 204 | 304));
+    let (body_len, framed) = if no_body {
+        (0, true)
+    } else if chunked {
+        (read_chunked(stream, buf).await?, true)
+    } else if let Some(len) = content_length {
+        skip(stream, buf, len).await?;
+        (len, true)
+    } else {
+        (discard(buf) + read_to_eof(stream).await?, false)
+    };
+    Ok(Response {
+        status,
+        body_len,
+        reusable: framed && !close,
+    })
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// 清空 `buf`，返回其中原有的字节数。
+fn discard(buf: &mut Vec<u8>) -> usize {
+    let len = buf.len();
+    buf.clear();
+    len
+}
+
+async fn fill(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> io::Result<usize> {
+    let mut chunk = [0; READ_CHUNK];
+    let n = stream.read(&mut chunk).await?;
+    buf.extend_from_slice(&chunk[..n]);
+    Ok(n)
+}
+
+async fn read_to_eof(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<usize> {
+    let mut chunk = [0; READ_CHUNK];
+    let mut total = 0;
+    loop {
+        match stream.read(&mut chunk).await? {
+            0 => return Ok(total),
+            n => total += n,
+        }
+    }
+}
+
+/// 跳过 `len` 个字节的响应体，多读出的数据留在 `buf` 中。
+async fn skip(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+    len: usize,
+) -> io::Result<()> {
+    let buffered = len.min(buf.len());
+    buf.drain(..buffered);
+    let mut remaining = len - buffered;
+    let mut chunk = [0; READ_CHUNK];
+    while remaining > 0 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if n > remaining {
+            // 多读出来的部分属于下一个响应。
+            buf.extend_from_slice(&chunk[remaining..n]);
+            return Ok(());
+        }
+        remaining -= n;
+    }
+    Ok(())
+}
+
+/// 读出一个 `chunked` 编码的响应体，返回解码之后的长度。尾部头部会被跳过。
+async fn read_chunked(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let mut total = 0;
+    loop {
+        let line = read_line(stream, buf).await?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| invalid("bad chunk size"))?;
+        if size == 0 {
+            // 尾部头部，以一个空行结束。
+            while !read_line(stream, buf).await?.is_empty() {}
+            return Ok(total);
+        }
+        skip(stream, buf, size).await?;
+        if !read_line(stream, buf).await?.is_empty() {
+            return Err(invalid("missing CRLF after chunk"));
+        }
+        total += size;
+    }
+}
+
+async fn read_line(stream: &mut (impl AsyncRead + Unpin), buf: &mut Vec<u8>) -> io::Result<String> {
+    loop {
+        if let Some(pos) = find(buf, b"\r\n") {
+            let line = String::from_utf8_lossy(&buf[..pos]).into_owned();
+            buf.drain(..pos + 2);
+            return Ok(line);
+        }
+        if buf.len() > MAX_HEAD_BYTES {
+            return Err(invalid("line is too long"));
+        }
+        if fill(stream, buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    fn read_all(input: &[u8]) -> Vec<io::Result<Response>> {
+        let mut stream = Cursor::new(input.to_vec());
+        let mut buf = Vec::new();
+        let mut responses = Vec::new();
+        loop {
+            let response = block_on(read_response(&mut stream, &mut buf));
+            let done = !matches!(&response, Ok(r) if r.reusable);
+            responses.push(response);
+            if done {
+                return responses;
+            }
+        }
+    }
+
+    #[test]
+    fn reads_framed_responses_back_to_back() {
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello\
+            HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2;x=y\r\nde\r\n0\r\n\r\n\
+            HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+        let responses: Vec<_> = read_all(input).into_iter().map(Result::unwrap).collect();
+        let summary: Vec<_> = responses
+            .iter()
+            .map(|r| (r.status, r.body_len, r.reusable))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some(200), 5, true),
+                (Some(404), 5, true),
+                (Some(204), 0, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_unframed_responses_to_eof() {
+        // `09_01` 到 `09_03` 的响应：没有 `Content-Length`，写完就关闭连接。
+        let response = read_all(b"HTTP/1.1 200 OK\r\n\r\n<html></html>")
+            .remove(0)
+            .unwrap();
+        assert_eq!(response.status, Some(200));
+        assert_eq!((response.body_len, response.reusable), (13, false));
+
+        // `09_04` 的响应只有一个 `hello`。
+        let response = read_all(b"hello").remove(0).unwrap();
+        assert_eq!((response.status, response.body_len), (None, 5));
+
+        let error = read_all(b"").remove(0).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,10 @@
+//! 第 9 章各个版本的 TCP 服务器的压测工具。
+//!
+//! 用若干个并发任务向服务器发出一定数量的 `GET` 请求，统计延迟的分位数和吞吐量，
+//! 用来比较单线程、逐个处理连接的 `09_01`，并发处理连接的 `09_04`，以及最终版本 `09_05`
+//! 在不同并发度下的表现。请求多个路径时（例如 `09_03` 中的 `/sleep` 和 `/`），
+//! 还会分别列出每个路径的延迟，可以看出一个慢请求会不会拖慢其他请求。
+
+pub mod http;
+pub mod report;
+pub mod run;
@@ -0,0 +1,197 @@
+//! 压测第 9 章的 TCP 服务器。
+//!
+//! 压测一个已经在运行的服务器：
+//!
+//! ```text
+//! cargo run --release -p load_generator -- -c 50 -n 5000 --keep-alive off
+//! ```
+//!
+//! 也可以用 `--server` 依次启动几个版本的服务器（通过 `cargo run --release`，
+//! 工作目录是各自的示例目录），分别压测之后关闭，最后列出对比。`09_03` 的场景是
+//! 一个 `/sleep` 请求混在普通请求之中，用 `--path` 给出多个路径，请求会轮流使用它们：
+//!
+//! ```text
+//! cargo run --release -p load_generator -- -c 8 -n 40 --path /sleep --path / \
+//!     --server 09_01_sync_tcp_server --server 09_04_concurrent_tcp_server \
+//!     --server 09_05_final_tcp_server
+//! ```
+
+use std::{
+    env, fmt,
+    net::TcpStream,
+    path::PathBuf,
+    process::{self, Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use load_generator::{
+    report::{pad, Report},
+    run::Options,
+};
+
+/// 等待 `--server` 启动的服务器开始监听的最长时间，其中包括编译的时间。
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(300);
+
+const USAGE: &str = "\
+用法：load_generator [选项]
+
+  -c, --concurrency N     同时进行的请求数（默认 10）
+  -n, --requests N        请求的总数（默认 1000）
+      --keep-alive on|off 是否在一个连接上发送多个请求（默认 on）
+      --path PATH         请求的路径，可以重复给出，请求会轮流使用（默认 /）
+      --addr HOST:PORT    服务器的地址（默认 127.0.0.1:7878）
+      --timeout SECS      单个请求的最长时间（默认 30）
+      --server EXAMPLE    先启动 examples 下的这个示例再压测，可以重复给出
+  -h, --help              显示这段说明";
+
+#[async_std::main]
+async fn main() {
+    let (options, servers) = match parse_args(env::args().skip(1)) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{USAGE}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            process::exit(2);
+        }
+    };
+    println!(
+        "并发 {}，请求 {} 个，保持连接：{}，路径：{}",
+        options.concurrency,
+        options.requests,
+        if options.keep_alive { "on" } else { "off" },
+        options.paths.join(" ")
+    );
+
+    if servers.is_empty() {
+        println!("\n== {} ==", options.addr);
+        print!("{}", load_generator::run::run(options).await);
+        return;
+    }
+    let mut results = Vec::new();
+    for server in servers {
+        println!("\n== {server} ==");
+        let mut child = start_server(&server, &options.addr).unwrap_or_else(|e| {
+            eprintln!("无法启动 {server}：{e}");
+            process::exit(1);
+        });
+        let report = load_generator::run::run(options.clone()).await;
+        let _ = child.kill();
+        let _ = child.wait();
+        print!("{report}");
+        results.push((server, report));
+    }
+    println!("\n{}", Comparison(&results));
+}
+
+/// 解析命令行参数，要求显示帮助时返回 `None`。
+fn parse_args(
+    mut args: impl Iterator<Item = String>,
+) -> Result<Option<(Options, Vec<String>)>, String> {
+    let mut options = Options::default();
+    let mut paths = Vec::new();
+    let mut servers = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        }
+        let value = args.next().ok_or_else(|| format!("{arg} 需要一个值"))?;
+        let number = || {
+            value
+                .parse::<usize>()
+                .map_err(|_| format!("{arg} 的值不是一个数：{value}"))
+        };
+        match arg.as_str() {
+            "-c" | "--concurrency" => options.concurrency = number()?.max(1),
+            "-n" | "--requests" => options.requests = number()?,
+            "--keep-alive" => {
+                options.keep_alive = match value.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(format!("--keep-alive 的值应当是 on 或 off：{value}")),
+                }
+            }
+            "--path" if value.starts_with('/') => paths.push(value),
+            "--path" => return Err(format!("路径应当以 / 开头：{value}")),
+            "--addr" => options.addr = value,
+            "--timeout" => options.timeout = Duration::from_secs(number()? as u64),
+            "--server" => servers.push(value),
+            _ => return Err(format!("未知的选项：{arg}")),
+        }
+    }
+    if !paths.is_empty() {
+        options.paths = paths;
+    }
+    Ok(Some((options, servers)))
+}
+
+/// 在示例 `example` 的目录中通过 `cargo run --release` 启动服务器，等到它开始监听 `addr`。
+fn start_server(example: &str, addr: &str) -> Result<Child, String> {
+    if TcpStream::connect(addr).is_ok() {
+        return Err(format!("{addr} 上已经有程序在监听了"));
+    }
+    let examples = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("..");
+    let dir = examples.join(example);
+    let manifest = std::fs::read_to_string(dir.join("Cargo.toml"))
+        .map_err(|e| format!("找不到示例 {}：{e}", dir.display()))?;
+    let package = manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("name = "))
+        .map(|name| name.trim_matches('"'))
+        .ok_or("Cargo.toml 中没有包名")?;
+    // 服务器从工作目录读取 `hello.html` 等文件，所以要在示例自己的目录中运行。
+    let mut child = Command::new(env::var("CARGO").unwrap_or("cargo".to_owned()))
+        .args(["run", "--release", "--quiet", "--package", package])
+        .current_dir(&dir)
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            return Err(format!("服务器退出了：{status}"));
+        }
+        if start.elapsed() > STARTUP_TIMEOUT {
+            let _ = child.kill();
+            return Err(format!(
+                "服务器没有在 {STARTUP_TIMEOUT:?} 之内开始监听 {addr}"
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(child)
+}
+
+/// 几个服务器的结果对比。
+struct Comparison<'a>(&'a [(String, Report)]);
+
+impl fmt::Display for Comparison<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self.0.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+        writeln!(
+            f,
+            "{} {:>10} {:>6} {:>10} {:>10}",
+            pad("服务器", width),
+            "req/s",
+            "failed",
+            "p50 ms",
+            "p99 ms"
+        )?;
+        for (name, report) in self.0 {
+            let mut latencies = report.latencies.clone();
+            let ms = |d: Option<Duration>| d.map_or(f64::NAN, |d| d.as_secs_f64() * 1000.0);
+            writeln!(
+                f,
+                "{} {:>10.1} {:>6} {:>10.2} {:>10.2}",
+                pad(name, width),
+                report.throughput(),
+                report.failed(),
+                ms(latencies.percentile(50.0)),
+                ms(latencies.percentile(99.0))
+            )?;
+        }
+        writeln!(f, "（延迟只统计成功的请求）")
+    }
+}
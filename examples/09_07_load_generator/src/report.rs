@@ -0,0 +1,276 @@
+//! 统计结果：延迟的分位数、吞吐量和各种结果的计数。
+
+use std::{collections::BTreeMap, fmt, io, time::Duration};
+
+use crate::http::Response;
+
+/// 报告中列出的延迟分位数。
+const PERCENTILES: [f64; 4] = [50.0, 90.0, 99.0, 99.9];
+
+/// 一组延迟样本。
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Latencies {
+    samples: Vec<Duration>,
+    sorted: bool,
+}
+
+impl Latencies {
+    pub fn push(&mut self, latency: Duration) {
+        self.samples.push(latency);
+        self.sorted = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    fn sort(&mut self) {
+        if !self.sorted {
+            self.samples.sort_unstable();
+            self.sorted = true;
+        }
+    }
+
+    /// 第 `p` 百分位的延迟（最近秩法），没有样本时返回 `None`。
+    pub fn percentile(&mut self, p: f64) -> Option<Duration> {
+        self.sort();
+        let rank = (p / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples
+            .get(rank.clamp(1, self.samples.len().max(1)) - 1)
+            .copied()
+    }
+
+    pub fn min(&mut self) -> Option<Duration> {
+        self.sort();
+        self.samples.first().copied()
+    }
+
+    pub fn max(&mut self) -> Option<Duration> {
+        self.sort();
+        self.samples.last().copied()
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let total: Duration = self.samples.iter().sum();
+        Some(total / u32::try_from(self.samples.len()).ok().filter(|&n| n > 0)?)
+    }
+
+    fn extend(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.sorted = false;
+    }
+}
+
+/// 一次压测的结果。
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// 从第一个请求开始到最后一个请求结束的时间。
+    pub elapsed: Duration,
+    /// 所有成功请求的延迟。
+    pub latencies: Latencies,
+    /// 失败请求（包括超时）从发出到失败的时间，与成功请求的延迟分开统计。
+    pub failures: Latencies,
+    /// 按路径分开的成功请求的延迟，只在请求了多个路径时才有意义。
+    pub by_path: BTreeMap<String, Latencies>,
+    /// 各个状态码的响应数，不是 HTTP 格式的响应记作 `-`。
+    pub statuses: BTreeMap<String, usize>,
+    /// 各种错误的次数。
+    pub errors: BTreeMap<String, usize>,
+    /// 建立的连接数。
+    pub connections: usize,
+    /// 收到的响应体的总字节数。
+    pub body_bytes: u64,
+}
+
+impl Report {
+    /// 记录一个请求的结果。超时和其他错误一样计入失败，
+    /// 它们的耗时记在 `failures` 中，不计入成功请求的延迟。
+    pub fn record(&mut self, path: &str, result: io::Result<Response>, latency: Duration) {
+        match result {
+            Ok(response) => {
+                self.latencies.push(latency);
+                self.by_path
+                    .entry(path.to_owned())
+                    .or_default()
+                    .push(latency);
+                let status = response.status.map_or("-".to_owned(), |s| s.to_string());
+                *self.statuses.entry(status).or_default() += 1;
+                self.body_bytes += response.body_len as u64;
+            }
+            Err(e) => {
+                self.failures.push(latency);
+                *self.errors.entry(describe(&e)).or_default() += 1;
+            }
+        }
+    }
+
+    /// 合并另一个并发任务的结果。
+    pub fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.failures.extend(other.failures);
+        for (path, latencies) in other.by_path {
+            self.by_path.entry(path).or_default().extend(latencies);
+        }
+        for (status, n) in other.statuses {
+            *self.statuses.entry(status).or_default() += n;
+        }
+        for (error, n) in other.errors {
+            *self.errors.entry(error).or_default() += n;
+        }
+        self.connections += other.connections;
+        self.body_bytes += other.body_bytes;
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.latencies.len()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.errors.values().sum()
+    }
+
+    /// 每秒完成的成功请求数。
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.succeeded() as f64 / secs,
+        }
+    }
+}
+
+fn describe(e: &io::Error) -> String {
+    match e.kind() {
+        io::ErrorKind::TimedOut => "timed out".to_owned(),
+        io::ErrorKind::Other => e.to_string(),
+        kind => kind.to_string(),
+    }
+}
+
+/// 把 `s` 用空格补齐到 `width` 列宽，中文等全角字符占两列。
+pub fn pad(s: &str, width: usize) -> String {
+    let columns: usize = s.chars().map(|c| if c > '\u{2e7f}' { 2 } else { 1 }).sum();
+    format!("{s}{}", " ".repeat(width.saturating_sub(columns)))
+}
+
+fn millis(latency: Option<Duration>) -> String {
+    latency.map_or("-".to_owned(), |d| {
+        format!("{:.2}", d.as_secs_f64() * 1000.0)
+    })
+}
+
+fn latency_row(f: &mut fmt::Formatter<'_>, label: &str, latencies: &Latencies) -> fmt::Result {
+    let mut latencies = latencies.clone();
+    write!(f, "  {} {:>9}", pad(label, 16), millis(latencies.min()))?;
+    for p in PERCENTILES {
+        write!(f, " {:>9}", millis(latencies.percentile(p)))?;
+    }
+    writeln!(
+        f,
+        " {:>9} {:>9}",
+        millis(latencies.max()),
+        millis(latencies.mean())
+    )
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "成功 {} 个，失败 {} 个，建立连接 {} 个，收到响应体 {} 字节",
+            self.succeeded(),
+            self.failed(),
+            self.connections,
+            self.body_bytes
+        )?;
+        writeln!(
+            f,
+            "用时 {:.3} 秒，吞吐量 {:.1} 请求/秒",
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )?;
+        let statuses: Vec<_> = self
+            .statuses
+            .iter()
+            .map(|(s, n)| format!("{s} × {n}"))
+            .collect();
+        if !statuses.is_empty() {
+            writeln!(f, "状态码：{}", statuses.join("，"))?;
+        }
+        for (error, n) in &self.errors {
+            writeln!(f, "错误：{error} × {n}")?;
+        }
+        write!(f, "{} {:>9}", pad("延迟（毫秒）", 18), "min")?;
+        for p in PERCENTILES {
+            write!(f, " {:>9}", format!("p{p}"))?;
+        }
+        writeln!(f, " {:>9} {:>9}", "max", "mean")?;
+        // 失败的请求往往要等到超时，混在一起会让分位数失去意义，所以分开列出。
+        latency_row(f, "成功", &self.latencies)?;
+        if self.by_path.len() > 1 {
+            for (path, latencies) in &self.by_path {
+                latency_row(f, path, latencies)?;
+            }
+        }
+        if !self.failures.is_empty() {
+            latency_row(f, "失败", &self.failures)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn computes_nearest_rank_percentiles() {
+        let mut latencies = Latencies::default();
+        for ms in (1..=100).rev() {
+            latencies.push(Duration::from_millis(ms));
+        }
+        let ms = |d: Option<Duration>| d.unwrap().as_millis();
+        assert_eq!(ms(latencies.percentile(50.0)), 50);
+        assert_eq!(ms(latencies.percentile(99.0)), 99);
+        assert_eq!(ms(latencies.percentile(99.9)), 100);
+        assert_eq!(ms(latencies.percentile(0.0)), 1);
+        assert_eq!((ms(latencies.min()), ms(latencies.max())), (1, 100));
+        assert_eq!(latencies.mean(), Some(Duration::from_micros(50_500)));
+        assert_eq!(Latencies::default().percentile(50.0), None);
+        assert_eq!(Latencies::default().mean(), None);
+    }
+
+    #[test]
+    fn merges_and_summarizes_results() {
+        let ok = |status| Response {
+            status,
+            body_len: 10,
+            reusable: true,
+        };
+        let mut report = Report::default();
+        report.record("/", Ok(ok(Some(200))), Duration::from_millis(1));
+        let mut other = Report::default();
+        other.record("/sleep", Ok(ok(None)), Duration::from_millis(3));
+        other.record(
+            "/",
+            Err(io::ErrorKind::TimedOut.into()),
+            Duration::from_secs(1),
+        );
+        other.connections = 2;
+        report.merge(other);
+        report.elapsed = Duration::from_millis(500);
+
+        assert_eq!((report.succeeded(), report.failed()), (2, 1));
+        assert_eq!(report.throughput(), 4.0);
+        assert_eq!(report.by_path["/sleep"].len(), 1);
+        let text = report.to_string();
+        assert!(text.contains("状态码：- × 1，200 × 1"), "{text}");
+        assert!(text.contains("错误：timed out × 1"), "{text}");
+        assert!(text.contains("  /sleep "), "{text}");
+        assert!(text.contains("  失败 "), "{text}");
+        assert_eq!(report.failures.clone().max(), Some(Duration::from_secs(1)));
+    }
+}
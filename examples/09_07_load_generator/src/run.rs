@@ -0,0 +1,281 @@
+//! 并发地发出请求。
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_std::{future::timeout, net::TcpStream, task};
+use futures::{
+    future,
+    io::{AsyncRead, AsyncWrite},
+};
+
+use crate::{
+    http::{self, Response},
+    report::Report,
+};
+
+/// 压测的参数。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// 服务器的地址。
+    pub addr: String,
+    /// 同时进行的请求数，每个并发任务各自使用一个连接。
+    pub concurrency: usize,
+    /// 请求的总数。
+    pub requests: usize,
+    /// 是否在一个连接上连续发送多个请求。服务器不支持时会自动改为每个请求一个连接。
+    pub keep_alive: bool,
+    /// 请求的路径，第 `i` 个请求使用 `paths[i % paths.len()]`。
+    pub paths: Vec<String>,
+    /// 单个请求（包括建立连接）的最长时间。
+    pub timeout: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            addr: "127.0.0.1:7878".to_owned(),
+            concurrency: 10,
+            requests: 1000,
+            keep_alive: true,
+            paths: vec!["/".to_owned()],
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// 一个并发任务持有的连接。
+struct Connection {
+    stream: TcpStream,
+    /// 上一个响应之后多读出来的数据。
+    buf: Vec<u8>,
+}
+
+/// 按照 `options` 发出所有请求，等它们都结束之后返回统计结果。
+///
+/// 每个并发任务都通过 `task::spawn` 运行在 async-std 的线程池上，
+/// 避免压测工具自己成为瓶颈。
+pub async fn run(options: Options) -> Report {
+    let options = Arc::new(options);
+    let next = Arc::new(AtomicUsize::new(0));
+    let start = Instant::now();
+    let workers =
+        (0..options.concurrency.max(1)).map(|_| task::spawn(worker(options.clone(), next.clone())));
+    let mut report = Report::default();
+    for partial in future::join_all(workers).await {
+        report.merge(partial);
+    }
+    report.elapsed = start.elapsed();
+    report
+}
+
+/// 不断领取下一个请求的编号，直到所有请求都发出去了。
+async fn worker(options: Arc<Options>, next: Arc<AtomicUsize>) -> Report {
+    let mut report = Report::default();
+    let mut connection = None;
+    loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        if i >= options.requests {
+            return report;
+        }
+        let path = &options.paths[i % options.paths.len()];
+        let request = http::request(&options.addr, path, options.keep_alive);
+        let start = Instant::now();
+        let exchange = send(&mut connection, &options.addr, &request, &mut report);
+        let result = match timeout(options.timeout, exchange).await {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::new(io::ErrorKind::TimedOut, e)),
+        };
+        // 出错、超时或者服务器不打算继续使用的连接都不能再用了。
+        if !matches!(&result, Ok(response) if response.reusable && options.keep_alive) {
+            connection = None;
+        }
+        report.record(path, result, start.elapsed());
+    }
+}
+
+/// 在已有的连接上发送请求，没有连接时先建立一个。
+async fn send(
+    connection: &mut Option<Connection>,
+    addr: &str,
+    request: &[u8],
+    report: &mut Report,
+) -> io::Result<Response> {
+    if let Some(Connection { stream, buf }) = connection {
+        let mut counted = Counted { stream, read: 0 };
+        match http::exchange(&mut counted, buf, request).await {
+            // 服务器可能已经关闭了这个空闲的连接，换一个新连接重试一次。
+            // 已经收到了一部分响应时就不能这样做了：那是一个真正失败的请求，不能悄悄算作成功。
+            Err(e) if counted.read == 0 && is_stale(&e) => *connection = None,
+            result => return result,
+        }
+    }
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    report.connections += 1;
+    let Connection { stream, buf } = connection.insert(Connection {
+        stream,
+        buf: Vec::new(),
+    });
+    http::exchange(stream, buf, request).await
+}
+
+/// 记下从连接上读到了多少字节。
+struct Counted<'a> {
+    stream: &'a mut TcpStream,
+    read: usize,
+}
+
+impl AsyncRead for Counted<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut *self.stream).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.read += n;
+        }
+        result
+    }
+}
+
+impl AsyncWrite for Counted<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_close(cx)
+    }
+}
+
+/// 在复用的连接上还没有收到任何响应就出现的这些错误，说明服务器已经关闭了这个连接。
+fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+    use futures::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        stream::StreamExt,
+    };
+
+    /// 一个极简的服务器：每个连接最多回答 `per_connection` 个请求，之后关闭连接。
+    async fn serve(listener: TcpListener, per_connection: usize) {
+        listener
+            .incoming()
+            .for_each_concurrent(None, |stream| async move {
+                let mut stream = stream.unwrap();
+                let mut buf = Vec::new();
+                let mut answered = 0;
+                let mut chunk = [0; 1024];
+                while answered < per_connection {
+                    let Ok(n) = stream.read(&mut chunk).await else {
+                        return;
+                    };
+                    if n == 0 {
+                        return;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+                    while let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let close = buf[..end].ends_with(b"close");
+                        buf.drain(..end + 4);
+                        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+                        stream.write_all(response).await.unwrap();
+                        answered += 1;
+                        if close {
+                            return;
+                        }
+                    }
+                }
+            })
+            .await;
+    }
+
+    async fn run_against(per_connection: usize, keep_alive: bool) -> Report {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        task::spawn(serve(listener, per_connection));
+        run(Options {
+            addr,
+            concurrency: 4,
+            requests: 40,
+            keep_alive,
+            ..Options::default()
+        })
+        .await
+    }
+
+    #[async_std::test]
+    async fn reuses_connections_and_reconnects_when_closed() {
+        let report = run_against(usize::MAX, true).await;
+        assert_eq!((report.succeeded(), report.failed()), (40, 0));
+        assert_eq!(report.connections, 4);
+        assert_eq!(report.statuses["200"], 40);
+
+        // 服务器每回答 5 个请求就关闭连接，客户端要悄悄换新连接重试。
+        let report = run_against(5, true).await;
+        assert_eq!((report.succeeded(), report.failed()), (40, 0));
+        assert!(report.connections >= 8, "{}", report.connections);
+
+        let report = run_against(usize::MAX, false).await;
+        assert_eq!((report.succeeded(), report.connections), (40, 40));
+        assert_eq!(report.body_bytes, 80);
+    }
+
+    #[async_std::test]
+    async fn truncated_responses_on_reused_connections_are_not_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        // 每个连接上的第一个响应是完整的，第二个只发出一半就关闭连接。
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(Ok(mut stream)) = incoming.next().await {
+                let mut chunk = [0; 1024];
+                for response in [
+                    &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"[..],
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nok",
+                ] {
+                    let _ = stream.read(&mut chunk).await;
+                    stream.write_all(response).await.unwrap();
+                }
+            }
+        });
+        let report = run(Options {
+            addr,
+            concurrency: 1,
+            requests: 2,
+            ..Options::default()
+        })
+        .await;
+        assert_eq!((report.succeeded(), report.failed()), (1, 1));
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.errors["unexpected end of file"], 1);
+    }
+}